@@ -0,0 +1,45 @@
+DROP FUNCTION create_order(uuid_nn, uuid_nn);
+DROP FUNCTION cart_delete_item(uuid_nn, uuid_nn, uuid_nn);
+DROP FUNCTION cart_update_item(uuid_nn, uuid_nn, uuid_nn, text_nn);
+DROP FUNCTION cart_create_item(uuid_nn, uuid_nn, uuid_nn, text, int_nn, text_nn);
+DROP FUNCTION put_cart(uuid, uuid_nn);
+DROP FUNCTION check_cart(uuid_nn, uuid_nn);
+DROP TABLE shop_order_item;
+DROP TABLE shop_order;
+DROP TABLE cart_item;
+DROP TABLE cart;
+DROP TABLE guest_session;
+
+DROP FUNCTION check_customize_selection(jsonb, jsonb);
+DROP FUNCTION shop_delete_product(uuid_nn, uuid_nn);
+DROP FUNCTION shop_set_product_has_picture(uuid_nn, uuid_nn, boolean);
+DROP FUNCTION shop_update_product(uuid_nn, uuid_nn, text_nn);
+DROP FUNCTION shop_create_product(uuid_nn, text_nn);
+DROP TABLE shop_product;
+
+DROP FUNCTION shop_user_update_authority(uuid_nn, uuid_nn, uuid_nn, authority_nn, permission_nn);
+DROP FUNCTION shop_user_create(uuid_nn, uuid_nn, uuid_nn);
+DROP FUNCTION create_shop(uuid_nn, text_nz);
+DROP FUNCTION assert_shop_user_authority(uuid_nn, uuid_nn, authority_nn, permission_nn);
+DROP FUNCTION check_shop_user_authority(uuid_nn, uuid_nn, authority_nn, permission_nn);
+DROP TABLE shop_user;
+DROP TABLE shop;
+
+DROP FUNCTION get_session_user(uuid_nn);
+DROP FUNCTION signout_user(uuid_nn);
+DROP FUNCTION signin_user(text_nz, text_nz);
+DROP FUNCTION register_user(uuid);
+DROP TABLE user_session;
+DROP TABLE user_register_session;
+DROP TABLE users;
+
+DROP FUNCTION option_create(text_nz, int_nn);
+DROP TYPE option;
+DROP DOMAIN authority_nn;
+DROP TYPE authority;
+DROP DOMAIN permission_nn;
+DROP TYPE permission;
+DROP DOMAIN uuid_nn;
+DROP DOMAIN int_nn;
+DROP DOMAIN text_nz;
+DROP DOMAIN text_nn;
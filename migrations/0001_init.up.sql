@@ -0,0 +1,494 @@
+-- Initial schema: the types, tables and functions the handlers rely on.
+--
+-- Functions report expected failures with custom SQLSTATE codes, mapped to
+-- API errors by `impl From<tokio_postgres::error::Error> for Error`:
+--
+--   C2002  user session (USSID) expired
+--   C3001  guest session (GSSID) expired
+--   C4101  customize selection not found
+--   C4301  customize selection not provided
+--   C6001  shop name has been used
+--   C6002  user lacks the shop authority
+--   C6003  shop not found
+--   C6004  shop member not found
+--   C6009  shop product not found
+--   C8001  cart not found
+--   C8002  cart item not found
+--   C8103  cart item expired
+
+CREATE EXTENSION IF NOT EXISTS "uuid-ossp";
+CREATE EXTENSION IF NOT EXISTS pgcrypto;
+
+CREATE DOMAIN text_nn AS text NOT NULL;
+CREATE DOMAIN text_nz AS text NOT NULL CHECK (VALUE <> '');
+CREATE DOMAIN int_nn AS integer NOT NULL;
+CREATE DOMAIN uuid_nn AS uuid NOT NULL;
+
+-- Ordered from the least to the most permissive.
+CREATE TYPE permission AS ENUM ('none', 'read-only', 'all');
+CREATE DOMAIN permission_nn AS permission NOT NULL;
+
+CREATE TYPE authority AS ENUM ('member_authority', 'order_authority', 'product_authority');
+CREATE DOMAIN authority_nn AS authority NOT NULL;
+
+CREATE TYPE option AS (
+    name text_nz,
+    price int_nn
+);
+
+CREATE FUNCTION option_create(name text_nz, price int_nn) RETURNS option AS $$
+    SELECT ROW($1, $2)::option;
+$$ LANGUAGE sql IMMUTABLE;
+
+------------------------------------------------------------------------------
+-- Users
+------------------------------------------------------------------------------
+
+CREATE TABLE users (
+    id uuid PRIMARY KEY DEFAULT uuid_generate_v4(),
+    username text NOT NULL UNIQUE,
+    -- bcrypt hash produced by `crypt`.
+    password text NOT NULL,
+    nickname text,
+    email text UNIQUE,
+    phone text UNIQUE,
+    created_at timestamptz NOT NULL DEFAULT now()
+);
+
+CREATE TABLE user_register_session (
+    id uuid PRIMARY KEY DEFAULT uuid_generate_v4(),
+    username text,
+    password text,
+    email text,
+    phone text,
+    created_at timestamptz NOT NULL DEFAULT now()
+);
+
+CREATE TABLE user_session (
+    id uuid PRIMARY KEY DEFAULT uuid_generate_v4(),
+    user_id uuid NOT NULL REFERENCES users (id) ON DELETE CASCADE,
+    created_at timestamptz NOT NULL DEFAULT now(),
+    expire_at timestamptz NOT NULL DEFAULT now() + interval '30 days'
+);
+
+CREATE INDEX user_session_user_id_idx ON user_session (user_id);
+
+-- Create the user from a complete register session and delete the session.
+CREATE FUNCTION register_user(_regssid uuid) RETURNS boolean AS $$
+DECLARE
+    _session user_register_session;
+BEGIN
+    SELECT * INTO _session FROM user_register_session WHERE id = _regssid FOR UPDATE;
+    IF NOT FOUND
+        OR _session.username IS NULL
+        OR _session.password IS NULL
+        OR _session.email IS NULL
+        OR _session.phone IS NULL
+    THEN
+        RETURN false;
+    END IF;
+
+    INSERT INTO users (username, password, nickname, email, phone)
+    VALUES (
+        _session.username,
+        crypt(_session.password, gen_salt('bf')),
+        _session.username,
+        _session.email,
+        _session.phone
+    );
+    DELETE FROM user_register_session WHERE id = _regssid;
+    RETURN true;
+END;
+$$ LANGUAGE plpgsql;
+
+-- Returns the id of the new session, or NULL if the credentials don't match.
+CREATE FUNCTION signin_user(_username text_nz, _password text_nz) RETURNS uuid AS $$
+DECLARE
+    _user_id uuid;
+    _session_id uuid;
+BEGIN
+    SELECT id INTO _user_id FROM users
+    WHERE username = _username AND password = crypt(_password, password);
+    IF NOT FOUND THEN
+        RETURN NULL;
+    END IF;
+
+    INSERT INTO user_session (user_id) VALUES (_user_id) RETURNING id INTO _session_id;
+    RETURN _session_id;
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE FUNCTION signout_user(_ussid uuid_nn) RETURNS void AS $$
+    DELETE FROM user_session WHERE id = _ussid;
+$$ LANGUAGE sql;
+
+CREATE FUNCTION get_session_user(_ussid uuid_nn) RETURNS uuid AS $$
+DECLARE
+    _user_id uuid;
+BEGIN
+    SELECT user_id INTO _user_id FROM user_session
+    WHERE id = _ussid AND expire_at > now();
+    IF NOT FOUND THEN
+        RAISE EXCEPTION 'User session expired.' USING ERRCODE = 'C2002';
+    END IF;
+    RETURN _user_id;
+END;
+$$ LANGUAGE plpgsql;
+
+------------------------------------------------------------------------------
+-- Shops
+------------------------------------------------------------------------------
+
+CREATE TABLE shop (
+    id uuid PRIMARY KEY DEFAULT uuid_generate_v4(),
+    name text NOT NULL UNIQUE,
+    created_at timestamptz NOT NULL DEFAULT now()
+);
+
+CREATE TABLE shop_user (
+    shop_id uuid NOT NULL REFERENCES shop (id) ON DELETE CASCADE,
+    user_id uuid NOT NULL REFERENCES users (id) ON DELETE CASCADE,
+    member_authority permission NOT NULL DEFAULT 'none',
+    order_authority permission NOT NULL DEFAULT 'none',
+    product_authority permission NOT NULL DEFAULT 'none',
+    PRIMARY KEY (shop_id, user_id)
+);
+
+CREATE FUNCTION check_shop_user_authority(
+    _shop_id uuid_nn,
+    _user_id uuid_nn,
+    _authority authority_nn,
+    _permission permission_nn
+) RETURNS boolean AS $$
+    SELECT COALESCE(
+        (
+            SELECT
+                CASE _authority::authority
+                    WHEN 'member_authority' THEN member_authority
+                    WHEN 'order_authority' THEN order_authority
+                    WHEN 'product_authority' THEN product_authority
+                END >= _permission::permission
+            FROM shop_user
+            WHERE shop_id = _shop_id AND user_id = _user_id
+        ),
+        false
+    );
+$$ LANGUAGE sql STABLE;
+
+CREATE FUNCTION assert_shop_user_authority(
+    _shop_id uuid_nn,
+    _user_id uuid_nn,
+    _authority authority_nn,
+    _permission permission_nn
+) RETURNS void AS $$
+BEGIN
+    IF NOT check_shop_user_authority(_shop_id, _user_id, _authority, _permission) THEN
+        RAISE EXCEPTION 'User has no % % of the shop.', _permission, _authority USING ERRCODE = 'C6002';
+    END IF;
+END;
+$$ LANGUAGE plpgsql STABLE;
+
+-- Create a shop owned by the user, who gets all the authorities.
+CREATE FUNCTION create_shop(_user_id uuid_nn, _shop_name text_nz) RETURNS uuid AS $$
+DECLARE
+    _shop_id uuid;
+BEGIN
+    IF EXISTS (SELECT 1 FROM shop WHERE name = _shop_name) THEN
+        RAISE EXCEPTION 'Shop name has been used.' USING ERRCODE = 'C6001';
+    END IF;
+
+    INSERT INTO shop (name) VALUES (_shop_name) RETURNING id INTO _shop_id;
+    INSERT INTO shop_user (shop_id, user_id, member_authority, order_authority, product_authority)
+    VALUES (_shop_id, _user_id, 'all', 'all', 'all');
+    RETURN _shop_id;
+END;
+$$ LANGUAGE plpgsql;
+
+-- Add a member without any authority to the shop.
+CREATE FUNCTION shop_user_create(_user_id uuid_nn, _shop_id uuid_nn, _member_id uuid_nn) RETURNS void AS $$
+BEGIN
+    PERFORM assert_shop_user_authority(_shop_id, _user_id, 'member_authority', 'all');
+    INSERT INTO shop_user (shop_id, user_id) VALUES (_shop_id, _member_id)
+    ON CONFLICT DO NOTHING;
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE FUNCTION shop_user_update_authority(
+    _user_id uuid_nn,
+    _shop_id uuid_nn,
+    _member_id uuid_nn,
+    _authority authority_nn,
+    _permission permission_nn
+) RETURNS void AS $$
+BEGIN
+    PERFORM assert_shop_user_authority(_shop_id, _user_id, 'member_authority', 'all');
+    UPDATE shop_user SET
+        member_authority = CASE WHEN _authority::authority = 'member_authority' THEN _permission ELSE member_authority END,
+        order_authority = CASE WHEN _authority::authority = 'order_authority' THEN _permission ELSE order_authority END,
+        product_authority = CASE WHEN _authority::authority = 'product_authority' THEN _permission ELSE product_authority END
+    WHERE shop_id = _shop_id AND user_id = _member_id;
+    IF NOT FOUND THEN
+        RAISE EXCEPTION 'Shop member not found.' USING ERRCODE = 'C6004';
+    END IF;
+END;
+$$ LANGUAGE plpgsql;
+
+------------------------------------------------------------------------------
+-- Products
+------------------------------------------------------------------------------
+
+-- The payload is a JSON object, its optional "customizes" member maps each
+-- customize name to an object whose "selections" member maps each selection
+-- name to its price, e.g.
+-- {"name": "Tea", "price": 30, "customizes": {"size": {"selections": {"L": 10, "M": 0}}}}
+CREATE TABLE shop_product (
+    shop_id uuid NOT NULL REFERENCES shop (id) ON DELETE CASCADE,
+    key uuid NOT NULL DEFAULT uuid_generate_v4(),
+    payload jsonb NOT NULL,
+    has_picture boolean NOT NULL DEFAULT false,
+    latest_update timestamptz NOT NULL DEFAULT now(),
+    PRIMARY KEY (shop_id, key)
+);
+
+CREATE FUNCTION shop_create_product(_shop_id uuid_nn, _payload text_nn) RETURNS TABLE (product_key uuid) AS $$
+BEGIN
+    IF NOT EXISTS (SELECT 1 FROM shop WHERE id = _shop_id) THEN
+        RAISE EXCEPTION 'Shop not found.' USING ERRCODE = 'C6003';
+    END IF;
+
+    RETURN QUERY
+    INSERT INTO shop_product (shop_id, payload) VALUES (_shop_id, _payload::jsonb)
+    RETURNING shop_product.key;
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE FUNCTION shop_update_product(_shop_id uuid_nn, _product_key uuid_nn, _payload text_nn) RETURNS void AS $$
+BEGIN
+    UPDATE shop_product SET payload = _payload::jsonb, latest_update = now()
+    WHERE shop_id = _shop_id AND key = _product_key;
+    IF NOT FOUND THEN
+        RAISE EXCEPTION 'Shop product not found.' USING ERRCODE = 'C6009';
+    END IF;
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE FUNCTION shop_set_product_has_picture(_shop_id uuid_nn, _product_key uuid_nn, _has_picture boolean) RETURNS void AS $$
+BEGIN
+    UPDATE shop_product SET has_picture = _has_picture
+    WHERE shop_id = _shop_id AND key = _product_key;
+    IF NOT FOUND THEN
+        RAISE EXCEPTION 'Shop product not found.' USING ERRCODE = 'C6009';
+    END IF;
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE FUNCTION shop_delete_product(_shop_id uuid_nn, _product_key uuid_nn) RETURNS void AS $$
+BEGIN
+    DELETE FROM shop_product WHERE shop_id = _shop_id AND key = _product_key;
+    IF NOT FOUND THEN
+        RAISE EXCEPTION 'Shop product not found.' USING ERRCODE = 'C6009';
+    END IF;
+END;
+$$ LANGUAGE plpgsql;
+
+-- Check that the selection picks an existing option for every customize of the product.
+CREATE FUNCTION check_customize_selection(_product_payload jsonb, _cus_sel jsonb) RETURNS void AS $$
+DECLARE
+    _customize record;
+    _selection text;
+BEGIN
+    IF jsonb_typeof(_product_payload -> 'customizes') <> 'object' THEN
+        RETURN;
+    END IF;
+
+    FOR _customize IN SELECT * FROM jsonb_each(_product_payload -> 'customizes') LOOP
+        _selection := _cus_sel ->> _customize.key;
+        IF _selection IS NULL THEN
+            RAISE EXCEPTION 'Customize selection of % not provided.', _customize.key USING ERRCODE = 'C4301';
+        END IF;
+        IF NOT COALESCE(_customize.value -> 'selections' ? _selection, false) THEN
+            RAISE EXCEPTION 'Customize selection % of % not found.', _selection, _customize.key USING ERRCODE = 'C4101';
+        END IF;
+    END LOOP;
+END;
+$$ LANGUAGE plpgsql IMMUTABLE;
+
+------------------------------------------------------------------------------
+-- Carts and orders
+------------------------------------------------------------------------------
+
+CREATE TABLE guest_session (
+    id uuid PRIMARY KEY DEFAULT uuid_generate_v4(),
+    created_at timestamptz NOT NULL DEFAULT now(),
+    expire_at timestamptz NOT NULL DEFAULT now() + interval '1 day'
+);
+
+CREATE TABLE cart (
+    guest_session_id uuid NOT NULL REFERENCES guest_session (id) ON DELETE CASCADE,
+    shop_id uuid NOT NULL REFERENCES shop (id) ON DELETE CASCADE,
+    PRIMARY KEY (guest_session_id, shop_id)
+);
+
+CREATE TABLE cart_item (
+    guest_session_id uuid NOT NULL,
+    shop_id uuid NOT NULL,
+    key uuid NOT NULL DEFAULT uuid_generate_v4(),
+    product_key uuid NOT NULL,
+    remark text,
+    count integer NOT NULL CHECK (count > 0),
+    cus_sel jsonb NOT NULL,
+    latest_update timestamptz NOT NULL DEFAULT now(),
+    PRIMARY KEY (guest_session_id, shop_id, key),
+    FOREIGN KEY (guest_session_id, shop_id) REFERENCES cart (guest_session_id, shop_id) ON DELETE CASCADE,
+    FOREIGN KEY (shop_id, product_key) REFERENCES shop_product (shop_id, key) ON DELETE CASCADE
+);
+
+CREATE TABLE shop_order (
+    id uuid PRIMARY KEY DEFAULT uuid_generate_v4(),
+    shop_id uuid NOT NULL REFERENCES shop (id) ON DELETE CASCADE,
+    created_at timestamptz NOT NULL DEFAULT now()
+);
+
+CREATE TABLE shop_order_item (
+    order_id uuid NOT NULL REFERENCES shop_order (id) ON DELETE CASCADE,
+    key uuid NOT NULL DEFAULT uuid_generate_v4(),
+    product_key uuid NOT NULL,
+    -- Snapshot of the product when the order was created.
+    product_payload jsonb NOT NULL,
+    remark text,
+    count integer NOT NULL,
+    cus_sel jsonb NOT NULL,
+    PRIMARY KEY (order_id, key)
+);
+
+CREATE FUNCTION check_cart(_gssid uuid_nn, _shop_id uuid_nn) RETURNS void AS $$
+BEGIN
+    IF NOT EXISTS (SELECT 1 FROM guest_session WHERE id = _gssid AND expire_at > now()) THEN
+        RAISE EXCEPTION 'Guest session expired.' USING ERRCODE = 'C3001';
+    END IF;
+    IF NOT EXISTS (SELECT 1 FROM cart WHERE guest_session_id = _gssid AND shop_id = _shop_id) THEN
+        RAISE EXCEPTION 'Cart not found.' USING ERRCODE = 'C8001';
+    END IF;
+END;
+$$ LANGUAGE plpgsql STABLE;
+
+-- Ensure a cart of the shop in the guest session, starting a new session if
+-- the given one is missing or expired. Returns the guest session id.
+CREATE FUNCTION put_cart(_gssid uuid, _shop_id uuid_nn) RETURNS uuid AS $$
+BEGIN
+    IF NOT EXISTS (SELECT 1 FROM shop WHERE id = _shop_id) THEN
+        RAISE EXCEPTION 'Shop not found.' USING ERRCODE = 'C6003';
+    END IF;
+
+    UPDATE guest_session SET expire_at = now() + interval '1 day'
+    WHERE id = _gssid AND expire_at > now();
+    IF NOT FOUND THEN
+        INSERT INTO guest_session DEFAULT VALUES RETURNING id INTO _gssid;
+    END IF;
+
+    INSERT INTO cart (guest_session_id, shop_id) VALUES (_gssid, _shop_id)
+    ON CONFLICT DO NOTHING;
+    RETURN _gssid;
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE FUNCTION cart_create_item(
+    _gssid uuid_nn,
+    _shop_id uuid_nn,
+    _product_key uuid_nn,
+    _remark text,
+    _count int_nn,
+    _cus_sel text_nn
+) RETURNS uuid AS $$
+DECLARE
+    _payload jsonb;
+    _item_key uuid;
+BEGIN
+    PERFORM check_cart(_gssid, _shop_id);
+
+    SELECT payload INTO _payload FROM shop_product WHERE shop_id = _shop_id AND key = _product_key;
+    IF NOT FOUND THEN
+        RAISE EXCEPTION 'Shop product not found.' USING ERRCODE = 'C6009';
+    END IF;
+    PERFORM check_customize_selection(_payload, _cus_sel::jsonb);
+
+    INSERT INTO cart_item (guest_session_id, shop_id, product_key, remark, count, cus_sel)
+    VALUES (_gssid, _shop_id, _product_key, _remark, _count, _cus_sel::jsonb)
+    RETURNING key INTO _item_key;
+    RETURN _item_key;
+END;
+$$ LANGUAGE plpgsql;
+
+-- The payload is a JSON object with any of the members "remark", "count" and "cus_sel".
+CREATE FUNCTION cart_update_item(_gssid uuid_nn, _shop_id uuid_nn, _item_key uuid_nn, _payload text_nn) RETURNS void AS $$
+DECLARE
+    _update jsonb := _payload::jsonb;
+    _item cart_item;
+BEGIN
+    PERFORM check_cart(_gssid, _shop_id);
+
+    SELECT * INTO _item FROM cart_item
+    WHERE guest_session_id = _gssid AND shop_id = _shop_id AND key = _item_key
+    FOR UPDATE;
+    IF NOT FOUND THEN
+        RAISE EXCEPTION 'Cart item not found.' USING ERRCODE = 'C8002';
+    END IF;
+
+    IF _update ? 'cus_sel' THEN
+        PERFORM check_customize_selection(
+            (SELECT payload FROM shop_product WHERE shop_id = _shop_id AND key = _item.product_key),
+            _update -> 'cus_sel'
+        );
+    END IF;
+
+    UPDATE cart_item SET
+        remark = CASE WHEN _update ? 'remark' THEN _update ->> 'remark' ELSE remark END,
+        count = COALESCE((_update ->> 'count')::integer, count),
+        cus_sel = COALESCE(_update -> 'cus_sel', cus_sel),
+        latest_update = now()
+    WHERE guest_session_id = _gssid AND shop_id = _shop_id AND key = _item_key;
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE FUNCTION cart_delete_item(_gssid uuid_nn, _shop_id uuid_nn, _item_key uuid_nn) RETURNS void AS $$
+BEGIN
+    PERFORM check_cart(_gssid, _shop_id);
+
+    DELETE FROM cart_item WHERE guest_session_id = _gssid AND shop_id = _shop_id AND key = _item_key;
+    IF NOT FOUND THEN
+        RAISE EXCEPTION 'Cart item not found.' USING ERRCODE = 'C8002';
+    END IF;
+END;
+$$ LANGUAGE plpgsql;
+
+-- Turn the items of the cart into an order. Items added before their product
+-- was last updated are expired and must be revised first.
+CREATE FUNCTION create_order(_gssid uuid_nn, _shop_id uuid_nn) RETURNS uuid AS $$
+DECLARE
+    _order_id uuid;
+BEGIN
+    PERFORM check_cart(_gssid, _shop_id);
+
+    IF NOT EXISTS (SELECT 1 FROM cart_item WHERE guest_session_id = _gssid AND shop_id = _shop_id) THEN
+        RAISE EXCEPTION 'Cart item not found.' USING ERRCODE = 'C8002';
+    END IF;
+
+    IF EXISTS (
+        SELECT 1 FROM cart_item i
+        JOIN shop_product p ON p.shop_id = i.shop_id AND p.key = i.product_key
+        WHERE i.guest_session_id = _gssid AND i.shop_id = _shop_id AND p.latest_update > i.latest_update
+    ) THEN
+        RAISE EXCEPTION 'Cart item expired.' USING ERRCODE = 'C8103';
+    END IF;
+
+    INSERT INTO shop_order (shop_id) VALUES (_shop_id) RETURNING id INTO _order_id;
+    INSERT INTO shop_order_item (order_id, product_key, product_payload, remark, count, cus_sel)
+    SELECT _order_id, i.product_key, p.payload, i.remark, i.count, i.cus_sel
+    FROM cart_item i
+    JOIN shop_product p ON p.shop_id = i.shop_id AND p.key = i.product_key
+    WHERE i.guest_session_id = _gssid AND i.shop_id = _shop_id;
+
+    DELETE FROM cart_item WHERE guest_session_id = _gssid AND shop_id = _shop_id;
+    RETURN _order_id;
+END;
+$$ LANGUAGE plpgsql;
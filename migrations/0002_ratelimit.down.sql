@@ -0,0 +1,4 @@
+DROP FUNCTION rate_limit_fail(text_nz, double precision);
+DROP FUNCTION rate_limit_take(text_nz, double precision, double precision);
+DROP TABLE rate_limit_failure;
+DROP TABLE rate_limit_bucket;
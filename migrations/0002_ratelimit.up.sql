@@ -0,0 +1,56 @@
+-- Rate limits shared by the instances of the server, kept here when
+-- `ratelimit.store` is "postgres". The tables are unlogged since losing
+-- them in a crash only resets the limits.
+
+CREATE UNLOGGED TABLE rate_limit_bucket (
+    key text PRIMARY KEY,
+    tokens double precision NOT NULL,
+    updated_at timestamptz NOT NULL
+);
+
+CREATE UNLOGGED TABLE rate_limit_failure (
+    key text PRIMARY KEY,
+    failures integer NOT NULL,
+    last_failure_at timestamptz NOT NULL,
+    locked_until timestamptz
+);
+
+-- Take a token of the bucket of `_key`, holding up to `_burst` tokens and
+-- refilled at `_per_second`. Returns 0 if one was taken, or the seconds
+-- until one is available. Buckets unused for a day are pruned once in a
+-- while, they are full again by then.
+CREATE FUNCTION rate_limit_take(_key text_nz, _burst double precision, _per_second double precision) RETURNS double precision AS $$
+DECLARE
+    _tokens double precision;
+BEGIN
+    IF random() < 0.001 THEN
+        DELETE FROM rate_limit_bucket WHERE updated_at < clock_timestamp() - interval '1 day';
+    END IF;
+
+    INSERT INTO rate_limit_bucket (key, tokens, updated_at) VALUES (_key, _burst, clock_timestamp())
+    ON CONFLICT (key) DO NOTHING;
+
+    SELECT LEAST(_burst, tokens + EXTRACT(EPOCH FROM clock_timestamp() - updated_at)::double precision * _per_second) INTO _tokens
+    FROM rate_limit_bucket WHERE key = _key FOR UPDATE;
+
+    IF _tokens < 1 THEN
+        UPDATE rate_limit_bucket SET tokens = _tokens, updated_at = clock_timestamp() WHERE key = _key;
+        RETURN (1 - _tokens) / _per_second;
+    END IF;
+    UPDATE rate_limit_bucket SET tokens = _tokens - 1, updated_at = clock_timestamp() WHERE key = _key;
+    RETURN 0;
+END;
+$$ LANGUAGE plpgsql;
+
+-- Count a failure of `_key`, returns the failures in a row. The count
+-- restarts after `_window` seconds without a failure.
+CREATE FUNCTION rate_limit_fail(_key text_nz, _window double precision) RETURNS integer AS $$
+    INSERT INTO rate_limit_failure AS f (key, failures, last_failure_at) VALUES (_key, 1, clock_timestamp())
+    ON CONFLICT (key) DO UPDATE SET
+        failures = CASE
+            WHEN f.last_failure_at < clock_timestamp() - make_interval(secs => _window) THEN 1
+            ELSE f.failures + 1
+        END,
+        last_failure_at = clock_timestamp()
+    RETURNING failures;
+$$ LANGUAGE sql;
@@ -0,0 +1,4 @@
+DROP FUNCTION redeem_password_reset(text_nz, text_nz);
+DROP FUNCTION create_password_reset(text_nz, int_nn);
+DROP FUNCTION change_user_password(uuid_nn, text_nz, text_nz);
+DROP TABLE user_password_reset;
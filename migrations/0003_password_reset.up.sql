@@ -0,0 +1,69 @@
+-- Password changes and resets. Reset tokens are sent by mail and only
+-- their SHA-256 digest is kept.
+
+CREATE TABLE user_password_reset (
+    token_hash bytea PRIMARY KEY,
+    user_id uuid NOT NULL REFERENCES users (id) ON DELETE CASCADE,
+    created_at timestamptz NOT NULL DEFAULT now(),
+    expire_at timestamptz NOT NULL
+);
+
+CREATE INDEX user_password_reset_user_id_idx ON user_password_reset (user_id);
+
+-- Change the password of the user of the session if `_current` matches,
+-- ending the other sessions of the user. Returns false if it does not.
+CREATE FUNCTION change_user_password(_ussid uuid_nn, _current text_nz, _password text_nz) RETURNS boolean AS $$
+DECLARE
+    _user_id uuid;
+BEGIN
+    _user_id := get_session_user(_ussid);
+    UPDATE users SET password = crypt(_password, gen_salt('bf'))
+    WHERE id = _user_id AND password = crypt(_current, password);
+    IF NOT FOUND THEN
+        RETURN false;
+    END IF;
+
+    DELETE FROM user_session WHERE user_id = _user_id AND id <> _ussid;
+    DELETE FROM user_password_reset WHERE user_id = _user_id;
+    RETURN true;
+END;
+$$ LANGUAGE plpgsql;
+
+-- A reset token valid for `_minutes` for the user with the email, and the
+-- username. Both are NULL if no user has the email. Replaces the earlier
+-- tokens of the user and prunes the expired ones.
+CREATE FUNCTION create_password_reset(_email text_nz, _minutes int_nn, OUT _token text, OUT _username text) AS $$
+DECLARE
+    _user_id uuid;
+BEGIN
+    SELECT id, username INTO _user_id, _username FROM users WHERE email = _email;
+    IF NOT FOUND THEN
+        RETURN;
+    END IF;
+
+    DELETE FROM user_password_reset WHERE user_id = _user_id OR expire_at <= now();
+    _token := encode(gen_random_bytes(32), 'hex');
+    INSERT INTO user_password_reset (token_hash, user_id, expire_at)
+    VALUES (digest(_token, 'sha256'), _user_id, now() + make_interval(mins => _minutes));
+END;
+$$ LANGUAGE plpgsql;
+
+-- Set the password of the user of a valid token, consuming it and ending
+-- every session of the user. Returns false if the token is invalid or expired.
+CREATE FUNCTION redeem_password_reset(_token text_nz, _password text_nz) RETURNS boolean AS $$
+DECLARE
+    _user_id uuid;
+BEGIN
+    DELETE FROM user_password_reset
+    WHERE token_hash = digest(_token, 'sha256') AND expire_at > now()
+    RETURNING user_id INTO _user_id;
+    IF NOT FOUND THEN
+        RETURN false;
+    END IF;
+
+    UPDATE users SET password = crypt(_password, gen_salt('bf')) WHERE id = _user_id;
+    DELETE FROM user_session WHERE user_id = _user_id;
+    DELETE FROM user_password_reset WHERE user_id = _user_id;
+    RETURN true;
+END;
+$$ LANGUAGE plpgsql;
@@ -0,0 +1,40 @@
+DROP FUNCTION register_verify_email(uuid_nn, text_nz);
+DROP FUNCTION register_email_code(uuid_nn, int_nn);
+DROP FUNCTION verification_code();
+DROP FUNCTION verification_code_max_attempts();
+
+-- Create the user from a complete register session and delete the session.
+CREATE OR REPLACE FUNCTION register_user(_regssid uuid) RETURNS boolean AS $$
+DECLARE
+    _session user_register_session;
+BEGIN
+    SELECT * INTO _session FROM user_register_session WHERE id = _regssid FOR UPDATE;
+    IF NOT FOUND
+        OR _session.username IS NULL
+        OR _session.password IS NULL
+        OR _session.email IS NULL
+        OR _session.phone IS NULL
+    THEN
+        RETURN false;
+    END IF;
+
+    INSERT INTO users (username, password, nickname, email, phone)
+    VALUES (
+        _session.username,
+        crypt(_session.password, gen_salt('bf')),
+        _session.username,
+        _session.email,
+        _session.phone
+    );
+    DELETE FROM user_register_session WHERE id = _regssid;
+    RETURN true;
+END;
+$$ LANGUAGE plpgsql;
+
+ALTER TABLE user_register_session
+    DROP COLUMN email_code_attempts,
+    DROP COLUMN email_code_expire_at,
+    DROP COLUMN email_code,
+    DROP COLUMN email_verified;
+
+ALTER TABLE users DROP COLUMN email_verified;
@@ -0,0 +1,115 @@
+-- Verification of the email of a register session by a mailed code.
+--
+--   C1001  register session (REGSSID) expired
+--   C1101  email of the register session not filled in
+--   C1102  email of the register session not verified
+
+ALTER TABLE users ADD COLUMN email_verified boolean NOT NULL DEFAULT false;
+
+ALTER TABLE user_register_session
+    ADD COLUMN email_verified boolean NOT NULL DEFAULT false,
+    ADD COLUMN email_code text,
+    ADD COLUMN email_code_expire_at timestamptz,
+    ADD COLUMN email_code_attempts integer NOT NULL DEFAULT 0;
+
+-- Wrong codes tried before a code is discarded.
+CREATE FUNCTION verification_code_max_attempts() RETURNS integer AS $$
+    SELECT 5;
+$$ LANGUAGE sql IMMUTABLE;
+
+-- A random code of 6 digits.
+CREATE FUNCTION verification_code() RETURNS text AS $$
+DECLARE
+    _bytes bytea := gen_random_bytes(4);
+BEGIN
+    RETURN lpad((((get_byte(_bytes, 0)::bigint << 24) | (get_byte(_bytes, 1) << 16) | (get_byte(_bytes, 2) << 8) | get_byte(_bytes, 3)) % 1000000)::text, 6, '0');
+END;
+$$ LANGUAGE plpgsql VOLATILE;
+
+-- A new code verifying the email of the register session, valid for
+-- `_minutes`, and the email to send it to. Earlier codes are replaced.
+CREATE FUNCTION register_email_code(_regssid uuid_nn, _minutes int_nn, OUT _email text, OUT _code text) AS $$
+BEGIN
+    SELECT email INTO _email FROM user_register_session WHERE id = _regssid FOR UPDATE;
+    IF NOT FOUND THEN
+        RAISE EXCEPTION 'Register session expired.' USING ERRCODE = 'C1001';
+    END IF;
+    IF _email IS NULL THEN
+        RAISE EXCEPTION 'Email not filled in.' USING ERRCODE = 'C1101';
+    END IF;
+
+    _code := verification_code();
+    UPDATE user_register_session SET
+        email_code = _code,
+        email_code_expire_at = now() + make_interval(mins => _minutes),
+        email_code_attempts = 0
+    WHERE id = _regssid;
+END;
+$$ LANGUAGE plpgsql;
+
+-- Verify the email of the register session with its code. Returns false if
+-- the code is wrong or expired, the code is discarded after too many tries.
+CREATE FUNCTION register_verify_email(_regssid uuid_nn, _code text_nz) RETURNS boolean AS $$
+DECLARE
+    _session user_register_session;
+BEGIN
+    SELECT * INTO _session FROM user_register_session WHERE id = _regssid FOR UPDATE;
+    IF NOT FOUND THEN
+        RAISE EXCEPTION 'Register session expired.' USING ERRCODE = 'C1001';
+    END IF;
+    IF _session.email_code IS NULL OR _session.email_code_expire_at <= now() THEN
+        RETURN false;
+    END IF;
+
+    IF _session.email_code <> _code THEN
+        UPDATE user_register_session SET
+            email_code_attempts = email_code_attempts + 1,
+            email_code = CASE
+                WHEN email_code_attempts + 1 >= verification_code_max_attempts() THEN NULL
+                ELSE email_code
+            END
+        WHERE id = _regssid;
+        RETURN false;
+    END IF;
+
+    UPDATE user_register_session SET
+        email_verified = true,
+        email_code = NULL,
+        email_code_expire_at = NULL,
+        email_code_attempts = 0
+    WHERE id = _regssid;
+    RETURN true;
+END;
+$$ LANGUAGE plpgsql;
+
+-- Same as before, refusing sessions whose email is not verified.
+CREATE OR REPLACE FUNCTION register_user(_regssid uuid) RETURNS boolean AS $$
+DECLARE
+    _session user_register_session;
+BEGIN
+    SELECT * INTO _session FROM user_register_session WHERE id = _regssid FOR UPDATE;
+    IF NOT FOUND
+        OR _session.username IS NULL
+        OR _session.password IS NULL
+        OR _session.email IS NULL
+        OR _session.phone IS NULL
+    THEN
+        RETURN false;
+    END IF;
+    IF NOT _session.email_verified THEN
+        RAISE EXCEPTION 'Email not verified.' USING ERRCODE = 'C1102';
+    END IF;
+
+    INSERT INTO users (username, password, nickname, email, email_verified, phone)
+    VALUES (
+        _session.username,
+        crypt(_session.password, gen_salt('bf')),
+        _session.username,
+        _session.email,
+        true,
+        _session.phone
+    );
+    DELETE FROM user_register_session WHERE id = _regssid;
+    RETURN true;
+END;
+$$ LANGUAGE plpgsql;
@@ -0,0 +1,44 @@
+DROP FUNCTION user_verify_phone(uuid_nn, text_nz);
+DROP FUNCTION user_phone_code(uuid_nn, text_nz, int_nn, int_nn, int_nn);
+DROP FUNCTION register_verify_phone(uuid_nn, text_nz);
+DROP FUNCTION register_phone_code(uuid_nn, int_nn, int_nn, int_nn);
+DROP FUNCTION check_phone_code(uuid, uuid, text_nz);
+DROP FUNCTION create_phone_code(text_nz, uuid, uuid, int_nn, int_nn, int_nn);
+
+-- Same as before, refusing sessions whose email is not verified.
+CREATE OR REPLACE FUNCTION register_user(_regssid uuid) RETURNS boolean AS $$
+DECLARE
+    _session user_register_session;
+BEGIN
+    SELECT * INTO _session FROM user_register_session WHERE id = _regssid FOR UPDATE;
+    IF NOT FOUND
+        OR _session.username IS NULL
+        OR _session.password IS NULL
+        OR _session.email IS NULL
+        OR _session.phone IS NULL
+    THEN
+        RETURN false;
+    END IF;
+    IF NOT _session.email_verified THEN
+        RAISE EXCEPTION 'Email not verified.' USING ERRCODE = 'C1102';
+    END IF;
+
+    INSERT INTO users (username, password, nickname, email, email_verified, phone)
+    VALUES (
+        _session.username,
+        crypt(_session.password, gen_salt('bf')),
+        _session.username,
+        _session.email,
+        true,
+        _session.phone
+    );
+    DELETE FROM user_register_session WHERE id = _regssid;
+    RETURN true;
+END;
+$$ LANGUAGE plpgsql;
+
+DROP TABLE phone_verification;
+
+ALTER TABLE user_register_session DROP COLUMN phone_verified;
+
+ALTER TABLE users DROP COLUMN phone_verified;
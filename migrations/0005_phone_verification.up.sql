@@ -0,0 +1,220 @@
+-- Verification of phones by a code sent in an SMS, for register sessions
+-- and for users changing their phone. The codes sent are kept for a day to
+-- throttle the sends to each number.
+--
+--   C1201  phone of the register session not filled in
+--   C1202  phone of the register session not verified
+--   C1203  phone used by another user
+
+ALTER TABLE users ADD COLUMN phone_verified boolean NOT NULL DEFAULT false;
+
+ALTER TABLE user_register_session ADD COLUMN phone_verified boolean NOT NULL DEFAULT false;
+
+CREATE TABLE phone_verification (
+    id uuid PRIMARY KEY DEFAULT uuid_generate_v4(),
+    phone text NOT NULL,
+    -- Either a register session or a user asked for the code.
+    regssid uuid REFERENCES user_register_session (id) ON DELETE CASCADE,
+    user_id uuid REFERENCES users (id) ON DELETE CASCADE,
+    code text NOT NULL,
+    attempts integer NOT NULL DEFAULT 0,
+    created_at timestamptz NOT NULL DEFAULT now(),
+    expire_at timestamptz NOT NULL,
+    CHECK ((regssid IS NULL) <> (user_id IS NULL))
+);
+
+CREATE INDEX phone_verification_phone_idx ON phone_verification (phone, created_at);
+CREATE INDEX phone_verification_regssid_idx ON phone_verification (regssid, created_at);
+CREATE INDEX phone_verification_user_id_idx ON phone_verification (user_id, created_at);
+
+-- A code for the phone valid for `_minutes`. No code is made while the
+-- number is throttled: `_resend_seconds` between codes and `_per_hour` at
+-- most. `_retry_after` is then the seconds until one can be sent.
+CREATE FUNCTION create_phone_code(
+    _phone text_nz,
+    _regssid uuid,
+    _user_id uuid,
+    _minutes int_nn,
+    _resend_seconds int_nn,
+    _per_hour int_nn,
+    OUT _code text,
+    OUT _retry_after double precision
+) AS $$
+DECLARE
+    _count integer;
+    _first timestamptz;
+    _last timestamptz;
+BEGIN
+    -- Serialize the sends to a number so concurrent ones are counted.
+    PERFORM pg_advisory_xact_lock(hashtext('phone_verification:' || _phone));
+    IF random() < 0.01 THEN
+        DELETE FROM phone_verification WHERE created_at < now() - interval '1 day';
+    END IF;
+
+    SELECT count(*), min(created_at), max(created_at) INTO _count, _first, _last
+    FROM phone_verification
+    WHERE phone = _phone AND created_at > now() - interval '1 hour';
+    IF _last > now() - make_interval(secs => _resend_seconds) THEN
+        _retry_after := EXTRACT(EPOCH FROM _last + make_interval(secs => _resend_seconds) - now());
+        RETURN;
+    END IF;
+    IF _count >= _per_hour THEN
+        _retry_after := EXTRACT(EPOCH FROM _first + interval '1 hour' - now());
+        RETURN;
+    END IF;
+
+    _code := verification_code();
+    INSERT INTO phone_verification (phone, regssid, user_id, code, expire_at)
+    VALUES (_phone, _regssid, _user_id, _code, now() + make_interval(mins => _minutes));
+END;
+$$ LANGUAGE plpgsql;
+
+-- Check the latest code sent for the register session or the user, and
+-- consume it. Returns the phone it verifies, NULL if the code is wrong or
+-- expired. The code is discarded after too many tries.
+CREATE FUNCTION check_phone_code(_regssid uuid, _user_id uuid, _code text_nz) RETURNS text AS $$
+DECLARE
+    _verification phone_verification;
+BEGIN
+    SELECT * INTO _verification FROM phone_verification
+    WHERE regssid = _regssid OR user_id = _user_id
+    ORDER BY created_at DESC
+    LIMIT 1
+    FOR UPDATE;
+    IF NOT FOUND
+        OR _verification.expire_at <= now()
+        OR _verification.attempts >= verification_code_max_attempts()
+    THEN
+        RETURN NULL;
+    END IF;
+
+    IF _verification.code <> _code THEN
+        UPDATE phone_verification SET attempts = attempts + 1 WHERE id = _verification.id;
+        RETURN NULL;
+    END IF;
+    -- Kept until pruned, it still counts for the throttling.
+    UPDATE phone_verification SET expire_at = now() WHERE id = _verification.id;
+    RETURN _verification.phone;
+END;
+$$ LANGUAGE plpgsql;
+
+-- A code for the phone of the register session, see `create_phone_code`.
+CREATE FUNCTION register_phone_code(
+    _regssid uuid_nn,
+    _minutes int_nn,
+    _resend_seconds int_nn,
+    _per_hour int_nn,
+    OUT _phone text,
+    OUT _code text,
+    OUT _retry_after double precision
+) AS $$
+BEGIN
+    SELECT phone INTO _phone FROM user_register_session WHERE id = _regssid FOR UPDATE;
+    IF NOT FOUND THEN
+        RAISE EXCEPTION 'Register session expired.' USING ERRCODE = 'C1001';
+    END IF;
+    IF _phone IS NULL THEN
+        RAISE EXCEPTION 'Phone not filled in.' USING ERRCODE = 'C1201';
+    END IF;
+
+    SELECT c._code, c._retry_after INTO _code, _retry_after
+    FROM create_phone_code(_phone, _regssid, NULL, _minutes, _resend_seconds, _per_hour) c;
+END;
+$$ LANGUAGE plpgsql;
+
+-- Verify the phone of the register session, false if the code is wrong,
+-- expired or was sent to a previous phone.
+CREATE FUNCTION register_verify_phone(_regssid uuid_nn, _code text_nz) RETURNS boolean AS $$
+DECLARE
+    _phone text;
+BEGIN
+    SELECT phone INTO _phone FROM user_register_session WHERE id = _regssid FOR UPDATE;
+    IF NOT FOUND THEN
+        RAISE EXCEPTION 'Register session expired.' USING ERRCODE = 'C1001';
+    END IF;
+    IF _phone IS NULL THEN
+        RAISE EXCEPTION 'Phone not filled in.' USING ERRCODE = 'C1201';
+    END IF;
+
+    IF check_phone_code(_regssid, NULL, _code) IS DISTINCT FROM _phone THEN
+        RETURN false;
+    END IF;
+    UPDATE user_register_session SET phone_verified = true WHERE id = _regssid;
+    RETURN true;
+END;
+$$ LANGUAGE plpgsql;
+
+-- A code for a new phone of the user, see `create_phone_code`.
+CREATE FUNCTION user_phone_code(
+    _user_id uuid_nn,
+    _phone text_nz,
+    _minutes int_nn,
+    _resend_seconds int_nn,
+    _per_hour int_nn,
+    OUT _code text,
+    OUT _retry_after double precision
+) AS $$
+BEGIN
+    IF EXISTS (SELECT 1 FROM users WHERE phone = _phone AND id <> _user_id) THEN
+        RAISE EXCEPTION 'Phone used by another user.' USING ERRCODE = 'C1203';
+    END IF;
+
+    SELECT c._code, c._retry_after INTO _code, _retry_after
+    FROM create_phone_code(_phone, NULL, _user_id, _minutes, _resend_seconds, _per_hour) c;
+END;
+$$ LANGUAGE plpgsql;
+
+-- Set the phone the code was sent to as the verified phone of the user,
+-- false if the code is wrong or expired.
+CREATE FUNCTION user_verify_phone(_user_id uuid_nn, _code text_nz) RETURNS boolean AS $$
+DECLARE
+    _phone text;
+BEGIN
+    _phone := check_phone_code(NULL, _user_id, _code);
+    IF _phone IS NULL THEN
+        RETURN false;
+    END IF;
+    IF EXISTS (SELECT 1 FROM users WHERE phone = _phone AND id <> _user_id) THEN
+        RAISE EXCEPTION 'Phone used by another user.' USING ERRCODE = 'C1203';
+    END IF;
+
+    UPDATE users SET phone = _phone, phone_verified = true WHERE id = _user_id;
+    RETURN true;
+END;
+$$ LANGUAGE plpgsql;
+
+-- Same as before, also refusing sessions whose phone is not verified.
+CREATE OR REPLACE FUNCTION register_user(_regssid uuid) RETURNS boolean AS $$
+DECLARE
+    _session user_register_session;
+BEGIN
+    SELECT * INTO _session FROM user_register_session WHERE id = _regssid FOR UPDATE;
+    IF NOT FOUND
+        OR _session.username IS NULL
+        OR _session.password IS NULL
+        OR _session.email IS NULL
+        OR _session.phone IS NULL
+    THEN
+        RETURN false;
+    END IF;
+    IF NOT _session.email_verified THEN
+        RAISE EXCEPTION 'Email not verified.' USING ERRCODE = 'C1102';
+    END IF;
+    IF NOT _session.phone_verified THEN
+        RAISE EXCEPTION 'Phone not verified.' USING ERRCODE = 'C1202';
+    END IF;
+
+    INSERT INTO users (username, password, nickname, email, email_verified, phone, phone_verified)
+    VALUES (
+        _session.username,
+        crypt(_session.password, gen_salt('bf')),
+        _session.username,
+        _session.email,
+        true,
+        _session.phone,
+        true
+    );
+    DELETE FROM user_register_session WHERE id = _regssid;
+    RETURN true;
+END;
+$$ LANGUAGE plpgsql;
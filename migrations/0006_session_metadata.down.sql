@@ -0,0 +1,40 @@
+DROP FUNCTION signout_user_everywhere(uuid_nn);
+DROP FUNCTION revoke_user_session(uuid_nn, uuid_nn);
+DROP FUNCTION get_user_sessions(uuid_nn);
+DROP FUNCTION signin_user(text_nz, text_nz, inet, text);
+
+-- Returns the id of the new session, or NULL if the credentials don't match.
+CREATE FUNCTION signin_user(_username text_nz, _password text_nz) RETURNS uuid AS $$
+DECLARE
+    _user_id uuid;
+    _session_id uuid;
+BEGIN
+    SELECT id INTO _user_id FROM users
+    WHERE username = _username AND password = crypt(_password, password);
+    IF NOT FOUND THEN
+        RETURN NULL;
+    END IF;
+
+    INSERT INTO user_session (user_id) VALUES (_user_id) RETURNING id INTO _session_id;
+    RETURN _session_id;
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE OR REPLACE FUNCTION get_session_user(_ussid uuid_nn) RETURNS uuid AS $$
+DECLARE
+    _user_id uuid;
+BEGIN
+    SELECT user_id INTO _user_id FROM user_session
+    WHERE id = _ussid AND expire_at > now();
+    IF NOT FOUND THEN
+        RAISE EXCEPTION 'User session expired.' USING ERRCODE = 'C2002';
+    END IF;
+    RETURN _user_id;
+END;
+$$ LANGUAGE plpgsql;
+
+ALTER TABLE user_session
+    DROP COLUMN user_agent,
+    DROP COLUMN ip,
+    DROP COLUMN last_seen_at,
+    DROP COLUMN key;
@@ -0,0 +1,83 @@
+-- Sessions listed to their user with where they signed in from. They are
+-- identified by `key`, the id being the secret of the USSID cookie.
+
+ALTER TABLE user_session
+    ADD COLUMN key uuid NOT NULL UNIQUE DEFAULT uuid_generate_v4(),
+    ADD COLUMN last_seen_at timestamptz NOT NULL DEFAULT now(),
+    ADD COLUMN ip inet,
+    ADD COLUMN user_agent text;
+
+DROP FUNCTION signin_user(text_nz, text_nz);
+
+-- Returns the id of the new session, or NULL if the credentials don't match.
+CREATE FUNCTION signin_user(_username text_nz, _password text_nz, _ip inet, _user_agent text) RETURNS uuid AS $$
+DECLARE
+    _user_id uuid;
+    _session_id uuid;
+BEGIN
+    SELECT id INTO _user_id FROM users
+    WHERE username = _username AND password = crypt(_password, password);
+    IF NOT FOUND THEN
+        RETURN NULL;
+    END IF;
+
+    INSERT INTO user_session (user_id, ip, user_agent) VALUES (_user_id, _ip, _user_agent) RETURNING id INTO _session_id;
+    RETURN _session_id;
+END;
+$$ LANGUAGE plpgsql;
+
+-- Same as before, also recording when the session was last used, at a
+-- minute's precision to spare the writes.
+CREATE OR REPLACE FUNCTION get_session_user(_ussid uuid_nn) RETURNS uuid AS $$
+DECLARE
+    _user_id uuid;
+BEGIN
+    UPDATE user_session SET last_seen_at = now()
+    WHERE id = _ussid AND expire_at > now() AND last_seen_at < now() - interval '1 minute';
+    SELECT user_id INTO _user_id FROM user_session
+    WHERE id = _ussid AND expire_at > now();
+    IF NOT FOUND THEN
+        RAISE EXCEPTION 'User session expired.' USING ERRCODE = 'C2002';
+    END IF;
+    RETURN _user_id;
+END;
+$$ LANGUAGE plpgsql;
+
+-- The live sessions of the user of the session, most recently used first.
+CREATE FUNCTION get_user_sessions(_ussid uuid_nn)
+RETURNS TABLE (key uuid, created_at timestamptz, last_seen_at timestamptz, ip inet, user_agent text, current boolean) AS $$
+DECLARE
+    _user_id uuid;
+BEGIN
+    _user_id := get_session_user(_ussid);
+    RETURN QUERY
+    SELECT s.key, s.created_at, s.last_seen_at, s.ip, s.user_agent, s.id = _ussid
+    FROM user_session s
+    WHERE s.user_id = _user_id AND s.expire_at > now()
+    ORDER BY s.last_seen_at DESC;
+END;
+$$ LANGUAGE plpgsql;
+
+-- End the session of the user with the key. Returns whether it was the
+-- session itself, NULL if the user has no such session.
+CREATE FUNCTION revoke_user_session(_ussid uuid_nn, _key uuid_nn) RETURNS boolean AS $$
+DECLARE
+    _user_id uuid;
+    _current boolean;
+BEGIN
+    _user_id := get_session_user(_ussid);
+    DELETE FROM user_session WHERE user_id = _user_id AND key = _key
+    RETURNING id = _ussid INTO _current;
+    RETURN _current;
+END;
+$$ LANGUAGE plpgsql;
+
+-- End every session of the user of the session, this one included.
+CREATE FUNCTION signout_user_everywhere(_ussid uuid_nn) RETURNS void AS $$
+DECLARE
+    _user_id uuid;
+BEGIN
+    _user_id := get_session_user(_ussid);
+    DELETE FROM user_session WHERE user_id = _user_id;
+END;
+$$ LANGUAGE plpgsql;
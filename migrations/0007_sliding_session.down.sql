@@ -0,0 +1,25 @@
+DROP FUNCTION renew_user_session(uuid_nn);
+DROP FUNCTION signin_user(text_nz, text_nz, inet, text, boolean, int_nn, int_nn);
+
+-- Returns the id of the new session, or NULL if the credentials don't match.
+CREATE FUNCTION signin_user(_username text_nz, _password text_nz, _ip inet, _user_agent text) RETURNS uuid AS $$
+DECLARE
+    _user_id uuid;
+    _session_id uuid;
+BEGIN
+    SELECT id INTO _user_id FROM users
+    WHERE username = _username AND password = crypt(_password, password);
+    IF NOT FOUND THEN
+        RETURN NULL;
+    END IF;
+
+    INSERT INTO user_session (user_id, ip, user_agent) VALUES (_user_id, _ip, _user_agent) RETURNING id INTO _session_id;
+    RETURN _session_id;
+END;
+$$ LANGUAGE plpgsql;
+
+ALTER TABLE user_session
+    ALTER COLUMN expire_at SET DEFAULT now() + interval '30 days',
+    DROP COLUMN max_expire_at,
+    DROP COLUMN lifetime,
+    DROP COLUMN remember;
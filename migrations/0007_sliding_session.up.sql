@@ -0,0 +1,66 @@
+-- Sessions last `lifetime` since their last use up to `max_expire_at`, both
+-- set by the server at sign-in instead of the 30 days defaulted here before.
+-- `remember` tells a long-lived cookie from one ending with the browser.
+
+ALTER TABLE user_session
+    ADD COLUMN remember boolean NOT NULL DEFAULT true,
+    ADD COLUMN lifetime interval NOT NULL DEFAULT interval '30 days',
+    ADD COLUMN max_expire_at timestamptz;
+
+UPDATE user_session SET max_expire_at = expire_at;
+
+ALTER TABLE user_session
+    ALTER COLUMN remember DROP DEFAULT,
+    ALTER COLUMN lifetime DROP DEFAULT,
+    ALTER COLUMN max_expire_at SET NOT NULL,
+    ALTER COLUMN expire_at DROP DEFAULT;
+
+DROP FUNCTION signin_user(text_nz, text_nz, inet, text);
+
+-- Returns the id of the new session, or NULL if the credentials don't match.
+CREATE FUNCTION signin_user(
+    _username text_nz,
+    _password text_nz,
+    _ip inet,
+    _user_agent text,
+    _remember boolean,
+    _lifetime_secs int_nn,
+    _max_secs int_nn
+) RETURNS uuid AS $$
+DECLARE
+    _user_id uuid;
+    _session_id uuid;
+BEGIN
+    SELECT id INTO _user_id FROM users
+    WHERE username = _username AND password = crypt(_password, password);
+    IF NOT FOUND THEN
+        RETURN NULL;
+    END IF;
+
+    INSERT INTO user_session (user_id, ip, user_agent, remember, lifetime, expire_at, max_expire_at)
+    VALUES (
+        _user_id,
+        _ip,
+        _user_agent,
+        _remember,
+        make_interval(secs => _lifetime_secs),
+        now() + make_interval(secs => least(_lifetime_secs, _max_secs)),
+        now() + make_interval(secs => _max_secs)
+    )
+    RETURNING id INTO _session_id;
+    RETURN _session_id;
+END;
+$$ LANGUAGE plpgsql;
+
+-- Extend the session by its lifetime once less than half of it is left.
+-- Returns the new expiry and whether the session is remembered, no row if
+-- the session was not extended.
+CREATE FUNCTION renew_user_session(_ussid uuid_nn)
+RETURNS TABLE (remember boolean, expire_at timestamptz) AS $$
+    UPDATE user_session s SET expire_at = least(now() + s.lifetime, s.max_expire_at)
+    WHERE s.id = _ussid
+        AND s.expire_at > now()
+        AND s.expire_at < now() + s.lifetime / 2
+        AND s.expire_at < s.max_expire_at
+    RETURNING s.remember, s.expire_at;
+$$ LANGUAGE sql;
@@ -0,0 +1,53 @@
+DROP FUNCTION signin_user_challenge(uuid_nn, text_nz);
+DROP FUNCTION signin_user(text_nz, text_nz, inet, text, boolean, int_nn, int_nn, int_nn);
+
+-- Returns the id of the new session, or NULL if the credentials don't match.
+CREATE FUNCTION signin_user(
+    _username text_nz,
+    _password text_nz,
+    _ip inet,
+    _user_agent text,
+    _remember boolean,
+    _lifetime_secs int_nn,
+    _max_secs int_nn
+) RETURNS uuid AS $$
+DECLARE
+    _user_id uuid;
+    _session_id uuid;
+BEGIN
+    SELECT id INTO _user_id FROM users
+    WHERE username = _username AND password = crypt(_password, password);
+    IF NOT FOUND THEN
+        RETURN NULL;
+    END IF;
+
+    INSERT INTO user_session (user_id, ip, user_agent, remember, lifetime, expire_at, max_expire_at)
+    VALUES (
+        _user_id,
+        _ip,
+        _user_agent,
+        _remember,
+        make_interval(secs => _lifetime_secs),
+        now() + make_interval(secs => least(_lifetime_secs, _max_secs)),
+        now() + make_interval(secs => _max_secs)
+    )
+    RETURNING id INTO _session_id;
+    RETURN _session_id;
+END;
+$$ LANGUAGE plpgsql;
+
+DROP FUNCTION create_user_session(uuid, inet, text, boolean, int, int);
+DROP FUNCTION disable_user_totp(uuid_nn, text_nz);
+DROP FUNCTION renew_recovery_codes(uuid_nn, text_nz, int_nn);
+DROP FUNCTION enable_user_totp(uuid_nn, text_nz, int_nn);
+DROP FUNCTION create_user_totp(uuid_nn);
+DROP FUNCTION get_user_totp(uuid_nn);
+DROP FUNCTION create_recovery_codes(uuid, int);
+DROP FUNCTION check_second_factor(uuid, text);
+DROP FUNCTION use_recovery_code(uuid, text);
+DROP FUNCTION check_totp(uuid, text, boolean);
+DROP FUNCTION totp_code(bytea, bigint);
+
+DROP TABLE user_signin_challenge;
+DROP TABLE user_recovery_code;
+DROP TABLE user_totp;
@@ -0,0 +1,297 @@
+-- Two-factor authentication with time-based one-time passwords (RFC 6238:
+-- HMAC-SHA1, 6 digits, 30 second steps) and single-use recovery codes. A
+-- user signing in with it enabled gets a challenge, which a code redeems for
+-- the session.
+--
+--   C2101  two-factor authentication already enabled
+--   C2102  two-factor authentication not set up
+--   C2103  sign-in challenge expired
+
+CREATE TABLE user_totp (
+    user_id uuid PRIMARY KEY REFERENCES users (id) ON DELETE CASCADE,
+    secret bytea NOT NULL,
+    -- Until confirmed with a code the secret is only being set up.
+    enabled boolean NOT NULL DEFAULT false,
+    -- The time step of the last code accepted, each code is accepted once.
+    last_step bigint NOT NULL DEFAULT 0,
+    created_at timestamptz NOT NULL DEFAULT now()
+);
+
+CREATE TABLE user_recovery_code (
+    user_id uuid NOT NULL REFERENCES users (id) ON DELETE CASCADE,
+    code_hash bytea NOT NULL,
+    PRIMARY KEY (user_id, code_hash)
+);
+
+-- A sign-in waiting for its second factor, with what the session needs.
+CREATE TABLE user_signin_challenge (
+    id uuid PRIMARY KEY DEFAULT uuid_generate_v4(),
+    user_id uuid NOT NULL REFERENCES users (id) ON DELETE CASCADE,
+    ip inet,
+    user_agent text,
+    remember boolean NOT NULL,
+    lifetime_secs int NOT NULL,
+    max_secs int NOT NULL,
+    attempts int NOT NULL DEFAULT 0,
+    expire_at timestamptz NOT NULL
+);
+
+-- The code of the secret for the time step.
+CREATE FUNCTION totp_code(_secret bytea, _step bigint) RETURNS text AS $$
+DECLARE
+    _hash bytea;
+    _offset int;
+BEGIN
+    _hash := hmac(int8send(_step), _secret, 'sha1');
+    _offset := get_byte(_hash, 19) & 15;
+    -- The operators share one precedence, hence the parentheses.
+    RETURN lpad(((
+        ((get_byte(_hash, _offset) & 127)::bigint << 24)
+        | (get_byte(_hash, _offset + 1) << 16)
+        | (get_byte(_hash, _offset + 2) << 8)
+        | get_byte(_hash, _offset + 3)
+    ) % 1000000)::text, 6, '0');
+END;
+$$ LANGUAGE plpgsql IMMUTABLE;
+
+-- Accept a code of the user for the current time step or one next to it,
+-- allowing for clock drift. `_pending` checks the secret being set up
+-- instead of the enabled one.
+CREATE FUNCTION check_totp(_user_id uuid, _code text, _pending boolean) RETURNS boolean AS $$
+DECLARE
+    _totp user_totp;
+    _now bigint := floor(extract(epoch FROM now()) / 30);
+    _step bigint;
+BEGIN
+    SELECT * INTO _totp FROM user_totp
+    WHERE user_id = _user_id AND enabled <> _pending
+    FOR UPDATE;
+    IF NOT FOUND THEN
+        RETURN false;
+    END IF;
+
+    FOR _step IN _now - 1 .. _now + 1 LOOP
+        IF _step > _totp.last_step AND totp_code(_totp.secret, _step) = _code THEN
+            UPDATE user_totp SET last_step = _step WHERE user_id = _user_id;
+            RETURN true;
+        END IF;
+    END LOOP;
+    RETURN false;
+END;
+$$ LANGUAGE plpgsql;
+
+-- Redeem one of the recovery codes of the user.
+CREATE FUNCTION use_recovery_code(_user_id uuid, _code text) RETURNS boolean AS $$
+    WITH used AS (
+        DELETE FROM user_recovery_code
+        WHERE user_id = _user_id AND code_hash = digest(lower(trim(_code)), 'sha256')
+        RETURNING 1
+    )
+    SELECT EXISTS (SELECT 1 FROM used);
+$$ LANGUAGE sql;
+
+-- Accept a code, or else a recovery code, of the user.
+CREATE FUNCTION check_second_factor(_user_id uuid, _code text) RETURNS boolean AS $$
+BEGIN
+    IF _code ~ '^[0-9]{6}$' THEN
+        RETURN check_totp(_user_id, _code, false);
+    END IF;
+    RETURN use_recovery_code(_user_id, _code);
+END;
+$$ LANGUAGE plpgsql;
+
+-- Replace the recovery codes of the user with `_count` new ones, which are
+-- only kept hashed.
+CREATE FUNCTION create_recovery_codes(_user_id uuid, _count int) RETURNS text[] AS $$
+DECLARE
+    _codes text[];
+BEGIN
+    SELECT array_agg(substr(hex, 1, 5) || '-' || substr(hex, 6, 5)) INTO _codes
+    FROM (SELECT encode(gen_random_bytes(5), 'hex') AS hex FROM generate_series(1, _count)) random;
+
+    DELETE FROM user_recovery_code WHERE user_id = _user_id;
+    INSERT INTO user_recovery_code (user_id, code_hash)
+    SELECT _user_id, digest(code, 'sha256') FROM unnest(_codes) code
+    ON CONFLICT DO NOTHING;
+    RETURN _codes;
+END;
+$$ LANGUAGE plpgsql;
+
+-- Whether the user has two-factor authentication enabled,
+-- and how many recovery codes are left.
+CREATE FUNCTION get_user_totp(_user_id uuid_nn, OUT _enabled boolean, OUT _recovery_codes int) AS $$
+BEGIN
+    _enabled := EXISTS (SELECT 1 FROM user_totp WHERE user_id = _user_id AND enabled);
+    SELECT count(*) INTO _recovery_codes FROM user_recovery_code WHERE user_id = _user_id;
+END;
+$$ LANGUAGE plpgsql;
+
+-- Set up a new secret for the user, replacing one not yet enabled. Returns
+-- it and the username, for the authenticator app.
+CREATE FUNCTION create_user_totp(_user_id uuid_nn, OUT _secret bytea, OUT _username text) AS $$
+BEGIN
+    IF EXISTS (SELECT 1 FROM user_totp WHERE user_id = _user_id AND enabled) THEN
+        RAISE EXCEPTION 'Two-factor authentication already enabled.' USING ERRCODE = 'C2101';
+    END IF;
+
+    _secret := gen_random_bytes(20);
+    INSERT INTO user_totp (user_id, secret) VALUES (_user_id, _secret)
+    ON CONFLICT (user_id) DO UPDATE SET secret = EXCLUDED.secret, last_step = 0, created_at = now();
+    SELECT username INTO _username FROM users WHERE id = _user_id;
+END;
+$$ LANGUAGE plpgsql;
+
+-- Enable the secret being set up with a code of it. Returns the recovery
+-- codes, NULL if the code is wrong.
+CREATE FUNCTION enable_user_totp(_user_id uuid_nn, _code text_nz, _recovery_codes int_nn) RETURNS text[] AS $$
+DECLARE
+    _enabled boolean;
+BEGIN
+    SELECT enabled INTO _enabled FROM user_totp WHERE user_id = _user_id;
+    IF NOT FOUND THEN
+        RAISE EXCEPTION 'Two-factor authentication not set up.' USING ERRCODE = 'C2102';
+    END IF;
+    IF _enabled THEN
+        RAISE EXCEPTION 'Two-factor authentication already enabled.' USING ERRCODE = 'C2101';
+    END IF;
+
+    IF NOT check_totp(_user_id, _code, true) THEN
+        RETURN NULL;
+    END IF;
+    UPDATE user_totp SET enabled = true WHERE user_id = _user_id;
+    RETURN create_recovery_codes(_user_id, _recovery_codes);
+END;
+$$ LANGUAGE plpgsql;
+
+-- New recovery codes for a code, replacing the others. NULL if the code is
+-- wrong.
+CREATE FUNCTION renew_recovery_codes(_user_id uuid_nn, _code text_nz, _recovery_codes int_nn) RETURNS text[] AS $$
+BEGIN
+    IF NOT EXISTS (SELECT 1 FROM user_totp WHERE user_id = _user_id AND enabled) THEN
+        RAISE EXCEPTION 'Two-factor authentication not set up.' USING ERRCODE = 'C2102';
+    END IF;
+
+    IF NOT check_totp(_user_id, _code, false) THEN
+        RETURN NULL;
+    END IF;
+    RETURN create_recovery_codes(_user_id, _recovery_codes);
+END;
+$$ LANGUAGE plpgsql;
+
+-- Turn two-factor authentication off with a code or a recovery code,
+-- `false` if it is wrong.
+CREATE FUNCTION disable_user_totp(_user_id uuid_nn, _code text_nz) RETURNS boolean AS $$
+BEGIN
+    IF NOT EXISTS (SELECT 1 FROM user_totp WHERE user_id = _user_id AND enabled) THEN
+        RAISE EXCEPTION 'Two-factor authentication not set up.' USING ERRCODE = 'C2102';
+    END IF;
+
+    IF NOT check_second_factor(_user_id, _code) THEN
+        RETURN false;
+    END IF;
+    DELETE FROM user_totp WHERE user_id = _user_id;
+    DELETE FROM user_recovery_code WHERE user_id = _user_id;
+    RETURN true;
+END;
+$$ LANGUAGE plpgsql;
+
+-- A new session of the user, see `signin_user`.
+CREATE FUNCTION create_user_session(
+    _user_id uuid,
+    _ip inet,
+    _user_agent text,
+    _remember boolean,
+    _lifetime_secs int,
+    _max_secs int
+) RETURNS uuid AS $$
+    INSERT INTO user_session (user_id, ip, user_agent, remember, lifetime, expire_at, max_expire_at)
+    VALUES (
+        _user_id,
+        _ip,
+        _user_agent,
+        _remember,
+        make_interval(secs => _lifetime_secs),
+        now() + make_interval(secs => least(_lifetime_secs, _max_secs)),
+        now() + make_interval(secs => _max_secs)
+    )
+    RETURNING id;
+$$ LANGUAGE sql;
+
+DROP FUNCTION signin_user(text_nz, text_nz, inet, text, boolean, int_nn, int_nn);
+
+-- Returns the id of the new session or, if the user has two-factor
+-- authentication enabled, of a challenge valid for `_challenge_minutes` to
+-- redeem with `signin_user_challenge`. Both are NULL if the credentials don't
+-- match.
+CREATE FUNCTION signin_user(
+    _username text_nz,
+    _password text_nz,
+    _ip inet,
+    _user_agent text,
+    _remember boolean,
+    _lifetime_secs int_nn,
+    _max_secs int_nn,
+    _challenge_minutes int_nn,
+    OUT _session_id uuid,
+    OUT _challenge_id uuid
+) AS $$
+DECLARE
+    _user_id uuid;
+BEGIN
+    SELECT id INTO _user_id FROM users
+    WHERE username = _username AND password = crypt(_password, password);
+    IF NOT FOUND THEN
+        RETURN;
+    END IF;
+
+    IF NOT EXISTS (SELECT 1 FROM user_totp WHERE user_id = _user_id AND enabled) THEN
+        _session_id := create_user_session(_user_id, _ip, _user_agent, _remember, _lifetime_secs, _max_secs);
+        RETURN;
+    END IF;
+
+    DELETE FROM user_signin_challenge WHERE expire_at <= now();
+    INSERT INTO user_signin_challenge (user_id, ip, user_agent, remember, lifetime_secs, max_secs, expire_at)
+    VALUES (_user_id, _ip, _user_agent, _remember, _lifetime_secs, _max_secs, now() + make_interval(mins => _challenge_minutes))
+    RETURNING id INTO _challenge_id;
+END;
+$$ LANGUAGE plpgsql;
+
+-- Redeem the challenge with a code or a recovery code for the session, and
+-- whether it is remembered. The session is NULL if the code is wrong, the
+-- challenge ending after a few wrong ones. The username is for the lockout.
+CREATE FUNCTION signin_user_challenge(
+    _challenge_id uuid_nn,
+    _code text_nz,
+    OUT _session_id uuid,
+    OUT _remember boolean,
+    OUT _username text
+) AS $$
+DECLARE
+    _challenge user_signin_challenge;
+BEGIN
+    SELECT * INTO _challenge FROM user_signin_challenge
+    WHERE id = _challenge_id
+        AND expire_at > now()
+        AND attempts < verification_code_max_attempts()
+    FOR UPDATE;
+    IF NOT FOUND THEN
+        RAISE EXCEPTION 'Sign-in challenge expired.' USING ERRCODE = 'C2103';
+    END IF;
+    SELECT username INTO _username FROM users WHERE id = _challenge.user_id;
+
+    IF NOT check_second_factor(_challenge.user_id, _code) THEN
+        UPDATE user_signin_challenge SET attempts = attempts + 1 WHERE id = _challenge_id;
+        RETURN;
+    END IF;
+    DELETE FROM user_signin_challenge WHERE id = _challenge_id;
+    _session_id := create_user_session(
+        _challenge.user_id,
+        _challenge.ip,
+        _challenge.user_agent,
+        _challenge.remember,
+        _challenge.lifetime_secs,
+        _challenge.max_secs
+    );
+    _remember := _challenge.remember;
+END;
+$$ LANGUAGE plpgsql;
@@ -0,0 +1,51 @@
+//! Derive macros of the pigskit server, the traits they implement live in the
+//! server crate and are referred to by their `crate::` paths.
+
+extern crate proc_macro;
+
+use proc_macro::TokenStream;
+use syn::{
+    DeriveInput,
+    parse_macro_input,
+};
+
+mod multipart;
+mod row;
+
+/// Extract a struct from the parts of a `multipart/form-data` body, see
+/// `route::utils::filter::form`.
+///
+/// Each field is a part of the same name, `Option<T>` fields are optional and
+/// `Vec<T>` fields collect repeated parts (except `Vec<u8>`, which is the raw
+/// content of a single part). Fields take `#[multipart(...)]` attributes:
+///
+/// - `rename = "name"`, the name of the part.
+/// - `limit = EXPR`, the maximum size in bytes of the part.
+/// - `content_type = "image/*"`, an accepted content type of the part, may be repeated.
+#[proc_macro_derive(MultipartForm, attributes(multipart))]
+pub fn derive_multipart_form(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    multipart::derive_form(input)
+        .unwrap_or_else(|err| err.to_compile_error())
+        .into()
+}
+
+/// Parse a enum of unit variants from a part holding the snake cased name of
+/// a variant, or the name given by `#[multipart(rename = "name")]`.
+#[proc_macro_derive(FormValue, attributes(multipart))]
+pub fn derive_form_value(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    multipart::derive_value(input)
+        .unwrap_or_else(|err| err.to_compile_error())
+        .into()
+}
+
+/// Build a struct from a row of a query result, each field is read from the
+/// column of the same name, see `sql::FromRow`.
+#[proc_macro_derive(FromRow)]
+pub fn derive_from_row(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    row::derive_from_row(input)
+        .unwrap_or_else(|err| err.to_compile_error())
+        .into()
+}
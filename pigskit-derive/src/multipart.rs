@@ -0,0 +1,280 @@
+use std::collections::HashSet;
+use proc_macro2::TokenStream;
+use quote::{
+    format_ident,
+    quote,
+};
+use syn::{
+    Attribute,
+    Data,
+    DataStruct,
+    DeriveInput,
+    Error,
+    Expr,
+    Fields,
+    GenericArgument,
+    Ident,
+    LitByteStr,
+    LitStr,
+    PathArguments,
+    Result,
+    Token,
+    Type,
+    TypePath,
+    ext::IdentExt,
+    parse::{
+        Parse,
+        ParseStream,
+    },
+    punctuated::Punctuated,
+};
+
+/// A `key = value` in a `#[multipart(...)]` attribute.
+struct Arg {
+    key: Ident,
+    value: Value,
+}
+
+enum Value {
+    Str(LitStr),
+    Expr(Box<Expr>),
+}
+
+impl Parse for Arg {
+    fn parse(input: ParseStream) -> Result<Self> {
+        let key: Ident = input.parse()?;
+        input.parse::<Token![=]>()?;
+        let value = if key == "limit" {
+            Value::Expr(input.parse()?)
+        } else {
+            Value::Str(input.parse()?)
+        };
+        Ok(Arg {
+            key,
+            value,
+        })
+    }
+}
+
+impl Arg {
+    fn str(&self) -> Result<&LitStr> {
+        match &self.value {
+            Value::Str(lit) => Ok(lit),
+            Value::Expr(expr) => Err(Error::new_spanned(expr, "expected a string literal")),
+        }
+    }
+
+    fn unknown(&self, expected: &str) -> Error {
+        Error::new(self.key.span(), format!("unknown argument `{}`, expected {}", self.key, expected))
+    }
+}
+
+fn args(attrs: &[Attribute]) -> Result<Vec<Arg>> {
+    let mut args = Vec::new();
+    for attr in attrs.iter().filter(|attr| attr.path.is_ident("multipart")) {
+        args.extend(attr.parse_args_with(Punctuated::<Arg, Token![,]>::parse_terminated)?);
+    }
+    Ok(args)
+}
+
+enum Kind {
+    Required,
+    Optional,
+    Repeated,
+}
+
+/// The `T` of a `name<T>` type.
+fn generic_argument<'a>(ty: &'a Type, name: &str) -> Option<&'a Type> {
+    let segment = match ty {
+        Type::Path(TypePath { qself: None, path }) => path.segments.last()?,
+        _ => return None,
+    };
+    if segment.ident != name {
+        return None
+    }
+    match &segment.arguments {
+        PathArguments::AngleBracketed(args) if args.args.len() == 1 => match &args.args[0] {
+            GenericArgument::Type(ty) => Some(ty),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+fn is_u8(ty: &Type) -> bool {
+    match ty {
+        Type::Path(ty) => ty.qself.is_none() && ty.path.is_ident("u8"),
+        _ => false,
+    }
+}
+
+/// How a field collects its parts, and the type of a single part.
+fn kind(ty: &Type) -> (Kind, &Type) {
+    if let Some(ty) = generic_argument(ty, "Option") {
+        (Kind::Optional, ty)
+    } else if let Some(ty) = generic_argument(ty, "Vec").filter(|ty| !is_u8(ty)) {
+        (Kind::Repeated, ty)
+    } else {
+        (Kind::Required, ty)
+    }
+}
+
+pub fn derive_form(input: DeriveInput) -> Result<TokenStream> {
+    let fields = match &input.data {
+        Data::Struct(DataStruct { fields: Fields::Named(fields), .. }) => &fields.named,
+        _ => return Err(Error::new(input.ident.span(), "MultipartForm can only be derived for structs with named fields")),
+    };
+    if !input.generics.params.is_empty() {
+        return Err(Error::new_spanned(&input.generics, "MultipartForm can not be derived for generic structs"))
+    }
+
+    let form = quote!(crate::route::utils::filter::form);
+    let mut names = HashSet::new();
+    let mut declares = Vec::new();
+    let mut arms = Vec::new();
+    let mut finishes = Vec::new();
+    for field in fields {
+        let ident = field.ident.as_ref().unwrap();
+        let var = format_ident!("__{}", ident.unraw());
+        let mut name = ident.unraw().to_string();
+        let mut limit = quote!(None);
+        let mut content_types = Vec::new();
+        for arg in args(&field.attrs)? {
+            if arg.key == "rename" {
+                name = arg.str()?.value();
+            } else if arg.key == "limit" {
+                if let Value::Expr(expr) = &arg.value {
+                    limit = quote!(Some((#expr) as u64));
+                }
+            } else if arg.key == "content_type" {
+                let content_type = arg.str()?;
+                if content_type.value().split('/').filter(|part| !part.is_empty()).count() != 2 {
+                    return Err(Error::new(content_type.span(), "expected a content type such as \"image/png\" or \"image/*\""))
+                }
+                content_types.push(content_type.clone());
+            } else {
+                return Err(arg.unknown("`rename`, `limit` or `content_type`"))
+            }
+        }
+        if !names.insert(name.clone()) {
+            return Err(Error::new(ident.span(), format!("duplicate part \"{}\"", name)))
+        }
+
+        let (kind, ty) = kind(&field.ty);
+        let read = quote! {
+            reader.read::<#ty>(
+                &#form::Field {
+                    name: #name,
+                    limit: #limit,
+                    content_types: &[#(#content_types),*],
+                },
+                part,
+            ).await?
+        };
+        match kind {
+            Kind::Required | Kind::Optional => {
+                declares.push(quote!(let mut #var: Option<#ty> = None;));
+                arms.push(quote! {
+                    #name => {
+                        if #var.is_some() {
+                            return Err(crate::error::Error::invalid_data(#name))
+                        }
+                        #var = Some(#read);
+                    }
+                });
+            }
+            Kind::Repeated => {
+                declares.push(quote!(let mut #var: Vec<#ty> = Vec::new();));
+                arms.push(quote! {
+                    #name => #var.push(#read),
+                });
+            }
+        }
+        finishes.push(match kind {
+            Kind::Required => quote!(#ident: #var.ok_or_else(|| crate::error::Error::no_valid_form(#name))?),
+            Kind::Optional | Kind::Repeated => quote!(#ident: #var),
+        });
+    }
+
+    let ident = &input.ident;
+    Ok(quote! {
+        #[::async_trait::async_trait]
+        impl #form::MultipartForm for #ident {
+            async fn from_form(
+                mut form: #form::FormData,
+                reader: &mut #form::PartReader,
+            ) -> ::std::result::Result<Self, crate::error::Error> {
+                #(#declares)*
+                while let Some(part) = form.next_part().await? {
+                    let name = part.name().to_string();
+                    match name.as_str() {
+                        #(#arms)*
+                        _ => {}
+                    }
+                }
+
+                Ok(#ident {
+                    #(#finishes),*
+                })
+            }
+        }
+    })
+}
+
+/// `PendingPayment` to `pending_payment`.
+fn snake_case(name: &str) -> String {
+    let mut snake = String::new();
+    for (i, c) in name.chars().enumerate() {
+        if c.is_uppercase() {
+            if i > 0 {
+                snake.push('_');
+            }
+            snake.extend(c.to_lowercase());
+        } else {
+            snake.push(c);
+        }
+    }
+    snake
+}
+
+pub fn derive_value(input: DeriveInput) -> Result<TokenStream> {
+    let variants = match &input.data {
+        Data::Enum(data) => &data.variants,
+        _ => return Err(Error::new(input.ident.span(), "FormValue can only be derived for enums")),
+    };
+
+    let ident = &input.ident;
+    let mut names = HashSet::new();
+    let mut arms = Vec::new();
+    for variant in variants {
+        if !matches!(variant.fields, Fields::Unit) {
+            return Err(Error::new_spanned(variant, "FormValue can only be derived for enums of unit variants"))
+        }
+        let mut name = snake_case(&variant.ident.unraw().to_string());
+        for arg in args(&variant.attrs)? {
+            if arg.key == "rename" {
+                name = arg.str()?.value();
+            } else {
+                return Err(arg.unknown("`rename`"))
+            }
+        }
+        if !names.insert(name.clone()) {
+            return Err(Error::new(variant.ident.span(), format!("duplicate value \"{}\"", name)))
+        }
+
+        let name = LitByteStr::new(name.as_bytes(), variant.ident.span());
+        let variant = &variant.ident;
+        arms.push(quote!(#name => Some(#ident::#variant),));
+    }
+
+    Ok(quote! {
+        impl crate::route::utils::filter::form::FormValue for #ident {
+            fn parse(data: Vec<u8>) -> Option<Self> {
+                match data.as_slice() {
+                    #(#arms)*
+                    _ => None,
+                }
+            }
+        }
+    })
+}
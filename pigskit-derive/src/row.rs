@@ -0,0 +1,35 @@
+use proc_macro2::TokenStream;
+use quote::quote;
+use syn::{
+    Data,
+    DataStruct,
+    DeriveInput,
+    Error,
+    Fields,
+    Result,
+    ext::IdentExt,
+};
+
+pub fn derive_from_row(input: DeriveInput) -> Result<TokenStream> {
+    let fields = match &input.data {
+        Data::Struct(DataStruct { fields: Fields::Named(fields), .. }) => &fields.named,
+        _ => return Err(Error::new(input.ident.span(), "FromRow can only be derived for structs with named fields")),
+    };
+
+    let (impl_generics, type_generics, where_clause) = input.generics.split_for_impl();
+    let ident = &input.ident;
+    let fields = fields.iter().map(|field| {
+        let ident = field.ident.as_ref().unwrap();
+        let column = ident.unraw().to_string();
+        quote!(#ident: row.try_get(#column)?)
+    });
+    Ok(quote! {
+        impl #impl_generics crate::sql::FromRow for #ident #type_generics #where_clause {
+            fn from_row(row: &::tokio_postgres::Row) -> ::std::result::Result<Self, ::tokio_postgres::Error> {
+                Ok(#ident {
+                    #(#fields),*
+                })
+            }
+        }
+    })
+}
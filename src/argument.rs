@@ -1,4 +1,4 @@
-use clap::{App, Arg, ArgMatches};
+use clap::{App, AppSettings, Arg, ArgMatches, SubCommand};
 
 pub fn parse_arguments<'a>() -> ArgMatches<'a> {
     App::new("pigskit-server")
@@ -13,11 +13,48 @@ pub fn parse_arguments<'a>() -> ArgMatches<'a> {
                 .help("Set the port that server will listen.")
                 .takes_value(true),
         )
+        .arg(
+            Arg::with_name("host")
+                .long("host")
+                .value_name("HOST")
+                .help("Set the address that server will bind.")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("config")
+                .short("c")
+                .long("config")
+                .value_name("FILE")
+                .help("Set the path of the config file.")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("database")
+                .long("database")
+                .value_name("DSN")
+                .help("Set the postgres connection string.")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("storage")
+                .long("storage")
+                .value_name("DIR")
+                .help("Set the root directory of the storage.")
+                .takes_value(true),
+        )
         .arg(
             Arg::with_name("dev")
                 .help("run server in development mode.")
                 .short("d"),
         )
+        .subcommand(
+            SubCommand::with_name("migrate")
+                .about("Manage the database schema.")
+                .setting(AppSettings::SubcommandRequiredElseHelp)
+                .subcommand(SubCommand::with_name("up").about("Apply all pending migrations."))
+                .subcommand(SubCommand::with_name("down").about("Revert the latest applied migration."))
+                .subcommand(SubCommand::with_name("status").about("Show the applied and pending migrations.")),
+        )
         .get_matches()
 }
 
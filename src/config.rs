@@ -0,0 +1,959 @@
+use std::{
+    env,
+    fmt,
+    fs,
+    net::{IpAddr, SocketAddr},
+    path::PathBuf,
+    time::Duration,
+};
+use clap::ArgMatches;
+use regex::Regex;
+use warp::http::{
+    HeaderName,
+    Method,
+};
+use toml::{
+    Value,
+    value::Table,
+};
+
+/// Prefix of the environment variables overriding the configuration,
+/// e.g. `PIGSKIT_DATABASE_POOL_SIZE=32` overrides `database.pool_size`.
+const ENV_PREFIX: &str = "PIGSKIT_";
+const ENV_CONFIG_FILE: &str = "PIGSKIT_CONFIG";
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    pub dev: bool,
+    pub server: ServerConfig,
+    pub database: DatabaseConfig,
+    pub storage: StorageConfig,
+    pub cors: CorsConfig,
+    pub cookie: CookieConfig,
+    pub ratelimit: RateLimitConfig,
+    pub mail: MailConfig,
+    pub sms: SmsConfig,
+    pub totp: TotpConfig,
+    pub upload: UploadConfig,
+    pub image: ImageConfig,
+    pub log: LogConfig,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct ServerConfig {
+    pub host: String,
+    pub port: u16,
+    /// Seconds to wait for in-flight requests to finish on shutdown.
+    pub shutdown_timeout: u64,
+    /// PEM certificate chain and private key, the server listens with TLS
+    /// when both are set. They are read again on SIGHUP.
+    pub tls_cert: Option<String>,
+    pub tls_key: Option<String>,
+    /// Port listening in plaintext which redirects to HTTPS, only with TLS.
+    pub redirect_port: Option<u16>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct DatabaseConfig {
+    pub dsn: String,
+    pub pool_size: u32,
+    /// Seconds to wait for a connection to be checked out from the pool.
+    pub connection_timeout: u64,
+    /// Seconds an idle connection is kept in the pool.
+    pub idle_timeout: Option<u64>,
+    /// Seconds a connection lives before being recycled.
+    pub max_lifetime: Option<u64>,
+    /// Whether to use TLS, overrides the `sslmode` of `dsn`.
+    pub sslmode: SslMode,
+    /// PEM bundle of the CAs trusted by `verify-full`, the system roots are used if not set.
+    pub sslrootcert: Option<String>,
+    /// PEM client certificate chain and its private key.
+    pub sslcert: Option<String>,
+    pub sslkey: Option<String>,
+}
+
+/// Same as the `sslmode` of libpq, except that `prefer` and `require` do not
+/// verify the server certificate at all.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum SslMode {
+    Disable,
+    Prefer,
+    Require,
+    /// Verify the certificate chain and that the host name matches it.
+    VerifyFull,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct StorageConfig {
+    pub backend: StorageBackend,
+    /// Root directory of the filesystem backend.
+    pub root: String,
+    /// Endpoint of an S3-compatible service such as MinIO, AWS S3 is used if not set.
+    pub s3_endpoint: Option<String>,
+    pub s3_region: String,
+    pub s3_bucket: Option<String>,
+    /// Static credentials, the AWS environment and profile are used if not set.
+    pub s3_access_key: Option<String>,
+    pub s3_secret_key: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum StorageBackend {
+    Filesystem,
+    S3,
+}
+
+/// Requests from browsers on other origins, which are refused by them
+/// unless allowed here.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct CorsConfig {
+    /// Exact origins such as `https://pigskit.com`.
+    pub allowed_origins: Vec<String>,
+    /// Regular expressions matching whole origins.
+    pub allowed_origin_patterns: Vec<String>,
+    pub allowed_methods: Vec<String>,
+    /// Request headers allowed besides the CORS-safelisted ones.
+    pub allowed_headers: Vec<String>,
+    /// Seconds browsers may cache the result of a preflight request.
+    pub max_age: Option<u64>,
+    /// Whether requests may carry cookies, which the sessions rely on.
+    pub allow_credentials: bool,
+}
+
+/// Lifetimes of the sessions and their cookies, in days.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct CookieConfig {
+    /// How long a sign-in with "remember me" lasts unused. Using it past half
+    /// of that extends it and issues its cookie again.
+    pub user_session_days: i64,
+    /// How long a sign-in without "remember me" lasts unused, its cookie
+    /// ending with the browser session.
+    pub user_session_browser_hours: i64,
+    /// How long any sign-in lasts at most, however much it is used.
+    pub user_session_max_days: i64,
+    pub guest_session_days: i64,
+    pub register_session_days: i64,
+}
+
+/// Token buckets of the endpoints open to anyone, and the lockout of
+/// usernames after failed sign-ins.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct RateLimitConfig {
+    /// Where the buckets are kept, `postgres` shares them between instances.
+    pub store: RateLimitStore,
+    pub signin_ip: BucketConfig,
+    pub signin_username: BucketConfig,
+    pub register_ip: BucketConfig,
+    pub cart_ip: BucketConfig,
+    /// Password reset requests, each sending a mail.
+    pub reset_ip: BucketConfig,
+    /// Password reset mails to an email, from any address.
+    pub reset_email: BucketConfig,
+    /// Verification codes sent.
+    pub code_ip: BucketConfig,
+    /// Failed sign-ins of a username in a row before it is locked.
+    pub lockout_failures: u32,
+    /// Seconds of the first lock, doubled by each further failure.
+    pub lockout_seconds: u64,
+    /// Longest lock in seconds, also how long failures are remembered.
+    pub lockout_max_seconds: u64,
+    /// Networks of the reverse proxies, in CIDR notation. The client of a
+    /// request from them is the right-most address of `X-Forwarded-For`
+    /// not in them.
+    pub trusted_proxies: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum RateLimitStore {
+    Memory,
+    Postgres,
+}
+
+/// Mails sent to the users, such as the password reset links.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct MailConfig {
+    pub backend: MailBackend,
+    /// Sender address of the mails.
+    pub from: String,
+    /// Directory the file backend writes the mails to.
+    pub dir: Option<String>,
+    /// Relay of the smtp backend.
+    pub smtp_host: Option<String>,
+    pub smtp_port: u16,
+    pub smtp_security: SmtpSecurity,
+    /// Credentials for `AUTH PLAIN`, sent only over TLS unless `smtp_security` is `none`.
+    pub smtp_username: Option<String>,
+    pub smtp_password: Option<String>,
+    /// Minutes a code verifying an email is valid.
+    pub verification_code_minutes: u32,
+    /// Page of the web client setting a new password, `{token}` is replaced
+    /// by the reset token.
+    pub reset_url: String,
+    /// Minutes a password reset token is valid.
+    pub reset_token_minutes: u32,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum MailBackend {
+    /// Log the mails instead of sending them, for development.
+    Log,
+    /// Write each mail to a file.
+    File,
+    Smtp,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum SmtpSecurity {
+    /// Upgrade the connection with `STARTTLS`, usually on port 587.
+    StartTls,
+    /// TLS from the start, usually on port 465.
+    Tls,
+    /// Plaintext, only for a relay on the same host.
+    None,
+}
+
+/// Text messages sent to the users, such as the codes verifying phones.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct SmsConfig {
+    pub backend: SmsBackend,
+    /// Minutes a code verifying a phone is valid.
+    pub code_minutes: u32,
+    /// Seconds between two codes sent to a number.
+    pub resend_seconds: u32,
+    /// Codes sent to a number in an hour at most.
+    pub max_per_hour: u32,
+}
+
+/// Two-factor authentication with codes from an authenticator app.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct TotpConfig {
+    /// Name of the service shown in the authenticator app.
+    pub issuer: String,
+    /// Minutes to enter the code after the password when signing in.
+    pub challenge_minutes: u32,
+    /// Single-use codes given to sign in without the app.
+    pub recovery_codes: u32,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum SmsBackend {
+    /// Log the messages instead of sending them, for development.
+    Log,
+}
+
+/// A network of addresses sharing the first `prefix` bits of `addr`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Cidr {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+impl Cidr {
+    /// Parse `10.0.0.0/8` or `fd00::/8`, or a single address.
+    pub fn parse(s: &str) -> Option<Self> {
+        let (addr, prefix) = match s.find('/') {
+            Some(index) => (&s[..index], Some(&s[index + 1..])),
+            None => (s, None),
+        };
+        let addr: IpAddr = addr.parse().ok()?;
+        let bits = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(prefix) => prefix.parse().ok().filter(|prefix| *prefix <= bits)?,
+            None => bits,
+        };
+        Some(Cidr {
+            addr,
+            prefix,
+        })
+    }
+
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        let (network, ip) = match (self.addr, ip) {
+            (IpAddr::V4(network), IpAddr::V4(ip)) => (network.octets().to_vec(), ip.octets().to_vec()),
+            (IpAddr::V6(network), IpAddr::V6(ip)) => (network.octets().to_vec(), ip.octets().to_vec()),
+            _ => return false,
+        };
+        let (bytes, bits) = (self.prefix as usize / 8, self.prefix % 8);
+        network[..bytes] == ip[..bytes] && (bits == 0 || (network[bytes] ^ ip[bytes]) >> (8 - bits) == 0)
+    }
+}
+
+/// Allows `burst` requests at once, refilled at `per_minute`.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct BucketConfig {
+    pub burst: u32,
+    pub per_minute: u32,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct UploadConfig {
+    /// Maximum size in bytes of a multipart form body, endpoints limit
+    /// their fields further.
+    pub max_length: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct ImageConfig {
+    /// Uploaded images wider or taller than this are refused.
+    pub max_width: u32,
+    pub max_height: u32,
+    /// JPEG quality of the re-encoded images, 1 to 100.
+    pub quality: u8,
+    /// Longest edges of the generated thumbnails.
+    pub thumbnail_sizes: Vec<u32>,
+    /// Seconds clients may cache public images before revalidating them.
+    pub cache_max_age: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct LogConfig {
+    /// Default log filter, in the syntax of `RUST_LOG` which takes precedence.
+    pub level: String,
+    pub format: LogFormat,
+    pub access_log: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    Text,
+    Json,
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Io(PathBuf, std::io::Error),
+    Parse(String),
+    Invalid(&'static str, String),
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::Io(path, err) => write!(f, "Failed to read config file {:?}: {}", path, err),
+            ConfigError::Parse(message) => write!(f, "Failed to parse config: {}", message),
+            ConfigError::Invalid(field, message) => write!(f, r#"Invalid config "{}": {}"#, field, message),
+        }
+    }
+}
+
+impl From<toml::de::Error> for ConfigError {
+    fn from(err: toml::de::Error) -> Self {
+        ConfigError::Parse(err.to_string())
+    }
+}
+
+impl From<toml::ser::Error> for ConfigError {
+    fn from(err: toml::ser::Error) -> Self {
+        ConfigError::Parse(err.to_string())
+    }
+}
+
+impl Config {
+    /// Built-in defaults, the bottom layer of the configuration.
+    pub fn defaults(dev: bool) -> Self {
+        Config {
+            dev,
+            server: ServerConfig {
+                host: "0.0.0.0".to_string(),
+                port: if dev { 8001 } else { 80 },
+                shutdown_timeout: 30,
+                tls_cert: None,
+                tls_key: None,
+                redirect_port: None,
+            },
+            database: DatabaseConfig {
+                dsn: if dev {
+                    "host=localhost user=postgres dbname=postgres".to_string()
+                } else {
+                    "host=postgres-server user=postgres dbname=postgres".to_string()
+                },
+                pool_size: 16,
+                connection_timeout: 30,
+                idle_timeout: Some(600),
+                max_lifetime: Some(1800),
+                sslmode: SslMode::Prefer,
+                sslrootcert: None,
+                sslcert: None,
+                sslkey: None,
+            },
+            storage: StorageConfig {
+                backend: StorageBackend::Filesystem,
+                root: default_storage_root(),
+                s3_endpoint: None,
+                s3_region: "us-east-1".to_string(),
+                s3_bucket: None,
+                s3_access_key: None,
+                s3_secret_key: None,
+            },
+            cors: CorsConfig {
+                allowed_origins: if dev {
+                    vec!["http://localhost:3000".to_string()]
+                } else {
+                    Vec::new()
+                },
+                allowed_origin_patterns: Vec::new(),
+                allowed_methods: ["GET", "POST", "PUT", "PATCH", "DELETE"].iter().map(|method| method.to_string()).collect(),
+                allowed_headers: vec!["Content-Type".to_string(), "X-CSRF-Token".to_string()],
+                max_age: Some(600),
+                allow_credentials: true,
+            },
+            cookie: CookieConfig {
+                user_session_days: 30,
+                user_session_browser_hours: 12,
+                user_session_max_days: 90,
+                guest_session_days: 1,
+                register_session_days: 1,
+            },
+            ratelimit: RateLimitConfig {
+                store: RateLimitStore::Memory,
+                signin_ip: BucketConfig { burst: 20, per_minute: 10 },
+                signin_username: BucketConfig { burst: 10, per_minute: 5 },
+                register_ip: BucketConfig { burst: 10, per_minute: 5 },
+                cart_ip: BucketConfig { burst: 30, per_minute: 20 },
+                reset_ip: BucketConfig { burst: 5, per_minute: 1 },
+                reset_email: BucketConfig { burst: 3, per_minute: 1 },
+                code_ip: BucketConfig { burst: 10, per_minute: 2 },
+                lockout_failures: 5,
+                lockout_seconds: 30,
+                lockout_max_seconds: 3600,
+                trusted_proxies: Vec::new(),
+            },
+            mail: MailConfig {
+                backend: MailBackend::Log,
+                from: "Pigskit <noreply@pigskit.com>".to_string(),
+                dir: None,
+                smtp_host: None,
+                smtp_port: 587,
+                smtp_security: SmtpSecurity::StartTls,
+                smtp_username: None,
+                smtp_password: None,
+                verification_code_minutes: 10,
+                reset_url: if dev {
+                    "http://localhost:3000/password/reset?token={token}".to_string()
+                } else {
+                    "https://pigskit.com/password/reset?token={token}".to_string()
+                },
+                reset_token_minutes: 30,
+            },
+            sms: SmsConfig {
+                backend: SmsBackend::Log,
+                code_minutes: 10,
+                resend_seconds: 60,
+                max_per_hour: 5,
+            },
+            totp: TotpConfig {
+                issuer: "Pigskit".to_string(),
+                challenge_minutes: 5,
+                recovery_codes: 10,
+            },
+            upload: UploadConfig {
+                max_length: 10000000,
+            },
+            image: ImageConfig {
+                max_width: 4096,
+                max_height: 4096,
+                quality: 85,
+                thumbnail_sizes: vec![64, 256, 1024],
+                cache_max_age: 3600,
+            },
+            log: LogConfig {
+                level: "info".to_string(),
+                format: LogFormat::Text,
+                access_log: true,
+            },
+        }
+    }
+
+    /// Load the configuration by layering, from lowest to highest priority:
+    /// built-in defaults, the TOML config file, `PIGSKIT_*` environment
+    /// variables and command line arguments.
+    pub fn load(args: &ArgMatches) -> Result<Self, ConfigError> {
+        let dev = args.is_present("dev");
+        let mut config = Value::try_from(Config::defaults(dev))?;
+
+        let file = args.value_of("config")
+            .map(PathBuf::from)
+            .or_else(|| env::var_os(ENV_CONFIG_FILE).map(PathBuf::from));
+        if let Some(path) = file {
+            let content = fs::read_to_string(&path).map_err(|err| ConfigError::Io(path.clone(), err))?;
+            merge(&mut config, content.parse::<Value>()?);
+        }
+
+        merge(&mut config, env_layer(env::vars())?);
+        merge(&mut config, args_layer(args));
+
+        let config: Config = config.try_into()?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        self.server.host.parse::<IpAddr>()
+            .map_err(|err| ConfigError::Invalid("server.host", err.to_string()))?;
+        if self.server.tls_cert.is_some() != self.server.tls_key.is_some() {
+            return Err(ConfigError::Invalid("server.tls_key", "must be set together with server.tls_cert.".to_string()))
+        }
+        match self.server.redirect_port {
+            Some(_) if self.server.tls().is_none() => {
+                return Err(ConfigError::Invalid("server.redirect_port", "requires server.tls_cert and server.tls_key.".to_string()))
+            }
+            Some(port) if port == self.server.port => {
+                return Err(ConfigError::Invalid("server.redirect_port", "must differ from server.port.".to_string()))
+            }
+            _ => {}
+        }
+
+        self.database.dsn.parse::<tokio_postgres::Config>()
+            .map_err(|err| ConfigError::Invalid("database.dsn", err.to_string()))?;
+        if self.database.pool_size == 0 {
+            return Err(ConfigError::Invalid("database.pool_size", "must be greater than 0.".to_string()))
+        }
+        if self.database.connection_timeout == 0 {
+            return Err(ConfigError::Invalid("database.connection_timeout", "must be greater than 0.".to_string()))
+        }
+        if self.database.sslcert.is_some() != self.database.sslkey.is_some() {
+            return Err(ConfigError::Invalid("database.sslkey", "must be set together with database.sslcert.".to_string()))
+        }
+
+        if self.storage.backend == StorageBackend::S3 {
+            if self.storage.s3_bucket.is_none() {
+                return Err(ConfigError::Invalid("storage.s3_bucket", "is required by the s3 backend.".to_string()))
+            }
+            if self.storage.s3_access_key.is_some() != self.storage.s3_secret_key.is_some() {
+                return Err(ConfigError::Invalid("storage.s3_secret_key", "must be set together with storage.s3_access_key.".to_string()))
+            }
+        }
+
+        for origin in self.cors.allowed_origins.iter() {
+            if !origin.starts_with("http://") && !origin.starts_with("https://") {
+                return Err(ConfigError::Invalid("cors.allowed_origins", format!("{} is not an http(s) origin.", origin)))
+            }
+        }
+        self.cors.origin_patterns()
+            .map_err(|err| ConfigError::Invalid("cors.allowed_origin_patterns", err.to_string()))?;
+        for method in self.cors.allowed_methods.iter() {
+            Method::from_bytes(method.as_bytes())
+                .map_err(|_| ConfigError::Invalid("cors.allowed_methods", format!("{} is not a method.", method)))?;
+        }
+        for header in self.cors.allowed_headers.iter() {
+            HeaderName::from_bytes(header.as_bytes())
+                .map_err(|_| ConfigError::Invalid("cors.allowed_headers", format!("{} is not a header name.", header)))?;
+        }
+
+        if self.cookie.user_session_days <= 0 {
+            return Err(ConfigError::Invalid("cookie.user_session_days", "must be greater than 0.".to_string()))
+        }
+        if self.cookie.user_session_browser_hours <= 0 {
+            return Err(ConfigError::Invalid("cookie.user_session_browser_hours", "must be greater than 0.".to_string()))
+        }
+        // The lifetimes are given to the database in seconds, as an `int`.
+        if self.cookie.user_session_max_days > i32::MAX as i64 / (24 * 3600) {
+            return Err(ConfigError::Invalid("cookie.user_session_max_days", format!("must be at most {}.", i32::MAX as i64 / (24 * 3600))))
+        }
+        if self.cookie.user_session_max_days < self.cookie.user_session_days
+            || self.cookie.user_session_max_days * 24 < self.cookie.user_session_browser_hours {
+            return Err(ConfigError::Invalid("cookie.user_session_max_days", "must not be shorter than the other user session lifetimes.".to_string()))
+        }
+        if self.cookie.guest_session_days <= 0 {
+            return Err(ConfigError::Invalid("cookie.guest_session_days", "must be greater than 0.".to_string()))
+        }
+        if self.cookie.register_session_days <= 0 {
+            return Err(ConfigError::Invalid("cookie.register_session_days", "must be greater than 0.".to_string()))
+        }
+
+        let ratelimit = &self.ratelimit;
+        for (field, bucket) in [
+            ("ratelimit.signin_ip", ratelimit.signin_ip),
+            ("ratelimit.signin_username", ratelimit.signin_username),
+            ("ratelimit.register_ip", ratelimit.register_ip),
+            ("ratelimit.cart_ip", ratelimit.cart_ip),
+            ("ratelimit.reset_ip", ratelimit.reset_ip),
+            ("ratelimit.reset_email", ratelimit.reset_email),
+            ("ratelimit.code_ip", ratelimit.code_ip),
+        ].iter() {
+            if bucket.burst == 0 || bucket.per_minute == 0 {
+                return Err(ConfigError::Invalid(field, "burst and per_minute must be greater than 0.".to_string()))
+            }
+        }
+        if ratelimit.lockout_failures == 0 {
+            return Err(ConfigError::Invalid("ratelimit.lockout_failures", "must be greater than 0.".to_string()))
+        }
+        if ratelimit.lockout_seconds == 0 {
+            return Err(ConfigError::Invalid("ratelimit.lockout_seconds", "must be greater than 0.".to_string()))
+        }
+        if ratelimit.lockout_max_seconds < ratelimit.lockout_seconds {
+            return Err(ConfigError::Invalid("ratelimit.lockout_max_seconds", "must not be less than ratelimit.lockout_seconds.".to_string()))
+        }
+        ratelimit.proxies()
+            .map_err(|proxy| ConfigError::Invalid("ratelimit.trusted_proxies", format!("{} is not a network in CIDR notation.", proxy)))?;
+
+        if !self.mail.from.contains('@') {
+            return Err(ConfigError::Invalid("mail.from", "must be a mail address.".to_string()))
+        }
+        if self.mail.backend == MailBackend::File && self.mail.dir.is_none() {
+            return Err(ConfigError::Invalid("mail.dir", "is required by the file backend.".to_string()))
+        }
+        if self.mail.backend == MailBackend::Smtp && self.mail.smtp_host.is_none() {
+            return Err(ConfigError::Invalid("mail.smtp_host", "is required by the smtp backend.".to_string()))
+        }
+        if self.mail.smtp_username.is_some() != self.mail.smtp_password.is_some() {
+            return Err(ConfigError::Invalid("mail.smtp_password", "must be set together with mail.smtp_username.".to_string()))
+        }
+        if self.mail.verification_code_minutes == 0 {
+            return Err(ConfigError::Invalid("mail.verification_code_minutes", "must be greater than 0.".to_string()))
+        }
+        if !self.mail.reset_url.contains("{token}") {
+            return Err(ConfigError::Invalid("mail.reset_url", "must contain {token}.".to_string()))
+        }
+        if self.mail.reset_token_minutes == 0 {
+            return Err(ConfigError::Invalid("mail.reset_token_minutes", "must be greater than 0.".to_string()))
+        }
+
+        if self.sms.code_minutes == 0 {
+            return Err(ConfigError::Invalid("sms.code_minutes", "must be greater than 0.".to_string()))
+        }
+        if self.sms.max_per_hour == 0 {
+            return Err(ConfigError::Invalid("sms.max_per_hour", "must be greater than 0.".to_string()))
+        }
+
+        if self.totp.issuer.is_empty() || !self.totp.issuer.chars().all(|c| c.is_ascii_alphanumeric() || c == ' ') {
+            return Err(ConfigError::Invalid("totp.issuer", "must be letters, digits and spaces.".to_string()))
+        }
+        if self.totp.challenge_minutes == 0 {
+            return Err(ConfigError::Invalid("totp.challenge_minutes", "must be greater than 0.".to_string()))
+        }
+        if self.totp.recovery_codes == 0 {
+            return Err(ConfigError::Invalid("totp.recovery_codes", "must be greater than 0.".to_string()))
+        }
+
+        if self.upload.max_length == 0 {
+            return Err(ConfigError::Invalid("upload.max_length", "must be greater than 0.".to_string()))
+        }
+
+        if self.image.max_width == 0 || self.image.max_height == 0 {
+            return Err(ConfigError::Invalid("image.max_width", "dimensions must be greater than 0.".to_string()))
+        }
+        if self.image.quality == 0 || self.image.quality > 100 {
+            return Err(ConfigError::Invalid("image.quality", "must be between 1 and 100.".to_string()))
+        }
+        if self.image.thumbnail_sizes.contains(&0) {
+            return Err(ConfigError::Invalid("image.thumbnail_sizes", "must be greater than 0.".to_string()))
+        }
+
+        if self.log.level.is_empty() {
+            return Err(ConfigError::Invalid("log.level", "must not be empty.".to_string()))
+        }
+
+        Ok(())
+    }
+
+    pub fn shutdown_timeout(&self) -> Duration {
+        Duration::from_secs(self.server.shutdown_timeout)
+    }
+
+    pub fn bind_address(&self) -> SocketAddr {
+        SocketAddr::new(self.server.host.parse().expect("validated server.host"), self.server.port)
+    }
+
+    pub fn redirect_address(&self) -> Option<SocketAddr> {
+        self.server.redirect_port.map(|port| SocketAddr::new(self.server.host.parse().expect("validated server.host"), port))
+    }
+}
+
+impl ServerConfig {
+    /// The paths of the certificate and the key if TLS is on.
+    pub fn tls(&self) -> Option<(&str, &str)> {
+        match (&self.tls_cert, &self.tls_key) {
+            (Some(cert), Some(key)) => Some((cert, key)),
+            _ => None,
+        }
+    }
+
+    pub fn is_tls(&self) -> bool {
+        self.tls().is_some()
+    }
+}
+
+impl CorsConfig {
+    /// The patterns anchored to match whole origins.
+    pub fn origin_patterns(&self) -> Result<Vec<Regex>, regex::Error> {
+        self.allowed_origin_patterns.iter()
+            .map(|pattern| Regex::new(&format!("^(?:{})$", pattern)))
+            .collect()
+    }
+}
+
+impl RateLimitConfig {
+    /// The trusted proxies, or the first entry not a network.
+    pub fn proxies(&self) -> Result<Vec<Cidr>, &str> {
+        self.trusted_proxies.iter()
+            .map(|proxy| Cidr::parse(proxy).ok_or(proxy.as_str()))
+            .collect()
+    }
+}
+
+impl BucketConfig {
+    pub fn per_second(&self) -> f64 {
+        self.per_minute as f64 / 60.0
+    }
+}
+
+impl DatabaseConfig {
+    pub fn connection_timeout(&self) -> Duration {
+        Duration::from_secs(self.connection_timeout)
+    }
+
+    pub fn idle_timeout(&self) -> Option<Duration> {
+        self.idle_timeout.map(Duration::from_secs)
+    }
+
+    pub fn max_lifetime(&self) -> Option<Duration> {
+        self.max_lifetime.map(Duration::from_secs)
+    }
+}
+
+/// The `storage` directory next to the directory of the executable.
+fn default_storage_root() -> String {
+    if let Ok(mut path) = env::current_exe() {
+        if path.pop() && path.pop() {
+            path.push("storage");
+            if let Some(path) = path.to_str() {
+                return path.to_owned()
+            }
+        }
+    }
+    "storage".to_owned()
+}
+
+/// Recursively merge the tables of `layer` into `base`, values in `layer` win.
+fn merge(base: &mut Value, layer: Value) {
+    match (base, layer) {
+        (Value::Table(base), Value::Table(layer)) => {
+            for (key, value) in layer {
+                if let Some(base) = base.get_mut(&key) {
+                    merge(base, value);
+                } else {
+                    base.insert(key, value);
+                }
+            }
+        }
+        (base, layer) => {
+            *base = layer;
+        }
+    }
+}
+
+/// Map `PIGSKIT_<SECTION>_<KEY>` variables into a table. Values are parsed as
+/// TOML values when possible (numbers, booleans, arrays) or taken as strings.
+fn env_layer<I: Iterator<Item = (String, String)>>(vars: I) -> Result<Value, ConfigError> {
+    let mut layer = Table::new();
+    for (name, raw) in vars {
+        if name == ENV_CONFIG_FILE || !name.starts_with(ENV_PREFIX) {
+            continue
+        }
+        let name = name[ENV_PREFIX.len()..].to_lowercase();
+        let value = format!("value = {}", raw)
+            .parse::<Value>()
+            .ok()
+            .and_then(|mut table| table.as_table_mut().and_then(|table| table.remove("value")))
+            .unwrap_or(Value::String(raw));
+
+        if name == "dev" {
+            layer.insert(name, value);
+            continue
+        }
+        let mut split = name.splitn(2, '_');
+        match (split.next(), split.next()) {
+            (Some(section), Some(key)) if !section.is_empty() && !key.is_empty() => {
+                layer.entry(section.to_owned())
+                    .or_insert_with(|| Value::Table(Table::new()))
+                    .as_table_mut()
+                    .ok_or_else(|| ConfigError::Parse(format!("Invalid environment variable {}{}.", ENV_PREFIX, name.to_uppercase())))?
+                    .insert(key.to_owned(), value);
+            }
+            _ => return Err(ConfigError::Parse(format!("Invalid environment variable {}{}.", ENV_PREFIX, name.to_uppercase())))
+        }
+    }
+    Ok(Value::Table(layer))
+}
+
+fn args_layer(args: &ArgMatches) -> Value {
+    let mut server = Table::new();
+    if let Some(host) = args.value_of("host") {
+        server.insert("host".to_owned(), Value::String(host.to_owned()));
+    }
+    if let Some(port) = crate::argument::args_port(args) {
+        server.insert("port".to_owned(), Value::Integer(port as i64));
+    }
+
+    let mut database = Table::new();
+    if let Some(dsn) = args.value_of("database") {
+        database.insert("dsn".to_owned(), Value::String(dsn.to_owned()));
+    }
+
+    let mut storage = Table::new();
+    if let Some(root) = args.value_of("storage") {
+        storage.insert("root".to_owned(), Value::String(root.to_owned()));
+    }
+
+    let mut layer = Table::new();
+    layer.insert("server".to_owned(), Value::Table(server));
+    layer.insert("database".to_owned(), Value::Table(database));
+    layer.insert("storage".to_owned(), Value::Table(storage));
+    Value::Table(layer)
+}
+
+#[cfg(test)]
+mod test {
+    use toml::Value;
+    use super::{
+        BucketConfig,
+        Config,
+        MailBackend,
+        SslMode,
+        merge,
+        env_layer,
+    };
+
+    #[test]
+    fn test_layering() {
+        let mut config = Value::try_from(Config::defaults(false)).unwrap();
+        merge(&mut config, r#"
+            [server]
+            port = 8080
+            [database]
+            dsn = "host=db user=pigskit"
+        "#.parse::<Value>().unwrap());
+        merge(&mut config, env_layer(vec![
+            ("PIGSKIT_DATABASE_POOL_SIZE".to_string(), "4".to_string()),
+            ("PIGSKIT_CORS_ALLOWED_ORIGINS".to_string(), r#"["https://pigskit.com"]"#.to_string()),
+            ("PIGSKIT_STORAGE_ROOT".to_string(), "/var/lib/pigskit".to_string()),
+            ("PIGSKIT_DATABASE_SSLMODE".to_string(), "verify-full".to_string()),
+            ("PIGSKIT_RATELIMIT_SIGNIN_IP".to_string(), "{ burst = 5, per_minute = 1 }".to_string()),
+            ("HOME".to_string(), "/root".to_string()),
+        ].into_iter()).unwrap());
+
+        let config: Config = config.try_into().unwrap();
+        assert_eq!(config.server.port, 8080);
+        assert_eq!(config.server.host, "0.0.0.0");
+        assert_eq!(config.database.dsn, "host=db user=pigskit");
+        assert_eq!(config.database.pool_size, 4);
+        assert_eq!(config.database.sslmode, SslMode::VerifyFull);
+        assert_eq!(config.cors.allowed_origins, vec!["https://pigskit.com".to_string()]);
+        assert_eq!(config.storage.root, "/var/lib/pigskit");
+        assert_eq!(config.ratelimit.signin_ip, BucketConfig { burst: 5, per_minute: 1 });
+    }
+
+    #[test]
+    fn test_unknown_field() {
+        let mut config = Value::try_from(Config::defaults(false)).unwrap();
+        merge(&mut config, env_layer(vec![
+            ("PIGSKIT_DATABASE_POOLSIZE".to_string(), "4".to_string()),
+        ].into_iter()).unwrap());
+        assert!(config.try_into::<Config>().is_err());
+    }
+
+    #[test]
+    fn test_validate() {
+        let mut config = Config::defaults(false);
+        assert!(config.validate().is_ok());
+
+        config.database.pool_size = 0;
+        assert!(config.validate().is_err());
+        config.database.pool_size = 16;
+
+        config.server.host = "localhost:80".to_string();
+        assert!(config.validate().is_err());
+        config.server.host = "0.0.0.0".to_string();
+
+        config.server.redirect_port = Some(8080);
+        assert!(config.validate().is_err());
+        config.server.tls_cert = Some("server.crt".to_string());
+        assert!(config.validate().is_err());
+        config.server.tls_key = Some("server.key".to_string());
+        assert!(config.validate().is_ok());
+        config.server.redirect_port = Some(config.server.port);
+        assert!(config.validate().is_err());
+        config.server.redirect_port = None;
+
+        config.cors.allowed_origin_patterns = vec![r"https://(.*\.pigskit\.com".to_string()];
+        assert!(config.validate().is_err());
+        config.cors.allowed_origin_patterns = vec![r"https://.*\.pigskit\.com".to_string()];
+        config.cors.allowed_methods.push("GET POST".to_string());
+        assert!(config.validate().is_err());
+        config.cors.allowed_methods.pop();
+        assert!(config.validate().is_ok());
+
+        config.database.sslcert = Some("client.crt".to_string());
+        assert!(config.validate().is_err());
+        config.database.sslkey = Some("client.key".to_string());
+        assert!(config.validate().is_ok());
+
+        config.ratelimit.signin_ip.per_minute = 0;
+        assert!(config.validate().is_err());
+        config.ratelimit.signin_ip.per_minute = 10;
+        config.ratelimit.lockout_max_seconds = 10;
+        assert!(config.validate().is_err());
+        config.ratelimit.lockout_max_seconds = 3600;
+        assert!(config.validate().is_ok());
+        config.ratelimit.trusted_proxies = vec!["10.0.0.0/8".to_string(), "fd00::/8".to_string(), "192.0.2.1".to_string()];
+        assert!(config.validate().is_ok());
+        config.ratelimit.trusted_proxies.push("10.0.0.0/33".to_string());
+        assert!(config.validate().is_err());
+        config.ratelimit.trusted_proxies.pop();
+        config.ratelimit.trusted_proxies.push("proxy".to_string());
+        assert!(config.validate().is_err());
+        config.ratelimit.trusted_proxies.clear();
+
+        config.cookie.user_session_max_days = 7;
+        assert!(config.validate().is_err());
+        config.cookie.user_session_max_days = 30000;
+        assert!(config.validate().is_err());
+        config.cookie.user_session_max_days = 90;
+        config.cookie.user_session_browser_hours = 0;
+        assert!(config.validate().is_err());
+        config.cookie.user_session_browser_hours = 12;
+        assert!(config.validate().is_ok());
+
+        config.totp.issuer = "Pigskit:Shop".to_string();
+        assert!(config.validate().is_err());
+        config.totp.issuer = "Pigskit Shop".to_string();
+        assert!(config.validate().is_ok());
+
+        config.mail.backend = MailBackend::File;
+        assert!(config.validate().is_err());
+        config.mail.dir = Some("/var/lib/pigskit/mail".to_string());
+        config.mail.reset_url = "https://pigskit.com/password/reset".to_string();
+        assert!(config.validate().is_err());
+        config.mail.reset_url = "https://pigskit.com/password/reset?token={token}".to_string();
+        assert!(config.validate().is_ok());
+        config.mail.backend = MailBackend::Smtp;
+        assert!(config.validate().is_err());
+        config.mail.smtp_host = Some("smtp.pigskit.com".to_string());
+        config.mail.smtp_username = Some("pigskit".to_string());
+        assert!(config.validate().is_err());
+        config.mail.smtp_password = Some("secret".to_string());
+        assert!(config.validate().is_ok());
+    }
+}
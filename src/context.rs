@@ -0,0 +1,94 @@
+use std::{
+    future::Future,
+    net::SocketAddr,
+    sync::{
+        Arc,
+        Mutex,
+    },
+};
+use uuid::Uuid;
+
+tokio::task_local! {
+    static CONTEXT: Arc<RequestContext>;
+}
+
+/// Per-request data available to everything running within the request's future.
+pub struct RequestContext {
+    request_id: String,
+    remote_addr: Option<SocketAddr>,
+    /// The `X-Forwarded-For` header, trusted only from the proxies of the
+    /// rate limiter.
+    forwarded_for: Option<String>,
+    user_id: Mutex<Option<Uuid>>,
+    /// The user session resolved from the cookie, if it is due for renewal.
+    renewal_due: Mutex<Option<Uuid>>,
+    /// The route of the end-point matched, for the metrics.
+    route: Mutex<Option<String>>,
+}
+
+impl RequestContext {
+    pub fn new(request_id: String, remote_addr: Option<SocketAddr>, forwarded_for: Option<String>) -> Arc<Self> {
+        Arc::new(RequestContext {
+            request_id,
+            remote_addr,
+            forwarded_for,
+            user_id: Mutex::new(None),
+            renewal_due: Mutex::new(None),
+            route: Mutex::new(None),
+        })
+    }
+
+    pub fn request_id(&self) -> &str {
+        &self.request_id
+    }
+
+    pub fn user_id(&self) -> Option<Uuid> {
+        *self.user_id.lock().unwrap()
+    }
+
+    /// Run `f` with this context as the current one.
+    pub async fn scope<F: Future>(self: Arc<Self>, f: F) -> F::Output {
+        CONTEXT.scope(self, f).await
+    }
+}
+
+pub fn request_id() -> Option<String> {
+    CONTEXT.try_with(|context| context.request_id.clone()).ok()
+}
+
+/// Address of the client, not known in tests.
+pub fn remote_addr() -> Option<SocketAddr> {
+    CONTEXT.try_with(|context| context.remote_addr).ok().flatten()
+}
+
+pub fn forwarded_for() -> Option<String> {
+    CONTEXT.try_with(|context| context.forwarded_for.clone()).ok().flatten()
+}
+
+/// Record the user resolved from the session cookie, for the access log.
+pub fn set_user_id(user_id: Uuid) {
+    let _ = CONTEXT.try_with(|context| {
+        *context.user_id.lock().unwrap() = Some(user_id);
+    });
+}
+
+/// Record the session resolved from the cookie as due for renewal.
+pub fn set_renewal_due(ussid: Uuid) {
+    let _ = CONTEXT.try_with(|context| {
+        *context.renewal_due.lock().unwrap() = Some(ussid);
+    });
+}
+
+pub fn renewal_due() -> Option<Uuid> {
+    CONTEXT.try_with(|context| *context.renewal_due.lock().unwrap()).ok().flatten()
+}
+
+pub fn set_route(route: String) {
+    let _ = CONTEXT.try_with(|context| {
+        *context.route.lock().unwrap() = Some(route);
+    });
+}
+
+pub fn route() -> Option<String> {
+    CONTEXT.try_with(|context| context.route.lock().unwrap().clone()).ok().flatten()
+}
@@ -1,3 +1,4 @@
+use std::time::Duration;
 use warp::{
     Reply,
     reply::{
@@ -6,9 +7,19 @@ use warp::{
         with_status,
     },
     reject::Reject,
-    http::StatusCode,
+    http::{
+        HeaderValue,
+        StatusCode,
+        header::RETRY_AFTER,
+    },
 };
 use serde::Serialize;
+use crate::{
+    context,
+    mail::MailError,
+    sms::SmsError,
+    storage::StorageError,
+};
 
 #[derive(Debug)]
 pub struct Error {
@@ -16,7 +27,9 @@ pub struct Error {
     r#type: String,
     message: String,
     data: Option<String>,
-    inner: Option<InnerError>,
+    /// Seconds sent in the `Retry-After` header.
+    retry_after: Option<u64>,
+    inner: Option<Box<InnerError>>,
 }
 
 impl Error {
@@ -26,6 +39,7 @@ impl Error {
             r#type: r#type.to_string(),
             message: message.to_string(),
             data: data,
+            retry_after: None,
             inner: None,
         }
     }
@@ -45,7 +59,8 @@ impl Error {
             r#type: "InternalServerError".to_string(),
             message: "Internal server error.".to_string(),
             data: None,
-            inner: Some(inner),
+            retry_after: None,
+            inner: Some(Box::new(inner)),
         }
     }
 
@@ -74,6 +89,37 @@ impl Error {
         )
     }
 
+    pub fn cors_forbidden(reason: &str) -> Self {
+        Self::new(
+            StatusCode::FORBIDDEN,
+            "CorsForbidden",
+            format!("CORS request forbidden: {}.", reason).as_str(),
+            None,
+        )
+    }
+
+    pub fn csrf_forbidden(reason: &str) -> Self {
+        Self::new(
+            StatusCode::FORBIDDEN,
+            "CsrfForbidden",
+            format!("Cross-site request forbidden: {}.", reason).as_str(),
+            None,
+        )
+    }
+
+    /// Rate limited or locked out, the client may retry after `retry_after`.
+    pub fn too_many_requests(retry_after: Duration) -> Self {
+        let seconds = (retry_after.as_secs() + if retry_after.subsec_nanos() > 0 { 1 } else { 0 }).max(1);
+        let mut err = Self::new(
+            StatusCode::TOO_MANY_REQUESTS,
+            "TooManyRequests",
+            "Too many requests, retry later.",
+            Some(serde_json::json!({ "retry_after": seconds }).to_string()),
+        );
+        err.retry_after = Some(seconds);
+        err
+    }
+
     pub fn missing_body(field: &str) -> Self {
         Self::bad_request(
             "BodyMissingField",
@@ -90,6 +136,14 @@ impl Error {
         )
     }
 
+    pub fn invalid_form() -> Self {
+        Self::bad_request(
+            "InvalidForm",
+            "Request body is not a valid multipart form.",
+            None,
+        )
+    }
+
     pub fn no_valid_form(part: &str) -> Self {
         Self::bad_request(
             "FormMissingPart",
@@ -114,6 +168,22 @@ impl Error {
         )
     }
 
+    pub fn invalid_reset_token() -> Self {
+        Self::bad_request(
+            "InvalidResetToken",
+            "Password reset token is invalid or expired.",
+            None,
+        )
+    }
+
+    pub fn invalid_verification_code() -> Self {
+        Self::bad_request(
+            "InvalidVerificationCode",
+            "Verification code is wrong or expired.",
+            None,
+        )
+    }
+
     pub fn unique_data_conflict(name: &str) -> Self {
         Self::new(
             StatusCode::CONFLICT,
@@ -139,18 +209,45 @@ impl Error {
         )
     }
 
+    pub fn service_unavailable() -> Self {
+        Self::new(
+            StatusCode::SERVICE_UNAVAILABLE,
+            "ServiceUnavailable",
+            "Server is shutting down.",
+            None,
+        )
+    }
+
+    pub fn http_status(&self) -> StatusCode {
+        self.http_status
+    }
+
+    pub fn error_type(&self) -> &str {
+        &self.r#type
+    }
+
+    pub fn message(&self) -> &str {
+        &self.message
+    }
+
     pub fn is_inner(&self) -> bool {
         self.inner.is_some()
     }
 }
 
+// The causes are only read through `Debug`, for the logs.
+#[allow(dead_code)]
 #[derive(Debug)]
 enum InnerError {
     Bb8(bb8::RunError::<tokio_postgres::error::Error>),
     Sql(tokio_postgres::error::Error),
     Uuid(uuid::Error),
     Warp(warp::Error),
+    Multipart(multer::Error),
     Io(std::io::Error),
+    Storage(StorageError),
+    Mail(MailError),
+    Sms(SmsError),
 }
 
 macro_rules! impl_from_for_error {
@@ -174,6 +271,37 @@ impl_from_for_error!(uuid::Error, Uuid);
 impl_from_for_error!(warp::Error, Warp);
 impl_from_for_error!(std::io::Error, Io);
 
+impl From<StorageError> for Error {
+    fn from(err: StorageError) -> Self {
+        match err {
+            StorageError::NotFound => Error::data_not_found("file"),
+            err => Error::internal(InnerError::Storage(err)),
+        }
+    }
+}
+
+/// Only a failure to read the body is the server's.
+impl From<multer::Error> for Error {
+    fn from(err: multer::Error) -> Self {
+        match err {
+            multer::Error::StreamReadFailed(_) => Error::internal(InnerError::Multipart(err)),
+            _ => Error::invalid_form(),
+        }
+    }
+}
+
+impl From<MailError> for Error {
+    fn from(err: MailError) -> Self {
+        Error::internal(InnerError::Mail(err))
+    }
+}
+
+impl From<SmsError> for Error {
+    fn from(err: SmsError) -> Self {
+        Error::internal(InnerError::Sms(err))
+    }
+}
+
 impl From<tokio_postgres::error::Error> for InnerError {
     fn from(err: tokio_postgres::error::Error) -> Self {
         InnerError::Sql(err)
@@ -189,9 +317,53 @@ impl From<tokio_postgres::error::Error> for Error {
         };
 
         match code {
+            "C1001" => {
+                Error::session_expired("REGSSID")
+            }
+            "C1101" => {
+                Error::data_not_found("email")
+            }
+            "C1102" => {
+                Error::bad_request(
+                    "EmailNotVerified",
+                    "Email is not verified.",
+                    None,
+                )
+            }
+            "C1201" => {
+                Error::data_not_found("phone")
+            }
+            "C1202" => {
+                Error::bad_request(
+                    "PhoneNotVerified",
+                    "Phone is not verified.",
+                    None,
+                )
+            }
+            "C1203" => {
+                Error::unique_data_conflict("phone")
+            }
             "C2002" => {
                 Error::session_expired("USSID")
             }
+            "C2101" => {
+                Error::new(
+                    StatusCode::CONFLICT,
+                    "TwoFactorEnabled",
+                    "Two-factor authentication is already enabled.",
+                    None,
+                )
+            }
+            "C2102" => {
+                Error::data_not_found("2fa")
+            }
+            "C2103" => {
+                Error::bad_request(
+                    "ChallengeExpired",
+                    "Sign-in challenge expired.",
+                    None,
+                )
+            }
             "C3001" => {
                 Error::session_expired("GSSID")
             }
@@ -212,6 +384,15 @@ impl From<tokio_postgres::error::Error> for Error {
                     None,
                 )
             }
+            "C6002" => {
+                Error::unauthorized()
+            }
+            "C6003" => {
+                Error::data_not_found("shop")
+            }
+            "C6004" => {
+                Error::data_not_found("shop_user")
+            }
             "C6009" => {
                 Error::data_not_found("shop_product")
             }
@@ -243,6 +424,7 @@ struct ApiError<'a> {
     r#type: &'a String,
     message: &'a String,
     data: serde_json::Value,
+    request_id: Option<String>,
 }
 
 impl Reply for &Error {
@@ -257,15 +439,20 @@ impl Reply for &Error {
             serde_json::Value::Null
         };
 
-        with_status(
+        let mut response = with_status(
             json(&ApiError {
                 status: self.http_status.as_u16(),
                 r#type: &self.r#type,
                 message: &self.message,
                 data: data,
+                request_id: context::request_id(),
             }),
             self.http_status,
         )
-        .into_response()
+        .into_response();
+        if let Some(seconds) = self.retry_after {
+            response.headers_mut().insert(RETRY_AFTER, HeaderValue::from(seconds));
+        }
+        response
     }
 }
\ No newline at end of file
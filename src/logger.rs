@@ -0,0 +1,91 @@
+use std::{
+    env,
+    io::Write,
+    net::SocketAddr,
+    time::Duration,
+};
+use chrono::Utc;
+use uuid::Uuid;
+use crate::{
+    config::{
+        LogConfig,
+        LogFormat,
+    },
+    context,
+};
+
+const ACCESS_TARGET: &str = "access";
+
+pub fn init(config: &LogConfig) {
+    let mut builder = env_logger::Builder::new();
+    builder.parse_filters(&config.level);
+    if let Ok(filters) = env::var("RUST_LOG") {
+        builder.parse_filters(&filters);
+    }
+
+    if let LogFormat::Json = config.format {
+        builder.format(|buf, record| {
+            if record.target() == ACCESS_TARGET {
+                // Access entries are already formatted as JSON objects.
+                writeln!(buf, "{}", record.args())
+            } else {
+                writeln!(buf, "{}", serde_json::json!({
+                    "timestamp": Utc::now().to_rfc3339(),
+                    "level": record.level().as_str(),
+                    "target": record.target(),
+                    "request_id": context::request_id(),
+                    "message": record.args().to_string(),
+                }))
+            }
+        });
+    }
+
+    builder.init();
+}
+
+pub struct AccessEntry<'a> {
+    pub request_id: &'a str,
+    pub remote_addr: Option<SocketAddr>,
+    pub method: &'a str,
+    pub path: &'a str,
+    pub status: u16,
+    pub latency: Duration,
+    pub user_id: Option<Uuid>,
+}
+
+pub fn access(format: LogFormat, entry: &AccessEntry) {
+    let latency_ms = entry.latency.as_secs_f64() * 1000.0;
+    match format {
+        LogFormat::Text => {
+            info!(
+                target: ACCESS_TARGET,
+                r#"{} {} "{} {}" {} {:.3}ms user={}"#,
+                entry.request_id,
+                entry.remote_addr.map(|addr| addr.to_string()).unwrap_or_else(|| "-".to_string()),
+                entry.method,
+                entry.path,
+                entry.status,
+                latency_ms,
+                entry.user_id.map(|id| id.to_string()).unwrap_or_else(|| "-".to_string()),
+            );
+        }
+        LogFormat::Json => {
+            info!(
+                target: ACCESS_TARGET,
+                "{}",
+                serde_json::json!({
+                    "timestamp": Utc::now().to_rfc3339(),
+                    "level": "INFO",
+                    "target": ACCESS_TARGET,
+                    "request_id": entry.request_id,
+                    "remote_addr": entry.remote_addr.map(|addr| addr.to_string()),
+                    "method": entry.method,
+                    "path": entry.path,
+                    "status": entry.status,
+                    "latency_ms": latency_ms,
+                    "user_id": entry.user_id,
+                })
+            );
+        }
+    }
+}
@@ -0,0 +1,39 @@
+use std::path::PathBuf;
+use async_trait::async_trait;
+use chrono::Utc;
+use tokio::fs;
+use uuid::Uuid;
+use crate::config::ConfigError;
+use super::{
+    MailError,
+    Mailer,
+    Message,
+};
+
+/// Writes each mail to a `.eml` file, named so they sort by time.
+pub struct FileMailer {
+    dir: PathBuf,
+}
+
+impl FileMailer {
+    /// The directory is created if it does not exist.
+    pub fn open(dir: &str) -> Result<Self, ConfigError> {
+        std::fs::create_dir_all(dir)
+            .map_err(|err| ConfigError::Invalid("mail.dir", format!("{}: {}", dir, err)))?;
+        Ok(FileMailer {
+            dir: PathBuf::from(dir),
+        })
+    }
+}
+
+#[async_trait]
+impl Mailer for FileMailer {
+    async fn send(&self, message: &Message) -> Result<(), MailError> {
+        let name = format!("{}-{}", Utc::now().format("%Y%m%dT%H%M%S%.6f"), Uuid::new_v4().to_simple());
+        // Write aside and rename so readers never see a partial mail.
+        let temp = self.dir.join(format!(".{}.tmp", name));
+        fs::write(&temp, message.format()).await?;
+        fs::rename(&temp, self.dir.join(format!("{}.eml", name))).await?;
+        Ok(())
+    }
+}
@@ -0,0 +1,17 @@
+use async_trait::async_trait;
+use super::{
+    MailError,
+    Mailer,
+    Message,
+};
+
+/// Logs the mails instead of sending them, so links can be followed in development.
+pub struct LogMailer;
+
+#[async_trait]
+impl Mailer for LogMailer {
+    async fn send(&self, message: &Message) -> Result<(), MailError> {
+        info!("Mail to {}, {:?}:\n{}", message.to, message.subject, message.body);
+        Ok(())
+    }
+}
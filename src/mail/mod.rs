@@ -0,0 +1,122 @@
+//! Mails to the users, delivered by the configured backend.
+
+use std::{
+    fmt::{self, Display},
+    sync::Arc,
+};
+use async_trait::async_trait;
+use chrono::Utc;
+use uuid::Uuid;
+use crate::config::{
+    ConfigError,
+    MailBackend,
+    MailConfig,
+};
+
+mod file;
+mod log;
+mod smtp;
+
+pub use self::file::FileMailer;
+pub use self::log::LogMailer;
+pub use self::smtp::SmtpMailer;
+
+#[derive(Debug)]
+pub enum MailError {
+    Io(std::io::Error),
+    /// An unexpected reply of the SMTP relay.
+    Smtp(String),
+}
+
+impl Display for MailError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MailError::Io(err) => write!(f, "{}", err),
+            MailError::Smtp(err) => write!(f, "SMTP: {}", err),
+        }
+    }
+}
+
+impl From<std::io::Error> for MailError {
+    fn from(err: std::io::Error) -> Self {
+        MailError::Io(err)
+    }
+}
+
+/// A plain text mail.
+#[derive(Debug, Clone)]
+pub struct Message {
+    pub from: String,
+    pub to: String,
+    pub subject: String,
+    pub body: String,
+}
+
+impl Message {
+    /// The message in the Internet Message Format, with CRLF line endings.
+    pub fn format(&self) -> String {
+        let domain = self.from
+            .rsplit('@')
+            .next()
+            .map(|domain| domain.trim_end_matches('>'))
+            .unwrap_or("localhost");
+        let mut message = format!(
+            "From: {}\r\nTo: {}\r\nSubject: {}\r\nDate: {}\r\nMessage-ID: <{}@{}>\r\nMIME-Version: 1.0\r\nContent-Type: text/plain; charset=utf-8\r\nContent-Transfer-Encoding: 8bit\r\n\r\n",
+            self.from,
+            self.to,
+            self.subject,
+            Utc::now().to_rfc2822(),
+            Uuid::new_v4().to_simple(),
+            domain,
+        );
+        for line in self.body.lines() {
+            message.push_str(line);
+            message.push_str("\r\n");
+        }
+        message
+    }
+}
+
+#[async_trait]
+pub trait Mailer: Send + Sync {
+    async fn send(&self, message: &Message) -> Result<(), MailError>;
+}
+
+/// The configured mailer and the sender of the mails.
+#[derive(Clone)]
+pub struct Mail {
+    mailer: Arc<dyn Mailer>,
+    from: String,
+}
+
+impl Mail {
+    pub fn new(mailer: Arc<dyn Mailer>, from: String) -> Self {
+        Mail {
+            mailer,
+            from,
+        }
+    }
+
+    pub fn open(config: &MailConfig) -> Result<Self, ConfigError> {
+        let mailer: Arc<dyn Mailer> = match config.backend {
+            MailBackend::Log => Arc::new(LogMailer),
+            MailBackend::File => {
+                let dir = config.dir.as_ref().ok_or_else(|| ConfigError::Invalid("mail.dir", "is required by the file backend.".to_string()))?;
+                Arc::new(FileMailer::open(dir)?)
+            }
+            MailBackend::Smtp => Arc::new(SmtpMailer::open(config)?),
+        };
+        Ok(Mail::new(mailer, config.from.clone()))
+    }
+
+    pub async fn send(&self, to: &str, subject: &str, body: String) -> Result<(), MailError> {
+        self.mailer.send(&Message {
+            from: self.from.clone(),
+            to: to.to_owned(),
+            subject: subject.to_owned(),
+            body,
+        }).await?;
+        debug!("Sent {:?} to {}.", subject, to);
+        Ok(())
+    }
+}
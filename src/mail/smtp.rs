@@ -0,0 +1,266 @@
+use std::{
+    sync::Arc,
+    time::Duration,
+};
+use async_trait::async_trait;
+use rustls::ClientConfig;
+use tokio::{
+    io::{
+        AsyncBufReadExt,
+        AsyncRead,
+        AsyncWrite,
+        AsyncWriteExt,
+        BufReader,
+    },
+    net::TcpStream,
+    time::timeout,
+};
+use tokio_rustls::TlsConnector;
+use webpki::DNSNameRef;
+use crate::config::{
+    ConfigError,
+    MailConfig,
+    SmtpSecurity,
+};
+use super::{
+    MailError,
+    Mailer,
+    Message,
+};
+
+/// Time to deliver a mail, from connecting to the end of the data.
+const SEND_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Hands the mails to an SMTP relay, one connection per mail.
+pub struct SmtpMailer {
+    host: String,
+    port: u16,
+    security: SmtpSecurity,
+    credentials: Option<(String, String)>,
+    tls: TlsConnector,
+}
+
+impl SmtpMailer {
+    pub fn open(config: &MailConfig) -> Result<Self, ConfigError> {
+        let host = config.smtp_host.clone()
+            .ok_or_else(|| ConfigError::Invalid("mail.smtp_host", "is required by the smtp backend.".to_string()))?;
+        if config.smtp_security != SmtpSecurity::None && DNSNameRef::try_from_ascii_str(&host).is_err() {
+            return Err(ConfigError::Invalid("mail.smtp_host", format!("{} is not a host name, which TLS needs.", host)))
+        }
+        let mut tls = ClientConfig::new();
+        tls.root_store = rustls_native_certs::load_native_certs()
+            .map_err(|(_, err)| ConfigError::Invalid("mail.smtp_host", format!("failed to load the system roots: {}", err)))?;
+        Ok(SmtpMailer {
+            host,
+            port: config.smtp_port,
+            security: config.smtp_security,
+            credentials: config.smtp_username.clone().zip(config.smtp_password.clone()),
+            tls: TlsConnector::from(Arc::new(tls)),
+        })
+    }
+
+    async fn deliver(&self, message: &Message) -> Result<(), MailError> {
+        let stream = TcpStream::connect((self.host.as_str(), self.port)).await?;
+        let name = DNSNameRef::try_from_ascii_str(&self.host).map_err(|_| MailError::Smtp("invalid host name".to_string()));
+        match self.security {
+            SmtpSecurity::Tls => {
+                let stream = self.tls.connect(name?, stream).await?;
+                let mut conn = Connection::new(stream);
+                conn.reply(220).await?;
+                conn.command("EHLO localhost", 250).await?;
+                self.transaction(conn, message).await
+            }
+            SmtpSecurity::StartTls => {
+                let mut conn = Connection::new(stream);
+                conn.reply(220).await?;
+                conn.command("EHLO localhost", 250).await?;
+                conn.command("STARTTLS", 220).await?;
+                let stream = self.tls.connect(name?, conn.into_inner()).await?;
+                let mut conn = Connection::new(stream);
+                conn.command("EHLO localhost", 250).await?;
+                self.transaction(conn, message).await
+            }
+            SmtpSecurity::None => {
+                let mut conn = Connection::new(stream);
+                conn.reply(220).await?;
+                conn.command("EHLO localhost", 250).await?;
+                self.transaction(conn, message).await
+            }
+        }
+    }
+
+    async fn transaction<S: AsyncRead + AsyncWrite + Unpin>(&self, mut conn: Connection<S>, message: &Message) -> Result<(), MailError> {
+        if let Some((username, password)) = &self.credentials {
+            let plain = base64::encode(format!("\0{}\0{}", username, password));
+            conn.command(&format!("AUTH PLAIN {}", plain), 235).await?;
+        }
+        conn.command(&format!("MAIL FROM:<{}>", address(&message.from)), 250).await?;
+        conn.command(&format!("RCPT TO:<{}>", address(&message.to)), 250).await?;
+        conn.command("DATA", 354).await?;
+
+        // Lines starting with a dot are escaped, a lone dot ends the data.
+        let mut data = String::new();
+        for line in message.format().split_terminator("\r\n") {
+            if line.starts_with('.') {
+                data.push('.');
+            }
+            data.push_str(line);
+            data.push_str("\r\n");
+        }
+        data.push('.');
+        conn.command(&data, 250).await?;
+        // The mail is accepted, a failed goodbye does not matter.
+        let _ = conn.command("QUIT", 221).await;
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl Mailer for SmtpMailer {
+    async fn send(&self, message: &Message) -> Result<(), MailError> {
+        timeout(SEND_TIMEOUT, self.deliver(message))
+            .await
+            .map_err(|_| MailError::Smtp("timed out".to_string()))?
+    }
+}
+
+/// The address of a mailbox such as `Pigskit <noreply@pigskit.com>`.
+fn address(mailbox: &str) -> &str {
+    match (mailbox.rfind('<'), mailbox.rfind('>')) {
+        (Some(start), Some(end)) if start < end => &mailbox[start + 1..end],
+        _ => mailbox.trim(),
+    }
+}
+
+struct Connection<S> {
+    stream: BufReader<S>,
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> Connection<S> {
+    fn new(stream: S) -> Self {
+        Connection {
+            stream: BufReader::new(stream),
+        }
+    }
+
+    fn into_inner(self) -> S {
+        self.stream.into_inner()
+    }
+
+    async fn command(&mut self, command: &str, expected: u16) -> Result<(), MailError> {
+        self.stream.get_mut().write_all(format!("{}\r\n", command).as_bytes()).await?;
+        self.stream.get_mut().flush().await?;
+        self.reply(expected).await
+    }
+
+    /// Read a reply, which may span several lines, failing unless it has the expected code.
+    async fn reply(&mut self, expected: u16) -> Result<(), MailError> {
+        loop {
+            let mut line = String::new();
+            if self.stream.read_line(&mut line).await? == 0 {
+                return Err(MailError::Smtp("connection closed".to_string()))
+            }
+            let line = line.trim_end();
+            let code = line.get(..3).and_then(|code| code.parse::<u16>().ok());
+            if code != Some(expected) {
+                return Err(MailError::Smtp(format!("expected {}, got {:?}", expected, line)))
+            }
+            // `250-` continues the reply, `250 ` ends it.
+            if line.as_bytes().get(3) != Some(&b'-') {
+                return Ok(())
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::net::SocketAddr;
+    use tokio::{
+        io::{
+            AsyncBufReadExt,
+            AsyncWriteExt,
+            BufReader,
+        },
+        net::TcpListener,
+    };
+    use crate::config::{
+        Config,
+        SmtpSecurity,
+    };
+    use super::{
+        Mailer,
+        Message,
+        SmtpMailer,
+    };
+
+    /// Accept one session, returns the lines the client sent.
+    async fn serve(mut listener: TcpListener) -> Vec<String> {
+        let (stream, _) = listener.accept().await.unwrap();
+        let mut stream = BufReader::new(stream);
+        stream.get_mut().write_all(b"220 pigskit ESMTP\r\n").await.unwrap();
+        let mut lines = Vec::new();
+        let mut data = false;
+        loop {
+            let mut line = String::new();
+            if stream.read_line(&mut line).await.unwrap() == 0 {
+                return lines
+            }
+            let line = line.trim_end().to_owned();
+            let reply: &[u8] = if data {
+                if line == "." {
+                    data = false;
+                    b"250 queued\r\n"
+                } else {
+                    b""
+                }
+            } else if line.starts_with("EHLO") {
+                b"250-pigskit\r\n250 AUTH PLAIN\r\n"
+            } else if line.starts_with("AUTH") {
+                b"235 ok\r\n"
+            } else if line == "DATA" {
+                data = true;
+                b"354 go ahead\r\n"
+            } else if line == "QUIT" {
+                stream.get_mut().write_all(b"221 bye\r\n").await.unwrap();
+                lines.push(line);
+                return lines
+            } else {
+                b"250 ok\r\n"
+            };
+            stream.get_mut().write_all(reply).await.unwrap();
+            lines.push(line);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_smtp() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address: SocketAddr = listener.local_addr().unwrap();
+        let server = tokio::spawn(serve(listener));
+
+        let mut config = Config::defaults(true).mail;
+        config.smtp_host = Some("127.0.0.1".to_string());
+        config.smtp_port = address.port();
+        config.smtp_security = SmtpSecurity::None;
+        config.smtp_username = Some("user".to_string());
+        config.smtp_password = Some("pass".to_string());
+        let mailer = SmtpMailer::open(&config).unwrap();
+        mailer.send(&Message {
+            from: "Pigskit <noreply@pigskit.com>".to_string(),
+            to: "alice@pigskit.com".to_string(),
+            subject: "Hello".to_string(),
+            body: "Hi,\n.\n..dots\n".to_string(),
+        }).await.unwrap();
+
+        let lines = server.await.unwrap();
+        assert_eq!(lines[0], "EHLO localhost");
+        assert_eq!(lines[1], "AUTH PLAIN AHVzZXIAcGFzcw==");
+        assert_eq!(lines[2], "MAIL FROM:<noreply@pigskit.com>");
+        assert_eq!(lines[3], "RCPT TO:<alice@pigskit.com>");
+        assert_eq!(lines[4], "DATA");
+        assert!(lines.contains(&"Subject: Hello".to_string()));
+        let body = lines.iter().position(|line| line == "Hi,").unwrap();
+        assert_eq!(&lines[body + 1..], &["..", "...dots", ".", "QUIT"]);
+    }
+}
@@ -1,61 +1,199 @@
 #![feature(async_closure)]
-#![feature(type_alias_impl_trait)]
 
 #[macro_use] extern crate serde_derive;
+#[macro_use] extern crate pigskit_derive;
 #[macro_use] extern crate log;
 #[macro_use] extern crate lazy_static;
+#[macro_use] extern crate prometheus;
 
 mod error;
+mod config;
+mod context;
+mod logger;
+mod mail;
+mod metrics;
+mod pem;
+mod ratelimit;
+mod server;
+mod sms;
 mod state;
-#[macro_use] mod sql;
+mod storage;
+mod sql;
 mod route;
 mod argument;
+#[cfg(test)] mod testing;
 
+use futures::{
+    FutureExt,
+    future::join_all,
+};
+use tokio::{
+    sync::oneshot,
+    signal::unix::{
+        signal,
+        SignalKind,
+    },
+    time::timeout,
+};
+use config::Config;
+use server::{
+    Connections,
+    ServerCert,
+};
 use state::{State, init_pool};
-
-const DEFAULT_PORT: u16 = 80;
-const DEFAULT_PORT_DEV: u16 = 8001;
-const PG_CONFIG: &'static str = "host=postgres-server user=postgres dbname=postgres";
-const PG_CONFIG_DEV: &'static str = "host=localhost user=postgres dbname=postgres";
-
-lazy_static! {
-    static ref STORAGE_DIR: String = {
-        let mut path = std::env::current_exe().unwrap();
-        assert!(path.pop());
-        assert!(path.pop());
-        path.push("storage");
-        if let Err(err) = std::fs::metadata(path.as_path()) {
-            panic!("Failed to config storage path: {:?}, {}", path, err);
-        }
-        path.as_path().to_str().unwrap().to_owned()
-    };
-}
+use storage::Storage;
+use mail::Mail;
+use sms::Sms;
+use sql::migration;
 
 #[tokio::main]
 async fn main() {
-    ::std::env::set_var("RUST_LOG", "info");
-    env_logger::init();
+    let args = argument::parse_arguments();
+    let config = match Config::load(&args) {
+        Ok(config) => config,
+        Err(err) => {
+            eprintln!("{}", err);
+            std::process::exit(1);
+        }
+    };
+    logger::init(&config.log);
 
-    info!("Storage path configed: {}", *STORAGE_DIR);
+    let db_pool = match init_pool(&config.database).await {
+        Ok(db_pool) => db_pool,
+        Err(err) => {
+            error!("{}", err);
+            std::process::exit(1);
+        }
+    };
 
-    let mut port = DEFAULT_PORT;
-    let mut pg_config = PG_CONFIG;
+    let mut conn = match db_pool.get().await {
+        Ok(conn) => conn,
+        Err(err) => {
+            error!("Failed to connect to the database: {:?}", err);
+            std::process::exit(1);
+        }
+    };
+    if let Some(migrate_args) = args.subcommand_matches("migrate") {
+        if let Err(err) = migration::command(&mut conn, migrate_args).await {
+            error!("Failed to migrate the database: {}", err);
+            std::process::exit(1);
+        }
+        return
+    }
+    match migration::current_version(&conn).await {
+        Ok(version) if version == migration::expected_version() => {}
+        Ok(version) => {
+            error!(
+                "Database schema version {} does not match the expected version {}, run `pigskit-server migrate up`.",
+                version,
+                migration::expected_version(),
+            );
+            std::process::exit(1);
+        }
+        Err(err) => {
+            error!("Failed to check the database schema version: {}", err);
+            std::process::exit(1);
+        }
+    }
+    drop(conn);
 
-    let args = argument::parse_arguments();
-    let is_dev = args.is_present("dev");
-    if is_dev {
-        port = DEFAULT_PORT_DEV;
-        pg_config = PG_CONFIG_DEV;
+    let address = config.bind_address();
+    let redirect_address = config.redirect_address();
+    let shutdown_timeout = config.shutdown_timeout();
+    let access_log = if config.log.access_log { Some(config.log.format) } else { None };
+    let cert = match config.server.tls().map(|(cert, key)| ServerCert::load(cert, key)).transpose() {
+        Ok(cert) => cert,
+        Err(err) => {
+            error!("{}", err);
+            std::process::exit(1);
+        }
+    };
+    let storage = match Storage::open(&config.storage) {
+        Ok(storage) => storage,
+        Err(err) => {
+            error!("{}", err);
+            std::process::exit(1);
+        }
+    };
+    info!("Storage configed: {}", storage.name());
+    let mail = match Mail::open(&config.mail) {
+        Ok(mail) => mail,
+        Err(err) => {
+            error!("{}", err);
+            std::process::exit(1);
+        }
+    };
+    let sms = Sms::open(&config.sms);
+    let state = State::init(db_pool, storage, mail, sms, config);
+
+    let (shutdown_tx, shutdown_rx) = oneshot::channel::<()>();
+    let shutdown = async {
+        let _ = shutdown_rx.await;
+    }.shared();
+    let connections = Connections::default();
+    let server = server::bind(route::routes(state.clone()), address, cert.clone(), access_log, connections.clone(), shutdown.clone())
+        .map(tokio::spawn);
+    let mut servers = match server {
+        Ok(server) => vec![server],
+        Err(err) => {
+            error!("Failed to bind {}: {}", address, err);
+            std::process::exit(1);
+        }
+    };
+    info!("Server listening on {}{}", address, if cert.is_some() { " with TLS" } else { "" });
+
+    if let Some(redirect_address) = redirect_address {
+        match server::bind(route::https_redirect(address.port()), redirect_address, None, access_log, connections.clone(), shutdown.clone()) {
+            Ok(server) => servers.push(tokio::spawn(server)),
+            Err(err) => {
+                error!("Failed to bind {}: {}", redirect_address, err);
+                std::process::exit(1);
+            }
+        }
+        info!("Redirecting to HTTPS from {}", redirect_address);
     }
-    if let Some(p) = argument::args_port(&args) {
-        port = p;
+    if let Some(cert) = cert {
+        tokio::spawn(reload_on_hangup(cert));
     }
 
-    let db_pool = init_pool(pg_config, 16).await;
+    shutdown_signal().await;
+    info!("Shutting down, draining connections for at most {:?}.", shutdown_timeout);
+    state.drain();
+    let _ = shutdown_tx.send(());
+
+    let mut servers = join_all(servers);
+    match timeout(shutdown_timeout, &mut servers).await {
+        Ok(_) => info!("All connections drained."),
+        Err(_) => {
+            let aborted = connections.abort();
+            warn!("Drain deadline exceeded, aborted {} remaining connections.", aborted);
+            // The servers resolve once the aborted connections are dropped.
+            servers.await;
+        }
+    }
+
+    // No connection holds the state anymore, so dropping the last handle of
+    // the pool closes its connections.
+    drop(state);
+    info!("Server stopped.");
+}
+
+/// Reload the TLS certificate on every SIGHUP.
+async fn reload_on_hangup(cert: ServerCert) {
+    let mut hangup = signal(SignalKind::hangup()).expect("install SIGHUP handler");
+    while hangup.recv().await.is_some() {
+        match cert.reload() {
+            Ok(()) => info!("TLS certificate reloaded."),
+            Err(err) => error!("Failed to reload the TLS certificate, keeping the current one: {}", err),
+        }
+    }
+}
 
-    if is_dev {
-        warp::serve(route::dev_routes(State::init(db_pool))).run(([0, 0, 0, 0], port)).await;
-    } else {
-        warp::serve(route::routes(State::init(db_pool))).run(([0, 0, 0, 0], port)).await;
+/// Resolve on SIGTERM or SIGINT.
+async fn shutdown_signal() {
+    let mut terminate = signal(SignalKind::terminate()).expect("install SIGTERM handler");
+    tokio::select! {
+        _ = terminate.recv() => {}
+        _ = tokio::signal::ctrl_c() => {}
     }
 }
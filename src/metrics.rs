@@ -0,0 +1,51 @@
+use prometheus::{
+    IntCounter,
+    IntCounterVec,
+    IntGauge,
+    HistogramVec,
+};
+
+lazy_static! {
+    pub static ref HTTP_REQUESTS: IntCounterVec = register_int_counter_vec!(
+        "pigskit_http_requests_total",
+        "Number of HTTP requests.",
+        &["route", "method", "status"]
+    ).unwrap();
+
+    pub static ref HTTP_REQUEST_DURATION: HistogramVec = register_histogram_vec!(
+        "pigskit_http_request_duration_seconds",
+        "HTTP request latencies in seconds.",
+        &["route", "method"]
+    ).unwrap();
+
+    pub static ref API_ERRORS: IntCounterVec = register_int_counter_vec!(
+        "pigskit_api_errors_total",
+        "Number of error responses by error type.",
+        &["type", "status"]
+    ).unwrap();
+
+    pub static ref DB_POOL_CONNECTIONS: IntGauge = register_int_gauge!(
+        "pigskit_db_pool_connections",
+        "Number of connections managed by the database pool."
+    ).unwrap();
+
+    pub static ref DB_POOL_IDLE: IntGauge = register_int_gauge!(
+        "pigskit_db_pool_idle_connections",
+        "Number of idle connections in the database pool."
+    ).unwrap();
+
+    pub static ref DB_POOL_WAITERS: IntGauge = register_int_gauge!(
+        "pigskit_db_pool_waiters",
+        "Number of tasks waiting for a connection from the database pool."
+    ).unwrap();
+
+    pub static ref STORAGE_BYTES_WRITTEN: IntCounter = register_int_counter!(
+        "pigskit_storage_written_bytes_total",
+        "Number of bytes written to the storage."
+    ).unwrap();
+
+    pub static ref STORAGE_BYTES_READ: IntCounter = register_int_counter!(
+        "pigskit_storage_read_bytes_total",
+        "Number of bytes read from the storage."
+    ).unwrap();
+}
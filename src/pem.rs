@@ -0,0 +1,36 @@
+//! Certificates and private keys in PEM files named by the configuration.
+
+use std::{
+    fs::File,
+    io::BufReader,
+};
+use rustls::{
+    Certificate,
+    PrivateKey,
+    internal::pemfile,
+};
+use crate::config::ConfigError;
+
+fn open(field: &'static str, path: &str) -> Result<BufReader<File>, ConfigError> {
+    File::open(path)
+        .map(BufReader::new)
+        .map_err(|err| ConfigError::Invalid(field, format!("{}: {}", path, err)))
+}
+
+/// All the certificates of the file, at least one.
+pub fn read_certs(field: &'static str, path: &str) -> Result<Vec<Certificate>, ConfigError> {
+    match pemfile::certs(&mut open(field, path)?) {
+        Ok(certs) if !certs.is_empty() => Ok(certs),
+        _ => Err(ConfigError::Invalid(field, format!("{}: no PEM certificate.", path))),
+    }
+}
+
+/// The first PKCS#8 or RSA private key of the file.
+pub fn read_key(field: &'static str, path: &str) -> Result<PrivateKey, ConfigError> {
+    let pkcs8 = pemfile::pkcs8_private_keys(&mut open(field, path)?).unwrap_or_default();
+    let rsa = pemfile::rsa_private_keys(&mut open(field, path)?).unwrap_or_default();
+    pkcs8.into_iter()
+        .chain(rsa)
+        .next()
+        .ok_or_else(|| ConfigError::Invalid(field, format!("{}: no PEM private key.", path)))
+}
@@ -0,0 +1,122 @@
+//! Limits of a single instance.
+
+use std::{
+    collections::HashMap,
+    sync::Mutex,
+    time::{
+        Duration,
+        Instant,
+    },
+};
+use async_trait::async_trait;
+use crate::error::Error;
+use super::LimitStore;
+
+/// Entries are pruned once a map holds this many keys.
+const PRUNE_THRESHOLD: usize = 10000;
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+    /// When the bucket is full again, and can be forgotten.
+    full_at: Instant,
+}
+
+struct Failures {
+    count: u32,
+    last: Instant,
+    locked_until: Option<Instant>,
+    /// When the failures and the lock no longer matter.
+    expires: Instant,
+}
+
+pub struct MemoryStore {
+    buckets: Mutex<HashMap<String, Bucket>>,
+    failures: Mutex<HashMap<String, Failures>>,
+}
+
+impl MemoryStore {
+    pub fn new() -> Self {
+        MemoryStore {
+            buckets: Mutex::new(HashMap::new()),
+            failures: Mutex::new(HashMap::new()),
+        }
+    }
+}
+
+#[async_trait]
+impl LimitStore for MemoryStore {
+    async fn take(&self, key: &str, burst: u32, per_second: f64) -> Result<Option<Duration>, Error> {
+        let now = Instant::now();
+        let burst = burst as f64;
+        let mut buckets = self.buckets.lock().unwrap();
+        if buckets.len() >= PRUNE_THRESHOLD {
+            buckets.retain(|_, bucket| bucket.full_at > now);
+        }
+        let bucket = buckets.entry(key.to_owned()).or_insert(Bucket {
+            tokens: burst,
+            updated: now,
+            full_at: now,
+        });
+
+        let tokens = (bucket.tokens + now.duration_since(bucket.updated).as_secs_f64() * per_second).min(burst);
+        let (tokens, wait) = if tokens < 1.0 {
+            (tokens, Some(Duration::from_secs_f64((1.0 - tokens) / per_second)))
+        } else {
+            (tokens - 1.0, None)
+        };
+        bucket.tokens = tokens;
+        bucket.updated = now;
+        bucket.full_at = now + Duration::from_secs_f64((burst - tokens) / per_second);
+        Ok(wait)
+    }
+
+    async fn fail(&self, key: &str, window: Duration) -> Result<u32, Error> {
+        let now = Instant::now();
+        let mut failures = self.failures.lock().unwrap();
+        if failures.len() >= PRUNE_THRESHOLD {
+            failures.retain(|_, failures| failures.expires > now);
+        }
+        let entry = failures.entry(key.to_owned()).or_insert(Failures {
+            count: 0,
+            last: now,
+            locked_until: None,
+            expires: now,
+        });
+        if now.duration_since(entry.last) > window {
+            entry.count = 0;
+        }
+        entry.count += 1;
+        entry.last = now;
+        entry.expires = entry.expires.max(now + window);
+        Ok(entry.count)
+    }
+
+    async fn lock(&self, key: &str, duration: Duration) -> Result<(), Error> {
+        let now = Instant::now();
+        let mut failures = self.failures.lock().unwrap();
+        let entry = failures.entry(key.to_owned()).or_insert(Failures {
+            count: 0,
+            last: now,
+            locked_until: None,
+            expires: now,
+        });
+        entry.locked_until = Some(now + duration);
+        entry.expires = entry.expires.max(now + duration);
+        Ok(())
+    }
+
+    async fn locked(&self, key: &str) -> Result<Option<Duration>, Error> {
+        let now = Instant::now();
+        let failures = self.failures.lock().unwrap();
+        Ok(failures.get(key)
+            .and_then(|failures| failures.locked_until)
+            .filter(|until| *until > now)
+            .map(|until| until - now))
+    }
+
+    async fn reset(&self, key: &str) -> Result<(), Error> {
+        self.failures.lock().unwrap().remove(key);
+        Ok(())
+    }
+}
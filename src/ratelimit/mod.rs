@@ -0,0 +1,307 @@
+//! Token buckets limiting the endpoints open to anyone, by client address
+//! and by username or email, and the lockout of usernames after failed sign-ins.
+//! They are kept in memory, or in Postgres to be shared by the instances
+//! behind a load balancer.
+
+use std::{
+    net::IpAddr,
+    sync::Arc,
+    time::Duration,
+};
+use async_trait::async_trait;
+use crate::{
+    config::{
+        BucketConfig,
+        Cidr,
+        RateLimitConfig,
+        RateLimitStore,
+    },
+    context,
+    error::Error,
+    state::Pool,
+};
+
+mod memory;
+mod postgres;
+
+pub use memory::MemoryStore;
+pub use postgres::PostgresStore;
+
+#[async_trait]
+pub trait LimitStore: Send + Sync {
+    /// Take a token of the bucket of `key`, holding up to `burst` tokens and
+    /// refilled at `per_second`. Returns how long until one is available if
+    /// it is empty.
+    async fn take(&self, key: &str, burst: u32, per_second: f64) -> Result<Option<Duration>, Error>;
+
+    /// Count a failure of `key`, returns the failures in a row. The count
+    /// restarts after `window` without a failure.
+    async fn fail(&self, key: &str, window: Duration) -> Result<u32, Error>;
+
+    async fn lock(&self, key: &str, duration: Duration) -> Result<(), Error>;
+
+    /// How long `key` stays locked.
+    async fn locked(&self, key: &str) -> Result<Option<Duration>, Error>;
+
+    /// Forget the failures and the lock of `key`.
+    async fn reset(&self, key: &str) -> Result<(), Error>;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Limit {
+    SigninIp,
+    SigninUsername,
+    RegisterIp,
+    CartIp,
+    ResetIp,
+    ResetEmail,
+    CodeIp,
+}
+
+impl Limit {
+    fn name(&self) -> &'static str {
+        match self {
+            Limit::SigninIp => "signin_ip",
+            Limit::SigninUsername => "signin_username",
+            Limit::RegisterIp => "register_ip",
+            Limit::CartIp => "cart_ip",
+            Limit::ResetIp => "reset_ip",
+            Limit::ResetEmail => "reset_email",
+            Limit::CodeIp => "code_ip",
+        }
+    }
+
+    fn bucket(&self, config: &RateLimitConfig) -> BucketConfig {
+        match self {
+            Limit::SigninIp => config.signin_ip,
+            Limit::SigninUsername => config.signin_username,
+            Limit::RegisterIp => config.register_ip,
+            Limit::CartIp => config.cart_ip,
+            Limit::ResetIp => config.reset_ip,
+            Limit::ResetEmail => config.reset_email,
+            Limit::CodeIp => config.code_ip,
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct RateLimiter {
+    store: Arc<dyn LimitStore>,
+    config: Arc<RateLimitConfig>,
+    proxies: Arc<Vec<Cidr>>,
+}
+
+impl RateLimiter {
+    pub fn new(store: Arc<dyn LimitStore>, config: RateLimitConfig) -> Self {
+        RateLimiter {
+            store,
+            proxies: Arc::new(config.proxies().unwrap_or_default()),
+            config: Arc::new(config),
+        }
+    }
+
+    pub fn open(config: &RateLimitConfig, db_pool: Pool) -> Self {
+        let store: Arc<dyn LimitStore> = match config.store {
+            RateLimitStore::Memory => Arc::new(MemoryStore::new()),
+            RateLimitStore::Postgres => Arc::new(PostgresStore::new(db_pool)),
+        };
+        RateLimiter::new(store, config.clone())
+    }
+
+    /// Take a token of the bucket of `key`, fails with `TooManyRequests` if it is empty.
+    pub async fn check(&self, limit: Limit, key: &str) -> Result<(), Error> {
+        let bucket = limit.bucket(&self.config);
+        let key = format!("{}:{}", limit.name(), key);
+        match self.store.take(&key, bucket.burst, bucket.per_second()).await? {
+            Some(retry_after) => {
+                info!("Rate limited {}.", key);
+                Err(Error::too_many_requests(retry_after))
+            }
+            None => Ok(()),
+        }
+    }
+
+    /// Take a token of the bucket of the client of the request.
+    pub async fn check_client(&self, limit: Limit) -> Result<(), Error> {
+        self.check(limit, &self.client_key()).await
+    }
+
+    /// Address of the client of the request, behind the trusted proxies.
+    pub fn client_ip(&self) -> Option<IpAddr> {
+        context::remote_addr().map(|addr| client_ip(addr.ip(), context::forwarded_for().as_deref(), &self.proxies))
+    }
+
+    /// The key of the client in the buckets by address. IPv6 clients usually
+    /// get a whole /64, so they are limited by it.
+    fn client_key(&self) -> String {
+        match self.client_ip() {
+            Some(IpAddr::V4(ip)) => ip.to_string(),
+            Some(IpAddr::V6(ip)) => match ip.to_ipv4() {
+                Some(ip) => ip.to_string(),
+                None => {
+                    let segments = ip.segments();
+                    format!("{:x}:{:x}:{:x}:{:x}::/64", segments[0], segments[1], segments[2], segments[3])
+                }
+            },
+            None => "unknown".to_owned(),
+        }
+    }
+
+    /// Fails with `TooManyRequests` while the username is locked out.
+    pub async fn check_lockout(&self, username: &str) -> Result<(), Error> {
+        match self.store.locked(&lockout_key(username)).await? {
+            Some(retry_after) => Err(Error::too_many_requests(retry_after)),
+            None => Ok(()),
+        }
+    }
+
+    /// Count a failed sign-in, locking the username once the failures in a
+    /// row reach `lockout_failures`. Each further failure doubles the lock.
+    pub async fn record_failure(&self, username: &str) -> Result<(), Error> {
+        let key = lockout_key(username);
+        let max = Duration::from_secs(self.config.lockout_max_seconds);
+        let failures = self.store.fail(&key, max).await?;
+        if failures >= self.config.lockout_failures {
+            let doublings = (failures - self.config.lockout_failures).min(32);
+            let seconds = self.config.lockout_seconds.saturating_mul(1 << doublings);
+            let duration = Duration::from_secs(seconds).min(max);
+            warn!("Locked out {:?} for {}s after {} failed sign-ins.", username, duration.as_secs(), failures);
+            self.store.lock(&key, duration).await?;
+        }
+        Ok(())
+    }
+
+    pub async fn record_success(&self, username: &str) -> Result<(), Error> {
+        self.store.reset(&lockout_key(username)).await
+    }
+}
+
+fn lockout_key(username: &str) -> String {
+    format!("lockout:{}", username)
+}
+
+/// The right-most hop of `forwarded_for` not a trusted proxy, as the hops
+/// left of it may be made up by the client. `peer` if it is not a proxy,
+/// the search also stops at a hop that is not an address.
+fn client_ip(peer: IpAddr, forwarded_for: Option<&str>, proxies: &[Cidr]) -> IpAddr {
+    let trusted = |ip: IpAddr| {
+        let ip = match ip {
+            IpAddr::V6(v6) => v6.to_ipv4_mapped().map(IpAddr::V4).unwrap_or(ip),
+            IpAddr::V4(_) => ip,
+        };
+        proxies.iter().any(|proxy| proxy.contains(ip))
+    };
+    let mut client = peer;
+    for hop in forwarded_for.unwrap_or("").rsplit(',') {
+        if !trusted(client) {
+            break
+        }
+        match hop.trim().parse() {
+            Ok(ip) => client = ip,
+            Err(_) => break,
+        }
+    }
+    client
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+    use tokio::time::delay_for;
+    use warp::{
+        http::StatusCode,
+        test::request,
+    };
+    use crate::{
+        config::Config,
+        context::RequestContext,
+        testing::TestServer,
+    };
+    use super::{
+        LimitStore,
+        MemoryStore,
+        PostgresStore,
+    };
+
+    async fn check_store(store: &dyn LimitStore) {
+        assert_eq!(store.take("a", 2, 10.0).await.unwrap(), None);
+        assert_eq!(store.take("a", 2, 10.0).await.unwrap(), None);
+        let wait = store.take("a", 2, 10.0).await.unwrap().unwrap();
+        assert!(wait > Duration::from_millis(50) && wait <= Duration::from_millis(100), "{:?}", wait);
+        // Buckets are independent.
+        assert_eq!(store.take("b", 2, 10.0).await.unwrap(), None);
+        delay_for(Duration::from_millis(150)).await;
+        assert_eq!(store.take("a", 2, 10.0).await.unwrap(), None);
+
+        assert_eq!(store.fail("user", Duration::from_millis(200)).await.unwrap(), 1);
+        assert_eq!(store.fail("user", Duration::from_millis(200)).await.unwrap(), 2);
+        assert_eq!(store.locked("user").await.unwrap(), None);
+        store.lock("user", Duration::from_secs(60)).await.unwrap();
+        let locked = store.locked("user").await.unwrap().unwrap();
+        assert!(locked > Duration::from_secs(59), "{:?}", locked);
+        store.reset("user").await.unwrap();
+        assert_eq!(store.locked("user").await.unwrap(), None);
+        assert_eq!(store.fail("user", Duration::from_millis(200)).await.unwrap(), 1);
+        // The count restarts after the window.
+        delay_for(Duration::from_millis(250)).await;
+        assert_eq!(store.fail("user", Duration::from_millis(200)).await.unwrap(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_memory_store() {
+        check_store(&MemoryStore::new()).await;
+    }
+
+    #[tokio::test]
+    async fn test_postgres_store() {
+        let server = TestServer::start().await;
+        check_store(&PostgresStore::new(server.state.db_pool().clone())).await;
+    }
+
+    #[tokio::test]
+    async fn test_lockout() {
+        let server = TestServer::start_with(|config: &mut Config| {
+            config.ratelimit.lockout_failures = 2;
+            config.ratelimit.lockout_seconds = 10;
+            config.ratelimit.lockout_max_seconds = 25;
+        }).await;
+        let limiter = server.state.rate_limiter();
+        let locked = || async { limiter.store.locked("lockout:alice").await.unwrap().map(|duration| duration.as_secs() + 1) };
+
+        limiter.record_failure("alice").await.unwrap();
+        assert!(limiter.check_lockout("alice").await.is_ok());
+        limiter.record_failure("alice").await.unwrap();
+        assert_eq!(locked().await, Some(10));
+        assert_eq!(limiter.check_lockout("alice").await.unwrap_err().http_status(), 429);
+        assert!(limiter.check_lockout("bob").await.is_ok());
+        limiter.record_failure("alice").await.unwrap();
+        assert_eq!(locked().await, Some(20));
+        limiter.record_failure("alice").await.unwrap();
+        assert_eq!(locked().await, Some(25));
+
+        limiter.record_success("alice").await.unwrap();
+        assert!(limiter.check_lockout("alice").await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_trusted_proxies() {
+        let server = TestServer::start_with(|config: &mut Config| {
+            config.ratelimit.register_ip.burst = 1;
+            config.ratelimit.trusted_proxies = vec!["10.0.0.0/8".to_string()];
+        }).await;
+        let register = |peer: &'static str, forwarded_for: &'static str| {
+            let context = RequestContext::new("test".to_string(), Some(peer.parse().unwrap()), Some(forwarded_for.to_string()));
+            let response = server.send_in(context, request().method("POST").path("/api/user/register"));
+            async { response.await.status() }
+        };
+
+        // Through the trusted peer and proxy, the client is the hop left of them.
+        assert_eq!(register("10.0.0.1:80", "203.0.113.7, 10.0.0.2").await, StatusCode::OK);
+        // The hops left of the client are not trusted.
+        assert_eq!(register("10.0.0.1:80", "198.51.100.1, 203.0.113.7").await, StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(register("[::ffff:10.0.0.1]:80", "203.0.113.8").await, StatusCode::OK);
+        // The header of an untrusted peer is ignored.
+        assert_eq!(register("192.0.2.1:80", "203.0.113.9").await, StatusCode::OK);
+        assert_eq!(register("192.0.2.1:80", "203.0.113.10").await, StatusCode::TOO_MANY_REQUESTS);
+    }
+}
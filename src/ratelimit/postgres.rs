@@ -0,0 +1,50 @@
+//! Limits shared by the instances using the same database.
+
+use std::time::Duration;
+use async_trait::async_trait;
+use crate::{
+    error::Error,
+    sql::ratelimit,
+    state::Pool,
+};
+use super::LimitStore;
+
+pub struct PostgresStore {
+    db_pool: Pool,
+}
+
+impl PostgresStore {
+    pub fn new(db_pool: Pool) -> Self {
+        PostgresStore {
+            db_pool,
+        }
+    }
+}
+
+#[async_trait]
+impl LimitStore for PostgresStore {
+    async fn take(&self, key: &str, burst: u32, per_second: f64) -> Result<Option<Duration>, Error> {
+        let conn = self.db_pool.get().await?;
+        ratelimit::take(&*conn, key, burst, per_second).await
+    }
+
+    async fn fail(&self, key: &str, window: Duration) -> Result<u32, Error> {
+        let conn = self.db_pool.get().await?;
+        ratelimit::fail(&*conn, key, window).await
+    }
+
+    async fn lock(&self, key: &str, duration: Duration) -> Result<(), Error> {
+        let conn = self.db_pool.get().await?;
+        ratelimit::lock(&*conn, key, duration).await
+    }
+
+    async fn locked(&self, key: &str) -> Result<Option<Duration>, Error> {
+        let conn = self.db_pool.get().await?;
+        ratelimit::locked(&*conn, key).await
+    }
+
+    async fn reset(&self, key: &str) -> Result<(), Error> {
+        let conn = self.db_pool.get().await?;
+        ratelimit::reset(&*conn, key).await
+    }
+}
@@ -8,13 +8,15 @@ use warp::{
     post,
     patch,
     delete,
-    path,
     body,
 };
 use uuid::Uuid;
 use crate::{
     route::utils::{
-        filter::cookie,
+        filter::{
+            cookie,
+            endpoint,
+        },
         handler::HandlerResult,
     },
     state::State,
@@ -23,6 +25,7 @@ use crate::{
         UuidNN,
         TextNN,
         IntNN,
+        carts,
     },
     error::Error,
 };
@@ -46,16 +49,14 @@ fn create_filter(state: BoxedFilter<(State,)>) -> BoxedFilter<(impl Reply,)> {
         async {
             let conn = state.db_pool().get().await?;
 
-            conn.execute(
-                "SELECT cart_create_item($1, $2, $3, $4, $5, $6);",
-                &[
-                    &UuidNN(gssid),
-                    &args.shop_id,
-                    &args.product_key,
-                    &args.remark,
-                    &args.count,
-                    &args.cus_sel,
-                ],
+            carts::create_item(
+                &*conn,
+                gssid,
+                args.shop_id.0,
+                args.product_key.0,
+                args.remark.as_deref(),
+                args.count.0,
+                &args.cus_sel.0,
             ).await?;
 
             Ok("Successfully create cart item.")
@@ -82,15 +83,7 @@ fn update_filter(state: BoxedFilter<(State,)>) -> BoxedFilter<(impl Reply,)> {
         async {
             let conn = state.db_pool().get().await?;
             
-            conn.execute(
-                "SELECT cart_update_item($1, $2, $3, $4);",
-                &[
-                    &UuidNN(gssid),
-                    &args.shop_id,
-                    &args.item_key,
-                    &args.payload,
-                ],
-            ).await?;
+            carts::update_item(&*conn, gssid, args.shop_id.0, args.item_key.0, &args.payload.0).await?;
 
             Ok("Successfully update cart item.")
         }
@@ -114,14 +107,7 @@ fn delete_filter(state: BoxedFilter<(State,)>) -> BoxedFilter<(impl Reply,)> {
         async {
             let conn = state.db_pool().get().await?;
 
-            conn.execute(
-                "SELECT cart_delete_item($1, $2, $3);",
-                &[
-                    &UuidNN(gssid),
-                    &args.shop_id,
-                    &args.item_key,
-                ],
-            ).await?;
+            carts::delete_item(&*conn, gssid, args.shop_id.0, args.item_key.0).await?;
 
             Ok("Successfully delete cart item.")
         }
@@ -132,7 +118,7 @@ fn delete_filter(state: BoxedFilter<(State,)>) -> BoxedFilter<(impl Reply,)> {
 }
 
 pub fn filter(state: BoxedFilter<(State,)>) -> BoxedFilter<(impl Reply,)> {
-    path::end().and(
+    endpoint::end().and(
         create_filter(state.clone())
         .or(update_filter(state.clone()))
         .or(delete_filter(state.clone()))
@@ -13,13 +13,18 @@ use warp::{
 use uuid::Uuid;
 use crate::{
     route::utils::{
-        filter::cookie,
+        filter::{
+            cookie,
+            endpoint,
+        },
         response,
         handler::HandlerResult,
     },
     state::State,
+    ratelimit::Limit,
     sql::{
         UuidNN,
+        carts,
     },
     error::Error,
 };
@@ -39,16 +44,12 @@ fn put_filter(state: BoxedFilter<(State,)>) -> BoxedFilter<(impl Reply,)> {
     .and(state)
     .and_then(async move |gssid_cookie: Option<Uuid>, args: PutArgs, state: State| -> HandlerResult<Response> {
         async {
+            state.rate_limiter().check_client(Limit::CartIp).await?;
             let conn = state.db_pool().get().await?;
-            
-            let (gssid,) = query_one!(
-                conn,
-                "SELECT put_cart($1, $2) AS gssid;",
-                &[&gssid_cookie, &args.shop_id],
-                (gssid: Uuid),
-            )?;
 
-            Ok(response::set_cookie("GSSID", &gssid.to_string(), 1))
+            let gssid = carts::put(&*conn, gssid_cookie, args.shop_id.0).await?;
+
+            Ok(response::set_cookie("GSSID", &gssid.to_string(), state.config().cookie.guest_session_days, state.config().server.is_tls()))
         }
         .await
         .map_err(|err: Error| reject::custom(err))
@@ -57,7 +58,7 @@ fn put_filter(state: BoxedFilter<(State,)>) -> BoxedFilter<(impl Reply,)> {
 }
 
 pub fn filter(state: BoxedFilter<(State,)>) -> BoxedFilter<(impl Reply,)> {
-    path::end().and(
+    endpoint::end().and(
         put_filter(state.clone())
     )
     .or(
@@ -6,17 +6,22 @@ use warp::{
     reject,
     filters::BoxedFilter,
     post,
-    path,
     body,
 };
 use uuid::Uuid;
 use crate::{
     route::utils::{
-        filter::cookie,
+        filter::{
+            cookie,
+            endpoint,
+        },
         handler::HandlerResult,
     },
     state::State,
-    sql::UuidNN,
+    sql::{
+        UuidNN,
+        orders,
+    },
     error::Error,
 };
 
@@ -34,13 +39,7 @@ fn create_filter(state: BoxedFilter<(State,)>) -> BoxedFilter<(impl Reply,)> {
         async {
             let conn = state.db_pool().get().await?;
 
-            conn.execute(
-                "SELECT create_order($1, $2);",
-                &[
-                    &UuidNN(gssid),
-                    &args.shop_id,
-                ],
-            ).await?;
+            orders::create(&*conn, gssid, args.shop_id.0).await?;
 
             Ok("Successfully create order.")
         }
@@ -51,7 +50,7 @@ fn create_filter(state: BoxedFilter<(State,)>) -> BoxedFilter<(impl Reply,)> {
 }
 
 pub fn filter(state: BoxedFilter<(State,)>) -> BoxedFilter<(impl Reply,)> {
-    path::end().and(
+    endpoint::end().and(
         create_filter(state.clone())
     )
     .boxed()
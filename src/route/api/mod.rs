@@ -5,6 +5,7 @@ use warp::{
     path,
 };
 use crate::{
+    config::UploadConfig,
     state::State,
 };
 
@@ -12,13 +13,14 @@ mod user;
 mod shop;
 mod cart;
 
-pub fn filter(state: BoxedFilter<(State,)>) -> BoxedFilter<(impl Reply,)> {
+/// `upload` limits the form bodies.
+pub fn filter(state: BoxedFilter<(State,)>, upload: &UploadConfig) -> BoxedFilter<(impl Reply,)> {
     path("user").and(
-        user::filter(state.clone())
+        user::filter(state.clone(), upload)
     )
     .or(
         path("shop").and(
-            shop::filter(state.clone())
+            shop::filter(state.clone(), upload)
         )
     )
     .or(
@@ -27,4 +29,976 @@ pub fn filter(state: BoxedFilter<(State,)>) -> BoxedFilter<(impl Reply,)> {
         )
     )
     .boxed()
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod test {
+    use bytes::Bytes;
+    use uuid::Uuid;
+    use warp::{
+        http::{
+            Response,
+            StatusCode,
+        },
+        test::{
+            RequestBuilder,
+            request,
+        },
+    };
+    use serde_json::{
+        json,
+        Value,
+    };
+    use crate::{
+        storage::StorageError,
+        testing::{
+            TestServer,
+            cookie,
+            error_type,
+            multipart,
+            png,
+            session_cookie,
+        },
+    };
+
+    async fn register(server: &TestServer, username: &str, password: &str) {
+        let response = server.send(request().method("POST").path("/api/user/register")).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let regssid = cookie(&response, "REGSSID").unwrap();
+
+        for (operation, data) in [
+            ("username", username.to_string()),
+            ("password", password.to_string()),
+            ("email", format!("{}@pigskit.com", username)),
+            ("phone", format!("09{:08}", username.bytes().map(u32::from).sum::<u32>())),
+        ] {
+            let response = server.send(
+                request()
+                .method("PATCH")
+                .path("/api/user/register")
+                .header("cookie", session_cookie("REGSSID", &regssid))
+                .json(&json!({ "operation": operation, "data": data }))
+            ).await;
+            assert_eq!(response.status(), StatusCode::OK, "register {}", operation);
+        }
+        verify_email(server, &regssid).await;
+        verify_phone(server, &regssid).await;
+
+        let response = server.send(
+            request()
+            .method("PATCH")
+            .path("/api/user/register")
+            .header("cookie", session_cookie("REGSSID", &regssid))
+            .json(&json!({ "operation": "submit" }))
+        ).await;
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    fn patch_register(regssid: &str, operation: &str, data: Option<&str>) -> RequestBuilder {
+        request()
+        .method("PATCH")
+        .path("/api/user/register")
+        .header("cookie", session_cookie("REGSSID", regssid))
+        .json(&json!({ "operation": operation, "data": data }))
+    }
+
+    fn code(message: &str) -> String {
+        let start = message.find("code is ").unwrap() + "code is ".len();
+        message[start..start + 6].to_owned()
+    }
+
+    /// The code in the last mail sent.
+    fn verification_code(server: &TestServer) -> String {
+        code(&server.mails().pop().unwrap())
+    }
+
+    /// The code in the last text message sent.
+    fn phone_code(server: &TestServer) -> String {
+        code(&server.texts().pop().unwrap().1)
+    }
+
+    async fn verify_email(server: &TestServer, regssid: &str) {
+        let response = server.send(patch_register(regssid, "send_email_code", None)).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let code = verification_code(server);
+        let response = server.send(patch_register(regssid, "verify_email", Some(&code))).await;
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    async fn verify_phone(server: &TestServer, regssid: &str) {
+        let response = server.send(patch_register(regssid, "send_phone_code", None)).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let code = phone_code(server);
+        let response = server.send(patch_register(regssid, "verify_phone", Some(&code))).await;
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    async fn sign_in(server: &TestServer, username: &str, password: &str) -> String {
+        let response = server.send(
+            request()
+            .method("POST")
+            .path("/api/user/session")
+            .json(&json!({ "username": username, "password": password }))
+        ).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        cookie(&response, "USSID").unwrap()
+    }
+
+    async fn create_shop(server: &TestServer, ussid: &str, name: &str) -> String {
+        let response = server.send(
+            request()
+            .method("POST")
+            .path("/api/shop")
+            .header("cookie", session_cookie("USSID", ussid))
+            .json(&json!({ "shop_name": name }))
+        ).await;
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let conn = server.state.db_pool().get().await.unwrap();
+        let row = conn.query_one("SELECT id::text FROM shop WHERE name = $1", &[&name]).await.unwrap();
+        row.get(0)
+    }
+
+    async fn create_product(server: &TestServer, ussid: &str, shop_id: &str, payload: &Value, image: Option<&[u8]>) -> String {
+        let payload = payload.to_string();
+        let mut parts: Vec<(&str, &[u8])> = vec![
+            ("shop_id", shop_id.as_bytes()),
+            ("payload", payload.as_bytes()),
+        ];
+        if let Some(image) = image {
+            parts.push(("image", image));
+        }
+        let (content_type, body) = multipart(&parts);
+        let response = server.send(
+            request()
+            .method("POST")
+            .path("/api/shop/product")
+            .header("cookie", session_cookie("USSID", ussid))
+            .header("content-type", content_type)
+            .body(body)
+        ).await;
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let conn = server.state.db_pool().get().await.unwrap();
+        let row = conn.query_one(
+            "SELECT key::text FROM shop_product WHERE shop_id = $1::text::uuid ORDER BY latest_update DESC LIMIT 1",
+            &[&shop_id],
+        ).await.unwrap();
+        row.get(0)
+    }
+
+    #[tokio::test]
+    async fn test_register() {
+        let server = TestServer::start().await;
+        register(&server, "alice", "Passw0rd").await;
+
+        let response = server.send(request().method("POST").path("/api/user/register")).await;
+        let regssid = cookie(&response, "REGSSID").unwrap();
+
+        let response = server.send(
+            request()
+            .method("PATCH")
+            .path("/api/user/register")
+            .header("cookie", session_cookie("REGSSID", &regssid))
+            .json(&json!({ "operation": "email", "data": "not an email" }))
+        ).await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(error_type(response.body()), "InvalidData");
+
+        let response = server.send(
+            request()
+            .method("PATCH")
+            .path("/api/user/register")
+            .header("cookie", session_cookie("REGSSID", &regssid))
+            .json(&json!({ "operation": "username", "data": "alice" }))
+        ).await;
+        assert_eq!(response.status(), StatusCode::CONFLICT);
+
+        let response = server.send(
+            request()
+            .method("PATCH")
+            .path("/api/user/register")
+            .header("cookie", session_cookie("REGSSID", &regssid))
+            .json(&json!({ "operation": "submit" }))
+        ).await;
+        assert_eq!(error_type(response.body()), "OperationFailed");
+
+        // The email has to be verified before submitting.
+        let response = server.send(request().method("POST").path("/api/user/register")).await;
+        let regssid = cookie(&response, "REGSSID").unwrap();
+        let response = server.send(patch_register(&regssid, "send_email_code", None)).await;
+        assert_eq!(error_type(response.body()), "DataNotFound");
+        for (operation, data) in [
+            ("username", "bob"),
+            ("password", "Passw0rd"),
+            ("email", "bob@pigskit.com"),
+            ("phone", "0912345678"),
+        ] {
+            let response = server.send(patch_register(&regssid, operation, Some(data))).await;
+            assert_eq!(response.status(), StatusCode::OK);
+        }
+        let response = server.send(patch_register(&regssid, "submit", None)).await;
+        assert_eq!(error_type(response.body()), "EmailNotVerified");
+
+        let response = server.send(patch_register(&regssid, "send_email_code", None)).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert!(server.mails().last().unwrap().contains("To: bob@pigskit.com\r\n"));
+        let code = verification_code(&server);
+        let wrong = if code == "000000" { "000001" } else { "000000" };
+        let response = server.send(patch_register(&regssid, "verify_email", Some(wrong))).await;
+        assert_eq!(error_type(response.body()), "InvalidVerificationCode");
+        // Changing the email discards the code.
+        let response = server.send(patch_register(&regssid, "email", Some("bob2@pigskit.com"))).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let response = server.send(patch_register(&regssid, "verify_email", Some(&code))).await;
+        assert_eq!(error_type(response.body()), "InvalidVerificationCode");
+
+        // The code is discarded after too many wrong ones.
+        let response = server.send(patch_register(&regssid, "send_email_code", None)).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let code = verification_code(&server);
+        let wrong = if code == "000000" { "000001" } else { "000000" };
+        for _ in 0..5 {
+            let response = server.send(patch_register(&regssid, "verify_email", Some(wrong))).await;
+            assert_eq!(error_type(response.body()), "InvalidVerificationCode");
+        }
+        let response = server.send(patch_register(&regssid, "verify_email", Some(&code))).await;
+        assert_eq!(error_type(response.body()), "InvalidVerificationCode");
+
+        verify_email(&server, &regssid).await;
+        let response = server.send(patch_register(&regssid, "submit", None)).await;
+        assert_eq!(error_type(response.body()), "PhoneNotVerified");
+
+        let response = server.send(patch_register(&regssid, "send_phone_code", None)).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(server.texts().last().unwrap().0, "0912345678");
+        // The number is throttled.
+        let response = server.send(patch_register(&regssid, "send_phone_code", None)).await;
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert!(response.headers().contains_key("retry-after"));
+        let code = phone_code(&server);
+        let wrong = if code == "000000" { "000001" } else { "000000" };
+        let response = server.send(patch_register(&regssid, "verify_phone", Some(wrong))).await;
+        assert_eq!(error_type(response.body()), "InvalidVerificationCode");
+        let response = server.send(patch_register(&regssid, "verify_phone", Some(&code))).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let response = server.send(patch_register(&regssid, "submit", None)).await;
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn test_profile_phone() {
+        let server = TestServer::start().await;
+        register(&server, "alice", "Passw0rd").await;
+        register(&server, "bob", "Passw0rd").await;
+        let ussid = sign_in(&server, "alice", "Passw0rd").await;
+        let patch = |operation: &str, data: &str| server.send(
+            request()
+            .method("PATCH")
+            .path("/api/user/profile/phone")
+            .header("cookie", session_cookie("USSID", &ussid))
+            .json(&json!({ "operation": operation, "data": data }))
+        );
+
+        assert_eq!(error_type(patch("send_phone_code", "12345").await.body()), "InvalidData");
+        // The phone of bob.
+        let response = patch("send_phone_code", "0900000307").await;
+        assert_eq!(response.status(), StatusCode::CONFLICT);
+        assert_eq!(patch("send_phone_code", "0912121212").await.status(), StatusCode::OK);
+        assert_eq!(server.texts().last().unwrap().0, "0912121212");
+        let code = phone_code(&server);
+        let wrong = if code == "000000" { "000001" } else { "000000" };
+        assert_eq!(error_type(patch("verify_phone", wrong).await.body()), "InvalidVerificationCode");
+        assert_eq!(patch("verify_phone", &code).await.status(), StatusCode::OK);
+        // Codes are single-use.
+        assert_eq!(error_type(patch("verify_phone", &code).await.body()), "InvalidVerificationCode");
+
+        let response = server.send(
+            request()
+            .method("GET")
+            .path("/api/user/session")
+            .header("cookie", session_cookie("USSID", &ussid))
+        ).await;
+        let body: Value = serde_json::from_slice(response.body()).unwrap();
+        assert_eq!(body["phone"], "0912121212");
+    }
+
+    #[tokio::test]
+    async fn test_session() {
+        let server = TestServer::start().await;
+        register(&server, "alice", "Passw0rd").await;
+
+        let response = server.send(
+            request()
+            .method("POST")
+            .path("/api/user/session")
+            .json(&json!({ "username": "alice", "password": "wrong" }))
+        ).await;
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+
+        let ussid = sign_in(&server, "alice", "Passw0rd").await;
+        let response = server.send(
+            request()
+            .method("GET")
+            .path("/api/user/session")
+            .header("cookie", session_cookie("USSID", &ussid))
+        ).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let body: Value = serde_json::from_slice(response.body()).unwrap();
+        assert_eq!(body["username"], "alice");
+        assert_eq!(body["email"], "alice@pigskit.com");
+
+        let response = server.send(
+            request()
+            .method("DELETE")
+            .path("/api/user/session")
+            .header("cookie", session_cookie("USSID", &ussid))
+        ).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(cookie(&response, "USSID").unwrap(), "");
+
+        let response = server.send(
+            request()
+            .method("GET")
+            .path("/api/user/session")
+            .header("cookie", session_cookie("USSID", &ussid))
+        ).await;
+        assert_eq!(error_type(response.body()), "SessionExpired");
+    }
+
+    #[tokio::test]
+    async fn test_sessions() {
+        let server = TestServer::start().await;
+        register(&server, "alice", "Passw0rd").await;
+        register(&server, "bob", "Passw0rd").await;
+        let response = server.send(
+            request()
+            .method("POST")
+            .path("/api/user/session")
+            .header("user-agent", "Phone/1.0")
+            .json(&json!({ "username": "alice", "password": "Passw0rd" }))
+        ).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let phone = cookie(&response, "USSID").unwrap();
+        let laptop = sign_in(&server, "alice", "Passw0rd").await;
+        let tablet = sign_in(&server, "alice", "Passw0rd").await;
+        let bob = sign_in(&server, "bob", "Passw0rd").await;
+        let list = |ussid: &str| server.send(
+            request()
+            .method("GET")
+            .path("/api/user/session/all")
+            .header("cookie", session_cookie("USSID", ussid))
+        );
+        let revoke = |ussid: &str, key: &str| server.send(
+            request()
+            .method("DELETE")
+            .path(&format!("/api/user/session/{}", key))
+            .header("cookie", session_cookie("USSID", ussid))
+        );
+        let sessions = |body: &[u8]| serde_json::from_slice::<Vec<Value>>(body).unwrap();
+
+        let response = list(&laptop).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let all = sessions(response.body());
+        assert_eq!(all.len(), 3);
+        assert_eq!(all.iter().filter(|session| session["current"] == true).count(), 1);
+        let phone_session = all.iter().find(|session| session["user_agent"] == "Phone/1.0").unwrap();
+        assert_eq!(phone_session["current"], false);
+        assert!(phone_session["created_at"].is_string());
+        assert!(phone_session["last_seen_at"].is_string());
+        let phone_key = phone_session["key"].as_str().unwrap().to_string();
+
+        // Only the sessions of the user may be revoked.
+        assert_eq!(error_type(revoke(&bob, &phone_key).await.body()), "DataNotFound");
+        let response = revoke(&laptop, &phone_key).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(cookie(&response, "USSID"), None);
+        assert_eq!(error_type(list(&phone).await.body()), "SessionExpired");
+        assert_eq!(error_type(revoke(&laptop, &phone_key).await.body()), "DataNotFound");
+
+        // Revoking the current session clears its cookie.
+        let all = sessions(list(&tablet).await.body());
+        assert_eq!(all.len(), 2);
+        let tablet_key = all.iter().find(|session| session["current"] == true).unwrap()["key"].as_str().unwrap().to_string();
+        let response = revoke(&tablet, &tablet_key).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(cookie(&response, "USSID").unwrap(), "");
+        assert_eq!(sessions(list(&laptop).await.body()).len(), 1);
+
+        // Signing out everywhere leaves the other users signed in.
+        let other = sign_in(&server, "alice", "Passw0rd").await;
+        let response = server.send(
+            request()
+            .method("DELETE")
+            .path("/api/user/session/all")
+            .header("cookie", session_cookie("USSID", &laptop))
+        ).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(cookie(&response, "USSID").unwrap(), "");
+        assert_eq!(error_type(list(&laptop).await.body()), "SessionExpired");
+        assert_eq!(error_type(list(&other).await.body()), "SessionExpired");
+        assert_eq!(sessions(list(&bob).await.body()).len(), 1);
+    }
+
+    /// Hours left to the session, rounded.
+    async fn session_hours_left(server: &TestServer, ussid: &str) -> i64 {
+        let conn = server.state.db_pool().get().await.unwrap();
+        let row = conn.query_one(
+            "SELECT extract(epoch FROM expire_at - now())::float8 / 3600 FROM user_session WHERE id = $1",
+            &[&Uuid::parse_str(ussid).unwrap()],
+        ).await.unwrap();
+        row.get::<usize, f64>(0).round() as i64
+    }
+
+    /// Move the expiry of the session, `set` assigning its columns.
+    async fn age_session(server: &TestServer, ussid: &str, set: &str) {
+        let conn = server.state.db_pool().get().await.unwrap();
+        conn.execute(
+            format!("UPDATE user_session SET {} WHERE id = $1", set).as_str(),
+            &[&Uuid::parse_str(ussid).unwrap()],
+        ).await.unwrap();
+    }
+
+    /// The `Set-Cookie` header of the user session.
+    fn session_set_cookie(response: &Response<Bytes>) -> Option<String> {
+        response.headers()
+            .get_all("set-cookie")
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .find(|value| value.starts_with("USSID="))
+            .map(|value| value.to_owned())
+    }
+
+    #[tokio::test]
+    async fn test_session_expiry() {
+        let server = TestServer::start().await;
+        register(&server, "alice", "Passw0rd").await;
+        let sign_in = |remember_me: bool| server.send(
+            request()
+            .method("POST")
+            .path("/api/user/session")
+            .json(&json!({ "username": "alice", "password": "Passw0rd", "remember_me": remember_me }))
+        );
+        let session = |ussid: &str| server.send(
+            request()
+            .method("GET")
+            .path("/api/user/session")
+            .header("cookie", session_cookie("USSID", ussid))
+        );
+
+        // A browser session cookie has no expiry, its session lasts hours.
+        let response = sign_in(false).await;
+        assert!(!session_set_cookie(&response).unwrap().contains("Expires="));
+        let browser = cookie(&response, "USSID").unwrap();
+        assert_eq!(session_hours_left(&server, &browser).await, 12);
+        let response = sign_in(true).await;
+        assert!(session_set_cookie(&response).unwrap().contains("Expires="));
+        let remembered = cookie(&response, "USSID").unwrap();
+        assert_eq!(session_hours_left(&server, &remembered).await, 30 * 24);
+
+        // Nothing is renewed before half of the lifetime.
+        let response = session(&remembered).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(session_set_cookie(&response), None);
+
+        // Past it the session is extended, and a remembered cookie issued again.
+        age_session(&server, &remembered, "expire_at = now() + interval '1 day'").await;
+        let response = session(&remembered).await;
+        assert_eq!(cookie(&response, "USSID").unwrap(), remembered);
+        assert!(session_set_cookie(&response).unwrap().contains("Expires="));
+        // The CSRF token stays the same.
+        assert_eq!(cookie(&response, "CSRF"), None);
+        assert_eq!(session_hours_left(&server, &remembered).await, 30 * 24);
+        age_session(&server, &browser, "expire_at = now() + interval '1 hour'").await;
+        let response = session(&browser).await;
+        assert_eq!(session_set_cookie(&response), None);
+        assert_eq!(session_hours_left(&server, &browser).await, 12);
+
+        // Never past the maximum lifetime.
+        age_session(&server, &remembered, "expire_at = now() + interval '1 day', max_expire_at = now() + interval '2 days'").await;
+        session(&remembered).await;
+        assert_eq!(session_hours_left(&server, &remembered).await, 2 * 24);
+        age_session(&server, &remembered, "expire_at = now() - interval '1 second'").await;
+        let response = session(&remembered).await;
+        assert_eq!(error_type(response.body()), "SessionExpired");
+        assert_eq!(session_set_cookie(&response), None);
+    }
+
+    /// The code of the authenticator app of the user, `steps` time steps from now.
+    async fn totp_code(server: &TestServer, username: &str, steps: i64) -> String {
+        let conn = server.state.db_pool().get().await.unwrap();
+        let row = conn.query_one(
+            "SELECT totp_code(secret, floor(extract(epoch FROM now()) / 30)::bigint + $2)
+             FROM user_totp JOIN users ON users.id = user_id
+             WHERE username = $1",
+            &[&username, &steps],
+        ).await.unwrap();
+        row.get(0)
+    }
+
+    #[tokio::test]
+    async fn test_two_factor() {
+        let server = TestServer::start_with(|config| {
+            config.ratelimit.signin_ip.burst = 100;
+        }).await;
+        register(&server, "alice", "Passw0rd").await;
+        let ussid = sign_in(&server, "alice", "Passw0rd").await;
+        let two_factor = |method: &'static str| request()
+            .method(method)
+            .path("/api/user/2fa")
+            .header("cookie", session_cookie("USSID", &ussid));
+        let patch = |operation: &'static str, code: String| server.send(
+            two_factor("PATCH").json(&json!({ "operation": operation, "data": code }))
+        );
+        let password = || server.send(
+            request()
+            .method("POST")
+            .path("/api/user/session")
+            .json(&json!({ "username": "alice", "password": "Passw0rd" }))
+        );
+        let challenge = |challenge: &str, code: &str| server.send(
+            request()
+            .method("POST")
+            .path("/api/user/session")
+            .json(&json!({ "challenge": challenge, "code": code }))
+        );
+        let new_challenge = || async {
+            let response = password().await;
+            assert_eq!(response.status(), StatusCode::OK);
+            assert_eq!(cookie(&response, "USSID"), None);
+            let body: Value = serde_json::from_slice(response.body()).unwrap();
+            body["challenge"].as_str().unwrap().to_string()
+        };
+
+        let body: Value = serde_json::from_slice(server.send(two_factor("GET")).await.body()).unwrap();
+        assert_eq!(body["enabled"], false);
+        assert_eq!(error_type(patch("enable", "123456".to_string()).await.body()), "DataNotFound");
+
+        let response = server.send(two_factor("POST")).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let body: Value = serde_json::from_slice(response.body()).unwrap();
+        let secret = body["secret"].as_str().unwrap();
+        assert_eq!(secret.len(), 32);
+        assert_eq!(body["uri"], format!("otpauth://totp/Pigskit:alice?secret={}&issuer=Pigskit&algorithm=SHA1&digits=6&period=30", secret));
+        // Not enabled until confirmed.
+        sign_in(&server, "alice", "Passw0rd").await;
+
+        assert_eq!(error_type(patch("enable", "wrong".to_string()).await.body()), "InvalidVerificationCode");
+        let code = totp_code(&server, "alice", 0).await;
+        let response = patch("enable", code.clone()).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let body: Value = serde_json::from_slice(response.body()).unwrap();
+        let recovery_codes: Vec<String> = serde_json::from_value(body["recovery_codes"].clone()).unwrap();
+        assert_eq!(recovery_codes.len(), 10);
+        assert_eq!(error_type(patch("enable", code.clone()).await.body()), "TwoFactorEnabled");
+        let body: Value = serde_json::from_slice(server.send(two_factor("GET")).await.body()).unwrap();
+        assert_eq!(body["enabled"], true);
+        assert_eq!(body["recovery_codes"], 10);
+
+        // The password gives a challenge, a code redeems it for the session.
+        let id = new_challenge().await;
+        assert_eq!(error_type(challenge(&id, "wrong").await.body()), "InvalidVerificationCode");
+        // Each code is accepted once.
+        assert_eq!(error_type(challenge(&id, &code).await.body()), "InvalidVerificationCode");
+        let response = challenge(&id, &totp_code(&server, "alice", 1).await).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let session = cookie(&response, "USSID").unwrap();
+        assert_eq!(server.send(
+            request()
+            .method("GET")
+            .path("/api/user/session")
+            .header("cookie", session_cookie("USSID", &session))
+        ).await.status(), StatusCode::OK);
+        assert_eq!(error_type(challenge(&id, "123456").await.body()), "ChallengeExpired");
+
+        // So does a recovery code, once.
+        let id = new_challenge().await;
+        assert_eq!(challenge(&id, &recovery_codes[0].to_uppercase()).await.status(), StatusCode::OK);
+        let id = new_challenge().await;
+        assert_eq!(error_type(challenge(&id, &recovery_codes[0]).await.body()), "InvalidVerificationCode");
+        // The challenge ends after a few wrong codes.
+        for _ in 0..4 {
+            assert_eq!(error_type(challenge(&id, "wrong").await.body()), "InvalidVerificationCode");
+        }
+        assert_eq!(error_type(challenge(&id, &recovery_codes[1]).await.body()), "ChallengeExpired");
+        // Which locks the username out as wrong passwords do.
+        assert_eq!(error_type(password().await.body()), "TooManyRequests");
+        server.state.rate_limiter().record_success("alice").await.unwrap();
+
+        // A code of a step already used is rejected, a new one renews the
+        // recovery codes. The steps are set rather than waited for.
+        let conn = server.state.db_pool().get().await.unwrap();
+        conn.execute("UPDATE user_totp SET last_step = floor(extract(epoch FROM now()) / 30)::bigint + 10", &[]).await.unwrap();
+        let response = patch("renew_recovery_codes", totp_code(&server, "alice", 1).await).await;
+        assert_eq!(error_type(response.body()), "InvalidVerificationCode");
+        conn.execute("UPDATE user_totp SET last_step = 0", &[]).await.unwrap();
+        let response = patch("renew_recovery_codes", totp_code(&server, "alice", 0).await).await;
+        let body: Value = serde_json::from_slice(response.body()).unwrap();
+        let renewed: Vec<String> = serde_json::from_value(body["recovery_codes"].clone()).unwrap();
+        assert_eq!(renewed.len(), 10);
+        let id = new_challenge().await;
+        assert_eq!(error_type(challenge(&id, &recovery_codes[1]).await.body()), "InvalidVerificationCode");
+
+        let disable = |code: &str| server.send(two_factor("DELETE").json(&json!({ "code": code })));
+        assert_eq!(error_type(disable("wrong").await.body()), "InvalidVerificationCode");
+        assert_eq!(disable(&renewed[0]).await.status(), StatusCode::OK);
+        assert_eq!(error_type(disable(&renewed[1]).await.body()), "DataNotFound");
+        sign_in(&server, "alice", "Passw0rd").await;
+    }
+
+    #[tokio::test]
+    async fn test_password() {
+        let server = TestServer::start().await;
+        register(&server, "alice", "Passw0rd").await;
+        let ussid = sign_in(&server, "alice", "Passw0rd").await;
+        let other = sign_in(&server, "alice", "Passw0rd").await;
+        let session = |ussid: String| server.send(
+            request()
+            .method("GET")
+            .path("/api/user/session")
+            .header("cookie", session_cookie("USSID", &ussid))
+        );
+        let change = |current: &'static str, new: &'static str| server.send(
+            request()
+            .method("PATCH")
+            .path("/api/user/password")
+            .header("cookie", session_cookie("USSID", &ussid))
+            .json(&json!({ "current_password": current, "new_password": new }))
+        );
+
+        assert_eq!(change("wrong", "Passw0rd2").await.status(), StatusCode::FORBIDDEN);
+        assert_eq!(error_type(change("Passw0rd", "password").await.body()), "InvalidData");
+        assert_eq!(change("Passw0rd", "Passw0rd2").await.status(), StatusCode::OK);
+        // The other sessions are ended.
+        assert_eq!(session(ussid.clone()).await.status(), StatusCode::OK);
+        assert_eq!(error_type(session(other).await.body()), "SessionExpired");
+        sign_in(&server, "alice", "Passw0rd2").await;
+
+        let request_reset = |email: &'static str| server.send(
+            request()
+            .method("POST")
+            .path("/api/user/password/reset")
+            .json(&json!({ "email": email }))
+        );
+        let reset = |token: String, new: &'static str| server.send(
+            request()
+            .method("PATCH")
+            .path("/api/user/password/reset")
+            .json(&json!({ "token": token, "new_password": new }))
+        );
+
+        let sent = server.mails().len();
+        assert_eq!(request_reset("bob@pigskit.com").await.status(), StatusCode::OK);
+        assert_eq!(server.mails().len(), sent);
+        assert_eq!(request_reset("alice@pigskit.com").await.status(), StatusCode::OK);
+        assert_eq!(request_reset("alice@pigskit.com").await.status(), StatusCode::OK);
+        let mails = server.mails().split_off(sent);
+        assert_eq!(mails.len(), 2);
+        assert!(mails[1].contains("To: alice@pigskit.com\r\n"));
+        let token = |mail: &str| {
+            let start = mail.find("token=").unwrap() + "token=".len();
+            mail[start..].chars().take_while(|c| c.is_ascii_hexdigit()).collect::<String>()
+        };
+
+        // A new request replaces the earlier token.
+        assert_eq!(error_type(reset(token(&mails[0]), "Passw0rd3").await.body()), "InvalidResetToken");
+        assert_eq!(error_type(reset(token(&mails[1]), "password").await.body()), "InvalidData");
+        assert_eq!(reset(token(&mails[1]), "Passw0rd3").await.status(), StatusCode::OK);
+        assert_eq!(error_type(session(ussid).await.body()), "SessionExpired");
+        // Tokens are single-use.
+        assert_eq!(error_type(reset(token(&mails[1]), "Passw0rd4").await.body()), "InvalidResetToken");
+        sign_in(&server, "alice", "Passw0rd3").await;
+    }
+
+    #[tokio::test]
+    async fn test_rate_limit() {
+        let server = TestServer::start_with(|config| {
+            config.ratelimit.register_ip.burst = 2;
+            config.ratelimit.reset_email.burst = 1;
+            config.ratelimit.lockout_failures = 2;
+        }).await;
+        register(&server, "alice", "Passw0rd").await;
+
+        let response = server.send(request().method("POST").path("/api/user/register")).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let response = server.send(request().method("POST").path("/api/user/register")).await;
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(error_type(response.body()), "TooManyRequests");
+        let retry_after: u64 = response.headers()["retry-after"].to_str().unwrap().parse().unwrap();
+        assert!(retry_after > 0 && retry_after <= 12);
+
+        let request_reset = |email: &'static str| server.send(
+            request()
+            .method("POST")
+            .path("/api/user/password/reset")
+            .json(&json!({ "email": email }))
+        );
+        let sent = server.mails().len();
+        assert_eq!(request_reset("alice@pigskit.com").await.status(), StatusCode::OK);
+        assert_eq!(request_reset("Alice@Pigskit.com").await.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(request_reset("bob@pigskit.com").await.status(), StatusCode::OK);
+        assert_eq!(server.mails().len(), sent + 1);
+
+        let ussid = sign_in(&server, "alice", "Passw0rd").await;
+        let change = |current: &'static str| server.send(
+            request()
+            .method("PATCH")
+            .path("/api/user/password")
+            .header("cookie", session_cookie("USSID", &ussid))
+            .json(&json!({ "current_password": current, "new_password": "Passw0rd2" }))
+        );
+        assert_eq!(change("wrong").await.status(), StatusCode::FORBIDDEN);
+        assert_eq!(change("wrong").await.status(), StatusCode::FORBIDDEN);
+        assert_eq!(change("Passw0rd").await.status(), StatusCode::TOO_MANY_REQUESTS);
+
+        let sign_in = |password: &'static str| server.send(
+            request()
+            .method("POST")
+            .path("/api/user/session")
+            .json(&json!({ "username": "alice", "password": password }))
+        );
+        assert_eq!(sign_in("wrong").await.status(), StatusCode::FORBIDDEN);
+        assert_eq!(sign_in("wrong").await.status(), StatusCode::FORBIDDEN);
+        // Locked out, even with the right password.
+        let response = sign_in("Passw0rd").await;
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        let body: Value = serde_json::from_slice(response.body()).unwrap();
+        assert_eq!(body["data"]["retry_after"], 30);
+        assert_eq!(response.headers()["retry-after"], "30");
+    }
+
+    #[tokio::test]
+    async fn test_shop_product() {
+        let server = TestServer::start().await;
+        register(&server, "alice", "Passw0rd").await;
+        register(&server, "bob", "Passw0rd").await;
+        let alice = sign_in(&server, "alice", "Passw0rd").await;
+        let bob = sign_in(&server, "bob", "Passw0rd").await;
+
+        let shop_id = create_shop(&server, &alice, "pigskit").await;
+        let response = server.send(
+            request()
+            .method("POST")
+            .path("/api/shop")
+            .header("cookie", session_cookie("USSID", &bob))
+            .json(&json!({ "shop_name": "pigskit" }))
+        ).await;
+        assert_eq!(error_type(response.body()), "ShopNameUsed");
+
+        let (content_type, body) = multipart(&[
+            ("shop_id", shop_id.as_bytes()),
+            ("payload", br#"{"name":"tea"}"#),
+            ("image", b"not an image"),
+        ]);
+        let response = server.send(
+            request()
+            .method("POST")
+            .path("/api/shop/product")
+            .header("cookie", session_cookie("USSID", &alice))
+            .header("content-type", content_type)
+            .body(body)
+        ).await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+        // Larger than the limit of the field, though within the limit of the form.
+        let mut image = png(1, 1);
+        image.resize(6_000_000, 0);
+        let (content_type, body) = multipart(&[
+            ("shop_id", shop_id.as_bytes()),
+            ("payload", br#"{"name":"tea"}"#),
+            ("image", &image),
+        ]);
+        let response = server.send(
+            request()
+            .method("POST")
+            .path("/api/shop/product")
+            .header("cookie", session_cookie("USSID", &alice))
+            .header("content-type", content_type)
+            .body(body)
+        ).await;
+        assert_eq!(error_type(response.body()), "PayloadTooLarge");
+
+        let product_key = create_product(&server, &alice, &shop_id, &json!({ "name": "tea" }), Some(&png(600, 300))).await;
+        let image = server.state.storage().product_image(&shop_id, &product_key, None);
+        assert!(server.state.storage().metadata(&image).await.is_ok());
+
+        for (size, width) in [("", 600), ("&size=256", 256)] {
+            let response = server.send(
+                request()
+                .method("GET")
+                .path(&format!("/api/shop/product/image?shop_id={}&product_key={}{}", shop_id, product_key, size))
+            ).await;
+            assert_eq!(response.status(), StatusCode::OK);
+            assert_eq!(response.headers()["content-type"], "image/jpeg");
+            let image = image::load_from_memory_with_format(response.body(), image::ImageFormat::Jpeg).unwrap();
+            assert_eq!(image.width(), width);
+
+            let response = server.send(
+                request()
+                .method("GET")
+                .path(&format!("/api/shop/product/image?shop_id={}&product_key={}{}", shop_id, product_key, size))
+                .header("if-none-match", response.headers()["etag"].to_str().unwrap())
+            ).await;
+            assert_eq!(response.status(), StatusCode::NOT_MODIFIED);
+            assert!(response.body().is_empty());
+        }
+
+        let response = server.send(
+            request()
+            .method("GET")
+            .path(&format!("/api/shop/product/image?shop_id={}&product_key={}&size=100", shop_id, product_key))
+        ).await;
+        assert_eq!(error_type(response.body()), "InvalidData");
+
+        // Bob is not a member of the shop.
+        let (content_type, body) = multipart(&[
+            ("shop_id", shop_id.as_bytes()),
+            ("product_key", product_key.as_bytes()),
+            ("delete_image", b"true"),
+        ]);
+        let response = server.send(
+            request()
+            .method("PATCH")
+            .path("/api/shop/product")
+            .header("cookie", session_cookie("USSID", &bob))
+            .header("content-type", content_type.clone())
+            .body(body.clone())
+        ).await;
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+
+        let response = server.send(
+            request()
+            .method("PATCH")
+            .path("/api/shop/product")
+            .header("cookie", session_cookie("USSID", &alice))
+            .header("content-type", content_type)
+            .body(body)
+        ).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert!(matches!(server.state.storage().metadata(&image).await, Err(StorageError::NotFound)));
+
+        let response = server.send(
+            request()
+            .method("DELETE")
+            .path("/api/shop/product")
+            .header("cookie", session_cookie("USSID", &alice))
+            .json(&json!({ "shop_id": shop_id, "product_key": product_key }))
+        ).await;
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let response = server.send(
+            request()
+            .method("DELETE")
+            .path("/api/shop/product")
+            .header("cookie", session_cookie("USSID", &alice))
+            .json(&json!({ "shop_id": shop_id, "product_key": product_key }))
+        ).await;
+        assert_eq!(error_type(response.body()), "DataNotFound");
+    }
+
+    #[tokio::test]
+    async fn test_form_limit() {
+        let server = TestServer::start_with(|config| {
+            config.upload.max_length = 1000;
+        }).await;
+        register(&server, "alice", "Passw0rd").await;
+        let alice = sign_in(&server, "alice", "Passw0rd").await;
+        let shop_id = create_shop(&server, &alice, "pigskit").await;
+
+        // The body is refused by its length, past an allowance for the
+        // headers of the parts, before any part is read.
+        let (content_type, body) = multipart(&[
+            ("shop_id", shop_id.as_bytes()),
+            ("payload", br#"{"name":"tea"}"#),
+            ("image", &[0; 20_000]),
+        ]);
+        let response = server.send(
+            request()
+            .method("POST")
+            .path("/api/shop/product")
+            .header("cookie", session_cookie("USSID", &alice))
+            .header("content-type", content_type)
+            .body(body)
+        ).await;
+        assert_eq!(error_type(response.body()), "PayloadTooLarge");
+    }
+
+    #[tokio::test]
+    async fn test_cart_order() {
+        let server = TestServer::start().await;
+        register(&server, "alice", "Passw0rd").await;
+        let alice = sign_in(&server, "alice", "Passw0rd").await;
+        let shop_id = create_shop(&server, &alice, "pigskit").await;
+        let product_key = create_product(
+            &server,
+            &alice,
+            &shop_id,
+            &json!({
+                "name": "tea",
+                "customizes": { "size": { "selections": { "L": 10, "M": 0 } } },
+            }),
+            None,
+        ).await;
+
+        let response = server.send(
+            request()
+            .method("PUT")
+            .path("/api/cart")
+            .json(&json!({ "shop_id": shop_id }))
+        ).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let gssid = cookie(&response, "GSSID").unwrap();
+
+        let response = server.send(
+            request()
+            .method("POST")
+            .path("/api/cart/item")
+            .header("cookie", session_cookie("GSSID", &gssid))
+            .json(&json!({
+                "shop_id": shop_id,
+                "product_key": product_key,
+                "count": "2",
+                "cus_sel": "{}",
+            }))
+        ).await;
+        assert_eq!(error_type(response.body()), "CusSelNotProvided");
+
+        let response = server.send(
+            request()
+            .method("POST")
+            .path("/api/cart/item")
+            .header("cookie", session_cookie("GSSID", &gssid))
+            .json(&json!({
+                "shop_id": shop_id,
+                "product_key": product_key,
+                "remark": "less ice",
+                "count": "2",
+                "cus_sel": r#"{"size":"L"}"#,
+            }))
+        ).await;
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let response = server.send(
+            request()
+            .method("POST")
+            .path("/api/cart/order")
+            .header("cookie", session_cookie("GSSID", &gssid))
+            .json(&json!({ "shop_id": shop_id }))
+        ).await;
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let conn = server.state.db_pool().get().await.unwrap();
+        let row = conn.query_one("SELECT count(*) FROM shop_order_item", &[]).await.unwrap();
+        assert_eq!(row.get::<usize, i64>(0), 1);
+
+        // The cart is empty now.
+        let response = server.send(
+            request()
+            .method("POST")
+            .path("/api/cart/order")
+            .header("cookie", session_cookie("GSSID", &gssid))
+            .json(&json!({ "shop_id": shop_id }))
+        ).await;
+        assert_eq!(error_type(response.body()), "DataNotFound");
+    }
+}
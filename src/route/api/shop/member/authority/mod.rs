@@ -4,19 +4,22 @@ use warp::{
     reject,
     filters::BoxedFilter,
     patch,
-    path,
     body,
 };
 use uuid::Uuid;
 use crate::{
     route::utils::{
-        filter::cookie,
+        filter::{
+            cookie,
+            endpoint,
+        },
         handler::HandlerResult,
     },
     sql::{
         UuidNN,
         AuthorityNN,
         PermissionNN,
+        shops,
     },
     state::State,
     error::Error,
@@ -38,15 +41,13 @@ fn update_filter(state: BoxedFilter<(State,)>) -> BoxedFilter<(impl Reply,)> {
     .and_then(async move |user_id: Uuid, args: UpdateArgs, state: State| -> HandlerResult<&'static str> {
         async {
             let conn = state.db_pool().get().await?;
-            conn.execute(
-                "SELECT shop_user_update_authority($1, $2, $3, $4, $5)",
-                &[
-                    &UuidNN(user_id),
-                    &args.shop_id,
-                    &args.member_id,
-                    &args.authority,
-                    &args.permission,
-                ],
+            shops::update_member_authority(
+                &*conn,
+                user_id,
+                args.shop_id.0,
+                args.member_id.0,
+                args.authority.0,
+                args.permission.0,
             ).await?;
             Ok("Successfully setted shop member authority.")
         }
@@ -57,7 +58,7 @@ fn update_filter(state: BoxedFilter<(State,)>) -> BoxedFilter<(impl Reply,)> {
 }
 
 pub fn filter(state: BoxedFilter<(State,)>) -> BoxedFilter<(impl Reply,)> {
-    path::end().and(
+    endpoint::end().and(
         update_filter(state.clone())
     )
     .boxed()
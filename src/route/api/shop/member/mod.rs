@@ -10,10 +10,16 @@ use warp::{
 use uuid::Uuid;
 use crate::{
     route::utils::{
-        filter::cookie,
+        filter::{
+            cookie,
+            endpoint,
+        },
         handler::HandlerResult,
     },
-    sql::UuidNN,
+    sql::{
+        UuidNN,
+        shops,
+    },
     state::State,
     error::Error,
 };
@@ -34,14 +40,7 @@ fn create_filter(state: BoxedFilter<(State,)>) -> BoxedFilter<(impl Reply,)> {
     .and_then(async move |user_id: Uuid, args: CreateArgs, state: State| -> HandlerResult<&'static str> {
         async {
             let conn = state.db_pool().get().await?;
-            conn.execute(
-                "SELECT shop_user_create($1, $2, $3)",
-                &[
-                    &UuidNN(user_id),
-                    &args.shop_id,
-                    &args.member_id,
-                ],
-            ).await?;
+            shops::create_member(&*conn, user_id, args.shop_id.0, args.member_id.0).await?;
             Ok("Successfully added shop member.")
         }
         .await
@@ -51,7 +50,7 @@ fn create_filter(state: BoxedFilter<(State,)>) -> BoxedFilter<(impl Reply,)> {
 }
 
 pub fn filter(state: BoxedFilter<(State,)>) -> BoxedFilter<(impl Reply,)> {
-    path::end().and(
+    endpoint::end().and(
         create_filter(state.clone())
     )
     .or(
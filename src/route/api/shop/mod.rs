@@ -9,13 +9,17 @@ use warp::{
 };
 use uuid::Uuid;
 use crate::{
+    config::UploadConfig,
     route::utils::{
         handler::HandlerResult,
-        filter::cookie,
+        filter::{
+            cookie,
+            endpoint,
+        },
     },
     sql::{
-        UuidNN,
         TextNZ,
+        shops,
     },
     state::State,
     error::Error,
@@ -37,10 +41,7 @@ fn create_filter(state: BoxedFilter<(State,)>) -> BoxedFilter<(impl Reply,)> {
     .and_then(async move |user_id: Uuid, args: CreateArgs, state: State| -> HandlerResult<&'static str> {
         async {
             let conn = state.db_pool().get().await?;
-            conn.execute(
-                "SELECT create_shop($1, $2)",
-                &[&UuidNN(user_id), &args.shop_name],
-            ).await?;
+            shops::create(&*conn, user_id, &args.shop_name.0).await?;
             Ok("Successfully created shop.")
         }
         .await
@@ -49,8 +50,8 @@ fn create_filter(state: BoxedFilter<(State,)>) -> BoxedFilter<(impl Reply,)> {
     .boxed()
 }
 
-pub fn filter(state: BoxedFilter<(State,)>) -> BoxedFilter<(impl Reply,)> {
-    path::end().and(
+pub fn filter(state: BoxedFilter<(State,)>, upload: &UploadConfig) -> BoxedFilter<(impl Reply,)> {
+    endpoint::end().and(
         create_filter(state.clone())
     )
     .or(
@@ -60,7 +61,7 @@ pub fn filter(state: BoxedFilter<(State,)>) -> BoxedFilter<(impl Reply,)> {
     )
     .or(
         path("product").and(
-            product::filter(state.clone())
+            product::filter(state.clone(), upload)
         )
     )
     .boxed()
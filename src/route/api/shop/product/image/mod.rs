@@ -1,39 +1,77 @@
 use warp::{
     Filter,
-    reply::Reply,
+    reply::{
+        Reply,
+        Response,
+    },
     filters::BoxedFilter,
+    reject,
     get,
-    path,
     query,
 };
 use crate::{
-    route::utils::handler::fs,
+    route::utils::{
+        filter::{
+            conditional::{
+                Conditionals,
+                conditionals,
+            },
+            endpoint,
+        },
+        handler::HandlerResult,
+        response,
+    },
     sql::UuidNN,
-    STORAGE_DIR,
+    state::State,
+    storage::{
+        StorageError,
+        imaging,
+    },
+    error::Error,
 };
 
 #[derive(Deserialize)]
 struct GetArgs {
     shop_id: UuidNN,
     product_key: UuidNN,
+    size: Option<u32>,
 }
 
-fn get_filter() -> BoxedFilter<(impl Reply,)> {
+fn get_filter(state: BoxedFilter<(State,)>) -> BoxedFilter<(impl Reply,)> {
     get()
     .and(query())
-    .map(|args: GetArgs| {
-        (
-            format!("{}/shop/{}/product/{}/image.jpg", *STORAGE_DIR, args.shop_id, args.product_key),
-        )
+    .and(conditionals())
+    .and(state)
+    .and_then(async move |args: GetArgs, conditionals: Conditionals, state: State| -> HandlerResult<Response> {
+        async {
+            let size = imaging::check_size(args.size, &state.config().image)?;
+            let storage = state.storage();
+            let not_found = |err| match err {
+                StorageError::NotFound => Error::data_not_found("image"),
+                err => err.into(),
+            };
+            let (key, metadata) = storage.find_image(size, |size| storage.product_image(&args.shop_id, &args.product_key, size))
+            .await
+            .map_err(not_found)?;
+            response::blob(
+                storage,
+                &key,
+                &metadata,
+                &format!("public, max-age={}", state.config().image.cache_max_age),
+                &conditionals,
+            )
+            .await
+            .map_err(not_found)
+        }
+        .await
+        .map_err(|err: Error| reject::custom(err))
     })
-    .untuple_one()
-    .and_then(fs::read_handler)
     .boxed()
 }
 
-pub fn filter() -> BoxedFilter<(impl Reply,)> {
-    path::end().and(
-        get_filter()
+pub fn filter(state: BoxedFilter<(State,)>) -> BoxedFilter<(impl Reply,)> {
+    endpoint::end().and(
+        get_filter(state.clone())
     )
     .boxed()
 }
\ No newline at end of file
@@ -8,80 +8,73 @@ use warp::{
     patch,
     path,
     body,
-    multipart::{
-        form,
-        FormData,
-    }
 };
-use futures::{
-    TryFutureExt,
-    TryStreamExt,
-};
-use bytes::BufMut;
+use serde_json::Value;
 use uuid::Uuid;
 use crate::{
+    config::UploadConfig,
     route::utils::{
-        filter::cookie,
-        handler::{
-            fs,
-            HandlerResult,
+        filter::{
+            cookie,
+            endpoint,
+            form::{
+                self,
+                Json,
+            },
         },
+        handler::HandlerResult,
     },
     sql::{
-        TextNN,
+        Authority,
+        Permission,
         UuidNN,
+        products,
+        shops,
     },
     state::State,
+    storage::{
+        Upload,
+        imaging,
+    },
     error::Error,
-    STORAGE_DIR,
 };
 
 mod image;
 
-fn create_filter(state: BoxedFilter<(State,)>) -> BoxedFilter<(impl Reply,)> {
+/// Maximum size in bytes of an uploaded product image.
+const MAX_IMAGE_LENGTH: u64 = 5_000_000;
+
+#[derive(MultipartForm)]
+struct CreateForm {
+    shop_id: Uuid,
+    payload: Json<Value>,
+    #[multipart(limit = MAX_IMAGE_LENGTH, content_type = "image/*")]
+    image: Option<Upload>,
+}
+
+fn create_filter(state: BoxedFilter<(State,)>, upload: &UploadConfig) -> BoxedFilter<(impl Reply,)> {
     post()
     .and(cookie::to_user_id("USSID", state.clone()))
-    .and(
-        form_filter!(
-            shop_id [ Uuid ]
-            payload [ String ]
-            image [ Option [ Vec<u8> ] ]
-        )
-    )
+    .and(form::multipart(state.clone(), upload))
     .and(state)
-    .and_then(async move |user_id: Uuid, shop_id: Uuid, payload: String, image: Option<Vec<u8>>, state: State| -> HandlerResult<&'static str> {
+    .and_then(async move |user_id: Uuid, form: CreateForm, state: State| -> HandlerResult<&'static str> {
         async {
+            let CreateForm { shop_id, payload, image } = form;
             let mut connection = state.db_pool().get().await?;
             let transaction = connection.transaction().await?;
 
-            let (ok,) = query_one!(
-                transaction,
-                "SELECT check_shop_user_authority($1, $2, 'product_authority', 'all') AS ok;",
-                &[&UuidNN(shop_id), &UuidNN(user_id)],
-                (ok: bool),
-            )?;
+            let ok = shops::check_authority(&transaction, shop_id, user_id, Authority::ProductAuthority, Permission::All).await?;
 
             if !ok { return Err(Error::unauthorized()) }
 
-            let (product_key,) = query_one!(
-                transaction,
-                "SELECT product_key FROM shop_create_product($1, $2);",
-                &[&UuidNN(shop_id), &TextNN(payload)],
-                (product_key: Uuid),
-            )?;
+            let product_key = products::create(&transaction, shop_id, &payload.0.to_string()).await?;
             
             if let Some(data) = image {
-                transaction.query(
-                    "SELECT shop_set_product_has_picture($1, $2, $3);",
-                    &[&UuidNN(shop_id), &UuidNN(product_key), &true],
-                ).await?;
-
-                fs::store(
-                    format!("{}/shop/{}/product/{}", *STORAGE_DIR, shop_id, product_key),
-                    "image.jpg".to_string(),
-                    data,
-                )
-                .await?;
+                products::set_has_picture(&transaction, shop_id, product_key, true).await?;
+
+                let variants = imaging::process("image", data, state.storage().staging_dir(), &state.config().image).await?;
+                let storage = state.storage();
+                storage.put_image(variants, |size| storage.product_image(shop_id, product_key, size)).await?;
             }
 
             transaction.commit().await?;
@@ -108,21 +101,13 @@ fn delete_filter(state: BoxedFilter<(State,)>) -> BoxedFilter<(impl Reply,)> {
         async {
             let connection = state.db_pool().get().await?;
 
-            let (ok,) = query_one!(
-                connection,
-                "SELECT check_shop_user_authority($1, $2, 'product_authority', 'all') AS ok;",
-                &[&args.shop_id, &UuidNN(user_id)],
-                (ok: bool),
-            )?;
+            let ok = shops::check_authority(&*connection, args.shop_id.0, user_id, Authority::ProductAuthority, Permission::All).await?;
 
             if !ok { return Err(Error::unauthorized()) }
 
-            connection.execute(
-                "SELECT shop_delete_product($1, $2)",
-                &[&args.shop_id, &args.product_key],
-            ).await?;
+            products::delete(&*connection, args.shop_id.0, args.product_key.0).await?;
 
-            let _ = fs::delete_all(format!("{}/shop/{}/product/{}", *STORAGE_DIR, args.shop_id, args.product_key)).await;
+            let _ = state.storage().delete_prefix(&state.storage().product_dir(&args.shop_id, &args.product_key)).await;
 
             Ok("Successfully deleted product.")
         }
@@ -132,45 +117,33 @@ fn delete_filter(state: BoxedFilter<(State,)>) -> BoxedFilter<(impl Reply,)> {
     .boxed()
 }
 
-fn patch_filter(state: BoxedFilter<(State,)>) -> BoxedFilter<(impl Reply,)> {
+#[derive(MultipartForm)]
+struct PatchForm {
+    shop_id: Uuid,
+    product_key: Uuid,
+    payload: Option<Json<Value>>,
+    delete_image: Option<bool>,
+    #[multipart(limit = MAX_IMAGE_LENGTH, content_type = "image/*")]
+    image: Option<Upload>,
+}
+
+fn patch_filter(state: BoxedFilter<(State,)>, upload: &UploadConfig) -> BoxedFilter<(impl Reply,)> {
     patch()
     .and(cookie::to_user_id("USSID", state.clone()))
-    .and(
-        form_filter!(
-            shop_id [ Uuid ]
-            product_key [ Uuid ]
-            payload [ Option [ String ] ]
-            delete_image [ Option [ bool ] ]
-            image [ Option [ Vec<u8> ] ]
-        )
-    )
+    .and(form::multipart(state.clone(), upload))
     .and(state)
-    .and_then(async move |user_id: Uuid, shop_id: Uuid, product_key: Uuid, payload: Option<String>, delete_image: Option<bool>, image: Option<Vec<u8>>, state: State| -> HandlerResult<&'static str> {
+    .and_then(async move |user_id: Uuid, form: PatchForm, state: State| -> HandlerResult<&'static str> {
         async {
+            let PatchForm { shop_id, product_key, payload, delete_image, image } = form;
             let mut connection = state.db_pool().get().await?;
             let transaction = connection.transaction().await?;
 
-            let (ok,) = query_one!(
-                transaction,
-                "SELECT check_shop_user_authority($1, $2, 'product_authority', 'all') AS ok;",
-                &[
-                    &UuidNN(shop_id),
-                    &UuidNN(user_id),
-                ],
-                (ok: bool),
-            )?;
+            let ok = shops::check_authority(&transaction, shop_id, user_id, Authority::ProductAuthority, Permission::All).await?;
 
             if !ok { return Err(Error::unauthorized()) }
 
             if let Some(payload) = payload {
-                transaction.execute(
-                    "SELECT shop_update_product($1, $2, $3);",
-                    &[
-                        &UuidNN(shop_id),
-                        &UuidNN(product_key),
-                        &TextNN(payload),
-                    ],
-                ).await?;
+                products::update(&transaction, shop_id, product_key, &payload.0.to_string()).await?;
             }
             
             let delete_image = if let Some(delete_image) = delete_image {
@@ -180,30 +153,14 @@ fn patch_filter(state: BoxedFilter<(State,)>) -> BoxedFilter<(impl Reply,)> {
             };
 
             if delete_image {
-                transaction.execute(
-                    "SELECT shop_set_product_has_picture($1, $2, $3);",
-                    &[
-                        &UuidNN(shop_id),
-                        &UuidNN(product_key),
-                        &false,
-                    ],
-                ).await?;
-                fs::delete(format!("{}/shop/{}/product/{}/image.jpg", *STORAGE_DIR, shop_id, product_key)).await?;
-            } else if let Some(image) = image {
-                transaction.execute(
-                    "SELECT shop_set_product_has_picture($1, $2, $3);",
-                    &[
-                        &UuidNN(shop_id),
-                        &UuidNN(product_key),
-                        &true,
-                    ],
-                ).await?;
-                fs::store(
-                    format!("{}/shop/{}/product/{}", *STORAGE_DIR, shop_id, product_key),
-                    "image.jpg".to_string(),
-                    image,
-                )
-                .await?;
+                products::set_has_picture(&transaction, shop_id, product_key, false).await?;
+                let storage = state.storage();
+                storage.delete_image(imaging::variants(&state.config().image), |size| storage.product_image(shop_id, product_key, size)).await?;
+            } else if let Some(data) = image {
+                products::set_has_picture(&transaction, shop_id, product_key, true).await?;
+                let variants = imaging::process("image", data, state.storage().staging_dir(), &state.config().image).await?;
+                let storage = state.storage();
+                storage.put_image(variants, |size| storage.product_image(shop_id, product_key, size)).await?;
             }
 
             transaction.commit().await?;
@@ -215,15 +172,15 @@ fn patch_filter(state: BoxedFilter<(State,)>) -> BoxedFilter<(impl Reply,)> {
     .boxed()
 }
 
-pub fn filter(state: BoxedFilter<(State,)>) -> BoxedFilter<(impl Reply,)> {
-    path::end().and(
-        create_filter(state.clone())
+pub fn filter(state: BoxedFilter<(State,)>, upload: &UploadConfig) -> BoxedFilter<(impl Reply,)> {
+    endpoint::end().and(
+        create_filter(state.clone(), upload)
         .or(delete_filter(state.clone()))
-        .or(patch_filter(state.clone()))
+        .or(patch_filter(state.clone(), upload))
     )
     .or(
         path("image").and(
-            image::filter()
+            image::filter(state.clone())
         )
     )
     .boxed()
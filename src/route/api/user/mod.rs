@@ -4,13 +4,18 @@ use warp::{
     filters::BoxedFilter,
     path,
 };
-use crate::state::State;
+use crate::{
+    config::UploadConfig,
+    state::State,
+};
 
 mod register;
 mod session;
 mod profile;
+mod password;
+mod two_factor;
 
-pub fn filter(state: BoxedFilter<(State,)>) -> BoxedFilter<(impl Reply,)> {
+pub fn filter(state: BoxedFilter<(State,)>, upload: &UploadConfig) -> BoxedFilter<(impl Reply,)> {
     path("register").and(
         register::filter(state.clone())
     )
@@ -21,7 +26,17 @@ pub fn filter(state: BoxedFilter<(State,)>) -> BoxedFilter<(impl Reply,)> {
     )
     .or(
         path("profile").and(
-            profile::filter(state.clone())
+            profile::filter(state.clone(), upload)
+        )
+    )
+    .or(
+        path("password").and(
+            password::filter(state.clone())
+        )
+    )
+    .or(
+        path("2fa").and(
+            two_factor::filter(state.clone())
         )
     )
     .boxed()
@@ -0,0 +1,77 @@
+use warp::{
+    Filter,
+    reply::Reply,
+    reject,
+    filters::BoxedFilter,
+    patch,
+    path,
+    body,
+};
+use uuid::Uuid;
+use crate::{
+    route::utils::{
+        filter::{
+            cookie,
+            endpoint,
+        },
+        handler::HandlerResult,
+    },
+    state::State,
+    sql::{
+        TextNZ,
+        users,
+    },
+    error::Error,
+};
+use super::register::is_valid_password;
+
+mod reset;
+
+#[derive(Serialize, Deserialize)]
+struct PatchArgs {
+    current_password: TextNZ,
+    new_password: TextNZ,
+}
+
+/// Change the password, the other sessions of the user are ended. Guessing
+/// the current password locks the user out as guessing it at sign-in does.
+fn patch_filter(state: BoxedFilter<(State,)>) -> BoxedFilter<(impl Reply,)> {
+    patch()
+    .and(cookie::to_uuid("USSID"))
+    .and(body::json())
+    .and(state)
+    .and_then(async move |ussid: Uuid, args: PatchArgs, state: State| -> HandlerResult<&'static str> {
+        async {
+            if !is_valid_password(&args.new_password.0) {
+                return Err(Error::invalid_data("new_password"))
+            }
+
+            let conn = state.db_pool().get().await?;
+            let key = users::session_user(&*conn, ussid).await?.to_string();
+            let limiter = state.rate_limiter();
+            limiter.check_lockout(&key).await?;
+            if users::change_password(&*conn, ussid, &args.current_password.0, &args.new_password.0).await? {
+                limiter.record_success(&key).await?;
+                Ok("Success.")
+            } else {
+                limiter.record_failure(&key).await?;
+                Err(Error::unauthorized())
+            }
+        }
+        .await
+        .map_err(|err: Error| reject::custom(err))
+    })
+    .boxed()
+}
+
+pub fn filter(state: BoxedFilter<(State,)>) -> BoxedFilter<(impl Reply,)> {
+    endpoint::end().and(
+        patch_filter(state.clone())
+    )
+    .or(
+        path("reset").and(
+            reset::filter(state.clone())
+        )
+    )
+    .boxed()
+}
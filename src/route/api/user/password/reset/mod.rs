@@ -0,0 +1,101 @@
+use warp::{
+    Filter,
+    reply::Reply,
+    reject,
+    filters::BoxedFilter,
+    post,
+    patch,
+    body,
+};
+use crate::{
+    route::utils::{
+        filter::endpoint,
+        handler::HandlerResult,
+    },
+    state::State,
+    ratelimit::Limit,
+    sql::{
+        TextNZ,
+        users,
+    },
+    error::Error,
+};
+use super::super::register::is_valid_password;
+
+#[derive(Serialize, Deserialize)]
+struct PostArgs {
+    email: TextNZ,
+}
+
+/// Mail a reset link to the user with the email. The reply is the same
+/// whether there is one or not, so it does not tell which emails are used.
+/// The mails to an email are limited too, as the requests may come from
+/// many addresses.
+fn post_filter(state: BoxedFilter<(State,)>) -> BoxedFilter<(impl Reply,)> {
+    post()
+    .and(body::json())
+    .and(state)
+    .and_then(async move |args: PostArgs, state: State| -> HandlerResult<&'static str> {
+        async {
+            let email = &args.email.0;
+            state.rate_limiter().check_client(Limit::ResetIp).await?;
+            state.rate_limiter().check(Limit::ResetEmail, &email.trim().to_lowercase()).await?;
+            let conn = state.db_pool().get().await?;
+            let config = &state.config().mail;
+
+            if let Some((token, username)) = users::create_password_reset(&*conn, email, config.reset_token_minutes).await? {
+                let body = format!(
+                    "Hi {},\n\nFollow this link within {} minutes to set a new password:\n\n{}\n\nIf you did not ask for it, ignore this mail and your password stays the same.\n",
+                    username,
+                    config.reset_token_minutes,
+                    config.reset_url.replace("{token}", &token),
+                );
+                if let Err(err) = state.mail().send(email, "Reset your Pigskit password", body).await {
+                    error!("Failed to send the password reset mail: {}", err);
+                }
+            }
+            Ok("Success.")
+        }
+        .await
+        .map_err(|err: Error| reject::custom(err))
+    })
+    .boxed()
+}
+
+#[derive(Serialize, Deserialize)]
+struct PatchArgs {
+    token: TextNZ,
+    new_password: TextNZ,
+}
+
+/// Set a new password with the token of a reset link, ending every session of the user.
+fn patch_filter(state: BoxedFilter<(State,)>) -> BoxedFilter<(impl Reply,)> {
+    patch()
+    .and(body::json())
+    .and(state)
+    .and_then(async move |args: PatchArgs, state: State| -> HandlerResult<&'static str> {
+        async {
+            if !is_valid_password(&args.new_password.0) {
+                return Err(Error::invalid_data("new_password"))
+            }
+
+            let conn = state.db_pool().get().await?;
+            if users::redeem_password_reset(&*conn, &args.token.0, &args.new_password.0).await? {
+                Ok("Success.")
+            } else {
+                Err(Error::invalid_reset_token())
+            }
+        }
+        .await
+        .map_err(|err: Error| reject::custom(err))
+    })
+    .boxed()
+}
+
+pub fn filter(state: BoxedFilter<(State,)>) -> BoxedFilter<(impl Reply,)> {
+    endpoint::end().and(
+        post_filter(state.clone())
+        .or(patch_filter(state.clone()))
+    )
+    .boxed()
+}
@@ -1,49 +1,67 @@
 use warp::{
     Filter,
-    reply::Reply,
+    reply::{
+        Reply,
+        Response,
+    },
     filters::BoxedFilter,
     reject,
     get,
-    path,
     query,
 };
 use uuid::Uuid;
 use crate::{
     route::utils::{
-        filter::cookie,
-        handler::{
-            HandlerResult,
-            fs,
+        filter::{
+            cookie,
+            endpoint,
+            conditional::{
+                Conditionals,
+                conditionals,
+            },
         },
+        handler::HandlerResult,
+        response,
     },
     state::State,
+    storage::{
+        StorageError,
+        imaging,
+    },
     error::Error,
-    STORAGE_DIR,
 };
 
 #[derive(Deserialize)]
 struct GetArgs {
-    default: Option<bool>
+    default: Option<bool>,
+    size: Option<u32>,
 }
 
 fn get_filter(state: BoxedFilter<(State,)>) -> BoxedFilter<(impl Reply,)> {
     get()
     .and(query())
+    .and(conditionals())
     .and(cookie::to_user_id("USSID", state.clone()))
-    .and_then(async move |args: GetArgs, user_id: Uuid| -> HandlerResult<Vec<u8>> {
+    .and(state)
+    .and_then(async move |args: GetArgs, conditionals: Conditionals, user_id: Uuid, state: State| -> HandlerResult<Response> {
         async {
-            if let Ok(data) = fs::read(format!("{}/user/{}/avatar.jpg", *STORAGE_DIR, user_id)).await {
-                Ok(data)
-            } else {
-                if args.default.unwrap_or(false) {
-                    fs::read(format!("{}/default/user/avatar.jpg", *STORAGE_DIR))
-                    .await
-                    .map_err(|_| Error::data_not_found("avatar"))
-                } else {
-                    Err(Error::data_not_found("avatar"))
+            let size = imaging::check_size(args.size, &state.config().image)?;
+            let storage = state.storage();
+            let (key, metadata) = match storage.find_image(size, |size| storage.user_avatar(user_id, size)).await {
+                Ok(image) => Ok(image),
+                Err(StorageError::NotFound) => {
+                    if args.default.unwrap_or(false) {
+                        storage.find_image(size, |size| storage.default_user_avatar(size))
+                        .await
+                        .map_err(|_| Error::data_not_found("avatar"))
+                    } else {
+                        Err(Error::data_not_found("avatar"))
+                    }
                 }
-            }
-
+                Err(err) => Err(Error::from(err)),
+            }?;
+            // Avatars are only served to their owner.
+            Ok(response::blob(storage, &key, &metadata, "private, no-cache", &conditionals).await?)
         }
         .await
         .map_err(|err: Error| reject::custom(err))
@@ -52,7 +70,7 @@ fn get_filter(state: BoxedFilter<(State,)>) -> BoxedFilter<(impl Reply,)> {
 }
 
 pub fn filter(state: BoxedFilter<(State,)>) -> BoxedFilter<(impl Reply,)> {
-    path::end().and(
+    endpoint::end().and(
         get_filter(state.clone())
     )
     .boxed()
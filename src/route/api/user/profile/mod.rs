@@ -7,55 +7,53 @@ use warp::{
     filters::BoxedFilter,
     patch,
     path,
-    multipart::{
-        form,
-        FormData,
-    },
-};
-use futures::{
-    TryFutureExt,
-    TryStreamExt,
 };
-use bytes::BufMut;
 use uuid::Uuid;
 use crate::{
+    config::UploadConfig,
     route::utils::{
-        filter::cookie,
-        handler::{
-            fs,
-            HandlerResult,
+        filter::{
+            cookie,
+            endpoint,
+            form,
         },
+        handler::HandlerResult,
     },
     state::State,
+    sql::users,
+    storage::{
+        Upload,
+        imaging,
+    },
     error::Error,
-    STORAGE_DIR,
 };
 
 mod avatar;
+mod phone;
+
+/// Maximum size in bytes of an uploaded avatar.
+const MAX_AVATAR_LENGTH: u64 = 2_000_000;
+
+#[derive(MultipartForm)]
+struct PatchForm {
+    nickname: Option<String>,
+    #[multipart(limit = MAX_AVATAR_LENGTH, content_type = "image/*")]
+    avatar: Option<Upload>,
+    delete_avatar: Option<bool>,
+}
 
-fn patch_filter(state: BoxedFilter<(State,)>) -> BoxedFilter<(impl Reply,)> {
+fn patch_filter(state: BoxedFilter<(State,)>, upload: &UploadConfig) -> BoxedFilter<(impl Reply,)> {
     patch()
     .and(cookie::to_user_id("USSID", state.clone()))
-    .and(
-        form_filter!(
-            nickname [ Option [ String ] ]
-            avatar [ Option [ Vec<u8> ] ]
-            delete_avatar [ Option [ bool ] ]
-        )
-    )
+    .and(form::multipart(state.clone(), upload))
     .and(state)
-    .and_then(async move |user_id: Uuid, nickname: Option<String>, avatar: Option<Vec<u8>>, delete_avatar: Option<bool>, state: State| -> HandlerResult<&'static str> {
+    .and_then(async move |user_id: Uuid, form: PatchForm, state: State| -> HandlerResult<&'static str> {
         async {
+            let PatchForm { nickname, avatar, delete_avatar } = form;
             let connection = state.db_pool().get().await?;
     
             if let Some(nickname) = nickname {
-                connection.execute(
-                    "UPDATE users SET nickname = $1 WHERE id = $2",
-                    &[
-                        &nickname,
-                        &user_id,
-                    ],
-                ).await?;
+                users::set_nickname(&*connection, user_id, &nickname).await?;
             }
 
             let should_delete_avatar = if let Some(delete_avatar) = delete_avatar {
@@ -65,15 +63,13 @@ fn patch_filter(state: BoxedFilter<(State,)>) -> BoxedFilter<(impl Reply,)> {
             };
 
             if should_delete_avatar {
-                fs::delete(format!("{}/user/{}/avatar.jpg", *STORAGE_DIR, user_id)).await?;
+                let storage = state.storage();
+                storage.delete_image(imaging::variants(&state.config().image), |size| storage.user_avatar(user_id, size)).await?;
             } else {
                 if let Some(avatar) = avatar {
-                    fs::store(
-                        format!("{}/user/{}", *STORAGE_DIR, user_id),
-                        "avatar.jpg".to_string(),
-                        avatar,
-                    )
-                    .await?;
+                    let variants = imaging::process("avatar", avatar, state.storage().staging_dir(), &state.config().image).await?;
+                    let storage = state.storage();
+                    storage.put_image(variants, |size| storage.user_avatar(user_id, size)).await?;
                 }
             }
     
@@ -85,14 +81,19 @@ fn patch_filter(state: BoxedFilter<(State,)>) -> BoxedFilter<(impl Reply,)> {
     .boxed()
 }
 
-pub fn filter(state: BoxedFilter<(State,)>) -> BoxedFilter<(impl Reply,)> {
-    path::end().and(
-        patch_filter(state.clone())
+pub fn filter(state: BoxedFilter<(State,)>, upload: &UploadConfig) -> BoxedFilter<(impl Reply,)> {
+    endpoint::end().and(
+        patch_filter(state.clone(), upload)
     )
     .or(
         path("avatar").and(
             avatar::filter(state.clone())
         )
     )
+    .or(
+        path("phone").and(
+            phone::filter(state.clone())
+        )
+    )
     .boxed()
 }
\ No newline at end of file
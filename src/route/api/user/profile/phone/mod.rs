@@ -0,0 +1,79 @@
+use warp::{
+    Filter,
+    reply::Reply,
+    reject,
+    filters::BoxedFilter,
+    patch,
+    body,
+};
+use uuid::Uuid;
+use crate::{
+    route::utils::{
+        filter::{
+            cookie,
+            endpoint,
+        },
+        handler::HandlerResult,
+    },
+    state::State,
+    ratelimit::Limit,
+    sql::users,
+    error::Error,
+};
+use super::super::register::{
+    is_valid_phone,
+    phone_code_text,
+};
+
+#[derive(Serialize, Deserialize)]
+struct PatchArgs {
+    operation: Option<String>,
+    data: Option<String>,
+}
+
+/// Change the phone: `send_phone_code` sends a code to the new phone in
+/// `data`, and `verify_phone` sets it with the code in `data`.
+fn patch_filter(state: BoxedFilter<(State,)>) -> BoxedFilter<(impl Reply,)> {
+    patch()
+    .and(cookie::to_user_id("USSID", state.clone()))
+    .and(body::json())
+    .and(state)
+    .and_then(async move |user_id: Uuid, mut args: PatchArgs, state: State| -> HandlerResult<&'static str> {
+        async {
+            let operation = args.operation.take().ok_or_else(|| Error::missing_body("operation"))?;
+            let data = args.data.take().filter(|data| !data.is_empty()).ok_or_else(|| Error::missing_body("data"))?;
+            let conn = state.db_pool().get().await?;
+
+            match operation.as_str() {
+                "send_phone_code" => {
+                    if !is_valid_phone(&data) {
+                        return Err(Error::invalid_data("data"))
+                    }
+                    state.rate_limiter().check_client(Limit::CodeIp).await?;
+                    let config = &state.config().sms;
+                    let code = users::create_phone_code(&*conn, user_id, &data, config).await?;
+                    state.sms().send(&data, &phone_code_text(&code, config.code_minutes)).await?;
+                    Ok("Success.")
+                }
+                "verify_phone" => {
+                    if users::verify_phone(&*conn, user_id, &data).await? {
+                        Ok("Success.")
+                    } else {
+                        Err(Error::invalid_verification_code())
+                    }
+                }
+                _ => Err(Error::unsupported_operation())
+            }
+        }
+        .await
+        .map_err(|err: Error| reject::custom(err))
+    })
+    .boxed()
+}
+
+pub fn filter(state: BoxedFilter<(State,)>) -> BoxedFilter<(impl Reply,)> {
+    endpoint::end().and(
+        patch_filter(state.clone())
+    )
+    .boxed()
+}
@@ -11,7 +11,6 @@ use warp::{
     get,
     post,
     patch,
-    path,
     body,
     query,
 };
@@ -19,11 +18,19 @@ use uuid::Uuid;
 use regex::Regex;
 use crate::{
     route::utils::{
-        filter::cookie,
+        filter::{
+            cookie,
+            endpoint,
+        },
         handler::HandlerResult,
         response::set_cookie,
     },
     state::State,
+    ratelimit::Limit,
+    sql::users::{
+        self,
+        RegisterField,
+    },
     error::Error,
 };
 
@@ -37,6 +44,24 @@ lazy_static! {
     static ref RE_VALID_PASSWORD_NUMBER: Regex = Regex::new(r#"[0-9]"#).unwrap();
 }
 
+/// Letters and digits, with at least an uppercase, a lowercase and a digit.
+pub(super) fn is_valid_password(password: &str) -> bool {
+    RE_VALID_PASSWORD.is_match(password)
+        && RE_VALID_PASSWORD_UPPER.is_match(password)
+        && RE_VALID_PASSWORD_LOWER.is_match(password)
+        && RE_VALID_PASSWORD_NUMBER.is_match(password)
+}
+
+/// Mobile numbers of Taiwan.
+pub(super) fn is_valid_phone(phone: &str) -> bool {
+    RE_VALID_PHONE.is_match(phone)
+}
+
+/// The text message sending a code verifying a phone.
+pub(super) fn phone_code_text(code: &str, minutes: u32) -> String {
+    format!("Your Pigskit verification code is {}. It expires in {} minutes.", code, minutes)
+}
+
 #[derive(Deserialize)]
 struct GetArgs {
     operation: Option<String>,
@@ -56,24 +81,16 @@ fn get_filter(state: BoxedFilter<(State,)>) -> BoxedFilter<(impl Reply,)> {
         async {
             let conn = state.db_pool().get().await?;
             let operation = args.operation.take().ok_or_else(|| Error::missing_body("operation"))?;
-            let field;
-            match operation.as_str() {
-                "email" | "phone" | "username" => {
-                    field = operation.as_str();
-                }
+            let field = match operation.as_str() {
+                "email" => RegisterField::Email,
+                "phone" => RegisterField::Phone,
+                "username" => RegisterField::Username,
                 _ => return Err(Error::unsupported_operation())
-            }
+            };
 
-            if let Some(row) = conn.query_opt(
-                format!("SELECT {0} FROM user_register_session WHERE id = $1", field).as_str(),
-                &[&regssid],
-            ).await? {
-                return Ok(json(&GetRes {
-                    data: row.get(field),
-                }))
-            } else {
-                return Err(Error::session_expired("REGSSID"))
-            }
+            Ok(json(&GetRes {
+                data: users::get_register_field(&*conn, regssid, field).await?,
+            }))
         }
         .await
         .map_err(|err: Error| reject::custom(err))
@@ -87,23 +104,16 @@ fn post_filter(state: BoxedFilter<(State,)>) -> BoxedFilter<(impl Reply,)> {
     .and(state)
     .and_then(async move |regssid_cookie: Option<Uuid>, state: State| -> HandlerResult<Response> {
         async {
+            state.rate_limiter().check_client(Limit::RegisterIp).await?;
             let conn = state.db_pool().get().await?;
 
             // Delete the session if existed.
             if let Some(regssid) = regssid_cookie {
-                let _ = conn.execute(
-                    "DELETE FROM user_register_session WHERE id = $1",
-                    &[&regssid],
-                ).await;
+                let _ = users::delete_register_session(&*conn, regssid).await;
             }
 
-            let (regssid,) = query_one!(
-                conn,
-                "INSERT INTO user_register_session (id) VALUES (uuid_generate_v4()) RETURNING id",
-                &[],
-                (id: Uuid),
-            )?;
-            Ok(set_cookie("REGSSID", &regssid.to_string(), 1))
+            let regssid = users::create_register_session(&*conn).await?;
+            Ok(set_cookie("REGSSID", &regssid.to_string(), state.config().cookie.register_session_days, state.config().server.is_tls()))
         }
         .await
         .map_err(|err: Error| reject::custom(err))
@@ -128,75 +138,83 @@ fn patch_filter(state: BoxedFilter<(State,)>) -> BoxedFilter<(impl Reply,)> {
             let operation = args.operation.take().ok_or_else(|| Error::missing_body("operation"))?;
 
             match operation.as_str() {
+                "send_email_code" => {
+                    state.rate_limiter().check_client(Limit::CodeIp).await?;
+                    let minutes = state.config().mail.verification_code_minutes;
+                    let (email, code) = users::create_email_code(&*conn, regssid, minutes).await?;
+                    let body = format!(
+                        "Your Pigskit verification code is {}.\n\nIt expires in {} minutes. If you did not sign up for Pigskit, ignore this mail.\n",
+                        code,
+                        minutes,
+                    );
+                    state.mail().send(&email, "Your Pigskit verification code", body).await?;
+                    Ok("Success.")
+                }
+                "verify_email" => {
+                    let code = args.data.take().filter(|code| !code.is_empty()).ok_or_else(|| Error::missing_body("data"))?;
+                    if users::verify_email(&*conn, regssid, &code).await? {
+                        Ok("Success.")
+                    } else {
+                        Err(Error::invalid_verification_code())
+                    }
+                }
+                "send_phone_code" => {
+                    state.rate_limiter().check_client(Limit::CodeIp).await?;
+                    let config = &state.config().sms;
+                    let (phone, code) = users::create_register_phone_code(&*conn, regssid, config).await?;
+                    state.sms().send(&phone, &phone_code_text(&code, config.code_minutes)).await?;
+                    Ok("Success.")
+                }
+                "verify_phone" => {
+                    let code = args.data.take().filter(|code| !code.is_empty()).ok_or_else(|| Error::missing_body("data"))?;
+                    if users::verify_register_phone(&*conn, regssid, &code).await? {
+                        Ok("Success.")
+                    } else {
+                        Err(Error::invalid_verification_code())
+                    }
+                }
                 "submit" => {
-                    let (ok,) = query_one!(
-                        conn,
-                        "SELECT register_user($1) AS ok",
-                        &[&regssid],
-                        (ok: bool),
-                    )?;
-                    if ok {
-                        return Ok("Success.")
+                    if users::register(&*conn, regssid).await? {
+                        Ok("Success.")
                     } else {
-                        return Err(Error::operation_failed())
+                        Err(Error::operation_failed())
                     }
                 }
                 _ => {
                     let data = args.data.take().ok_or_else(|| Error::missing_body("data"))?;
-                    let field;
-                    let is_unique;
-                    match operation.as_str() {
+                    let field = match operation.as_str() {
                         "email" => {
                             if !RE_VALID_EMAIL.is_match(&data) {
                                 return Err(Error::invalid_data("data"))
                             }
-                            field = "email";
-                            is_unique = true;
+                            RegisterField::Email
                         }
                         "phone" => {
-                            if !RE_VALID_PHONE.is_match(&data) {
+                            if !is_valid_phone(&data) {
                                 return Err(Error::invalid_data("data"))
                             }
-                            field = "phone";
-                            is_unique = true;
+                            RegisterField::Phone
                         }
                         "username" => {
                             if !RE_VALID_USERNAME.is_match(&data) {
                                 return Err(Error::invalid_data("data"))
                             }
-                            field = "username";
-                            is_unique = true;
+                            RegisterField::Username
                         }
                         "password" => {
-                            if !RE_VALID_PASSWORD.is_match(&data)
-                                || !RE_VALID_PASSWORD_UPPER.is_match(&data)
-                                || !RE_VALID_PASSWORD_LOWER.is_match(&data)
-                                || !RE_VALID_PASSWORD_NUMBER.is_match(&data)
-                            {
+                            if !is_valid_password(&data) {
                                 return Err(Error::invalid_data("data"))
                             }
-                            field = "password";
-                            is_unique = false;
+                            RegisterField::Password
                         }
                         _ => return Err(Error::unsupported_operation())
-                    }
+                    };
 
-                    if is_unique {
-                        if let Some(_) = conn.query_opt(
-                            format!("SELECT {0} FROM users WHERE {0} = $1", field).as_str(),
-                            &[&data],
-                        ).await? {
-                            return Err(Error::unique_data_conflict(field))
-                        }
-                    }
-                    if let 1 = conn.execute(
-                        format!("UPDATE user_register_session SET {0} = $1 WHERE id = $2", field).as_str(),
-                        &[&data, &regssid],
-                    ).await? {
-                        return Ok("Success.")
-                    } else {
-                        return Err(Error::session_expired("REGSSID"))
+                    if field.is_unique() && users::is_taken(&*conn, field, &data).await? {
+                        return Err(Error::unique_data_conflict(field.column()))
                     }
+                    users::set_register_field(&*conn, regssid, field, &data).await?;
+                    Ok("Success.")
                 }
             }
         }
@@ -207,7 +225,7 @@ fn patch_filter(state: BoxedFilter<(State,)>) -> BoxedFilter<(impl Reply,)> {
 }
 
 pub fn filter(state: BoxedFilter<(State,)>) -> BoxedFilter<(impl Reply,)> {
-    path::end().and(
+    endpoint::end().and(
         get_filter(state.clone())
         .or(
             post_filter(state.clone())
@@ -13,18 +13,29 @@ use warp::{
     delete,
     path,
     body,
+    header,
 };
 use uuid::Uuid;
 use crate::{
     route::utils::{
-        filter::cookie,
+        filter::{
+            cookie,
+            endpoint,
+        },
         response,
         handler::HandlerResult,
     },
     state::State,
+    ratelimit::{
+        Limit,
+        RateLimiter,
+    },
     sql::{
         TextNZ,
-        UuidNN,
+        users::{
+            self,
+            Signin,
+        },
     },
     error::Error,
 };
@@ -33,34 +44,100 @@ use crate::{
 struct CreateArgs {
     username: TextNZ,
     password: TextNZ,
+    /// Keep the session past the browser session.
+    #[serde(default)]
+    remember_me: bool,
+}
+
+/// The second step of signing in with two-factor authentication.
+#[derive(Serialize, Deserialize)]
+struct ChallengeArgs {
+    challenge: Uuid,
+    /// A code of the authenticator app, or a recovery code.
+    code: TextNZ,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(untagged)]
+enum CreateBody {
+    Challenge(ChallengeArgs),
+    Password(CreateArgs),
 }
 
+#[derive(Serialize)]
+struct ChallengeRes {
+    challenge: Uuid,
+}
+
+/// Reset the failed sign-ins of the username. The session exists by then,
+/// so a failing limiter store is only logged.
+async fn reset_failures(limiter: &RateLimiter, username: &str) {
+    if let Err(err) = limiter.record_success(username).await {
+        warn!("Failed to reset the sign-in failures of {:?}: {:?}", username, err);
+    }
+}
+
+/// Sign in with the credentials. Users with two-factor authentication get a
+/// challenge instead of the session, signing in with it and a code.
 fn create_filter(state: BoxedFilter<(State,)>) -> BoxedFilter<(impl Reply,)> {
     post()
     .and(cookie::to_uuid_optional("USSID"))
+    .and(header::optional::<String>("user-agent"))
     .and(body::json())
     .and(state)
-    .and_then(async move |ussid_cookie: Option<Uuid>, args: CreateArgs, state: State| -> HandlerResult<Response> {
+    .and_then(async move |ussid_cookie: Option<Uuid>, user_agent: Option<String>, body: CreateBody, state: State| -> HandlerResult<Response> {
         async {
+            let limiter = state.rate_limiter();
+            limiter.check_client(Limit::SigninIp).await?;
+            if let CreateBody::Password(args) = &body {
+                limiter.check_lockout(&args.username.0).await?;
+                limiter.check(Limit::SigninUsername, &args.username.0).await?;
+            }
+
             let conn = state.db_pool().get().await?;
 
             // Delete the session if existed, or do nothing.
             if let Some(ussid) = ussid_cookie {
-                let _ = conn.execute(
-                    "SELECT signout_user($1)",
-                    &[&UuidNN(ussid)],
-                ).await;
+                let _ = users::signout(&*conn, ussid).await;
             }
 
-            let row = conn.query_one(
-                "SELECT signin_user($1, $2) AS id",
-                &[&args.username, &args.password],
-            ).await?;
-            
-            if let Ok(session_id) = row.try_get::<&str, Uuid>("id") {
-                Ok(response::set_cookie("USSID", &session_id.to_string(), 30))
+            let config = state.config();
+            let (session_id, remember) = match body {
+                CreateBody::Password(args) => {
+                    let username = &args.username.0;
+                    let ip = limiter.client_ip();
+                    match users::signin(&*conn, username, &args.password.0, args.remember_me, config, ip, user_agent.as_deref()).await? {
+                        Some(Signin::Session(session_id)) => {
+                            reset_failures(limiter, username).await;
+                            (session_id, args.remember_me)
+                        }
+                        // The failures in a row go on until the second factor.
+                        Some(Signin::Challenge(challenge)) => {
+                            return Ok(json(&ChallengeRes { challenge }).into_response())
+                        }
+                        None => {
+                            limiter.record_failure(username).await?;
+                            return Err(Error::unauthorized())
+                        }
+                    }
+                }
+                CreateBody::Challenge(args) => {
+                    match users::signin_challenge(&*conn, args.challenge, &args.code.0).await? {
+                        (username, Some(session)) => {
+                            reset_failures(limiter, &username).await;
+                            session
+                        }
+                        (username, None) => {
+                            limiter.record_failure(&username).await?;
+                            return Err(Error::invalid_verification_code())
+                        }
+                    }
+                }
+            };
+            if remember {
+                Ok(response::set_cookie("USSID", &session_id.to_string(), config.cookie.user_session_days, config.server.is_tls()))
             } else {
-                return Err(Error::unauthorized())
+                Ok(response::set_cookie_until("USSID", &session_id.to_string(), None, config.server.is_tls()))
             }
         }
         .await
@@ -69,13 +146,6 @@ fn create_filter(state: BoxedFilter<(State,)>) -> BoxedFilter<(impl Reply,)> {
     .boxed()
 }
 
-#[derive(Serialize)]
-struct GetRes {
-    username: Option<String>,
-    nickname: Option<String>,
-    email: Option<String>,
-    phone: Option<String>,
-}
 fn get_filter(state: BoxedFilter<(State,)>) -> BoxedFilter<(impl Reply,)> {
     get()
     .and(cookie::to_user_id("USSID", state.clone()))
@@ -83,24 +153,7 @@ fn get_filter(state: BoxedFilter<(State,)>) -> BoxedFilter<(impl Reply,)> {
     .and_then(async move |user_id: Uuid, state: State| -> HandlerResult<Json> {
         async {
             let conn = state.db_pool().get().await?;
-            let row = conn.query_one(
-                "SELECT
-                    username,
-                    nickname,
-                    email,
-                    phone
-                FROM
-                    users
-                WHERE
-                    id = $1",
-                &[&user_id],
-            ).await?;
-            Ok(json(&GetRes {
-                username: row.get("username"),
-                nickname: row.get("nickname"),
-                email: row.get("email"),
-                phone: row.get("phone"),
-            }))
+            Ok(json(&users::get(&*conn, user_id).await?))
         }
         .await
         .map_err(|err: Error| reject::custom(err))
@@ -116,12 +169,64 @@ fn delete_filter(state: BoxedFilter<(State,)>) -> BoxedFilter<(impl Reply,)> {
         async {
             let conn = state.db_pool().get().await?;
             if let Some(ussid) = ussid_cookie {
-                let _ = conn.execute(
-                    "SELECT signout_user($1)",
-                    &[&UuidNN(ussid)],
-                ).await;
+                let _ = users::signout(&*conn, ussid).await;
+            }
+            Ok(response::set_cookie("USSID", "", 0, state.config().server.is_tls()))
+        }
+        .await
+        .map_err(|err: Error| reject::custom(err))
+    })
+    .boxed()
+}
+
+/// The sessions of the user, for them to tell their devices apart.
+fn get_all_filter(state: BoxedFilter<(State,)>) -> BoxedFilter<(impl Reply,)> {
+    get()
+    .and(cookie::to_uuid("USSID"))
+    .and(state)
+    .and_then(async move |ussid: Uuid, state: State| -> HandlerResult<Json> {
+        async {
+            let conn = state.db_pool().get().await?;
+            Ok(json(&users::sessions(&*conn, ussid).await?))
+        }
+        .await
+        .map_err(|err: Error| reject::custom(err))
+    })
+    .boxed()
+}
+
+/// Sign out everywhere, this session included.
+fn delete_all_filter(state: BoxedFilter<(State,)>) -> BoxedFilter<(impl Reply,)> {
+    delete()
+    .and(cookie::to_uuid("USSID"))
+    .and(state)
+    .and_then(async move |ussid: Uuid, state: State| -> HandlerResult<Response> {
+        async {
+            let conn = state.db_pool().get().await?;
+            users::signout_all(&*conn, ussid).await?;
+            Ok(response::set_cookie("USSID", "", 0, state.config().server.is_tls()))
+        }
+        .await
+        .map_err(|err: Error| reject::custom(err))
+    })
+    .boxed()
+}
+
+/// Revoke one session by its key, the cookie is cleared if it is this one.
+fn delete_key_filter(state: BoxedFilter<(State,)>) -> BoxedFilter<(impl Reply,)> {
+    delete()
+    .and(path::param::<Uuid>())
+    .and(endpoint::end())
+    .and(cookie::to_uuid("USSID"))
+    .and(state)
+    .and_then(async move |key: Uuid, ussid: Uuid, state: State| -> HandlerResult<Response> {
+        async {
+            let conn = state.db_pool().get().await?;
+            if users::revoke_session(&*conn, ussid, key).await? {
+                Ok(response::set_cookie("USSID", "", 0, state.config().server.is_tls()))
+            } else {
+                Ok("Success.".into_response())
             }
-            Ok(response::set_cookie("USSID", "", 0))
         }
         .await
         .map_err(|err: Error| reject::custom(err))
@@ -130,10 +235,15 @@ fn delete_filter(state: BoxedFilter<(State,)>) -> BoxedFilter<(impl Reply,)> {
 }
 
 pub fn filter(state: BoxedFilter<(State,)>) -> BoxedFilter<(impl Reply,)> {
-    path::end().and(
+    endpoint::end().and(
         create_filter(state.clone())
         .or(get_filter(state.clone()))
         .or(delete_filter(state.clone()))
     )
+    .or(path("all").and(endpoint::end()).and(
+        get_all_filter(state.clone())
+        .or(delete_all_filter(state.clone()))
+    ))
+    .or(delete_key_filter(state.clone()))
     .boxed()
 }
\ No newline at end of file
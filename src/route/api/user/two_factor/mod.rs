@@ -0,0 +1,198 @@
+use warp::{
+    Filter,
+    reply::{
+        Reply,
+        json,
+        Json,
+    },
+    reject,
+    filters::BoxedFilter,
+    get,
+    post,
+    patch,
+    delete,
+    body,
+};
+use uuid::Uuid;
+use percent_encoding::{
+    AsciiSet,
+    NON_ALPHANUMERIC,
+    utf8_percent_encode,
+};
+use crate::{
+    route::utils::{
+        filter::{
+            cookie,
+            endpoint,
+        },
+        handler::HandlerResult,
+    },
+    state::State,
+    sql::{
+        TextNZ,
+        totp,
+    },
+    error::Error,
+};
+
+#[derive(Serialize)]
+struct CreateRes {
+    /// The secret in base32, for entering it by hand.
+    secret: String,
+    /// The `otpauth://` URI, for a QR code.
+    uri: String,
+}
+
+#[derive(Serialize)]
+struct RecoveryCodesRes {
+    recovery_codes: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct PatchArgs {
+    operation: Option<String>,
+    data: Option<String>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct DeleteArgs {
+    /// A code of the authenticator app, or a recovery code.
+    code: TextNZ,
+}
+
+/// Everything but the unreserved characters of RFC 3986.
+const URI_COMPONENT: &AsciiSet = &NON_ALPHANUMERIC.remove(b'-').remove(b'.').remove(b'_').remove(b'~');
+
+fn otpauth_uri(issuer: &str, username: &str, secret: &str) -> String {
+    let issuer = utf8_percent_encode(issuer, URI_COMPONENT).to_string();
+    format!(
+        "otpauth://totp/{}:{}?secret={}&issuer={}&algorithm=SHA1&digits=6&period=30",
+        issuer,
+        utf8_percent_encode(username, URI_COMPONENT),
+        utf8_percent_encode(secret, URI_COMPONENT),
+        issuer,
+    )
+}
+
+/// Guessing the codes of the user locks them out as guessing passwords does.
+async fn record_code(state: &State, user_id: Uuid, ok: bool) -> Result<(), Error> {
+    let key = user_id.to_string();
+    if ok {
+        state.rate_limiter().record_success(&key).await
+    } else {
+        state.rate_limiter().record_failure(&key).await?;
+        Err(Error::invalid_verification_code())
+    }
+}
+
+fn get_filter(state: BoxedFilter<(State,)>) -> BoxedFilter<(impl Reply,)> {
+    get()
+    .and(cookie::to_user_id("USSID", state.clone()))
+    .and(state)
+    .and_then(async move |user_id: Uuid, state: State| -> HandlerResult<Json> {
+        async {
+            let conn = state.db_pool().get().await?;
+            Ok(json(&totp::status(&*conn, user_id).await?))
+        }
+        .await
+        .map_err(|err: Error| reject::custom(err))
+    })
+    .boxed()
+}
+
+/// Set up a new secret, which `enable` confirms.
+fn post_filter(state: BoxedFilter<(State,)>) -> BoxedFilter<(impl Reply,)> {
+    post()
+    .and(cookie::to_user_id("USSID", state.clone()))
+    .and(state)
+    .and_then(async move |user_id: Uuid, state: State| -> HandlerResult<Json> {
+        async {
+            let conn = state.db_pool().get().await?;
+            let (secret, username) = totp::create(&*conn, user_id).await?;
+            let secret = base32::encode(base32::Alphabet::RFC4648 { padding: false }, &secret);
+            let uri = otpauth_uri(&state.config().totp.issuer, &username, &secret);
+            Ok(json(&CreateRes {
+                secret,
+                uri,
+            }))
+        }
+        .await
+        .map_err(|err: Error| reject::custom(err))
+    })
+    .boxed()
+}
+
+/// `enable` turns two-factor authentication on with a code of the secret
+/// set up in `data`, `renew_recovery_codes` replaces the recovery codes for
+/// a code in `data`. Both return the new recovery codes.
+fn patch_filter(state: BoxedFilter<(State,)>) -> BoxedFilter<(impl Reply,)> {
+    patch()
+    .and(cookie::to_user_id("USSID", state.clone()))
+    .and(body::json())
+    .and(state)
+    .and_then(async move |user_id: Uuid, mut args: PatchArgs, state: State| -> HandlerResult<Json> {
+        async {
+            let operation = args.operation.take().ok_or_else(|| Error::missing_body("operation"))?;
+            let data = args.data.take().filter(|data| !data.is_empty()).ok_or_else(|| Error::missing_body("data"))?;
+            state.rate_limiter().check_lockout(&user_id.to_string()).await?;
+            let conn = state.db_pool().get().await?;
+            let count = state.config().totp.recovery_codes;
+
+            let codes = match operation.as_str() {
+                "enable" => totp::enable(&*conn, user_id, &data, count).await?,
+                "renew_recovery_codes" => totp::renew_recovery_codes(&*conn, user_id, &data, count).await?,
+                _ => return Err(Error::unsupported_operation())
+            };
+            record_code(&state, user_id, codes.is_some()).await?;
+            Ok(json(&RecoveryCodesRes {
+                recovery_codes: codes.unwrap_or_default(),
+            }))
+        }
+        .await
+        .map_err(|err: Error| reject::custom(err))
+    })
+    .boxed()
+}
+
+/// Turn two-factor authentication off with a code.
+fn delete_filter(state: BoxedFilter<(State,)>) -> BoxedFilter<(impl Reply,)> {
+    delete()
+    .and(cookie::to_user_id("USSID", state.clone()))
+    .and(body::json())
+    .and(state)
+    .and_then(async move |user_id: Uuid, args: DeleteArgs, state: State| -> HandlerResult<&'static str> {
+        async {
+            state.rate_limiter().check_lockout(&user_id.to_string()).await?;
+            let conn = state.db_pool().get().await?;
+            let ok = totp::disable(&*conn, user_id, &args.code.0).await?;
+            record_code(&state, user_id, ok).await?;
+            Ok("Success.")
+        }
+        .await
+        .map_err(|err: Error| reject::custom(err))
+    })
+    .boxed()
+}
+
+pub fn filter(state: BoxedFilter<(State,)>) -> BoxedFilter<(impl Reply,)> {
+    endpoint::end().and(
+        get_filter(state.clone())
+        .or(post_filter(state.clone()))
+        .or(patch_filter(state.clone()))
+        .or(delete_filter(state.clone()))
+    )
+    .boxed()
+}
+
+#[cfg(test)]
+mod test {
+    use super::otpauth_uri;
+
+    #[test]
+    fn test_otpauth_uri() {
+        assert_eq!(
+            otpauth_uri("Pigskit Shop", "a:b?c&d#e%f g", "ABC234"),
+            "otpauth://totp/Pigskit%20Shop:a%3Ab%3Fc%26d%23e%25f%20g?secret=ABC234&issuer=Pigskit%20Shop&algorithm=SHA1&digits=6&period=30",
+        );
+    }
+}
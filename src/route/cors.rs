@@ -0,0 +1,250 @@
+//! Cross-origin requests: preflight requests are answered here, and the
+//! responses to allowed origins carry the CORS headers.
+
+use std::sync::Arc;
+use regex::Regex;
+use warp::{
+    Filter,
+    Reply,
+    filters::BoxedFilter,
+    header,
+    http::{
+        HeaderValue,
+        Method,
+        StatusCode,
+        header::{
+            ACCESS_CONTROL_ALLOW_CREDENTIALS,
+            ACCESS_CONTROL_ALLOW_HEADERS,
+            ACCESS_CONTROL_ALLOW_METHODS,
+            ACCESS_CONTROL_ALLOW_ORIGIN,
+            ACCESS_CONTROL_EXPOSE_HEADERS,
+            ACCESS_CONTROL_MAX_AGE,
+            VARY,
+        },
+    },
+    options,
+    reply::{
+        Response,
+        with_status,
+    },
+};
+use crate::{
+    config::CorsConfig,
+    error::Error,
+};
+use super::{
+    csrf,
+    metrics,
+};
+
+/// The policy of a validated `CorsConfig`.
+pub struct Cors {
+    origins: Vec<String>,
+    origin_patterns: Vec<Regex>,
+    methods: Vec<Method>,
+    /// Lowercase.
+    headers: Vec<String>,
+    max_age: Option<u64>,
+    allow_credentials: bool,
+}
+
+impl Cors {
+    pub fn new(config: &CorsConfig) -> Self {
+        Cors {
+            origins: config.allowed_origins.clone(),
+            origin_patterns: config.origin_patterns().expect("validated cors.allowed_origin_patterns"),
+            methods: config.allowed_methods.iter()
+                .map(|method| Method::from_bytes(method.as_bytes()).expect("validated cors.allowed_methods"))
+                .collect(),
+            headers: config.allowed_headers.iter().map(|header| header.to_lowercase()).collect(),
+            max_age: config.max_age,
+            allow_credentials: config.allow_credentials,
+        }
+    }
+
+    pub fn allows_origin(&self, origin: &str) -> bool {
+        self.origins.iter().any(|allowed| allowed == origin)
+            || self.origin_patterns.iter().any(|pattern| pattern.is_match(origin))
+    }
+
+    /// Check a preflight request, `headers` is the comma separated list of
+    /// `Access-Control-Request-Headers`.
+    fn preflight(&self, origin: &str, method: &str, headers: Option<&str>) -> Result<Response, Error> {
+        if !self.allows_origin(origin) {
+            return Err(Error::cors_forbidden("origin not allowed"))
+        }
+        if !self.methods.iter().any(|allowed| allowed.as_str() == method) {
+            return Err(Error::cors_forbidden("method not allowed"))
+        }
+        let headers = headers.unwrap_or("")
+            .split(',')
+            .map(|header| header.trim().to_lowercase())
+            .filter(|header| !header.is_empty());
+        for header in headers {
+            if !self.headers.contains(&header) {
+                return Err(Error::cors_forbidden("header not allowed"))
+            }
+        }
+
+        let mut response = with_status(warp::reply(), StatusCode::NO_CONTENT).into_response();
+        self.allow(origin, &mut response);
+        let methods = self.methods.iter().map(|method| method.as_str()).collect::<Vec<_>>().join(", ");
+        let headers = response.headers_mut();
+        if let Ok(methods) = HeaderValue::from_str(&methods) {
+            headers.insert(ACCESS_CONTROL_ALLOW_METHODS, methods);
+        }
+        if !self.headers.is_empty() {
+            if let Ok(allowed) = HeaderValue::from_str(&self.headers.join(", ")) {
+                headers.insert(ACCESS_CONTROL_ALLOW_HEADERS, allowed);
+            }
+        }
+        if let Some(max_age) = self.max_age {
+            headers.insert(ACCESS_CONTROL_MAX_AGE, HeaderValue::from(max_age));
+        }
+        Ok(response)
+    }
+
+    /// Let the origin, which must be allowed, read the response.
+    fn allow(&self, origin: &str, response: &mut Response) {
+        let headers = response.headers_mut();
+        if let Ok(origin) = HeaderValue::from_str(origin) {
+            headers.insert(ACCESS_CONTROL_ALLOW_ORIGIN, origin);
+        }
+        if self.allow_credentials {
+            headers.insert(ACCESS_CONTROL_ALLOW_CREDENTIALS, HeaderValue::from_static("true"));
+        }
+        // Scripts of other origins need the token to make requests.
+        headers.insert(ACCESS_CONTROL_EXPOSE_HEADERS, HeaderValue::from_static(csrf::TOKEN_HEADER));
+    }
+}
+
+/// Answer preflight requests and add the CORS headers to the responses of
+/// `routes`. Requests from origins which are not allowed are served without
+/// them, leaving the browser to refuse the response. Every response varies
+/// by the origin, for caches not to serve one origin the response of another.
+pub fn filter<T: Reply + 'static>(cors: Arc<Cors>, routes: BoxedFilter<(T,)>) -> BoxedFilter<(Response,)> {
+    let preflight = {
+        let cors = cors.clone();
+        options()
+        .and(header::<String>("origin"))
+        .and(header::<String>("access-control-request-method"))
+        .and(header::optional::<String>("access-control-request-headers"))
+        .map(move |origin: String, method: String, headers: Option<String>| {
+            match cors.preflight(&origin, &method, headers.as_deref()) {
+                Ok(response) => response,
+                Err(error) => {
+                    info!("Rejected a preflight request from {}: {}", origin, error.message());
+                    metrics::record_error(error.error_type(), error.http_status());
+                    error.into_response()
+                }
+            }
+        })
+    };
+
+    preflight
+    .or(
+        header::optional::<String>("origin")
+        .and(routes)
+        .map(move |origin: Option<String>, reply: T| {
+            let mut response = reply.into_response();
+            if let Some(origin) = origin.filter(|origin| cors.allows_origin(origin)) {
+                cors.allow(&origin, &mut response);
+            }
+            response
+        })
+    )
+    .unify()
+    .map(|mut response: Response| {
+        response.headers_mut().append(VARY, HeaderValue::from_static("Origin"));
+        response
+    })
+    .boxed()
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+    use warp::{
+        Filter,
+        http::StatusCode,
+        test::request,
+    };
+    use crate::config::Config;
+    use super::{
+        Cors,
+        filter,
+    };
+
+    #[tokio::test]
+    async fn test_cors() {
+        let mut config = Config::defaults(false).cors;
+        config.allowed_origins = vec!["https://pigskit.com".to_string()];
+        config.allowed_origin_patterns = vec![r"https://[a-z]+\.pigskit\.com".to_string()];
+        let routes = filter(Arc::new(Cors::new(&config)), warp::path("shop").map(|| "shop").boxed());
+
+        let response = request()
+            .method("OPTIONS")
+            .path("/shop")
+            .header("origin", "https://admin.pigskit.com")
+            .header("access-control-request-method", "PATCH")
+            .header("access-control-request-headers", "content-type,X-CSRF-Token")
+            .reply(&routes)
+            .await;
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+        assert_eq!(response.headers()["access-control-allow-origin"], "https://admin.pigskit.com");
+        assert_eq!(response.headers()["access-control-allow-methods"], "GET, POST, PUT, PATCH, DELETE");
+        assert_eq!(response.headers()["access-control-allow-headers"], "content-type, x-csrf-token");
+        assert_eq!(response.headers()["access-control-allow-credentials"], "true");
+        assert_eq!(response.headers()["access-control-max-age"], "600");
+
+        for (origin, method, headers) in [
+            // The pattern matches whole origins only.
+            ("https://admin.pigskit.com.evil.com", "GET", ""),
+            ("http://pigskit.com", "GET", ""),
+            ("https://pigskit.com", "TRACE", ""),
+            ("https://pigskit.com", "POST", "content-type, x-evil"),
+        ] {
+            let response = request()
+                .method("OPTIONS")
+                .path("/shop")
+                .header("origin", origin)
+                .header("access-control-request-method", method)
+                .header("access-control-request-headers", headers)
+                .reply(&routes)
+                .await;
+            assert_eq!(response.status(), StatusCode::FORBIDDEN, "{} {} {}", origin, method, headers);
+            assert!(response.headers().get("access-control-allow-origin").is_none());
+            assert_eq!(response.headers()["vary"], "Origin");
+        }
+
+        // Other requests reach the routes.
+        let response = request()
+            .path("/shop")
+            .header("origin", "https://pigskit.com")
+            .reply(&routes)
+            .await;
+        assert_eq!(response.body(), "shop");
+        assert_eq!(response.headers()["access-control-allow-origin"], "https://pigskit.com");
+        assert_eq!(response.headers()["vary"], "Origin");
+        let response = request()
+            .path("/shop")
+            .header("origin", "https://evil.com")
+            .reply(&routes)
+            .await;
+        assert_eq!(response.body(), "shop");
+        assert!(response.headers().get("access-control-allow-origin").is_none());
+        assert_eq!(response.headers()["vary"], "Origin");
+        let response = request()
+            .path("/shop")
+            .reply(&routes)
+            .await;
+        assert_eq!(response.headers()["vary"], "Origin");
+        let response = request()
+            .method("OPTIONS")
+            .path("/shop")
+            .header("origin", "https://pigskit.com")
+            .reply(&routes)
+            .await;
+        assert_ne!(response.status(), StatusCode::NO_CONTENT);
+    }
+}
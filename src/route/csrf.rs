@@ -0,0 +1,254 @@
+//! Protection of the cookie sessions against cross-site request forgery.
+//!
+//! A token is issued in the `CSRF` cookie along with the session cookies,
+//! readable by scripts and also sent in the `X-CSRF-Token` response header.
+//! Requests changing data with a session cookie must echo it in the
+//! `X-CSRF-Token` header, which other sites cannot do. Their `Origin`, or
+//! `Referer` if absent, must also be this server or an allowed CORS origin.
+
+use std::sync::Arc;
+use uuid::Uuid;
+use warp::{
+    Filter,
+    Reply,
+    filters::BoxedFilter,
+    header,
+    http::{
+        HeaderMap,
+        HeaderValue,
+        Method,
+        Uri,
+        header::{
+            COOKIE,
+            HOST,
+            ORIGIN,
+            REFERER,
+            SET_COOKIE,
+        },
+    },
+    reject,
+    reply::Response,
+};
+use crate::{
+    config::Config,
+    error::Error,
+};
+use super::{
+    cors::Cors,
+    utils::response,
+};
+
+pub const TOKEN_COOKIE: &str = "CSRF";
+pub const TOKEN_HEADER: &str = "X-CSRF-Token";
+
+/// Cookies authenticating requests, which a forged request would carry.
+const SESSION_COOKIES: [&str; 3] = ["USSID", "GSSID", "REGSSID"];
+
+pub struct Csrf {
+    cors: Arc<Cors>,
+    /// The token outlives every session cookie.
+    cookie_days: i64,
+    secure: bool,
+}
+
+impl Csrf {
+    pub fn new(cors: Arc<Cors>, config: &Config) -> Self {
+        let cookie = &config.cookie;
+        Csrf {
+            cors,
+            cookie_days: cookie.user_session_max_days.max(cookie.guest_session_days).max(cookie.register_session_days),
+            secure: config.server.is_tls(),
+        }
+    }
+
+    fn check(&self, method: &Method, headers: &HeaderMap) -> Result<(), Error> {
+        if method == Method::GET || method == Method::HEAD || method == Method::OPTIONS {
+            return Ok(())
+        }
+
+        let source = match headers.get(ORIGIN) {
+            Some(origin) => Some(origin.to_str().ok().map(|origin| origin.to_owned())),
+            None => headers.get(REFERER).map(referer_origin),
+        };
+        if let Some(source) = source {
+            let host = headers.get(HOST).and_then(|host| host.to_str().ok());
+            let allowed = source.is_some_and(|source| {
+                same_origin(&source, host) || self.cors.allows_origin(&source)
+            });
+            if !allowed {
+                return Err(Error::csrf_forbidden("origin not allowed"))
+            }
+        }
+
+        let cookies = cookies(headers);
+        if cookies.iter().any(|(name, _)| SESSION_COOKIES.contains(&name.as_str())) {
+            let token = cookies.iter()
+                .find(|(name, _)| name == TOKEN_COOKIE)
+                .map(|(_, value)| value.as_str())
+                .filter(|token| !token.is_empty());
+            let echoed = headers.get(TOKEN_HEADER).and_then(|token| token.to_str().ok());
+            if token.is_none() || token != echoed {
+                return Err(Error::csrf_forbidden("missing or invalid token"))
+            }
+        }
+        Ok(())
+    }
+
+    /// Issue a new token with a new session, or to a session without one. A
+    /// session cookie issued again with its value, renewed, keeps its token.
+    fn issue(&self, request: &HeaderMap, response: &mut Response) {
+        let cookies = cookies(request);
+        let sets_session = response.headers()
+            .get_all(SET_COOKIE)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .any(|value| SESSION_COOKIES.iter().any(|name| {
+                let value = match value.strip_prefix(&format!("{}=", name)) {
+                    Some(value) => value.split(';').next().unwrap_or(""),
+                    None => return false,
+                };
+                !value.is_empty() && !cookies.iter().any(|(cookie, current)| cookie == name && current == value)
+            }));
+        let lacks_token = cookies.iter().any(|(name, _)| SESSION_COOKIES.contains(&name.as_str()))
+            && !cookies.iter().any(|(name, _)| name == TOKEN_COOKIE);
+        if !sets_session && !lacks_token {
+            return
+        }
+
+        let token = Uuid::new_v4().to_simple().to_string();
+        let cookie = response::cookie(TOKEN_COOKIE, &token, self.cookie_days, self.secure, false);
+        let headers = response.headers_mut();
+        if let (Ok(cookie), Ok(token)) = (HeaderValue::from_str(&cookie), HeaderValue::from_str(&token)) {
+            headers.append(SET_COOKIE, cookie);
+            headers.insert(TOKEN_HEADER, token);
+        }
+    }
+}
+
+/// The name and value of every cookie of the request.
+fn cookies(headers: &HeaderMap) -> Vec<(String, String)> {
+    headers.get_all(COOKIE)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(';'))
+        .filter_map(|cookie| {
+            let mut pair = cookie.splitn(2, '=');
+            Some((pair.next()?.trim().to_owned(), pair.next()?.trim().to_owned()))
+        })
+        .collect()
+}
+
+/// `scheme://authority` of the referring page.
+fn referer_origin(referer: &HeaderValue) -> Option<String> {
+    let uri = referer.to_str().ok()?.parse::<Uri>().ok()?;
+    Some(format!("{}://{}", uri.scheme_str()?, uri.authority()?))
+}
+
+/// Whether the origin is this server as the client addressed it.
+fn same_origin(origin: &str, host: Option<&str>) -> bool {
+    let authority = origin.parse::<Uri>()
+        .ok()
+        .and_then(|uri| uri.authority().map(|authority| authority.as_str().to_owned()));
+    match (authority, host) {
+        (Some(authority), Some(host)) => authority.eq_ignore_ascii_case(host),
+        _ => false,
+    }
+}
+
+/// Reject requests failing the checks with `CsrfForbidden`.
+pub fn check(csrf: BoxedFilter<(Arc<Csrf>,)>) -> BoxedFilter<()> {
+    warp::method()
+    .and(header::headers_cloned())
+    .and(csrf)
+    .and_then(async move |method: Method, headers: HeaderMap, csrf: Arc<Csrf>| {
+        csrf.check(&method, &headers).map_err(reject::custom)
+    })
+    .untuple_one()
+    .boxed()
+}
+
+/// Issue tokens along the session cookies set by `routes`.
+pub fn issue<T: Reply + 'static>(csrf: BoxedFilter<(Arc<Csrf>,)>, routes: BoxedFilter<(T,)>) -> BoxedFilter<(Response,)> {
+    header::headers_cloned()
+    .and(csrf)
+    .and(routes)
+    .map(|headers: HeaderMap, csrf: Arc<Csrf>, reply: T| {
+        let mut response = reply.into_response();
+        csrf.issue(&headers, &mut response);
+        response
+    })
+    .boxed()
+}
+
+#[cfg(test)]
+mod test {
+    use warp::{
+        http::StatusCode,
+        test::{
+            RequestBuilder,
+            request,
+        },
+    };
+    use serde_json::json;
+    use crate::testing::{
+        TestServer,
+        cookie,
+        error_type,
+    };
+
+    fn patch(regssid: &str) -> RequestBuilder {
+        request()
+            .method("PATCH")
+            .path("/api/user/register")
+            .header("host", "localhost:8001")
+            .json(&json!({ "operation": "username", "data": "alice" }))
+            .header("cookie", format!("REGSSID={}; CSRF=token", regssid))
+    }
+
+    #[tokio::test]
+    async fn test_csrf() {
+        let server = TestServer::start().await;
+        let routes = server.routes();
+
+        // A new session comes with a token.
+        let response = request().method("POST").path("/api/user/register").reply(&routes).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let regssid = cookie(&response, "REGSSID").unwrap();
+        let token = cookie(&response, "CSRF").unwrap();
+        assert_eq!(response.headers()["x-csrf-token"], token.as_str());
+
+        for request in [
+            patch(&regssid),
+            patch(&regssid).header("x-csrf-token", "forged"),
+            patch(&regssid).header("x-csrf-token", "token").header("origin", "https://evil.com"),
+            patch(&regssid).header("x-csrf-token", "token").header("origin", "null"),
+            patch(&regssid).header("x-csrf-token", "token").header("referer", "https://evil.com/localhost:8001"),
+        ] {
+            let response = request.reply(&routes).await;
+            assert_eq!(response.status(), StatusCode::FORBIDDEN);
+            assert_eq!(error_type(response.body()), "CsrfForbidden");
+        }
+
+        for request in [
+            patch(&regssid).header("x-csrf-token", "token"),
+            patch(&regssid).header("x-csrf-token", "token").header("origin", "http://localhost:8001"),
+            // An allowed CORS origin.
+            patch(&regssid).header("x-csrf-token", "token").header("origin", "http://localhost:3000"),
+            patch(&regssid).header("x-csrf-token", "token").header("referer", "http://localhost:8001/register"),
+        ] {
+            let response = request.reply(&routes).await;
+            assert_eq!(response.status(), StatusCode::OK);
+            assert!(cookie(&response, "CSRF").is_none());
+        }
+
+        // Sessions without a token get one.
+        let response = request()
+            .path("/healthz")
+            .header("cookie", format!("REGSSID={}", regssid))
+            .reply(&routes)
+            .await;
+        assert!(cookie(&response, "CSRF").is_some());
+        let response = request().path("/healthz").reply(&routes).await;
+        assert!(cookie(&response, "CSRF").is_none());
+    }
+}
@@ -0,0 +1,120 @@
+use warp::{
+    Filter,
+    reply::{
+        Reply,
+        Response,
+        json,
+        with_status,
+    },
+    filters::BoxedFilter,
+    http::StatusCode,
+    get,
+    path,
+};
+use uuid::Uuid;
+use crate::{
+    route::utils::{
+        filter::endpoint,
+        handler::HandlerResult,
+    },
+    state::State,
+    error::Error,
+};
+
+const VERSION: &str = env!("CARGO_PKG_VERSION");
+const GIT_HASH: &str = env!("PIGSKIT_GIT_HASH");
+const BUILD_TIME: &str = env!("PIGSKIT_BUILD_TIME");
+
+#[derive(Serialize)]
+struct HealthRes {
+    status: &'static str,
+}
+
+/// The process is alive.
+fn healthz_filter() -> BoxedFilter<(impl Reply,)> {
+    get()
+    .map(|| json(&HealthRes { status: "ok" }))
+    .boxed()
+}
+
+#[derive(Serialize)]
+struct ReadyRes {
+    status: &'static str,
+    database: bool,
+    storage: bool,
+}
+
+async fn check_database(state: &State) -> Result<(), Error> {
+    let conn = state.db_pool().get().await?;
+    conn.execute("SELECT 1", &[]).await?;
+    Ok(())
+}
+
+async fn check_storage(state: &State) -> Result<(), Error> {
+    let key = format!(".readyz-{}", Uuid::new_v4());
+    state.storage().put(&key, Vec::new()).await?;
+    state.storage().delete(&key).await?;
+    Ok(())
+}
+
+/// The server can serve requests: a database connection can be checked out
+/// and queried, and the storage is writable.
+fn readyz_filter(state: BoxedFilter<(State,)>) -> BoxedFilter<(impl Reply,)> {
+    get()
+    .and(state)
+    .and_then(async move |state: State| -> HandlerResult<Response> {
+        let database = check_database(&state)
+            .await
+            .map_err(|err| warn!("Readiness check of database failed: {:?}", err))
+            .is_ok();
+        let storage = check_storage(&state)
+            .await
+            .map_err(|err| warn!("Readiness check of storage failed: {:?}", err))
+            .is_ok();
+
+        let ready = database && storage && !state.is_draining();
+        Ok(with_status(
+            json(&ReadyRes {
+                status: if ready { "ok" } else { "unavailable" },
+                database,
+                storage,
+            }),
+            if ready { StatusCode::OK } else { StatusCode::SERVICE_UNAVAILABLE },
+        ).into_response())
+    })
+    .boxed()
+}
+
+#[derive(Serialize)]
+struct VersionRes {
+    version: &'static str,
+    git_hash: &'static str,
+    build_time: &'static str,
+}
+
+fn version_filter() -> BoxedFilter<(impl Reply,)> {
+    get()
+    .map(|| json(&VersionRes {
+        version: VERSION,
+        git_hash: GIT_HASH,
+        build_time: BUILD_TIME,
+    }))
+    .boxed()
+}
+
+pub fn filter(state: BoxedFilter<(State,)>) -> BoxedFilter<(impl Reply,)> {
+    path("healthz").and(endpoint::end()).and(
+        healthz_filter()
+    )
+    .or(
+        path("readyz").and(endpoint::end()).and(
+            readyz_filter(state.clone())
+        )
+    )
+    .or(
+        path("version").and(endpoint::end()).and(
+            version_filter()
+        )
+    )
+    .boxed()
+}
@@ -0,0 +1,108 @@
+use warp::{
+    Filter,
+    reply::{
+        Reply,
+        Response,
+        with_header,
+    },
+    filters::{
+        BoxedFilter,
+        log::Info,
+    },
+    reject,
+    http::StatusCode,
+    get,
+};
+use prometheus::{
+    Encoder,
+    TextEncoder,
+};
+use crate::{
+    context,
+    route::utils::{
+        filter::endpoint,
+        handler::HandlerResult,
+    },
+    state::State,
+    error::Error,
+    metrics,
+};
+
+/// Record the request count and latency of a finished request, used with
+/// `warp::log::custom`. The `route` label is the end-point matched, so
+/// unknown paths don't blow up the number of series.
+pub fn record(info: Info) {
+    let route = context::route();
+    let route = route.as_deref().unwrap_or("unmatched");
+    let method = info.method().as_str();
+    metrics::HTTP_REQUESTS
+        .with_label_values(&[route, method, info.status().as_str()])
+        .inc();
+    metrics::HTTP_REQUEST_DURATION
+        .with_label_values(&[route, method])
+        .observe(info.elapsed().as_secs_f64());
+}
+
+/// Count an error response by its `Error.type`.
+pub fn record_error(error_type: &str, status: StatusCode) {
+    metrics::API_ERRORS
+        .with_label_values(&[error_type, status.as_str()])
+        .inc();
+}
+
+fn get_filter(state: BoxedFilter<(State,)>) -> BoxedFilter<(impl Reply,)> {
+    get()
+    .and(state)
+    .and_then(async move |state: State| -> HandlerResult<Response> {
+        let pool = state.db_pool().state();
+        metrics::DB_POOL_CONNECTIONS.set(pool.connections as i64);
+        metrics::DB_POOL_IDLE.set(pool.idle_connections as i64);
+
+        let encoder = TextEncoder::new();
+        let mut buf = Vec::new();
+        encoder.encode(&prometheus::gather(), &mut buf)
+            .map_err(|err| {
+                error!("Failed to encode metrics: {}", err);
+                reject::custom(Error::operation_failed())
+            })?;
+        Ok(with_header(buf, "Content-Type", encoder.format_type()).into_response())
+    })
+    .boxed()
+}
+
+pub fn filter(state: BoxedFilter<(State,)>) -> BoxedFilter<(impl Reply,)> {
+    endpoint::end().and(
+        get_filter(state.clone())
+    )
+    .boxed()
+}
+
+#[cfg(test)]
+mod test {
+    use warp::test::request;
+    use crate::{
+        metrics::HTTP_REQUESTS,
+        testing::TestServer,
+    };
+
+    #[tokio::test]
+    async fn test_route_label() {
+        let server = TestServer::start().await;
+        let requests = [
+            ("GET", "/healthz", "/healthz"),
+            ("POST", "/api/user/password/reset", "/api/user/password/reset"),
+            ("GET", "/api/user/profile/", "/api/user/profile"),
+            ("GET", "/api/user/session/all", "/api/user/session/all"),
+            ("DELETE", "/api/user/session/4f1c2d3e-5a6b-4c7d-8e9f-0a1b2c3d4e5f", "/api/user/session/{id}"),
+            // Whatever the method, the path matched an end-point.
+            ("PUT", "/api/shop/product/image", "/api/shop/product/image"),
+            ("GET", "/api/user/session/a/b", "unmatched"),
+            ("GET", "/api/unknown", "unmatched"),
+        ];
+        for (method, path, route) in requests.iter() {
+            let response = server.send(request().method(method).path(path)).await;
+            let count = HTTP_REQUESTS.with_label_values(&[route, method, response.status().as_str()]).get();
+            assert!(count > 0, "{} {} is not counted as {}", method, path, route);
+        }
+    }
+}
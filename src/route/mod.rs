@@ -1,3 +1,4 @@
+use std::sync::Arc;
 use warp::{
     reply::{
         Reply,
@@ -6,13 +7,21 @@ use warp::{
     },
     filters::BoxedFilter,
     reject::{
+        self,
         Rejection,
         PayloadTooLarge,
     },
     Filter,
-    http::StatusCode,
+    http::{
+        StatusCode,
+        uri::{
+            Authority,
+            Uri,
+        },
+    },
     path,
-    options,
+    header,
+    query,
 };
 use crate::{
     state::State,
@@ -21,22 +30,62 @@ use crate::{
 
 #[macro_use] mod utils;
 mod api;
+mod cors;
+mod csrf;
+mod health;
+mod metrics;
+mod session;
+
+use cors::Cors;
+use csrf::Csrf;
+use utils::response;
 
 pub fn routes(state: State) -> BoxedFilter<(impl Reply,)> {
+    let cors = Arc::new(Cors::new(&state.config().cors));
+    let csrf = Arc::new(Csrf::new(cors.clone(), state.config()));
+    let csrf = warp::any().map(move || csrf.clone()).boxed();
+    let upload = state.config().upload.clone();
     let state = warp::any().map(move || state.clone()).boxed();
 
-    path("api").and(
-        api::filter(state.clone())
+    // Probes answer on their own, bypassing the drain check and the logging in `recover`.
+    let routes = health::filter(state.clone())
+    .or(
+        path("metrics").and(
+            metrics::filter(state.clone())
+        )
+    )
+    .or(
+        // Reject new requests while draining connections for shutdown.
+        state.clone()
+        .and_then(async move |state: State| {
+            if state.is_draining() {
+                Err(reject::custom(Error::service_unavailable()))
+            } else {
+                Ok(())
+            }
+        })
+        .untuple_one()
+        .and(
+            path("api")
+            .and(csrf::check(csrf.clone()))
+            .and(
+                session::renew(state.clone(), api::filter(state.clone(), &upload))
+            )
+        )
     )
     .recover(async move |rejection: Rejection| -> Result<Response, Rejection> {
         if rejection.is_not_found() {
             info!("Rejected a request which the end-point was not found.");
+            metrics::record_error("NotFound", StatusCode::NOT_FOUND);
             Ok(with_status("Not Found.", StatusCode::NOT_FOUND).into_response())
         } else if rejection.find::<PayloadTooLarge>().is_some() {
             info!("Rejected a request which the size of payload too large.");
-            Ok(Error::payload_too_large().into_response())
+            let error = Error::payload_too_large();
+            metrics::record_error(error.error_type(), error.http_status());
+            Ok(error.into_response())
         } else {
             if let Some(error) = rejection.find::<Error>() {
+                metrics::record_error(error.error_type(), error.http_status());
                 if error.is_inner() {
                     info!("Encountered a server internal error: {:?}", error);
                 } else {
@@ -49,18 +98,66 @@ pub fn routes(state: State) -> BoxedFilter<(impl Reply,)> {
             }
         }
     })
+    .boxed();
+
+    cors::filter(cors, csrf::issue(csrf, routes))
+    .with(warp::log::custom(metrics::record))
     .boxed()
 }
 
-pub fn dev_routes(state: State) -> BoxedFilter<(impl Reply,)> {
-    // filter for preflight requests.
-    options().map(warp::reply)
-    .or(
-        routes(state)
+/// Redirect every request to the same location over HTTPS on `https_port`.
+pub fn https_redirect(https_port: u16) -> BoxedFilter<(impl Reply,)> {
+    header::optional::<Authority>("host")
+    .and(path::full())
+    .and(
+        query::raw()
+        .or(warp::any().map(String::new))
+        .unify()
     )
-    .map(|reply| warp::reply::with_header(reply, "Access-Control-Allow-Headers", "Content-Type"))
-    .map(|reply| warp::reply::with_header(reply, "Access-Control-Allow-Credentials", "true"))
-    .map(|reply| warp::reply::with_header(reply, "Access-Control-Allow-Origin", "http://localhost:3000"))
-    .map(|reply| warp::reply::with_header(reply, "Access-Control-Allow-Methods", "GET, POST, OPTIONS, PUT, PATCH, DELETE"))
+    .map(move |host: Option<Authority>, path: path::FullPath, query: String| {
+        let location = host.map(|host| {
+            let port = if https_port == 443 { String::new() } else { format!(":{}", https_port) };
+            let query = if query.is_empty() { query } else { format!("?{}", query) };
+            format!("https://{}{}{}{}", host.host(), port, path.as_str(), query)
+        });
+        match location.and_then(|location| location.parse::<Uri>().ok()) {
+            Some(location) => response::redirect_to(location),
+            None => with_status("Bad Request.", StatusCode::BAD_REQUEST).into_response(),
+        }
+    })
     .boxed()
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod test {
+    use warp::{
+        http::StatusCode,
+        test::request,
+    };
+    use super::https_redirect;
+
+    #[tokio::test]
+    async fn test_https_redirect() {
+        let response = request()
+            .path("/api/shop?name=tea")
+            .header("host", "pigskit.com:8080")
+            .reply(&https_redirect(443))
+            .await;
+        assert_eq!(response.status(), StatusCode::MOVED_PERMANENTLY);
+        assert_eq!(response.headers()["location"], "https://pigskit.com/api/shop?name=tea");
+
+        let response = request()
+            .method("POST")
+            .path("/")
+            .header("host", "[::1]")
+            .reply(&https_redirect(8443))
+            .await;
+        assert_eq!(response.headers()["location"], "https://[::1]:8443/");
+
+        let response = request()
+            .path("/")
+            .reply(&https_redirect(443))
+            .await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
+}
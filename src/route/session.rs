@@ -0,0 +1,78 @@
+//! Sliding expiry of the user sessions.
+//!
+//! A session authenticating a successful API request once less than half of
+//! its lifetime is left is extended by the lifetime, up to its maximum, and
+//! its cookie issued again with the new expiry if it is remembered. Cookies
+//! ending with the browser are left as is. Whether the session is due is
+//! found when it is resolved to its user, so other requests touch nothing.
+
+use uuid::Uuid;
+use warp::{
+    Filter,
+    Reply,
+    filters::BoxedFilter,
+    http::{
+        HeaderValue,
+        header::SET_COOKIE,
+    },
+    reject::Rejection,
+    reply::Response,
+};
+use crate::{
+    context,
+    error::Error,
+    sql::users,
+    state::State,
+};
+use super::utils::{
+    filter::cookie,
+    response,
+};
+
+const SESSION_COOKIE: &str = "USSID";
+
+/// The cookie to issue again if the session was extended.
+async fn extend(state: &State, ussid: Uuid) -> Result<Option<String>, Error> {
+    let conn = state.db_pool().get().await?;
+    let cookie = users::renew_session(&*conn, ussid).await?
+        .filter(|(remember, _)| *remember)
+        .map(|(_, expire_at)| {
+            response::cookie_until(SESSION_COOKIE, &ussid.to_string(), Some(expire_at), state.config().server.is_tls(), true)
+        });
+    Ok(cookie)
+}
+
+/// Whether the response signs in or out, setting the cookie itself.
+fn sets_cookie(response: &Response) -> bool {
+    let prefix = format!("{}=", SESSION_COOKIE);
+    response.headers()
+        .get_all(SET_COOKIE)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .any(|value| value.starts_with(&prefix))
+}
+
+/// Extend the sessions of the requests `routes` reply to, which rejected
+/// requests are not.
+pub fn renew<T: Reply + 'static>(state: BoxedFilter<(State,)>, routes: BoxedFilter<(T,)>) -> BoxedFilter<(Response,)> {
+    cookie::to_uuid_optional(SESSION_COOKIE)
+    .and(state)
+    .and(routes)
+    .and_then(async move |ussid: Option<Uuid>, state: State, reply: T| -> Result<Response, Rejection> {
+        let mut response = reply.into_response();
+        let due = ussid.filter(|ussid| context::renewal_due() == Some(*ussid) && !sets_cookie(&response));
+        if let Some(ussid) = due {
+            match extend(&state, ussid).await {
+                Ok(Some(cookie)) => {
+                    if let Ok(cookie) = HeaderValue::from_str(&cookie) {
+                        response.headers_mut().append(SET_COOKIE, cookie);
+                    }
+                }
+                Ok(None) => {}
+                Err(err) => warn!("Failed to renew a user session: {:?}", err),
+            }
+        }
+        Ok(response)
+    })
+    .boxed()
+}
@@ -0,0 +1,30 @@
+use warp::{
+    Filter,
+    filters::BoxedFilter,
+    header,
+};
+
+/// The headers of a conditional or partial GET.
+#[derive(Debug, Default, Clone)]
+pub struct Conditionals {
+    pub if_none_match: Option<String>,
+    pub if_modified_since: Option<String>,
+    pub range: Option<String>,
+    pub if_range: Option<String>,
+}
+
+pub fn conditionals() -> BoxedFilter<(Conditionals,)> {
+    header::optional::<String>("if-none-match")
+    .and(header::optional::<String>("if-modified-since"))
+    .and(header::optional::<String>("range"))
+    .and(header::optional::<String>("if-range"))
+    .map(|if_none_match, if_modified_since, range, if_range| {
+        Conditionals {
+            if_none_match,
+            if_modified_since,
+            range,
+            if_range,
+        }
+    })
+    .boxed()
+}
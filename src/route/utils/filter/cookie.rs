@@ -7,8 +7,9 @@ use warp::{
 use uuid::Uuid;
 use crate::{
     state::State,
-    sql::UuidNN,
+    sql::users,
     error::Error,
+    context,
 };
 
 pub fn to_user_id(name: &'static str, state: BoxedFilter<(State,)>) -> BoxedFilter<(Uuid,)> {
@@ -21,12 +22,11 @@ pub fn to_user_id(name: &'static str, state: BoxedFilter<(State,)>) -> BoxedFilt
         async {
             if let Some(ussid) = cookie {
                 let conn = state.db_pool().get().await?;
-                let (user_id,) = query_one!(
-                    conn,
-                    "SELECT get_session_user($1) AS id",
-                    &[&UuidNN(ussid)],
-                    (id: Uuid),
-                )?;
+                let (user_id, renewal_due) = users::session_user_renewal(&*conn, ussid).await?;
+                context::set_user_id(user_id);
+                if renewal_due {
+                    context::set_renewal_due(ussid);
+                }
                 Ok(user_id)
             } else {
                 Err(Error::no_valid_cookie(name.as_ref()))
@@ -0,0 +1,44 @@
+use uuid::Uuid;
+use warp::{
+    Filter,
+    filters::BoxedFilter,
+    path::{
+        self,
+        FullPath,
+    },
+};
+use crate::context;
+
+/// The end of the path of an end-point, recording the route matched for the
+/// metrics. Segments which are ids are recorded as `{id}`, so the routes are
+/// only the mounted ones.
+pub fn end() -> BoxedFilter<()> {
+    path::end()
+    .and(path::full())
+    .map(|path: FullPath| context::set_route(route(path.as_str())))
+    .untuple_one()
+    .boxed()
+}
+
+fn route(path: &str) -> String {
+    path.trim_end_matches('/')
+        .split('/')
+        .map(|segment| if Uuid::parse_str(segment).is_ok() { "{id}" } else { segment })
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+#[cfg(test)]
+mod test {
+    use super::route;
+
+    #[test]
+    fn test_route() {
+        assert_eq!(route("/api/user/password/reset"), "/api/user/password/reset");
+        assert_eq!(route("/api/user/profile/"), "/api/user/profile");
+        assert_eq!(
+            route("/api/user/session/4f1c2d3e-5a6b-4c7d-8e9f-0a1b2c3d4e5f"),
+            "/api/user/session/{id}",
+        );
+    }
+}
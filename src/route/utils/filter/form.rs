@@ -1,119 +1,471 @@
-#[macro_export]
-macro_rules! form_filter {
-    ( TYPE Option [ $($type:tt)+ ] ) => {
-        Option<$($type)+>
-    };
+//! Extraction of `multipart/form-data` bodies into structs deriving
+//! `MultipartForm`, whose fields are parsed from the parts of the same name.
 
-    ( TYPE $($type:tt)+ ) => {
-        $($type)+
-    };
+use std::{
+    error::Error as StdError,
+    path::PathBuf,
+};
+use async_trait::async_trait;
+use bytes::Buf;
+use futures::{
+    Stream,
+    TryStreamExt,
+    future,
+};
+use multer::{
+    Field as MultipartField,
+    Multipart,
+    bytes::Bytes,
+};
+use serde::de::DeserializeOwned;
+use uuid::Uuid;
+use warp::{
+    Filter,
+    filters::BoxedFilter,
+    reject::{
+        self,
+        Rejection,
+    },
+    body,
+    header,
+};
+use crate::{
+    config::UploadConfig,
+    error::Error,
+    state::State,
+    storage::Upload,
+};
 
-    ( DECLARE $field:ident Option [ $($type:tt)+ ] ) => {
-        form_filter!( DECLARE $field $($type)+ )
-    };
+/// Default limit of a field buffered in memory.
+pub const MAX_FIELD_LENGTH: u64 = 64 * 1024;
 
-    ( DECLARE $field:ident $($type:tt)+ ) => {
-        let mut $field: Option<$($type)+> = None;
-    };
+/// Allowance for the boundaries and headers of the parts, on top of
+/// `upload.max_length` for their content.
+const MAX_HEADERS_LENGTH: u64 = 16 * 1024;
 
-    ( PARSE $pair:ident $field:ident Option [ $($type:tt)+ ] ) => {
-        form_filter!( PARSE $pair $field $($type)+ )
-    };
+/// A struct extracted from a form, implemented by `#[derive(MultipartForm)]`.
+#[async_trait]
+pub trait MultipartForm: Sized + Send {
+    async fn from_form(form: FormData, reader: &mut PartReader) -> Result<Self, Error>;
+}
 
-    ( PARSE $pair:ident $field:ident bool ) => {
-        if let Ok(bool_string) = String::from_utf8($pair.1) {
-            if let "true" = bool_string.as_str() {
-                $field = Some(true)
-            } else {
-                $field = Some(false)
-            }
+/// Extract a `T` from the form in the request body.
+///
+/// The body is limited by `upload.max_length` before it is read, each part
+/// further by its field. The parts are parsed as the body arrives, file parts
+/// are streamed to the staging directory of the storage and the others buffered.
+pub fn multipart<T: MultipartForm + 'static>(state: BoxedFilter<(State,)>, upload: &UploadConfig) -> BoxedFilter<(T,)> {
+    state
+    .and(form(upload.max_length + MAX_HEADERS_LENGTH))
+    .and_then(|state: State, form: FormData| {
+        let mut reader = PartReader::new(state.storage().staging_dir());
+        async move {
+            T::from_form(form, &mut reader)
+                .await
+                .map_err(|err: Error| reject::custom(err))
         }
-    };
+    })
+    .boxed()
+}
+
+/// The form in the request body, refused if longer than `max_length`.
+fn form(max_length: u64) -> BoxedFilter<(FormData,)> {
+    header::<String>("content-type")
+    .and(body::content_length_limit(max_length))
+    .and(body::stream())
+    .and_then(|content_type: String, body| future::ready(form_data(&content_type, body)))
+    .boxed()
+}
+
+/// Parse the parts of `body` with the boundary in `content_type`, a chunk
+/// at a time.
+fn form_data<S, B>(content_type: &str, body: S) -> Result<FormData, Rejection>
+where
+    S: Stream<Item = Result<B, warp::Error>> + Send + 'static,
+    B: Buf,
+{
+    let boundary = multer::parse_boundary(content_type)
+        .map_err(|err| reject::custom(Error::from(err)))?;
+    Ok(FormData::new(body.map_ok(|mut data| data.to_bytes().to_vec()), boundary))
+}
+
+/// The parts of a form, parsed from a body as it arrives.
+pub struct FormData(Multipart<'static>);
+
+impl FormData {
+    pub fn new<S, O, E>(body: S, boundary: String) -> Self
+    where
+        S: Stream<Item = Result<O, E>> + Send + 'static,
+        O: Into<Bytes> + 'static,
+        E: Into<Box<dyn StdError + Send + Sync>> + 'static,
+    {
+        FormData(Multipart::new(body, boundary))
+    }
+
+    /// The next part, once the one before it is dropped.
+    pub async fn next_part(&mut self) -> Result<Option<Part>, Error> {
+        Ok(self.0.next_field().await?.map(Part))
+    }
+}
+
+/// A part of a form, of which the data is read as it arrives.
+pub struct Part(MultipartField<'static>);
+
+impl Part {
+    pub fn name(&self) -> &str {
+        self.0.name().unwrap_or("")
+    }
+
+    pub fn content_type(&self) -> Option<&str> {
+        self.0.content_type().map(|mime| mime.as_ref())
+    }
+}
+
+/// How a field of a form accepts its part.
+pub struct Field {
+    pub name: &'static str,
+    /// Defaults to `MAX_FIELD_LENGTH` for buffered fields, file parts are
+    /// only limited by the whole body.
+    pub limit: Option<u64>,
+    /// Any content type is accepted if empty, `type/*` matches a whole type.
+    pub content_types: &'static [&'static str],
+}
 
-    ( PARSE $pair:ident $field:ident Uuid ) => {
-        if let Ok(id_string) = String::from_utf8($pair.1) {
-            if let Ok(id) = Uuid::parse_str(id_string.as_str()) {
-                $field = Some(id);
+impl Field {
+    fn accepts(&self, content_type: Option<&str>) -> bool {
+        if self.content_types.is_empty() {
+            return true
+        }
+        let content_type = match content_type {
+            Some(content_type) => content_type.split(';').next().unwrap_or("").trim().to_ascii_lowercase(),
+            None => return false,
+        };
+        self.content_types.iter().any(|accepted| {
+            match accepted.strip_suffix("/*") {
+                Some(r#type) => content_type.split('/').next() == Some(r#type),
+                None => content_type == *accepted,
             }
+        })
+    }
+}
+
+/// Reads the parts of a form, each within the limit of its field.
+pub struct PartReader {
+    staging_dir: PathBuf,
+}
+
+impl PartReader {
+    pub fn new(staging_dir: PathBuf) -> Self {
+        PartReader {
+            staging_dir,
         }
-    };
+    }
 
-    ( PARSE $pair:ident $field:ident String ) => {
-        if let Ok(string) = String::from_utf8($pair.1) {
-            $field = Some(string);
+    /// Check the content type of a part and parse it as a `T`.
+    pub async fn read<T: FromPart>(&mut self, field: &Field, part: Part) -> Result<T, Error> {
+        if !field.accepts(part.content_type()) {
+            return Err(Error::invalid_data(field.name))
         }
-    };
+        T::from_part(field, part, self).await
+    }
 
-    ( PARSE $pair:ident $field:ident Vec<u8> ) => {
-        $field = Some($pair.1);
-    };
+    /// Buffer a part in memory.
+    pub async fn buffer(&mut self, field: &Field, mut part: Part) -> Result<Vec<u8>, Error> {
+        let max_length = field.limit.unwrap_or(MAX_FIELD_LENGTH);
+        let mut buf = Vec::new();
+        while let Some(data) = part.0.chunk().await? {
+            if (buf.len() + data.len()) as u64 > max_length {
+                return Err(Error::payload_too_large())
+            }
+            buf.extend_from_slice(&data);
+        }
+        Ok(buf)
+    }
+
+    /// Stream a part to a temporary file in the staging directory.
+    pub async fn receive(&mut self, field: &Field, part: Part) -> Result<Upload, Error> {
+        let max_length = field.limit.unwrap_or(u64::MAX);
+        Upload::receive(&self.staging_dir, part.0, max_length).await
+    }
+}
+
+/// A type read from a single part.
+#[async_trait]
+pub trait FromPart: Sized + Send {
+    async fn from_part(field: &Field, part: Part, reader: &mut PartReader) -> Result<Self, Error>;
+}
+
+/// A type parsed from a part buffered in memory, `#[derive(FormValue)]`
+/// implements it for enums.
+pub trait FormValue: Sized {
+    /// `None` if the data is invalid.
+    fn parse(data: Vec<u8>) -> Option<Self>;
+}
 
-    ( CHECK $field:ident Option [ $($type:tt)+ ] ) => {};
+#[async_trait]
+impl<T: FormValue + Send> FromPart for T {
+    async fn from_part(field: &Field, part: Part, reader: &mut PartReader) -> Result<Self, Error> {
+        let data = reader.buffer(field, part).await?;
+        T::parse(data).ok_or_else(|| Error::invalid_data(field.name))
+    }
+}
 
-    ( CHECK $field:ident $($type:tt)+ ) => {
-        if let None = $field {
-            return Err(Error::no_valid_form(stringify!($field)))
+#[async_trait]
+impl FromPart for Upload {
+    async fn from_part(field: &Field, part: Part, reader: &mut PartReader) -> Result<Self, Error> {
+        reader.receive(field, part).await
+    }
+}
+
+impl FormValue for Vec<u8> {
+    fn parse(data: Vec<u8>) -> Option<Self> {
+        Some(data)
+    }
+}
+
+impl FormValue for String {
+    fn parse(data: Vec<u8>) -> Option<Self> {
+        String::from_utf8(data).ok()
+    }
+}
+
+/// Only `true` and `false`.
+impl FormValue for bool {
+    fn parse(data: Vec<u8>) -> Option<Self> {
+        match data.as_slice() {
+            b"true" => Some(true),
+            b"false" => Some(false),
+            _ => None,
         }
-    };
+    }
+}
 
-    ( UNWRAP $field:ident Option [ $($type:tt)+ ] ) => {
-        $field
-    };
+impl FormValue for Uuid {
+    fn parse(data: Vec<u8>) -> Option<Self> {
+        Uuid::parse_str(std::str::from_utf8(&data).ok()?).ok()
+    }
+}
+
+macro_rules! impl_form_value_from_str {
+    ( $( $type:ty ),+ ) => {
+        $(
+            impl FormValue for $type {
+                fn parse(data: Vec<u8>) -> Option<Self> {
+                    std::str::from_utf8(&data).ok()?.parse().ok()
+                }
+            }
+        )+
+    }
+}
 
-    ( UNWRAP $field:ident $($type:tt)+ ) => {
-        $field.unwrap()
+impl_form_value_from_str!(i16, i32, i64, u8, u16, u32, u64, f32, f64);
+
+/// A part holding a JSON document.
+#[derive(Debug, PartialEq)]
+pub struct Json<T>(pub T);
+
+impl<T: DeserializeOwned> FormValue for Json<T> {
+    fn parse(data: Vec<u8>) -> Option<Self> {
+        serde_json::from_slice(&data).ok().map(Json)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        fs,
+        io,
+        path::Path,
+        time::Duration,
+    };
+    use futures::{
+        SinkExt,
+        channel::mpsc,
+    };
+    use tempfile::TempDir;
+    use tokio::time::delay_for;
+    use uuid::Uuid;
+    use warp::{
+        Filter,
+        reject,
+        test::request,
+    };
+    use crate::{
+        error::Error,
+        storage::Upload,
+        testing::multipart,
+    };
+    use super::{
+        Field,
+        FormData,
+        Json,
+        MultipartForm,
+        PartReader,
+        form,
     };
 
-    ( $( $field:ident [ $($type:tt)+ ] )+ ) => {
-        form()
-        .max_length(2000000)
-        .and_then(async move |form: FormData| -> HandlerResult<($( form_filter!( TYPE $($type)+ ) ),+,)> {
-            async {
-                $(
-                    form_filter!( DECLARE $field $($type)+ );
-                )+
-
-                let pairs: Vec<(String, Vec<u8>)> =
-                    form
-                    .and_then(|part| {
-                        let name = part.name().to_string();
-                        part.stream()
-                        .try_fold(
-                            Vec::new(),
-                            |mut buf, data| {
-                                buf.put(data);
-                                async move { Ok(buf) }
-                            },
-                        )
-                        .map_ok(move |buf| (name, buf))
-                    })
-                    .try_collect()
-                    .await?;
-
-                for pair in pairs {
-                    match pair.0.as_str() {
-                        $(
-                            stringify!($field) => {
-                                form_filter!( PARSE pair $field $($type)+ )
-                            }
-                        )+
-                        _ => {}
-                    }
-                }
+    #[derive(Debug, PartialEq, FormValue)]
+    enum Color {
+        Red,
+        DarkBlue,
+        #[multipart(rename = "grey")]
+        Gray,
+    }
 
-                $(
-                    form_filter!( CHECK $field $($type)+ );
-                )+
+    #[derive(Debug, PartialEq, Deserialize)]
+    struct Payload {
+        name: String,
+    }
 
-                Ok((
-                    $(
-                        form_filter!( UNWRAP $field $($type)+ )
-                    ),+,
-                ))
+    #[derive(MultipartForm)]
+    struct TestForm {
+        id: Uuid,
+        count: i32,
+        color: Option<Color>,
+        payload: Json<Payload>,
+        visible: Option<bool>,
+        tags: Vec<String>,
+        #[multipart(rename = "type")]
+        r#type: Option<String>,
+        #[multipart(limit = 4)]
+        note: Option<String>,
+        #[multipart(content_type = "image/*")]
+        image: Option<Upload>,
+    }
+
+    async fn extract(parts: &[(&str, &[u8])]) -> Result<TestForm, String> {
+        let dir = TempDir::new().unwrap();
+        let staging_dir = dir.path().to_owned();
+        let filter = form(100_000).and_then(move |form: FormData| {
+            let mut reader = PartReader::new(staging_dir.clone());
+            async move {
+                TestForm::from_form(form, &mut reader)
+                    .await
+                    .map_err(|err: Error| reject::custom(err))
             }
+        });
+        let (content_type, body) = multipart(parts);
+        request()
+            .method("POST")
+            .header("content-type", content_type)
+            .body(body)
+            .filter(&filter)
             .await
-            .map_err(|err: Error| reject::custom(err))
-        })
-        .untuple_one()
-    };
-}
\ No newline at end of file
+            .map_err(|rejection| {
+                let err = rejection.find::<Error>().expect("rejected by the form");
+                format!("{}: {}", err.error_type(), err.message())
+            })
+    }
+
+    #[tokio::test]
+    async fn test_multipart_form() {
+        let id = Uuid::new_v4().to_string();
+        let required: Vec<(&str, &[u8])> = vec![
+            ("id", id.as_bytes()),
+            ("count", b"-3"),
+            ("payload", br#"{"name":"tea"}"#),
+        ];
+
+        let form = extract(&required).await.unwrap();
+        assert_eq!(form.id.to_string(), id);
+        assert_eq!(form.count, -3);
+        assert_eq!(form.payload, Json(Payload { name: "tea".to_string() }));
+        assert_eq!(form.color, None);
+        assert!(form.tags.is_empty());
+        assert!(form.image.is_none());
+
+        let png = crate::testing::png(1, 1);
+        let mut parts = required.clone();
+        parts.extend_from_slice(&[
+            ("color", b"dark_blue"),
+            ("visible", b"false"),
+            ("tags", b"a"),
+            ("tags", b"b"),
+            ("type", b"tea"),
+            ("note", b"note"),
+            ("image", &png),
+            ("unknown", b"ignored"),
+        ]);
+        let form = extract(&parts).await.unwrap();
+        assert_eq!(form.color, Some(Color::DarkBlue));
+        assert_eq!(form.visible, Some(false));
+        assert_eq!(form.tags, vec!["a", "b"]);
+        assert_eq!(form.r#type.as_deref(), Some("tea"));
+        assert_eq!(form.note.as_deref(), Some("note"));
+        assert_eq!(form.image.unwrap().len(), png.len() as u64);
+
+        let mut parts = required.clone();
+        parts.push(("color", b"grey"));
+        assert_eq!(extract(&parts).await.unwrap().color, Some(Color::Gray));
+
+        let invalid: Vec<(&str, &[u8], &str)> = vec![
+            ("id", b"not-a-uuid", "InvalidData"),
+            ("count", b"3.5", "InvalidData"),
+            ("color", b"Red", "InvalidData"),
+            ("payload", b"{}", "InvalidData"),
+            ("visible", b"yes", "InvalidData"),
+            ("image", b"not an image", "InvalidData"),
+            ("note", b"too long", "PayloadTooLarge"),
+        ];
+        for (name, data, error_type) in invalid {
+            let mut parts: Vec<(&str, &[u8])> = required.iter()
+                .filter(|(required, _)| *required != name)
+                .cloned()
+                .collect();
+            parts.push((name, data));
+            let err = extract(&parts).await.err().unwrap();
+            assert!(err.starts_with(error_type), "{}: {}", name, err);
+            if error_type == "InvalidData" {
+                assert!(err.contains(&format!("\"{}\"", name)), "{}: {}", name, err);
+            }
+        }
+
+        let mut parts = required.clone();
+        parts.push(("count", b"4"));
+        let err = extract(&parts).await.err().unwrap();
+        assert!(err.starts_with("InvalidData") && err.contains("\"count\""), "{}", err);
+
+        let err = extract(&required[1..]).await.err().unwrap();
+        assert!(err.starts_with("FormMissingPart") && err.contains("\"id\""), "{}", err);
+    }
+
+    fn staged_length(dir: &Path) -> u64 {
+        fs::read_dir(dir).unwrap()
+            .map(|entry| entry.unwrap().metadata().unwrap().len())
+            .sum()
+    }
+
+    #[tokio::test]
+    async fn test_streamed_part() {
+        const CHUNK_LENGTH: usize = 256 * 1024;
+        let dir = TempDir::new().unwrap();
+        let staging_dir = dir.path().to_owned();
+        let (mut body, chunks) = mpsc::channel::<Result<Vec<u8>, io::Error>>(0);
+        let receive = tokio::spawn(async move {
+            let mut form = FormData::new(chunks, "boundary".to_string());
+            let part = form.next_part().await?.unwrap();
+            let field = Field { name: "file", limit: None, content_types: &[] };
+            PartReader::new(staging_dir).receive(&field, part).await
+        });
+
+        body.send(Ok(b"--boundary\r\nContent-Disposition: form-data; name=\"file\"\r\n\r\n".to_vec())).await.unwrap();
+        for sent in 1..=8 {
+            body.send(Ok(vec![0; CHUNK_LENGTH])).await.unwrap();
+            // The part is written out as it arrives, with most of it yet to come.
+            if sent == 4 {
+                let mut waited = 0;
+                while staged_length(dir.path()) < CHUNK_LENGTH as u64 {
+                    assert!(waited < 100, "nothing of the part was staged");
+                    delay_for(Duration::from_millis(50)).await;
+                    waited += 1;
+                }
+            }
+        }
+        body.send(Ok(b"\r\n--boundary--\r\n".to_vec())).await.unwrap();
+        drop(body);
+
+        let upload = receive.await.unwrap().unwrap();
+        assert_eq!(upload.len(), 8 * CHUNK_LENGTH as u64);
+        assert_eq!(staged_length(dir.path()), 8 * CHUNK_LENGTH as u64);
+    }
+}
@@ -1,2 +1,4 @@
-#[macro_use] pub mod form;
-pub mod cookie;
\ No newline at end of file
+pub mod form;
+pub mod cookie;
+pub mod conditional;
+pub mod endpoint;
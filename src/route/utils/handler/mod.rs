@@ -1,5 +1,3 @@
 use warp::reject::Rejection;
 
-pub mod fs;
-
-pub type HandlerResult<T> = Result<T, Rejection>;
\ No newline at end of file
+pub type HandlerResult<T> = Result<T, Rejection>;
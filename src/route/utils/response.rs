@@ -5,24 +5,345 @@ use warp::{
         with_header,
         Response,
     },
-    http,
+    http::{
+        self,
+        StatusCode,
+        header,
+    },
+    hyper::Body,
     redirect,
 };
 use chrono::{
+    DateTime,
     Utc,
     Duration,
+    Timelike,
+};
+use crate::{
+    route::utils::filter::conditional::Conditionals,
+    storage::{
+        Metadata,
+        Storage,
+        StorageResult,
+    },
 };
 
-pub fn set_cookie(name: &str, value: &str, duration: i64) -> Response {
-    let expire = Utc::now() + Duration::days(duration);
+const HTTP_DATE: &str = "%a, %d %b %Y %T GMT";
+
+/// `secure` restricts the cookie to HTTPS, set when the server terminates TLS.
+pub fn set_cookie(name: &str, value: &str, duration: i64, secure: bool) -> Response {
+    set_cookie_until(name, value, Some(Utc::now() + Duration::days(duration)), secure)
+}
+
+/// A cookie expiring at `expire`, or with the browser session if `None`.
+pub fn set_cookie_until(name: &str, value: &str, expire: Option<DateTime<Utc>>, secure: bool) -> Response {
     with_header(
         reply(),
         "Set-Cookie",
-        format!("{}={}; Path=/; Expires={}; HttpOnly; SameSite=Strict", name, value, expire.format("%a, %d %b %Y %T GMT")),
+        cookie_until(name, value, expire, secure, true),
     ).into_response()
 }
 
-#[allow(dead_code)]
-pub fn redirect_to(uri: &'static str) -> Response {
-    redirect(http::Uri::from_static(uri)).into_response()
-}
\ No newline at end of file
+/// The value of a `Set-Cookie` header, `http_only` hides the cookie from scripts.
+pub fn cookie(name: &str, value: &str, duration: i64, secure: bool, http_only: bool) -> String {
+    cookie_until(name, value, Some(Utc::now() + Duration::days(duration)), secure, http_only)
+}
+
+pub fn cookie_until(name: &str, value: &str, expire: Option<DateTime<Utc>>, secure: bool, http_only: bool) -> String {
+    format!(
+        "{}={}; Path=/{}; SameSite=Strict{}{}",
+        name,
+        value,
+        expire.map_or(String::new(), |expire| format!("; Expires={}", expire.format(HTTP_DATE))),
+        if http_only { "; HttpOnly" } else { "" },
+        if secure { "; Secure" } else { "" },
+    )
+}
+
+pub fn redirect_to(uri: http::Uri) -> Response {
+    redirect(uri).into_response()
+}
+
+/// A validator derived from the metadata, which changes whenever the blob
+/// is replaced, so it is known without reading the blob.
+pub fn etag(metadata: &Metadata) -> String {
+    let (seconds, nanos) = metadata.last_modified.map_or((0, 0), |date| (date.timestamp(), date.timestamp_subsec_nanos()));
+    format!("\"{:x}.{:x}-{:x}\"", seconds, nanos, metadata.length)
+}
+
+/// The type of the content by the extension of its key.
+fn content_type(key: &str) -> &'static str {
+    match key.rsplit('.').next().map(|extension| extension.to_ascii_lowercase()).as_deref() {
+        Some("jpg") | Some("jpeg") => "image/jpeg",
+        Some("png") => "image/png",
+        Some("webp") => "image/webp",
+        _ => "application/octet-stream",
+    }
+}
+
+fn parse_http_date(date: &str) -> Option<DateTime<Utc>> {
+    DateTime::parse_from_rfc2822(date.trim())
+        .ok()
+        .map(|date| date.with_timezone(&Utc))
+}
+
+fn etag_matches(header: &str, etag: &str) -> bool {
+    header.split(',')
+        .map(|tag| tag.trim())
+        .any(|tag| tag == "*" || tag.trim_start_matches("W/") == etag)
+}
+
+fn not_modified(conditionals: &Conditionals, etag: &str, last_modified: Option<DateTime<Utc>>) -> bool {
+    // If-Modified-Since is ignored when If-None-Match is present.
+    if let Some(if_none_match) = &conditionals.if_none_match {
+        return etag_matches(if_none_match, etag)
+    }
+    match (conditionals.if_modified_since.as_ref().and_then(|date| parse_http_date(date)), last_modified) {
+        (Some(since), Some(last_modified)) => last_modified <= since,
+        _ => false,
+    }
+}
+
+/// The inclusive byte range requested by a `Range` header. `None` if the
+/// whole content should be served instead, `Some(Err(()))` if the range can
+/// not be satisfied. Multiple ranges are not supported and served in whole.
+fn parse_range(range: &str, length: u64) -> Option<Result<(u64, u64), ()>> {
+    let spec = range.trim().strip_prefix("bytes=")?.trim();
+    if spec.contains(',') {
+        return None
+    }
+    let (start, end) = spec.split_at(spec.find('-')?);
+    let end = &end[1..];
+    if start.is_empty() {
+        let suffix = end.parse::<u64>().ok()?;
+        if suffix == 0 || length == 0 {
+            return Some(Err(()))
+        }
+        return Some(Ok((length.saturating_sub(suffix), length - 1)))
+    }
+    let start = start.parse::<u64>().ok()?;
+    let end = if end.is_empty() {
+        u64::MAX
+    } else {
+        end.parse::<u64>().ok()?
+    };
+    if end < start {
+        return None
+    }
+    if start >= length {
+        return Some(Err(()))
+    }
+    Some(Ok((start, end.min(length - 1))))
+}
+
+/// Serve the blob at `key` with its validators, answering conditional GETs
+/// with 304 and `Range` requests with the partial content. Only what is sent
+/// is read from the storage.
+pub async fn blob(storage: &Storage, key: &str, metadata: &Metadata, cache_control: &str, conditionals: &Conditionals) -> StorageResult<Response> {
+    let etag = etag(metadata);
+    // HTTP dates have a resolution of seconds.
+    let last_modified = metadata.last_modified.and_then(|date| date.with_nanosecond(0));
+
+    let mut builder = http::Response::builder()
+        .header(header::ETAG, etag.as_str())
+        .header(header::CACHE_CONTROL, cache_control)
+        .header(header::ACCEPT_RANGES, "bytes");
+    if let Some(last_modified) = last_modified {
+        builder = builder.header(header::LAST_MODIFIED, last_modified.format(HTTP_DATE).to_string());
+    }
+
+    if not_modified(conditionals, &etag, last_modified) {
+        return Ok(
+            builder
+                .status(StatusCode::NOT_MODIFIED)
+                .body(Body::empty())
+                .unwrap()
+        )
+    }
+    builder = builder.header(header::CONTENT_TYPE, content_type(key));
+
+    let length = metadata.length;
+    let if_range = match &conditionals.if_range {
+        Some(if_range) if if_range.starts_with('"') || if_range.starts_with("W/") => if_range.trim() == etag,
+        Some(if_range) => parse_http_date(if_range).is_some() && parse_http_date(if_range) == last_modified,
+        None => true,
+    };
+    let range = conditionals.range
+        .as_ref()
+        .filter(|_| if_range)
+        .and_then(|range| parse_range(range, length));
+
+    let response = match range {
+        Some(Ok((start, end))) => {
+            builder
+                .status(StatusCode::PARTIAL_CONTENT)
+                .header(header::CONTENT_RANGE, format!("bytes {}-{}/{}", start, end, length))
+                .body(Body::from(storage.get_range(key, start, end).await?))
+        }
+        Some(Err(())) => {
+            builder
+                .status(StatusCode::RANGE_NOT_SATISFIABLE)
+                .header(header::CONTENT_RANGE, format!("bytes */{}", length))
+                .body(Body::empty())
+        }
+        None => {
+            builder
+                .status(StatusCode::OK)
+                .body(Body::from(storage.get(key).await?))
+        }
+    };
+    Ok(response.unwrap())
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::{
+        Arc,
+        atomic::{AtomicU64, Ordering},
+    };
+    use async_trait::async_trait;
+    use chrono::{
+        TimeZone,
+        Utc,
+    };
+    use tempfile::TempDir;
+    use warp::http::StatusCode;
+    use crate::{
+        route::utils::filter::conditional::Conditionals,
+        storage::{
+            BlobStore,
+            FsStore,
+            Metadata,
+            Storage,
+            StorageResult,
+        },
+    };
+    use super::{
+        blob,
+        etag,
+        parse_range,
+    };
+
+    /// Counts the bytes read through it.
+    struct CountingStore {
+        store: FsStore,
+        read: AtomicU64,
+    }
+
+    #[async_trait]
+    impl BlobStore for CountingStore {
+        async fn put(&self, key: &str, data: Vec<u8>) -> StorageResult<()> {
+            self.store.put(key, data).await
+        }
+
+        async fn get(&self, key: &str) -> StorageResult<Vec<u8>> {
+            let data = self.store.get(key).await?;
+            self.read.fetch_add(data.len() as u64, Ordering::SeqCst);
+            Ok(data)
+        }
+
+        async fn get_range(&self, key: &str, start: u64, end: u64) -> StorageResult<Vec<u8>> {
+            let data = self.store.get_range(key, start, end).await?;
+            self.read.fetch_add(data.len() as u64, Ordering::SeqCst);
+            Ok(data)
+        }
+
+        async fn delete(&self, key: &str) -> StorageResult<()> {
+            self.store.delete(key).await
+        }
+
+        async fn delete_prefix(&self, prefix: &str) -> StorageResult<()> {
+            self.store.delete_prefix(prefix).await
+        }
+
+        async fn metadata(&self, key: &str) -> StorageResult<Metadata> {
+            self.store.metadata(key).await
+        }
+    }
+
+    #[test]
+    fn test_parse_range() {
+        assert_eq!(parse_range("bytes=0-9", 100), Some(Ok((0, 9))));
+        assert_eq!(parse_range("bytes=90-", 100), Some(Ok((90, 99))));
+        assert_eq!(parse_range("bytes=90-200", 100), Some(Ok((90, 99))));
+        assert_eq!(parse_range("bytes=-10", 100), Some(Ok((90, 99))));
+        assert_eq!(parse_range("bytes=-200", 100), Some(Ok((0, 99))));
+        assert_eq!(parse_range("bytes=100-", 100), Some(Err(())));
+        assert_eq!(parse_range("bytes=-0", 100), Some(Err(())));
+        assert_eq!(parse_range("bytes=9-0", 100), None);
+        assert_eq!(parse_range("bytes=0-1,5-6", 100), None);
+        assert_eq!(parse_range("items=0-1", 100), None);
+    }
+
+    #[tokio::test]
+    async fn test_blob() {
+        let dir = TempDir::new().unwrap();
+        let store = Arc::new(CountingStore {
+            store: FsStore::open(dir.path().to_str().unwrap()).unwrap(),
+            read: AtomicU64::new(0),
+        });
+        let storage = Storage::new(store.clone(), "test".to_string());
+        storage.put("a/blob.jpg", b"0123456789".to_vec()).await.unwrap();
+        let modified = Utc.with_ymd_and_hms(2020, 10, 1, 12, 0, 0).unwrap();
+        let metadata = Metadata {
+            length: 10,
+            last_modified: Some(modified),
+        };
+        let tag = etag(&metadata);
+        assert_ne!(tag, etag(&Metadata { length: 11, ..metadata.clone() }));
+        let read = || store.read.swap(0, Ordering::SeqCst);
+
+        let response = blob(&storage, "a/blob.jpg", &metadata, "no-cache", &Conditionals::default()).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()["etag"], tag.as_str());
+        assert_eq!(response.headers()["last-modified"], "Thu, 01 Oct 2020 12:00:00 GMT");
+        assert_eq!(response.headers()["content-type"], "image/jpeg");
+        assert_eq!(read(), 10);
+
+        let response = blob(&storage, "a/blob.jpg", &metadata, "no-cache", &Conditionals {
+            if_none_match: Some(format!("\"other\", W/{}", tag)),
+            ..Default::default()
+        }).await.unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_MODIFIED);
+        assert_eq!(read(), 0);
+
+        let response = blob(&storage, "a/blob.jpg", &metadata, "no-cache", &Conditionals {
+            if_none_match: Some("\"other\"".to_string()),
+            if_modified_since: Some("Thu, 01 Oct 2020 12:00:00 GMT".to_string()),
+            ..Default::default()
+        }).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(read(), 10);
+
+        let response = blob(&storage, "a/blob.jpg", &metadata, "no-cache", &Conditionals {
+            if_modified_since: Some("Thu, 01 Oct 2020 12:00:00 GMT".to_string()),
+            ..Default::default()
+        }).await.unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_MODIFIED);
+        assert_eq!(read(), 0);
+
+        let response = blob(&storage, "a/blob.jpg", &metadata, "no-cache", &Conditionals {
+            range: Some("bytes=2-4".to_string()),
+            ..Default::default()
+        }).await.unwrap();
+        assert_eq!(response.status(), StatusCode::PARTIAL_CONTENT);
+        assert_eq!(response.headers()["content-range"], "bytes 2-4/10");
+        assert_eq!(read(), 3);
+
+        let response = blob(&storage, "a/blob.jpg", &metadata, "no-cache", &Conditionals {
+            range: Some("bytes=2-4".to_string()),
+            if_range: Some("\"stale\"".to_string()),
+            ..Default::default()
+        }).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(read(), 10);
+
+        let response = blob(&storage, "a/blob.jpg", &metadata, "no-cache", &Conditionals {
+            range: Some("bytes=20-".to_string()),
+            ..Default::default()
+        }).await.unwrap();
+        assert_eq!(response.status(), StatusCode::RANGE_NOT_SATISFIABLE);
+        assert_eq!(response.headers()["content-range"], "bytes */10");
+        assert_eq!(read(), 0);
+    }
+}
@@ -0,0 +1,289 @@
+mod tls;
+
+use std::{
+    collections::HashMap,
+    convert::Infallible,
+    future::Future,
+    net::SocketAddr,
+    sync::{
+        Arc,
+        Mutex,
+    },
+    time::Instant,
+};
+use warp::{
+    Reply,
+    filters::BoxedFilter,
+    http::{
+        Request,
+        Response,
+        HeaderValue,
+    },
+};
+use hyper::{
+    Body,
+    Server,
+    rt::Executor,
+    server::{
+        accept::Accept,
+        conn::{
+            AddrIncoming,
+            AddrStream,
+        },
+    },
+    service::{
+        make_service_fn,
+        service_fn,
+    },
+};
+use futures::{
+    FutureExt,
+    future::{
+        AbortHandle,
+        Abortable,
+        Either,
+    },
+};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio_rustls::server::TlsStream;
+use tower_service::Service;
+use uuid::Uuid;
+use tls::TlsIncoming;
+use crate::{
+    config::LogFormat,
+    context::RequestContext,
+    logger::{
+        self,
+        AccessEntry,
+    },
+};
+
+pub use tls::ServerCert;
+
+pub const REQUEST_ID_HEADER: &str = "X-Request-Id";
+const FORWARDED_FOR_HEADER: &str = "X-Forwarded-For";
+
+/// A connection of which the address of the client is known.
+pub trait Connection {
+    fn remote_addr(&self) -> SocketAddr;
+}
+
+impl Connection for AddrStream {
+    fn remote_addr(&self) -> SocketAddr {
+        AddrStream::remote_addr(self)
+    }
+}
+
+impl Connection for TlsStream<AddrStream> {
+    fn remote_addr(&self) -> SocketAddr {
+        self.get_ref().0.remote_addr()
+    }
+}
+
+/// Spawns the connections of the servers, so those outlasting the drain at
+/// shutdown can be aborted.
+#[derive(Clone, Default)]
+pub struct Connections {
+    inner: Arc<Mutex<ConnectionsInner>>,
+}
+
+#[derive(Default)]
+struct ConnectionsInner {
+    next_id: u64,
+    tasks: HashMap<u64, AbortHandle>,
+}
+
+impl Connections {
+    /// Abort every connection still served, returns how many there were.
+    pub fn abort(&self) -> usize {
+        let tasks = std::mem::take(&mut self.inner.lock().unwrap().tasks);
+        for task in tasks.values() {
+            task.abort();
+        }
+        tasks.len()
+    }
+}
+
+impl<F: Future<Output = ()> + Send + 'static> Executor<F> for Connections {
+    fn execute(&self, future: F) {
+        let (handle, registration) = AbortHandle::new_pair();
+        let id = {
+            let mut inner = self.inner.lock().unwrap();
+            let id = inner.next_id;
+            inner.next_id += 1;
+            inner.tasks.insert(id, handle);
+            id
+        };
+        let inner = self.inner.clone();
+        tokio::spawn(async move {
+            let _ = Abortable::new(future, registration).await;
+            inner.lock().unwrap().tasks.remove(&id);
+        });
+    }
+}
+
+/// Accept an incoming request id only if it is short printable ASCII.
+fn incoming_request_id(req: &Request<Body>) -> Option<String> {
+    req.headers()
+        .get(REQUEST_ID_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .filter(|id| !id.is_empty() && id.len() <= 128 && id.chars().all(|c| c.is_ascii_graphic()))
+        .map(|id| id.to_owned())
+}
+
+/// The hops of every `X-Forwarded-For` header, in order.
+fn forwarded_for(req: &Request<Body>) -> Option<String> {
+    let hops: Vec<&str> = req.headers()
+        .get_all(FORWARDED_FOR_HEADER)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .collect();
+    if hops.is_empty() {
+        None
+    } else {
+        Some(hops.join(","))
+    }
+}
+
+async fn handle<S>(
+    mut service: S,
+    access_log: Option<LogFormat>,
+    remote_addr: Option<SocketAddr>,
+    req: Request<Body>,
+) -> Result<Response<Body>, Infallible>
+where
+    S: Service<Request<Body>, Response = Response<Body>, Error = Infallible>,
+{
+    let request_id = incoming_request_id(&req).unwrap_or_else(|| Uuid::new_v4().to_string());
+    let context = RequestContext::new(request_id, remote_addr, forwarded_for(&req));
+    let method = req.method().to_string();
+    let path = req.uri().path().to_owned();
+    let start = Instant::now();
+
+    let mut response = context.clone().scope(service.call(req)).await?;
+
+    if let Ok(value) = HeaderValue::from_str(context.request_id()) {
+        response.headers_mut().insert(REQUEST_ID_HEADER, value);
+    }
+    if let Some(format) = access_log {
+        logger::access(format, &AccessEntry {
+            request_id: context.request_id(),
+            remote_addr,
+            method: &method,
+            path: &path,
+            status: response.status().as_u16(),
+            latency: start.elapsed(),
+            user_id: context.user_id(),
+        });
+    }
+    Ok(response)
+}
+
+/// Bind the routes on `address`, running every request within a `RequestContext`,
+/// terminating TLS with `cert` if given and spawning the connections with
+/// `connections`. The returned future resolves once `shutdown` resolves and
+/// connections are drained, or aborted.
+pub fn bind<T: Reply + 'static>(
+    routes: BoxedFilter<(T,)>,
+    address: SocketAddr,
+    cert: Option<ServerCert>,
+    access_log: Option<LogFormat>,
+    connections: Connections,
+    shutdown: impl Future<Output = ()> + Send + 'static,
+) -> Result<impl Future<Output = ()> + Send, hyper::Error> {
+    let incoming = AddrIncoming::bind(&address)?;
+    Ok(match cert {
+        Some(cert) => Either::Left(serve(routes, TlsIncoming::new(incoming, cert), access_log, connections, shutdown)),
+        None => Either::Right(serve(routes, incoming, access_log, connections, shutdown)),
+    })
+}
+
+fn serve<T, I>(
+    routes: BoxedFilter<(T,)>,
+    incoming: I,
+    access_log: Option<LogFormat>,
+    connections: Connections,
+    shutdown: impl Future<Output = ()> + Send + 'static,
+) -> impl Future<Output = ()> + Send
+where
+    T: Reply + 'static,
+    I: Accept<Error = std::io::Error> + Send + 'static,
+    I::Conn: Connection + AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let service = warp::service(routes);
+    let make_service = make_service_fn(move |conn: &I::Conn| {
+        let remote_addr = conn.remote_addr();
+        let service = service.clone();
+        async move {
+            Ok::<_, Infallible>(service_fn(move |req| {
+                handle(service.clone(), access_log, Some(remote_addr), req)
+            }))
+        }
+    });
+
+    Server::builder(incoming)
+        .executor(connections)
+        .serve(make_service)
+        .with_graceful_shutdown(shutdown)
+        .map(|result| {
+            if let Err(err) = result {
+                error!("Server error: {}", err);
+            }
+        })
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        net::TcpListener,
+        time::Duration,
+    };
+    use futures::future;
+    use hyper::{
+        Body,
+        Request,
+        client::conn::handshake,
+    };
+    use tokio::{
+        net::TcpStream,
+        sync::oneshot,
+        time::{
+            delay_for,
+            timeout,
+        },
+    };
+    use warp::{
+        Filter,
+        Rejection,
+    };
+    use super::{
+        Connections,
+        bind,
+    };
+
+    #[tokio::test]
+    async fn test_abort() {
+        let address = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+        let (shutdown_tx, shutdown_rx) = oneshot::channel::<()>();
+        let routes = warp::any().and_then(future::pending::<Result<&'static str, Rejection>>).boxed();
+        let connections = Connections::default();
+        let server = bind(routes, address, None, None, connections.clone(), async {
+            let _ = shutdown_rx.await;
+        }).unwrap();
+        let mut server = tokio::spawn(server);
+
+        let (mut client, connection) = handshake(TcpStream::connect(address).await.unwrap()).await.unwrap();
+        tokio::spawn(connection);
+        let request = Request::get("/").header("host", "localhost").body(Body::empty()).unwrap();
+        let response = tokio::spawn(client.send_request(request));
+        delay_for(Duration::from_millis(100)).await;
+
+        // The stuck connection keeps the server from draining.
+        let _ = shutdown_tx.send(());
+        assert!(timeout(Duration::from_millis(200), &mut server).await.is_err());
+        assert_eq!(connections.abort(), 1);
+        timeout(Duration::from_secs(5), server).await.unwrap().unwrap();
+        assert!(response.await.unwrap().is_err());
+        assert_eq!(connections.abort(), 0);
+    }
+}
@@ -0,0 +1,259 @@
+//! TLS termination, with a certificate which can be replaced while serving.
+
+use std::{
+    future::Future,
+    io,
+    pin::Pin,
+    sync::{
+        Arc,
+        RwLock,
+    },
+    task::{
+        Context,
+        Poll,
+    },
+    time::Duration,
+};
+use futures::stream::{
+    FuturesUnordered,
+    Stream,
+};
+use hyper::server::{
+    accept::Accept,
+    conn::{
+        AddrIncoming,
+        AddrStream,
+    },
+};
+use rustls::{
+    ClientHello,
+    NoClientAuth,
+    ResolvesServerCert,
+    ServerConfig,
+    sign::{
+        self,
+        CertifiedKey,
+    },
+};
+use tokio::time::{
+    Elapsed,
+    timeout,
+};
+use tokio_rustls::{
+    TlsAcceptor,
+    server::TlsStream,
+};
+use crate::{
+    config::ConfigError,
+    pem::{
+        read_certs,
+        read_key,
+    },
+};
+
+/// Time for a client to complete the handshake before it is dropped.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The certificate chain and key of the server. A reload affects the
+/// connections accepted after it, established ones are left alone.
+#[derive(Clone)]
+pub struct ServerCert {
+    cert_path: String,
+    key_path: String,
+    current: Arc<RwLock<CertifiedKey>>,
+}
+
+impl ServerCert {
+    pub fn load(cert_path: &str, key_path: &str) -> Result<Self, ConfigError> {
+        Ok(ServerCert {
+            cert_path: cert_path.to_owned(),
+            key_path: key_path.to_owned(),
+            current: Arc::new(RwLock::new(read(cert_path, key_path)?)),
+        })
+    }
+
+    /// Read the files again, the current certificate is kept if they are not valid.
+    pub fn reload(&self) -> Result<(), ConfigError> {
+        let cert = read(&self.cert_path, &self.key_path)?;
+        if let Ok(mut current) = self.current.write() {
+            *current = cert;
+        }
+        Ok(())
+    }
+}
+
+impl ResolvesServerCert for ServerCert {
+    fn resolve(&self, _client_hello: ClientHello) -> Option<CertifiedKey> {
+        self.current.read().ok().map(|cert| cert.clone())
+    }
+}
+
+fn read(cert_path: &str, key_path: &str) -> Result<CertifiedKey, ConfigError> {
+    let certs = read_certs("server.tls_cert", cert_path)?;
+    let key = sign::any_supported_type(&read_key("server.tls_key", key_path)?)
+        .map_err(|()| ConfigError::Invalid("server.tls_key", format!("{}: unsupported private key.", key_path)))?;
+    let cert = CertifiedKey::new(certs, Arc::new(key));
+    cert.cross_check_end_entity_cert(None)
+        .map_err(|err| ConfigError::Invalid("server.tls_cert", format!("{}: {}", cert_path, err)))?;
+    Ok(cert)
+}
+
+type Handshake = Pin<Box<dyn Future<Output = Result<io::Result<TlsStream<AddrStream>>, Elapsed>> + Send>>;
+
+/// Accepts TCP connections and completes their handshakes concurrently,
+/// yielding the established ones. Failed handshakes are only logged since
+/// an error would stop the server.
+pub struct TlsIncoming {
+    incoming: AddrIncoming,
+    acceptor: TlsAcceptor,
+    handshakes: FuturesUnordered<Handshake>,
+}
+
+impl TlsIncoming {
+    pub fn new(incoming: AddrIncoming, cert: ServerCert) -> Self {
+        let mut config = ServerConfig::new(NoClientAuth::new());
+        config.cert_resolver = Arc::new(cert);
+        config.set_protocols(&[b"h2".to_vec(), b"http/1.1".to_vec()]);
+        TlsIncoming {
+            incoming,
+            acceptor: TlsAcceptor::from(Arc::new(config)),
+            handshakes: FuturesUnordered::new(),
+        }
+    }
+}
+
+impl Accept for TlsIncoming {
+    type Conn = TlsStream<AddrStream>;
+    type Error = io::Error;
+
+    fn poll_accept(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<io::Result<Self::Conn>>> {
+        let this = self.get_mut();
+        loop {
+            match Pin::new(&mut this.incoming).poll_accept(cx) {
+                Poll::Ready(Some(Ok(stream))) => {
+                    this.handshakes.push(Box::pin(timeout(HANDSHAKE_TIMEOUT, this.acceptor.accept(stream))));
+                }
+                Poll::Ready(Some(Err(err))) => return Poll::Ready(Some(Err(err))),
+                Poll::Ready(None) => return Poll::Ready(None),
+                Poll::Pending => break,
+            }
+        }
+        loop {
+            match Pin::new(&mut this.handshakes).poll_next(cx) {
+                Poll::Ready(Some(Ok(Ok(stream)))) => return Poll::Ready(Some(Ok(stream))),
+                Poll::Ready(Some(Ok(Err(err)))) => debug!("TLS handshake failed: {}", err),
+                Poll::Ready(Some(Err(_))) => debug!("TLS handshake timed out."),
+                // No handshake in progress, woken by the listener.
+                Poll::Ready(None) | Poll::Pending => return Poll::Pending,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        fs,
+        net::{SocketAddr, TcpListener},
+        sync::Arc,
+    };
+    use hyper::{
+        Body,
+        Request,
+        client::conn::{
+            SendRequest,
+            handshake,
+        },
+    };
+    use rustls::{
+        ClientConfig,
+        Session,
+    };
+    use tempfile::TempDir;
+    use tokio::{
+        net::TcpStream,
+        sync::oneshot,
+    };
+    use tokio_rustls::TlsConnector;
+    use warp::Filter;
+    use webpki::DNSNameRef;
+    use crate::{
+        server::{
+            Connections,
+            bind,
+        },
+        testing::{
+            ca_certificate,
+            signed_certificate,
+        },
+    };
+    use super::ServerCert;
+
+    /// Returns the certificate presented by the server and a client on the connection.
+    async fn connect(address: SocketAddr, ca: &str) -> (Vec<u8>, SendRequest<Body>) {
+        let mut config = ClientConfig::new();
+        config.root_store.add_pem_file(&mut ca.as_bytes()).unwrap();
+        let stream = TcpStream::connect(address).await.unwrap();
+        let stream = TlsConnector::from(Arc::new(config))
+            .connect(DNSNameRef::try_from_ascii_str("localhost").unwrap(), stream)
+            .await
+            .unwrap();
+        let cert = stream.get_ref().1.get_peer_certificates().unwrap()[0].0.clone();
+        let (client, connection) = handshake(stream).await.unwrap();
+        tokio::spawn(connection);
+        (cert, client)
+    }
+
+    async fn get(client: &mut SendRequest<Body>) -> String {
+        let request = Request::get("/").header("host", "localhost").body(Body::empty()).unwrap();
+        let response = client.send_request(request).await.unwrap();
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        String::from_utf8(body.to_vec()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_reload() {
+        let dir = TempDir::new().unwrap();
+        let cert_path = dir.path().join("server.crt");
+        let key_path = dir.path().join("server.key");
+        let ca = ca_certificate("pigskit test ca");
+        let ca_pem = ca.serialize_pem().unwrap();
+        let write_cert = || {
+            let (cert, key) = signed_certificate(&ca, "localhost", vec!["localhost".to_string()]);
+            fs::write(&cert_path, &cert).unwrap();
+            fs::write(&key_path, key).unwrap();
+            rustls::internal::pemfile::certs(&mut cert.as_bytes()).unwrap()[0].0.clone()
+        };
+        let first = write_cert();
+        let cert = ServerCert::load(cert_path.to_str().unwrap(), key_path.to_str().unwrap()).unwrap();
+
+        let address = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+        let (shutdown_tx, shutdown_rx) = oneshot::channel::<()>();
+        let routes = warp::any().map(|| "pigskit").boxed();
+        let server = bind(routes, address, Some(cert.clone()), None, Connections::default(), async {
+            let _ = shutdown_rx.await;
+        }).unwrap();
+        let server = tokio::spawn(server);
+
+        let (served, mut established) = connect(address, &ca_pem).await;
+        assert_eq!(served, first);
+        assert_eq!(get(&mut established).await, "pigskit");
+
+        // A broken file keeps the current certificate.
+        fs::write(&key_path, "").unwrap();
+        assert!(cert.reload().is_err());
+        assert_eq!(connect(address, &ca_pem).await.0, first);
+
+        let second = write_cert();
+        cert.reload().unwrap();
+        let (served, mut client) = connect(address, &ca_pem).await;
+        assert_eq!(served, second);
+        assert_eq!(get(&mut client).await, "pigskit");
+        // Connections made before the reload are kept.
+        assert_eq!(get(&mut established).await, "pigskit");
+
+        drop((client, established));
+        let _ = shutdown_tx.send(());
+        server.await.unwrap();
+    }
+}
@@ -0,0 +1,16 @@
+use async_trait::async_trait;
+use super::{
+    SmsError,
+    SmsSender,
+};
+
+/// Logs the messages instead of sending them, for local development.
+pub struct LogSender;
+
+#[async_trait]
+impl SmsSender for LogSender {
+    async fn send(&self, to: &str, text: &str) -> Result<(), SmsError> {
+        info!("Text message to {}: {}", to, text);
+        Ok(())
+    }
+}
@@ -0,0 +1,60 @@
+//! Text messages to the users, delivered by the configured provider.
+
+use std::{
+    fmt::{self, Display},
+    sync::Arc,
+};
+use async_trait::async_trait;
+use crate::config::{
+    SmsBackend,
+    SmsConfig,
+};
+
+mod log;
+
+pub use self::log::LogSender;
+
+#[derive(Debug)]
+pub enum SmsError {
+    /// The provider refused or failed to send the message.
+    Provider(String),
+}
+
+impl Display for SmsError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SmsError::Provider(err) => write!(f, "SMS provider: {}", err),
+        }
+    }
+}
+
+#[async_trait]
+pub trait SmsSender: Send + Sync {
+    async fn send(&self, to: &str, text: &str) -> Result<(), SmsError>;
+}
+
+/// The configured SMS provider.
+#[derive(Clone)]
+pub struct Sms {
+    sender: Arc<dyn SmsSender>,
+}
+
+impl Sms {
+    pub fn new(sender: Arc<dyn SmsSender>) -> Self {
+        Sms {
+            sender,
+        }
+    }
+
+    pub fn open(config: &SmsConfig) -> Self {
+        match config.backend {
+            SmsBackend::Log => Sms::new(Arc::new(LogSender)),
+        }
+    }
+
+    pub async fn send(&self, to: &str, text: &str) -> Result<(), SmsError> {
+        self.sender.send(to, text).await?;
+        debug!("Sent a text message to {}.", to);
+        Ok(())
+    }
+}
@@ -0,0 +1,67 @@
+//! Carts of guest sessions, one per shop, and their items.
+
+use tokio_postgres::GenericClient;
+use uuid::Uuid;
+use crate::error::Error;
+use super::{
+    IntNN,
+    TextNN,
+    UuidNN,
+    execute,
+    query_one,
+};
+
+/// Create the cart of the shop if it does not exist yet, starting a guest
+/// session if `gssid` is not a valid one. Returns the guest session.
+pub async fn put<C: GenericClient + Sync>(client: &C, gssid: Option<Uuid>, shop_id: Uuid) -> Result<Uuid, Error> {
+    let (gssid,) = query_one(
+        client,
+        "SELECT put_cart($1, $2)",
+        &[&gssid, &UuidNN(shop_id)],
+    ).await?;
+    Ok(gssid)
+}
+
+/// The key of the new item, `cus_sel` is a JSON object of the customize selections.
+pub async fn create_item<C: GenericClient + Sync>(
+    client: &C,
+    gssid: Uuid,
+    shop_id: Uuid,
+    product_key: Uuid,
+    remark: Option<&str>,
+    count: i32,
+    cus_sel: &str,
+) -> Result<Uuid, Error> {
+    let (item_key,) = query_one(
+        client,
+        "SELECT cart_create_item($1, $2, $3, $4, $5, $6)",
+        &[
+            &UuidNN(gssid),
+            &UuidNN(shop_id),
+            &UuidNN(product_key),
+            &remark,
+            &IntNN(count),
+            &TextNN(cus_sel.to_string()),
+        ],
+    ).await?;
+    Ok(item_key)
+}
+
+/// `payload` is a JSON object with any of the members "remark", "count" and "cus_sel".
+pub async fn update_item<C: GenericClient + Sync>(client: &C, gssid: Uuid, shop_id: Uuid, item_key: Uuid, payload: &str) -> Result<(), Error> {
+    execute(
+        client,
+        "SELECT cart_update_item($1, $2, $3, $4)",
+        &[&UuidNN(gssid), &UuidNN(shop_id), &UuidNN(item_key), &TextNN(payload.to_string())],
+    ).await?;
+    Ok(())
+}
+
+pub async fn delete_item<C: GenericClient + Sync>(client: &C, gssid: Uuid, shop_id: Uuid, item_key: Uuid) -> Result<(), Error> {
+    execute(
+        client,
+        "SELECT cart_delete_item($1, $2, $3)",
+        &[&UuidNN(gssid), &UuidNN(shop_id), &UuidNN(item_key)],
+    ).await?;
+    Ok(())
+}
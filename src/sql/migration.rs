@@ -0,0 +1,179 @@
+use clap::ArgMatches;
+use chrono::{
+    DateTime,
+    Utc,
+};
+use tokio_postgres::{
+    Client,
+    Error,
+};
+
+/// Advisory lock key serializing concurrent migration runs.
+const LOCK_KEY: i64 = 0x0070_6967_736b_6974;
+
+pub struct Migration {
+    pub version: i32,
+    pub name: &'static str,
+    up: &'static str,
+    down: &'static str,
+}
+
+macro_rules! migration {
+    ($version:expr, $name:expr, $file:expr) => {
+        Migration {
+            version: $version,
+            name: $name,
+            up: include_str!(concat!("../../migrations/", $file, ".up.sql")),
+            down: include_str!(concat!("../../migrations/", $file, ".down.sql")),
+        }
+    };
+}
+
+/// The migrations embedded in the binary, in the order of their versions.
+pub const MIGRATIONS: &[Migration] = &[
+    migration!(1, "init", "0001_init"),
+    migration!(2, "ratelimit", "0002_ratelimit"),
+    migration!(3, "password_reset", "0003_password_reset"),
+    migration!(4, "email_verification", "0004_email_verification"),
+    migration!(5, "phone_verification", "0005_phone_verification"),
+    migration!(6, "session_metadata", "0006_session_metadata"),
+    migration!(7, "sliding_session", "0007_sliding_session"),
+    migration!(8, "two_factor", "0008_two_factor"),
+];
+
+/// The schema version this build of the server expects.
+pub fn expected_version() -> i32 {
+    MIGRATIONS.last().map(|migration| migration.version).unwrap_or(0)
+}
+
+async fn table_exists(client: &Client) -> Result<bool, Error> {
+    let row = client.query_one(
+        "SELECT to_regclass('schema_migrations') IS NOT NULL AS exists",
+        &[],
+    ).await?;
+    Ok(row.get("exists"))
+}
+
+async fn ensure_table(client: &Client) -> Result<(), Error> {
+    if table_exists(client).await? {
+        return Ok(())
+    }
+    client.batch_execute(
+        "CREATE TABLE IF NOT EXISTS schema_migrations (
+            version integer PRIMARY KEY,
+            name text NOT NULL,
+            applied_at timestamptz NOT NULL DEFAULT now()
+        )"
+    ).await
+}
+
+/// The version of the latest applied migration, 0 for an empty database.
+pub async fn current_version(client: &Client) -> Result<i32, Error> {
+    if !table_exists(client).await? {
+        return Ok(0)
+    }
+    let row = client.query_one(
+        "SELECT COALESCE(MAX(version), 0) AS version FROM schema_migrations",
+        &[],
+    ).await?;
+    Ok(row.get("version"))
+}
+
+/// Apply all pending migrations, each in its own transaction.
+pub async fn up(client: &mut Client) -> Result<Vec<&'static Migration>, Error> {
+    ensure_table(client).await?;
+    let mut applied = Vec::new();
+    for migration in MIGRATIONS.iter() {
+        let transaction = client.transaction().await?;
+        transaction.execute("SELECT pg_advisory_xact_lock($1)", &[&LOCK_KEY]).await?;
+        let done = transaction.query_opt(
+            "SELECT version FROM schema_migrations WHERE version = $1",
+            &[&migration.version],
+        ).await?;
+        if done.is_some() {
+            continue
+        }
+        transaction.batch_execute(migration.up).await?;
+        transaction.execute(
+            "INSERT INTO schema_migrations (version, name) VALUES ($1, $2)",
+            &[&migration.version, &migration.name],
+        ).await?;
+        transaction.commit().await?;
+        applied.push(migration);
+    }
+    Ok(applied)
+}
+
+/// Revert the latest applied migration.
+pub async fn down(client: &mut Client) -> Result<Option<&'static Migration>, Error> {
+    ensure_table(client).await?;
+    let transaction = client.transaction().await?;
+    transaction.execute("SELECT pg_advisory_xact_lock($1)", &[&LOCK_KEY]).await?;
+    let row = transaction.query_opt(
+        "SELECT version FROM schema_migrations ORDER BY version DESC LIMIT 1",
+        &[],
+    ).await?;
+    let version: i32 = match row {
+        Some(row) => row.get("version"),
+        None => return Ok(None),
+    };
+    let migration = match MIGRATIONS.iter().find(|migration| migration.version == version) {
+        Some(migration) => migration,
+        None => return Ok(None),
+    };
+    transaction.batch_execute(migration.down).await?;
+    transaction.execute(
+        "DELETE FROM schema_migrations WHERE version = $1",
+        &[&migration.version],
+    ).await?;
+    transaction.commit().await?;
+    Ok(Some(migration))
+}
+
+/// Every known migration with the time it was applied, if it was.
+pub async fn status(client: &Client) -> Result<Vec<(&'static Migration, Option<DateTime<Utc>>)>, Error> {
+    ensure_table(client).await?;
+    let rows = client.query("SELECT version, applied_at FROM schema_migrations", &[]).await?;
+    Ok(
+        MIGRATIONS.iter()
+        .map(|migration| {
+            let applied_at = rows.iter()
+                .find(|row| row.get::<&str, i32>("version") == migration.version)
+                .map(|row| row.get("applied_at"));
+            (migration, applied_at)
+        })
+        .collect()
+    )
+}
+
+/// Run the `migrate` sub command.
+pub async fn command(client: &mut Client, args: &ArgMatches<'_>) -> Result<(), Error> {
+    match args.subcommand_name() {
+        Some("up") => {
+            let applied = up(client).await?;
+            if applied.is_empty() {
+                println!("Database schema is up to date.");
+            }
+            for migration in applied {
+                println!("Applied {:04}_{}.", migration.version, migration.name);
+            }
+        }
+        Some("down") => {
+            if let Some(migration) = down(client).await? {
+                println!("Reverted {:04}_{}.", migration.version, migration.name);
+            } else {
+                println!("No migration to revert.");
+            }
+        }
+        _ => {
+            for (migration, applied_at) in status(client).await? {
+                if let Some(applied_at) = applied_at {
+                    println!("{:04}_{}\tapplied at {}", migration.version, migration.name, applied_at.to_rfc3339());
+                } else {
+                    println!("{:04}_{}\tpending", migration.version, migration.name);
+                }
+            }
+        }
+    }
+    Ok(())
+}
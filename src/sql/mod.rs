@@ -5,7 +5,21 @@ use std::{
 };
 use postgres_types::{ToSql, FromSql};
 use serde::de::{self, Deserialize, Deserializer};
+use tokio_postgres::{
+    GenericClient,
+    Row,
+};
 use uuid::Uuid;
+use crate::error::Error;
+
+pub mod migration;
+pub mod users;
+pub mod shops;
+pub mod products;
+pub mod carts;
+pub mod orders;
+pub mod ratelimit;
+pub mod totp;
 
 #[derive(Serialize, Deserialize, Debug, ToSql, FromSql)]
 #[postgres(name = "permission")]
@@ -84,32 +98,87 @@ pub fn from_str<'de, T, D>(deserializer: D) -> Result<T, D::Error>
     T::from_str(&s).map_err(de::Error::custom)
 }
 
-#[macro_export]
-macro_rules! query_one {
-    (
-        $conn:ident,
-        $statement:expr,
-        $params:expr,
-        ($($column:ident: $type:ty),+),
-    ) => {
-        match $conn.query_one($statement, $params).await {
-            Ok(row) => {
-                let res: ($($type,)+) = ($(row.get(stringify!($column)),)+);
-                Ok(res)
-            },
-            Err(error) => Err(error),
+/// A type built from a row of a query result, `#[derive(FromRow)]` reads each
+/// field from the column of the same name and tuples read the columns in order.
+///
+/// Columns of the wrong type or missing are errors rather than panics.
+pub trait FromRow: Sized {
+    fn from_row(row: &Row) -> Result<Self, tokio_postgres::Error>;
+}
+
+macro_rules! impl_from_row_for_tuple {
+    ( $( $type:ident $index:tt ),+ ) => {
+        impl<$( $type ),+> FromRow for ($( $type, )+)
+        where
+            $( $type: for<'a> FromSql<'a> ),+
+        {
+            fn from_row(row: &Row) -> Result<Self, tokio_postgres::Error> {
+                Ok(($( row.try_get::<_, $type>($index)?, )+))
+            }
         }
     }
 }
 
+impl_from_row_for_tuple!(A 0);
+impl_from_row_for_tuple!(A 0, B 1);
+impl_from_row_for_tuple!(A 0, B 1, C 2);
+impl_from_row_for_tuple!(A 0, B 1, C 2, D 3);
+
+pub type Params<'a> = [&'a (dyn ToSql + Sync)];
+
+/// Exactly one row, a missing row is an error.
+pub async fn query_one<T, C>(client: &C, statement: &str, params: &Params<'_>) -> Result<T, Error>
+where
+    T: FromRow,
+    C: GenericClient + Sync,
+{
+    let row = client.query_one(statement, params).await?;
+    Ok(T::from_row(&row)?)
+}
+
+/// At most one row.
+pub async fn query_opt<T, C>(client: &C, statement: &str, params: &Params<'_>) -> Result<Option<T>, Error>
+where
+    T: FromRow,
+    C: GenericClient + Sync,
+{
+    match client.query_opt(statement, params).await? {
+        Some(row) => Ok(Some(T::from_row(&row)?)),
+        None => Ok(None),
+    }
+}
+
+pub async fn query_all<T, C>(client: &C, statement: &str, params: &Params<'_>) -> Result<Vec<T>, Error>
+where
+    T: FromRow,
+    C: GenericClient + Sync,
+{
+    let rows = client.query(statement, params).await?;
+    let mut results = Vec::with_capacity(rows.len());
+    for row in rows.iter() {
+        results.push(T::from_row(row)?);
+    }
+    Ok(results)
+}
+
+/// The number of rows affected.
+pub async fn execute<C>(client: &C, statement: &str, params: &Params<'_>) -> Result<u64, Error>
+where
+    C: GenericClient + Sync,
+{
+    Ok(client.execute(statement, params).await?)
+}
+
 #[cfg(test)]
 mod test {
     use postgres_types::{ToSql, FromSql};
-    use crate::state::init_pool;
-    use crate::PG_CONFIG_DEV;
+    use crate::testing::TestServer;
     use super::{
         TextNZ,
         IntNN,
+        query_all,
+        query_one,
+        query_opt,
     };
 
     #[derive(Serialize, Deserialize, Debug, ToSql, FromSql)]
@@ -119,16 +188,39 @@ mod test {
         price: IntNN,
     }
 
+    #[derive(Debug, PartialEq, FromRow)]
+    struct Pair {
+        id: i32,
+        name: Option<String>,
+    }
+
     #[tokio::test]
     async fn test_option() {
-        let pool = init_pool(PG_CONFIG_DEV, 1).await;
-        let conn = pool.get().await.unwrap();
-        let (opt,) = query_one!(
-            conn,
-            "SELECT option_create('new', 123)",
-            &[],
-            (option_create: SqlOption),
-        ).unwrap();
-        println!("opt:{:?}", opt);
+        let server = TestServer::start().await;
+        let conn = server.state.db_pool().get().await.unwrap();
+        let (opt,): (SqlOption,) = query_one(&*conn, "SELECT option_create('new', 123)", &[]).await.unwrap();
+        assert_eq!(opt.name.0, "new");
+        assert_eq!(opt.price.0, 123);
+    }
+
+    #[tokio::test]
+    async fn test_query() {
+        let server = TestServer::start().await;
+        let conn = server.state.db_pool().get().await.unwrap();
+        let pairs = "SELECT * FROM (VALUES (1, 'a'), (2, NULL)) AS pair (id, name) WHERE id >= $1 ORDER BY id";
+
+        let all: Vec<Pair> = query_all(&*conn, pairs, &[&1]).await.unwrap();
+        assert_eq!(all, vec![
+            Pair { id: 1, name: Some("a".to_string()) },
+            Pair { id: 2, name: None },
+        ]);
+        let none: Option<Pair> = query_opt(&*conn, pairs, &[&3]).await.unwrap();
+        assert_eq!(none, None);
+        assert!(query_one::<Pair, _>(&*conn, pairs, &[&3]).await.is_err());
+
+        // Mismatched and missing columns are errors rather than panics.
+        let err = query_one::<(String,), _>(&*conn, "SELECT 1", &[]).await.err().unwrap();
+        assert!(err.is_inner());
+        assert!(query_one::<Pair, _>(&*conn, "SELECT 1 AS id", &[]).await.is_err());
     }
-}
\ No newline at end of file
+}
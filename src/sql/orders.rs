@@ -0,0 +1,19 @@
+//! Orders placed from carts.
+
+use tokio_postgres::GenericClient;
+use uuid::Uuid;
+use crate::error::Error;
+use super::{
+    UuidNN,
+    query_one,
+};
+
+/// Turn the items of the cart into an order, returns the order.
+pub async fn create<C: GenericClient + Sync>(client: &C, gssid: Uuid, shop_id: Uuid) -> Result<Uuid, Error> {
+    let (order_id,) = query_one(
+        client,
+        "SELECT create_order($1, $2)",
+        &[&UuidNN(gssid), &UuidNN(shop_id)],
+    ).await?;
+    Ok(order_id)
+}
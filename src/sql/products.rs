@@ -0,0 +1,48 @@
+//! Products of shops, their payload is a JSON document.
+
+use tokio_postgres::GenericClient;
+use uuid::Uuid;
+use crate::error::Error;
+use super::{
+    TextNN,
+    UuidNN,
+    execute,
+    query_one,
+};
+
+/// The key of the new product.
+pub async fn create<C: GenericClient + Sync>(client: &C, shop_id: Uuid, payload: &str) -> Result<Uuid, Error> {
+    let (product_key,) = query_one(
+        client,
+        "SELECT product_key FROM shop_create_product($1, $2)",
+        &[&UuidNN(shop_id), &TextNN(payload.to_string())],
+    ).await?;
+    Ok(product_key)
+}
+
+pub async fn update<C: GenericClient + Sync>(client: &C, shop_id: Uuid, product_key: Uuid, payload: &str) -> Result<(), Error> {
+    execute(
+        client,
+        "SELECT shop_update_product($1, $2, $3)",
+        &[&UuidNN(shop_id), &UuidNN(product_key), &TextNN(payload.to_string())],
+    ).await?;
+    Ok(())
+}
+
+pub async fn set_has_picture<C: GenericClient + Sync>(client: &C, shop_id: Uuid, product_key: Uuid, has_picture: bool) -> Result<(), Error> {
+    execute(
+        client,
+        "SELECT shop_set_product_has_picture($1, $2, $3)",
+        &[&UuidNN(shop_id), &UuidNN(product_key), &has_picture],
+    ).await?;
+    Ok(())
+}
+
+pub async fn delete<C: GenericClient + Sync>(client: &C, shop_id: Uuid, product_key: Uuid) -> Result<(), Error> {
+    execute(
+        client,
+        "SELECT shop_delete_product($1, $2)",
+        &[&UuidNN(shop_id), &UuidNN(product_key)],
+    ).await?;
+    Ok(())
+}
@@ -0,0 +1,62 @@
+//! Rate limit buckets and sign-in failures shared by the instances.
+
+use std::time::Duration;
+use tokio_postgres::GenericClient;
+use crate::error::Error;
+use super::{
+    TextNZ,
+    execute,
+    query_one,
+    query_opt,
+};
+
+/// Take a token of the bucket, returns how long until one is available if it is empty.
+pub async fn take<C: GenericClient + Sync>(client: &C, key: &str, burst: u32, per_second: f64) -> Result<Option<Duration>, Error> {
+    let (wait,): (f64,) = query_one(
+        client,
+        "SELECT rate_limit_take($1, $2, $3)",
+        &[&TextNZ(key.to_string()), &(burst as f64), &per_second],
+    ).await?;
+    Ok(if wait > 0.0 { Some(Duration::from_secs_f64(wait)) } else { None })
+}
+
+/// Count a failure, returns the failures in a row.
+pub async fn fail<C: GenericClient + Sync>(client: &C, key: &str, window: Duration) -> Result<u32, Error> {
+    let (failures,): (i32,) = query_one(
+        client,
+        "SELECT rate_limit_fail($1, $2)",
+        &[&TextNZ(key.to_string()), &window.as_secs_f64()],
+    ).await?;
+    Ok(failures as u32)
+}
+
+pub async fn lock<C: GenericClient + Sync>(client: &C, key: &str, duration: Duration) -> Result<(), Error> {
+    execute(
+        client,
+        "INSERT INTO rate_limit_failure (key, failures, last_failure_at, locked_until)
+         VALUES ($1, 0, clock_timestamp(), clock_timestamp() + make_interval(secs => $2))
+         ON CONFLICT (key) DO UPDATE SET locked_until = EXCLUDED.locked_until",
+        &[&key, &duration.as_secs_f64()],
+    ).await?;
+    Ok(())
+}
+
+/// How long the key stays locked.
+pub async fn locked<C: GenericClient + Sync>(client: &C, key: &str) -> Result<Option<Duration>, Error> {
+    let remaining: Option<(f64,)> = query_opt(
+        client,
+        "SELECT EXTRACT(EPOCH FROM locked_until - clock_timestamp())::double precision
+         FROM rate_limit_failure WHERE key = $1 AND locked_until > clock_timestamp()",
+        &[&key],
+    ).await?;
+    Ok(remaining.map(|(seconds,)| Duration::from_secs_f64(seconds.max(0.0))))
+}
+
+pub async fn reset<C: GenericClient + Sync>(client: &C, key: &str) -> Result<(), Error> {
+    execute(
+        client,
+        "DELETE FROM rate_limit_failure WHERE key = $1",
+        &[&key],
+    ).await?;
+    Ok(())
+}
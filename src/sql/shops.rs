@@ -0,0 +1,74 @@
+//! Shops and the authorities of their members.
+
+use tokio_postgres::GenericClient;
+use uuid::Uuid;
+use crate::error::Error;
+use super::{
+    Authority,
+    AuthorityNN,
+    Permission,
+    PermissionNN,
+    TextNZ,
+    UuidNN,
+    execute,
+    query_one,
+};
+
+/// Fails with `ShopNameUsed` if another shop has the name.
+pub async fn create<C: GenericClient + Sync>(client: &C, user_id: Uuid, shop_name: &str) -> Result<Uuid, Error> {
+    let (shop_id,) = query_one(
+        client,
+        "SELECT create_shop($1, $2)",
+        &[&UuidNN(user_id), &TextNZ(shop_name.to_string())],
+    ).await?;
+    Ok(shop_id)
+}
+
+/// Whether the user has `permission` of `authority` in the shop.
+pub async fn check_authority<C: GenericClient + Sync>(
+    client: &C,
+    shop_id: Uuid,
+    user_id: Uuid,
+    authority: Authority,
+    permission: Permission,
+) -> Result<bool, Error> {
+    let (ok,) = query_one(
+        client,
+        "SELECT check_shop_user_authority($1, $2, $3, $4)",
+        &[&UuidNN(shop_id), &UuidNN(user_id), &AuthorityNN(authority), &PermissionNN(permission)],
+    ).await?;
+    Ok(ok)
+}
+
+/// Add a member to the shop, the user needs all of the member authority.
+pub async fn create_member<C: GenericClient + Sync>(client: &C, user_id: Uuid, shop_id: Uuid, member_id: Uuid) -> Result<(), Error> {
+    execute(
+        client,
+        "SELECT shop_user_create($1, $2, $3)",
+        &[&UuidNN(user_id), &UuidNN(shop_id), &UuidNN(member_id)],
+    ).await?;
+    Ok(())
+}
+
+/// Set the permission of a member, the user needs all of the member authority.
+pub async fn update_member_authority<C: GenericClient + Sync>(
+    client: &C,
+    user_id: Uuid,
+    shop_id: Uuid,
+    member_id: Uuid,
+    authority: Authority,
+    permission: Permission,
+) -> Result<(), Error> {
+    execute(
+        client,
+        "SELECT shop_user_update_authority($1, $2, $3, $4, $5)",
+        &[
+            &UuidNN(user_id),
+            &UuidNN(shop_id),
+            &UuidNN(member_id),
+            &AuthorityNN(authority),
+            &PermissionNN(permission),
+        ],
+    ).await?;
+    Ok(())
+}
@@ -0,0 +1,69 @@
+//! Two-factor authentication of the users with codes from an authenticator
+//! app, and the recovery codes standing in for it.
+
+use tokio_postgres::GenericClient;
+use uuid::Uuid;
+use crate::error::Error;
+use super::{
+    IntNN,
+    TextNZ,
+    UuidNN,
+    query_one,
+};
+
+#[derive(Serialize, FromRow)]
+pub struct Status {
+    pub enabled: bool,
+    /// Recovery codes not used yet.
+    pub recovery_codes: i32,
+}
+
+pub async fn status<C: GenericClient + Sync>(client: &C, user_id: Uuid) -> Result<Status, Error> {
+    query_one(
+        client,
+        "SELECT _enabled AS enabled, _recovery_codes AS recovery_codes FROM get_user_totp($1)",
+        &[&UuidNN(user_id)],
+    ).await
+}
+
+/// Set up a new secret for the user, returns it and the
+/// username. Two-factor authentication is enabled once a code confirms it.
+pub async fn create<C: GenericClient + Sync>(client: &C, user_id: Uuid) -> Result<(Vec<u8>, String), Error> {
+    query_one(
+        client,
+        "SELECT * FROM create_user_totp($1)",
+        &[&UuidNN(user_id)],
+    ).await
+}
+
+/// Enable the secret set up with a code of it, returns the recovery codes.
+/// `None` if the code is wrong.
+pub async fn enable<C: GenericClient + Sync>(client: &C, user_id: Uuid, code: &str, recovery_codes: u32) -> Result<Option<Vec<String>>, Error> {
+    let (codes,) = query_one(
+        client,
+        "SELECT enable_user_totp($1, $2, $3)",
+        &[&UuidNN(user_id), &TextNZ(code.to_string()), &IntNN(recovery_codes as i32)],
+    ).await?;
+    Ok(codes)
+}
+
+/// Replace the recovery codes for a code of the app, `None` if it is wrong.
+pub async fn renew_recovery_codes<C: GenericClient + Sync>(client: &C, user_id: Uuid, code: &str, recovery_codes: u32) -> Result<Option<Vec<String>>, Error> {
+    let (codes,) = query_one(
+        client,
+        "SELECT renew_recovery_codes($1, $2, $3)",
+        &[&UuidNN(user_id), &TextNZ(code.to_string()), &IntNN(recovery_codes as i32)],
+    ).await?;
+    Ok(codes)
+}
+
+/// Turn two-factor authentication off with a code or a recovery code,
+/// `false` if it is wrong.
+pub async fn disable<C: GenericClient + Sync>(client: &C, user_id: Uuid, code: &str) -> Result<bool, Error> {
+    let (ok,) = query_one(
+        client,
+        "SELECT disable_user_totp($1, $2)",
+        &[&UuidNN(user_id), &TextNZ(code.to_string())],
+    ).await?;
+    Ok(ok)
+}
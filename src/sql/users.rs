@@ -0,0 +1,409 @@
+//! Users, their sessions and the register sessions creating them.
+
+use std::{
+    net::IpAddr,
+    time::Duration,
+};
+use chrono::{
+    DateTime,
+    Utc,
+};
+use tokio_postgres::GenericClient;
+use uuid::Uuid;
+use crate::{
+    config::{
+        Config,
+        SmsConfig,
+    },
+    error::Error,
+};
+use super::{
+    IntNN,
+    TextNZ,
+    UuidNN,
+    execute,
+    query_all,
+    query_one,
+    query_opt,
+};
+
+/// Longest user agent kept with a session, in characters.
+const USER_AGENT_MAX_CHARS: usize = 512;
+
+/// A field of a register session, filled in one at a time.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RegisterField {
+    Email,
+    Phone,
+    Username,
+    Password,
+}
+
+impl RegisterField {
+    pub fn column(self) -> &'static str {
+        match self {
+            RegisterField::Email => "email",
+            RegisterField::Phone => "phone",
+            RegisterField::Username => "username",
+            RegisterField::Password => "password",
+        }
+    }
+
+    /// Assignments discarding the verification of the previous value.
+    fn unverify(self) -> &'static str {
+        match self {
+            RegisterField::Email => ", email_verified = false, email_code = NULL, email_code_expire_at = NULL",
+            RegisterField::Phone => ", phone_verified = false",
+            _ => "",
+        }
+    }
+
+    /// Whether the value may only belong to one user.
+    pub fn is_unique(self) -> bool {
+        self != RegisterField::Password
+    }
+}
+
+/// A signed in session as listed to its user, `key` names it in place of
+/// the id which is the secret of the cookie.
+#[derive(Serialize, FromRow)]
+pub struct Session {
+    pub key: Uuid,
+    pub created_at: DateTime<Utc>,
+    pub last_seen_at: DateTime<Utc>,
+    pub ip: Option<IpAddr>,
+    pub user_agent: Option<String>,
+    pub current: bool,
+}
+
+#[derive(Serialize, FromRow)]
+pub struct User {
+    pub username: Option<String>,
+    pub nickname: Option<String>,
+    pub email: Option<String>,
+    pub phone: Option<String>,
+}
+
+pub async fn create_register_session<C: GenericClient + Sync>(client: &C) -> Result<Uuid, Error> {
+    let (regssid,) = query_one(
+        client,
+        "INSERT INTO user_register_session (id) VALUES (uuid_generate_v4()) RETURNING id",
+        &[],
+    ).await?;
+    Ok(regssid)
+}
+
+pub async fn delete_register_session<C: GenericClient + Sync>(client: &C, regssid: Uuid) -> Result<(), Error> {
+    execute(
+        client,
+        "DELETE FROM user_register_session WHERE id = $1",
+        &[&regssid],
+    ).await?;
+    Ok(())
+}
+
+/// The value of a field, `None` if it is not filled in yet.
+pub async fn get_register_field<C: GenericClient + Sync>(client: &C, regssid: Uuid, field: RegisterField) -> Result<Option<String>, Error> {
+    let row: Option<(Option<String>,)> = query_opt(
+        client,
+        format!("SELECT {0} FROM user_register_session WHERE id = $1", field.column()).as_str(),
+        &[&regssid],
+    ).await?;
+    row.map(|(data,)| data).ok_or_else(|| Error::session_expired("REGSSID"))
+}
+
+pub async fn set_register_field<C: GenericClient + Sync>(client: &C, regssid: Uuid, field: RegisterField, data: &str) -> Result<(), Error> {
+    let updated = execute(
+        client,
+        format!("UPDATE user_register_session SET {0} = $1{1} WHERE id = $2", field.column(), field.unverify()).as_str(),
+        &[&data, &regssid],
+    ).await?;
+    if updated == 1 {
+        Ok(())
+    } else {
+        Err(Error::session_expired("REGSSID"))
+    }
+}
+
+/// A new code verifying the email, valid for `minutes`, and the email to send it to.
+pub async fn create_email_code<C: GenericClient + Sync>(client: &C, regssid: Uuid, minutes: u32) -> Result<(String, String), Error> {
+    query_one(
+        client,
+        "SELECT * FROM register_email_code($1, $2)",
+        &[&UuidNN(regssid), &IntNN(minutes as i32)],
+    ).await
+}
+
+/// Verify the email with its code, `false` if it is wrong or expired.
+pub async fn verify_email<C: GenericClient + Sync>(client: &C, regssid: Uuid, code: &str) -> Result<bool, Error> {
+    let (ok,) = query_one(
+        client,
+        "SELECT register_verify_email($1, $2)",
+        &[&UuidNN(regssid), &TextNZ(code.to_string())],
+    ).await?;
+    Ok(ok)
+}
+
+/// The code, or `TooManyRequests` while the number is throttled.
+fn phone_code(code: Option<String>, retry_after: Option<f64>) -> Result<String, Error> {
+    code.ok_or_else(|| Error::too_many_requests(Duration::from_secs_f64(retry_after.unwrap_or(0.0).max(0.0))))
+}
+
+/// A new code verifying the phone and the phone to send it to.
+pub async fn create_register_phone_code<C: GenericClient + Sync>(client: &C, regssid: Uuid, config: &SmsConfig) -> Result<(String, String), Error> {
+    let (phone, code, retry_after): (String, Option<String>, Option<f64>) = query_one(
+        client,
+        "SELECT * FROM register_phone_code($1, $2, $3, $4)",
+        &[
+            &UuidNN(regssid),
+            &IntNN(config.code_minutes as i32),
+            &IntNN(config.resend_seconds as i32),
+            &IntNN(config.max_per_hour as i32),
+        ],
+    ).await?;
+    Ok((phone, phone_code(code, retry_after)?))
+}
+
+/// Verify the phone with its code, `false` if it is wrong or expired.
+pub async fn verify_register_phone<C: GenericClient + Sync>(client: &C, regssid: Uuid, code: &str) -> Result<bool, Error> {
+    let (ok,) = query_one(
+        client,
+        "SELECT register_verify_phone($1, $2)",
+        &[&UuidNN(regssid), &TextNZ(code.to_string())],
+    ).await?;
+    Ok(ok)
+}
+
+/// A new code verifying a new phone of the user.
+pub async fn create_phone_code<C: GenericClient + Sync>(client: &C, user_id: Uuid, phone: &str, config: &SmsConfig) -> Result<String, Error> {
+    let (code, retry_after): (Option<String>, Option<f64>) = query_one(
+        client,
+        "SELECT * FROM user_phone_code($1, $2, $3, $4, $5)",
+        &[
+            &UuidNN(user_id),
+            &TextNZ(phone.to_string()),
+            &IntNN(config.code_minutes as i32),
+            &IntNN(config.resend_seconds as i32),
+            &IntNN(config.max_per_hour as i32),
+        ],
+    ).await?;
+    phone_code(code, retry_after)
+}
+
+/// Set the phone the code was sent to, `false` if the code is wrong or expired.
+pub async fn verify_phone<C: GenericClient + Sync>(client: &C, user_id: Uuid, code: &str) -> Result<bool, Error> {
+    let (ok,) = query_one(
+        client,
+        "SELECT user_verify_phone($1, $2)",
+        &[&UuidNN(user_id), &TextNZ(code.to_string())],
+    ).await?;
+    Ok(ok)
+}
+
+/// Whether a user already has the value of a unique field.
+pub async fn is_taken<C: GenericClient + Sync>(client: &C, field: RegisterField, data: &str) -> Result<bool, Error> {
+    let row: Option<(String,)> = query_opt(
+        client,
+        format!("SELECT {0} FROM users WHERE {0} = $1", field.column()).as_str(),
+        &[&data],
+    ).await?;
+    Ok(row.is_some())
+}
+
+/// Create the user from a complete register session, `false` if it is not.
+pub async fn register<C: GenericClient + Sync>(client: &C, regssid: Uuid) -> Result<bool, Error> {
+    let (ok,) = query_one(
+        client,
+        "SELECT register_user($1)",
+        &[&regssid],
+    ).await?;
+    Ok(ok)
+}
+
+/// What signing in with the right credentials gives.
+pub enum Signin {
+    Session(Uuid),
+    /// The user has two-factor authentication enabled, the challenge is
+    /// redeemed with a code for the session.
+    Challenge(Uuid),
+}
+
+/// Sign in, recording where from. The session lasts as the cookie config
+/// tells for a remembered one or not. `None` if the credentials don't match.
+pub async fn signin<C: GenericClient + Sync>(
+    client: &C,
+    username: &str,
+    password: &str,
+    remember: bool,
+    config: &Config,
+    ip: Option<IpAddr>,
+    user_agent: Option<&str>,
+) -> Result<Option<Signin>, Error> {
+    // In seconds, which `Config::validate` keeps within an `int`.
+    let cookie = &config.cookie;
+    let lifetime = if remember {
+        cookie.user_session_days * 24 * 3600
+    } else {
+        cookie.user_session_browser_hours * 3600
+    };
+    let user_agent = user_agent.map(|agent| agent.chars().take(USER_AGENT_MAX_CHARS).collect::<String>());
+    let (session_id, challenge_id): (Option<Uuid>, Option<Uuid>) = query_one(
+        client,
+        "SELECT * FROM signin_user($1, $2, $3, $4, $5, $6, $7, $8)",
+        &[
+            &TextNZ(username.to_string()),
+            &TextNZ(password.to_string()),
+            &ip,
+            &user_agent,
+            &remember,
+            &IntNN(lifetime as i32),
+            &IntNN((cookie.user_session_max_days * 24 * 3600) as i32),
+            &IntNN(config.totp.challenge_minutes as i32),
+        ],
+    ).await?;
+    Ok(session_id.map(Signin::Session).or(challenge_id.map(Signin::Challenge)))
+}
+
+/// Redeem the sign-in challenge with a code or a recovery code. Returns the
+/// username, and the session and whether it is remembered unless the code
+/// is wrong.
+pub async fn signin_challenge<C: GenericClient + Sync>(client: &C, challenge: Uuid, code: &str) -> Result<(String, Option<(Uuid, bool)>), Error> {
+    let (ussid, remember, username): (Option<Uuid>, Option<bool>, String) = query_one(
+        client,
+        "SELECT * FROM signin_user_challenge($1, $2)",
+        &[&UuidNN(challenge), &TextNZ(code.to_string())],
+    ).await?;
+    Ok((username, ussid.zip(remember)))
+}
+
+/// Extend the session once past half of its lifetime. The new expiry, and
+/// whether the session is remembered, if it was extended.
+pub async fn renew_session<C: GenericClient + Sync>(client: &C, ussid: Uuid) -> Result<Option<(bool, DateTime<Utc>)>, Error> {
+    query_opt(
+        client,
+        "SELECT remember, expire_at FROM renew_user_session($1)",
+        &[&UuidNN(ussid)],
+    ).await
+}
+
+pub async fn signout<C: GenericClient + Sync>(client: &C, ussid: Uuid) -> Result<(), Error> {
+    execute(
+        client,
+        "SELECT signout_user($1)",
+        &[&UuidNN(ussid)],
+    ).await?;
+    Ok(())
+}
+
+/// End every session of the user of the session, this one included.
+pub async fn signout_all<C: GenericClient + Sync>(client: &C, ussid: Uuid) -> Result<(), Error> {
+    execute(
+        client,
+        "SELECT signout_user_everywhere($1)",
+        &[&UuidNN(ussid)],
+    ).await?;
+    Ok(())
+}
+
+/// The live sessions of the user of the session, most recently used first.
+pub async fn sessions<C: GenericClient + Sync>(client: &C, ussid: Uuid) -> Result<Vec<Session>, Error> {
+    query_all(
+        client,
+        "SELECT key, created_at, last_seen_at, ip, user_agent, current FROM get_user_sessions($1)",
+        &[&UuidNN(ussid)],
+    ).await
+}
+
+/// End the session of the user with the key, returns whether it was the
+/// session itself.
+pub async fn revoke_session<C: GenericClient + Sync>(client: &C, ussid: Uuid, key: Uuid) -> Result<bool, Error> {
+    let (current,): (Option<bool>,) = query_one(
+        client,
+        "SELECT revoke_user_session($1, $2)",
+        &[&UuidNN(ussid), &UuidNN(key)],
+    ).await?;
+    current.ok_or_else(|| Error::data_not_found("session"))
+}
+
+/// Change the password of the user of the session, ending the other
+/// sessions. `false` if the current password is wrong.
+pub async fn change_password<C: GenericClient + Sync>(client: &C, ussid: Uuid, current: &str, password: &str) -> Result<bool, Error> {
+    let (ok,) = query_one(
+        client,
+        "SELECT change_user_password($1, $2, $3)",
+        &[&UuidNN(ussid), &TextNZ(current.to_string()), &TextNZ(password.to_string())],
+    ).await?;
+    Ok(ok)
+}
+
+/// A reset token valid for `minutes` and the username, `None` if no user has the email.
+pub async fn create_password_reset<C: GenericClient + Sync>(client: &C, email: &str, minutes: u32) -> Result<Option<(String, String)>, Error> {
+    let (token, username): (Option<String>, Option<String>) = query_one(
+        client,
+        "SELECT * FROM create_password_reset($1, $2)",
+        &[&TextNZ(email.to_string()), &IntNN(minutes as i32)],
+    ).await?;
+    Ok(token.zip(username))
+}
+
+/// Set the password with a reset token and end every session of the user,
+/// `false` if the token is invalid or expired.
+pub async fn redeem_password_reset<C: GenericClient + Sync>(client: &C, token: &str, password: &str) -> Result<bool, Error> {
+    let (ok,) = query_one(
+        client,
+        "SELECT redeem_password_reset($1, $2)",
+        &[&TextNZ(token.to_string()), &TextNZ(password.to_string())],
+    ).await?;
+    Ok(ok)
+}
+
+/// The user of a session and whether less than half of its lifetime is
+/// left, fails with `SessionExpired` if it is not valid.
+pub async fn session_user_renewal<C: GenericClient + Sync>(client: &C, ussid: Uuid) -> Result<(Uuid, bool), Error> {
+    query_one(
+        client,
+        "SELECT
+            get_session_user($1),
+            coalesce((
+                SELECT expire_at < now() + lifetime / 2 AND expire_at < max_expire_at
+                FROM user_session
+                WHERE id = $1
+            ), false)",
+        &[&UuidNN(ussid)],
+    ).await
+}
+
+/// The user of a session, fails with `SessionExpired` if it is not valid.
+pub async fn session_user<C: GenericClient + Sync>(client: &C, ussid: Uuid) -> Result<Uuid, Error> {
+    let (user_id,) = query_one(
+        client,
+        "SELECT get_session_user($1)",
+        &[&UuidNN(ussid)],
+    ).await?;
+    Ok(user_id)
+}
+
+pub async fn get<C: GenericClient + Sync>(client: &C, user_id: Uuid) -> Result<User, Error> {
+    query_one(
+        client,
+        "SELECT
+            username,
+            nickname,
+            email,
+            phone
+        FROM
+            users
+        WHERE
+            id = $1",
+        &[&user_id],
+    ).await
+}
+
+pub async fn set_nickname<C: GenericClient + Sync>(client: &C, user_id: Uuid, nickname: &str) -> Result<(), Error> {
+    execute(
+        client,
+        "UPDATE users SET nickname = $1 WHERE id = $2",
+        &[&nickname, &user_id],
+    ).await?;
+    Ok(())
+}
@@ -1,17 +1,78 @@
-use tokio_postgres::NoTls;
+use tokio_postgres::config::SslMode as PgSslMode;
+use bb8::{
+    PooledConnection,
+    RunError,
+};
 use bb8_postgres::PostgresConnectionManager;
+use crate::{
+    config::{
+        ConfigError,
+        DatabaseConfig,
+        SslMode,
+    },
+    metrics,
+};
+use super::tls::MakeTls;
 
-pub type Pool = bb8::Pool<PostgresConnectionManager<NoTls>>;
+type Manager = PostgresConnectionManager<MakeTls>;
 
-pub async fn init_pool(config: &str, size: u32) -> Pool {
-    let config = config.parse().expect("parse pg config.");
+/// The connection pool, tracking the number of tasks waiting for a connection.
+#[derive(Clone)]
+pub struct Pool {
+    inner: bb8::Pool<Manager>,
+}
+
+/// Counts a waiter for as long as it lives, which includes a waiting future
+/// being dropped.
+struct Waiter;
+
+impl Waiter {
+    fn new() -> Self {
+        metrics::DB_POOL_WAITERS.inc();
+        Waiter
+    }
+}
+
+impl Drop for Waiter {
+    fn drop(&mut self) {
+        metrics::DB_POOL_WAITERS.dec();
+    }
+}
+
+impl Pool {
+    pub async fn get(&self) -> Result<PooledConnection<'_, Manager>, RunError<tokio_postgres::Error>> {
+        let _waiter = Waiter::new();
+        self.inner.get().await
+    }
+
+    pub fn state(&self) -> bb8::State {
+        self.inner.state()
+    }
+}
+
+/// Connections are made lazily, a bad DSN or TLS setting fails here while an
+/// unreachable server fails when getting a connection.
+pub async fn init_pool(config: &DatabaseConfig) -> Result<Pool, ConfigError> {
+    let mut pg_config: tokio_postgres::Config = config.dsn.parse()
+        .map_err(|err: tokio_postgres::Error| ConfigError::Invalid("database.dsn", err.to_string()))?;
+    pg_config.ssl_mode(match config.sslmode {
+        SslMode::Disable => PgSslMode::Disable,
+        SslMode::Prefer => PgSslMode::Prefer,
+        SslMode::Require | SslMode::VerifyFull => PgSslMode::Require,
+    });
     let manager = PostgresConnectionManager::new(
-        config,
-        NoTls,
+        pg_config,
+        MakeTls::new(config)?,
     );
-    Pool::builder()
-        .max_size(size)
+    let inner = bb8::Pool::builder()
+        .max_size(config.pool_size)
+        .connection_timeout(config.connection_timeout())
+        .idle_timeout(config.idle_timeout())
+        .max_lifetime(config.max_lifetime())
         .build(manager)
         .await
-        .expect("init pool")
+        .expect("no connection is made by build");
+    Ok(Pool {
+        inner,
+    })
 }
@@ -1,20 +1,77 @@
 #[macro_use] mod db;
+mod tls;
+
+use std::sync::{
+    Arc,
+    atomic::{
+        AtomicBool,
+        Ordering,
+    },
+};
+use crate::{
+    config::Config,
+    mail::Mail,
+    ratelimit::RateLimiter,
+    sms::Sms,
+    storage::Storage,
+};
 
 pub use db::{Pool, init_pool};
 
 #[derive(Clone)]
 pub struct State {
-    db_pool: Pool
+    db_pool: Pool,
+    storage: Storage,
+    mail: Mail,
+    sms: Sms,
+    rate_limiter: RateLimiter,
+    config: Arc<Config>,
+    draining: Arc<AtomicBool>,
 }
 
 impl State {
-    pub fn init(db_pool: Pool) -> Self {
+    pub fn init(db_pool: Pool, storage: Storage, mail: Mail, sms: Sms, config: Config) -> Self {
         State {
-            db_pool: db_pool,
+            rate_limiter: RateLimiter::open(&config.ratelimit, db_pool.clone()),
+            db_pool,
+            storage,
+            mail,
+            sms,
+            config: Arc::new(config),
+            draining: Arc::new(AtomicBool::new(false)),
         }
     }
 
     pub fn db_pool(&self) -> &Pool {
         &self.db_pool
     }
+
+    pub fn storage(&self) -> &Storage {
+        &self.storage
+    }
+
+    pub fn mail(&self) -> &Mail {
+        &self.mail
+    }
+
+    pub fn sms(&self) -> &Sms {
+        &self.sms
+    }
+
+    pub fn rate_limiter(&self) -> &RateLimiter {
+        &self.rate_limiter
+    }
+
+    pub fn config(&self) -> &Config {
+        &self.config
+    }
+
+    /// Mark the server as shutting down, new requests will be rejected.
+    pub fn drain(&self) {
+        self.draining.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_draining(&self) -> bool {
+        self.draining.load(Ordering::SeqCst)
+    }
 }
@@ -0,0 +1,188 @@
+//! TLS of the database connections, with the `sslmode` semantics of libpq.
+
+use std::{
+    io,
+    sync::Arc,
+};
+use rustls::{
+    Certificate,
+    ClientConfig,
+    RootCertStore,
+    ServerCertVerified,
+    ServerCertVerifier,
+    TLSError,
+};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio_postgres::tls::MakeTlsConnect;
+use tokio_postgres_rustls::{
+    MakeRustlsConnect,
+    RustlsConnect,
+    RustlsStream,
+};
+use webpki::DNSNameRef;
+use crate::{
+    config::{
+        ConfigError,
+        DatabaseConfig,
+        SslMode,
+    },
+    pem::{
+        read_certs,
+        read_key,
+    },
+};
+
+/// Name sent to servers which are not addressed by a host name.
+const PLACEHOLDER_HOST: &str = "localhost";
+
+/// Accepts any certificate, for `prefer` and `require` which only ask for
+/// an encrypted connection.
+struct NoVerifier;
+
+impl ServerCertVerifier for NoVerifier {
+    fn verify_server_cert(
+        &self,
+        _roots: &RootCertStore,
+        _presented_certs: &[Certificate],
+        _dns_name: DNSNameRef,
+        _ocsp_response: &[u8],
+    ) -> Result<ServerCertVerified, TLSError> {
+        Ok(ServerCertVerified::assertion())
+    }
+}
+
+/// Makes the TLS connectors of the pool. Unlike `MakeRustlsConnect` this
+/// accepts hosts given by an IP address or a Unix socket, unless the
+/// certificate has to be verified against the host name.
+#[derive(Clone)]
+pub struct MakeTls {
+    inner: MakeRustlsConnect,
+    verify: bool,
+}
+
+impl MakeTls {
+    pub fn new(config: &DatabaseConfig) -> Result<Self, ConfigError> {
+        let mut tls = ClientConfig::new();
+        let verify = config.sslmode == SslMode::VerifyFull;
+        if !verify {
+            tls.dangerous().set_certificate_verifier(Arc::new(NoVerifier));
+        } else if let Some(path) = &config.sslrootcert {
+            for cert in read_certs("database.sslrootcert", path)? {
+                tls.root_store.add(&cert)
+                    .map_err(|err| ConfigError::Invalid("database.sslrootcert", format!("{}: {:?}", path, err)))?;
+            }
+        } else {
+            tls.root_store = rustls_native_certs::load_native_certs()
+                .map_err(|(_, err)| ConfigError::Invalid("database.sslrootcert", format!("failed to load the system roots: {}", err)))?;
+        }
+        if let (Some(cert), Some(key)) = (&config.sslcert, &config.sslkey) {
+            tls.set_single_client_cert(read_certs("database.sslcert", cert)?, read_key("database.sslkey", key)?)
+                .map_err(|err| ConfigError::Invalid("database.sslkey", err.to_string()))?;
+        }
+        Ok(MakeTls {
+            inner: MakeRustlsConnect::new(tls),
+            verify,
+        })
+    }
+}
+
+impl<S> MakeTlsConnect<S> for MakeTls
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    type Stream = RustlsStream<S>;
+    type TlsConnect = RustlsConnect;
+    type Error = io::Error;
+
+    fn make_tls_connect(&mut self, hostname: &str) -> io::Result<RustlsConnect> {
+        if DNSNameRef::try_from_ascii_str(hostname).is_ok() {
+            return MakeTlsConnect::<S>::make_tls_connect(&mut self.inner, hostname)
+        }
+        if self.verify {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("sslmode verify-full needs a host name, got {:?}.", hostname),
+            ))
+        }
+        // An IP address or a Unix socket, which has an empty host name. The
+        // certificate is not verified so any name will do.
+        MakeTlsConnect::<S>::make_tls_connect(&mut self.inner, PLACEHOLDER_HOST)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs;
+    use crate::{
+        config::{
+            Config,
+            DatabaseConfig,
+            SslMode,
+        },
+        sql::query_one,
+        state::init_pool,
+        testing::{
+            TlsCluster,
+            ca_certificate,
+        },
+    };
+
+    /// Whether the connection uses TLS and the client certificate sent.
+    async fn connect(config: &DatabaseConfig) -> Result<(bool, Option<String>), String> {
+        let pool = init_pool(config).await.map_err(|err| err.to_string())?;
+        let conn = pool.get().await.map_err(|err| format!("{:?}", err))?;
+        query_one(
+            &*conn,
+            "SELECT ssl, client_dn FROM pg_stat_ssl WHERE pid = pg_backend_pid()",
+            &[],
+        ).await.map_err(|err| format!("{:?}", err))
+    }
+
+    #[tokio::test]
+    async fn test_sslmode() {
+        let cluster = match TlsCluster::start() {
+            Some(cluster) => cluster,
+            None => {
+                eprintln!("test_sslmode skipped, it needs to start its own cluster.");
+                return
+            }
+        };
+        let path = |name: &str| Some(cluster.dir.join(name).to_str().unwrap().to_owned());
+        let mut config = Config::defaults(true).database;
+        config.dsn = cluster.dsn.clone();
+        config.connection_timeout = 2;
+
+        config.sslmode = SslMode::Disable;
+        assert_eq!(connect(&config).await, Ok((false, None)));
+        config.sslmode = SslMode::Prefer;
+        assert_eq!(connect(&config).await, Ok((true, None)));
+        // The sslmode of the DSN is overridden.
+        config.dsn = format!("{} sslmode=disable", cluster.dsn);
+        config.sslmode = SslMode::Require;
+        assert_eq!(connect(&config).await, Ok((true, None)));
+
+        // Any certificate and any host is fine without verification.
+        config.dsn = cluster.dsn.replace("host=localhost", "host=127.0.0.1");
+        config.sslrootcert = path("wrong-ca.crt");
+        assert_eq!(connect(&config).await, Ok((true, None)));
+        fs::write(cluster.dir.join("wrong-ca.crt"), ca_certificate("wrong ca").serialize_pem().unwrap()).unwrap();
+        config.sslmode = SslMode::VerifyFull;
+        assert!(connect(&config).await.is_err());
+        config.dsn = cluster.dsn.clone();
+        assert!(connect(&config).await.is_err());
+
+        config.sslrootcert = path("ca.crt");
+        assert_eq!(connect(&config).await, Ok((true, None)));
+        config.dsn = cluster.dsn.replace("host=localhost", "host=127.0.0.1");
+        assert!(connect(&config).await.is_err());
+        config.dsn = cluster.dsn.clone();
+
+        config.sslcert = path("client.crt");
+        config.sslkey = path("client.key");
+        assert_eq!(connect(&config).await, Ok((true, Some("/CN=postgres".to_string()))));
+        config.sslkey = path("client.crt");
+        assert!(connect(&config).await.unwrap_err().contains("database.sslkey"));
+        config.sslrootcert = path("missing.crt");
+        assert!(connect(&config).await.unwrap_err().contains("database.sslrootcert"));
+    }
+}
@@ -0,0 +1,149 @@
+use std::{
+    io::SeekFrom,
+    path::{Path, PathBuf},
+};
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use tokio::{
+    fs,
+    prelude::*,
+};
+use uuid::Uuid;
+use crate::config::ConfigError;
+use super::{
+    BlobStore,
+    Metadata,
+    StorageError,
+    StorageResult,
+};
+
+/// Blobs kept as files under a root directory, keys are relative paths.
+pub struct FsStore {
+    root: PathBuf,
+}
+
+/// Uploads are staged inside the root, on the same filesystem, so moving
+/// them into place is an atomic rename.
+const STAGING_DIR: &str = ".uploads";
+
+impl FsStore {
+    /// The root must be an existing directory.
+    pub fn open(root: &str) -> Result<Self, ConfigError> {
+        match std::fs::metadata(root) {
+            Ok(metadata) if metadata.is_dir() => {}
+            Ok(_) => {
+                return Err(ConfigError::Invalid("storage.root", format!("{} is not a directory.", root)))
+            }
+            Err(err) => {
+                return Err(ConfigError::Invalid("storage.root", format!("{}: {}", root, err)))
+            }
+        }
+        std::fs::create_dir_all(Path::new(root).join(STAGING_DIR))
+            .map_err(|err| ConfigError::Invalid("storage.root", format!("{}: {}", root, err)))?;
+        Ok(FsStore {
+            root: PathBuf::from(root),
+        })
+    }
+
+    fn path(&self, key: &str) -> PathBuf {
+        self.root.join(key)
+    }
+}
+
+#[async_trait]
+impl BlobStore for FsStore {
+    async fn put(&self, key: &str, data: Vec<u8>) -> StorageResult<()> {
+        let path = self.path(key);
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir).await?;
+        }
+        // Write aside and rename so readers never see a partial file.
+        let mut temp = path.clone().into_os_string();
+        temp.push(format!(".{}.tmp", Uuid::new_v4().to_simple()));
+        fs::write(&temp, data).await?;
+        if let Err(err) = fs::rename(&temp, &path).await {
+            let _ = fs::remove_file(&temp).await;
+            return Err(StorageError::Io(err))
+        }
+        Ok(())
+    }
+
+    async fn put_file(&self, key: &str, file: &Path) -> StorageResult<()> {
+        let path = self.path(key);
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir).await?;
+        }
+        if fs::rename(file, &path).await.is_err() {
+            // Not staged on the same filesystem, copy it aside first.
+            self.put(key, fs::read(file).await?).await?;
+        }
+        Ok(())
+    }
+
+    fn staging_dir(&self) -> PathBuf {
+        self.root.join(STAGING_DIR)
+    }
+
+    async fn get(&self, key: &str) -> StorageResult<Vec<u8>> {
+        Ok(fs::read(self.path(key)).await?)
+    }
+
+    async fn get_range(&self, key: &str, start: u64, end: u64) -> StorageResult<Vec<u8>> {
+        let mut file = fs::File::open(self.path(key)).await?;
+        file.seek(SeekFrom::Start(start)).await?;
+        let mut data = Vec::new();
+        file.take(end - start + 1).read_to_end(&mut data).await?;
+        Ok(data)
+    }
+
+    async fn delete(&self, key: &str) -> StorageResult<()> {
+        match fs::remove_file(self.path(key)).await.map_err(StorageError::from) {
+            Err(StorageError::NotFound) => Ok(()),
+            result => result,
+        }
+    }
+
+    async fn delete_prefix(&self, prefix: &str) -> StorageResult<()> {
+        match fs::remove_dir_all(self.path(prefix)).await.map_err(StorageError::from) {
+            Err(StorageError::NotFound) => Ok(()),
+            result => result,
+        }
+    }
+
+    async fn metadata(&self, key: &str) -> StorageResult<Metadata> {
+        let metadata = fs::metadata(self.path(key)).await?;
+        if !metadata.is_file() {
+            return Err(StorageError::NotFound)
+        }
+        Ok(Metadata {
+            length: metadata.len(),
+            last_modified: metadata.modified().ok().map(DateTime::<Utc>::from),
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use tempfile::TempDir;
+    use super::FsStore;
+    use crate::storage::{
+        BlobStore,
+        Upload,
+        test::check_store,
+    };
+
+    #[tokio::test]
+    async fn test_fs_store() {
+        let dir = TempDir::new().unwrap();
+        let store = FsStore::open(dir.path().to_str().unwrap()).unwrap();
+        check_store(&store).await;
+
+        let upload = Upload::write(&store.staging_dir(), |writer| writer.write_all(b"staged")).unwrap();
+        let staged = upload.path().to_owned();
+        store.put_file("a/staged.jpg", upload.path()).await.unwrap();
+        assert!(!staged.exists());
+        assert_eq!(store.get("a/staged.jpg").await.unwrap(), b"staged");
+
+        assert!(FsStore::open(dir.path().join("missing").to_str().unwrap()).is_err());
+    }
+}
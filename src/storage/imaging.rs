@@ -0,0 +1,159 @@
+//! Uploaded images are decoded, validated and re-encoded as JPEG before they
+//! are stored, which also drops any EXIF or other metadata. Thumbnails are
+//! generated for every configured size and stored next to the original.
+
+use std::path::{Path, PathBuf};
+use image::{
+    DynamicImage,
+    ImageDecoder,
+    ImageFormat,
+    ImageReader,
+    codecs::jpeg::JpegEncoder,
+    imageops::FilterType,
+};
+use crate::{
+    config::ImageConfig,
+    error::Error,
+};
+use super::Upload;
+
+/// One stored rendition of an image, `size` is `None` for the full sized one.
+pub struct Variant {
+    pub size: Option<u32>,
+    pub upload: Upload,
+}
+
+/// The sizes an uploaded image is stored at, the full sized one first.
+pub fn variants(config: &ImageConfig) -> Vec<Option<u32>> {
+    let mut variants = vec![None];
+    variants.extend(config.thumbnail_sizes.iter().map(|size| Some(*size)));
+    variants
+}
+
+/// Check the `size` requested by a client is one of the configured thumbnail sizes.
+pub fn check_size(size: Option<u32>, config: &ImageConfig) -> Result<Option<u32>, Error> {
+    match size {
+        Some(size) if !config.thumbnail_sizes.contains(&size) => Err(Error::invalid_data("size")),
+        size => Ok(size),
+    }
+}
+
+fn decode(field: &str, path: &Path, config: &ImageConfig) -> Result<DynamicImage, Error> {
+    let reader = ImageReader::open(path)?
+        .with_guessed_format()
+        .map_err(|_| Error::invalid_data(field))?;
+    match reader.format() {
+        Some(ImageFormat::Jpeg) | Some(ImageFormat::Png) | Some(ImageFormat::WebP) => {}
+        _ => return Err(Error::invalid_data(field)),
+    }
+
+    let mut decoder = reader.into_decoder().map_err(|_| Error::invalid_data(field))?;
+    // Refuse before allocating the pixels.
+    let (width, height) = decoder.dimensions();
+    if !(1..=config.max_width).contains(&width) || !(1..=config.max_height).contains(&height) {
+        return Err(Error::invalid_data(field))
+    }
+    let orientation = decoder.orientation().map_err(|_| Error::invalid_data(field))?;
+    let mut image = DynamicImage::from_decoder(decoder).map_err(|_| Error::invalid_data(field))?;
+    // The orientation is lost with the EXIF data, so bake it into the pixels.
+    image.apply_orientation(orientation);
+    Ok(image)
+}
+
+fn encode(image: &DynamicImage, dir: &Path, quality: u8) -> Result<Upload, Error> {
+    let image = DynamicImage::ImageRgb8(image.to_rgb8());
+    let upload = Upload::write(dir, |writer| {
+        image
+            .write_with_encoder(JpegEncoder::new_with_quality(writer, quality))
+            .map_err(std::io::Error::other)
+    })?;
+    Ok(upload)
+}
+
+fn process_blocking(field: &str, upload: &Upload, dir: &Path, config: &ImageConfig) -> Result<Vec<Variant>, Error> {
+    let image = decode(field, upload.path(), config)?;
+    let mut variants = vec![
+        Variant {
+            size: None,
+            upload: encode(&image, dir, config.quality)?,
+        },
+    ];
+    for size in config.thumbnail_sizes.iter() {
+        let thumbnail = if image.width() > *size || image.height() > *size {
+            image.resize(*size, *size, FilterType::Lanczos3)
+        } else {
+            image.clone()
+        };
+        variants.push(Variant {
+            size: Some(*size),
+            upload: encode(&thumbnail, dir, config.quality)?,
+        });
+    }
+    Ok(variants)
+}
+
+/// Decode the uploaded `field` and render all the variants of it into `dir`,
+/// refusing anything but a JPEG, PNG or WebP image within the configured
+/// dimensions.
+pub async fn process(field: &'static str, upload: Upload, dir: PathBuf, config: &ImageConfig) -> Result<Vec<Variant>, Error> {
+    let config = config.clone();
+    tokio::task::spawn_blocking(move || process_blocking(field, &upload, &dir, &config))
+        .await
+        .map_err(|err| Error::from(std::io::Error::other(err)))?
+}
+
+#[cfg(test)]
+mod test {
+    use image::ImageFormat;
+    use tempfile::TempDir;
+    use crate::{
+        config::Config,
+        storage::Upload,
+        testing::png,
+    };
+    use super::{
+        check_size,
+        process,
+    };
+
+    #[tokio::test]
+    async fn test_process() {
+        let config = Config::defaults(true).image;
+        let dir = TempDir::new().unwrap();
+        let upload = |data: Vec<u8>| Upload::write(dir.path(), |writer| writer.write_all(&data)).unwrap();
+        let dir = || dir.path().to_owned();
+
+        let variants = process("image", upload(png(2000, 500)), dir(), &config).await.unwrap();
+        let sizes: Vec<_> = variants.iter().map(|variant| variant.size).collect();
+        assert_eq!(sizes, vec![None, Some(64), Some(256), Some(1024)]);
+        for variant in variants.iter() {
+            let data = std::fs::read(variant.upload.path()).unwrap();
+            let image = image::load_from_memory_with_format(&data, ImageFormat::Jpeg).unwrap();
+            let expected = match variant.size {
+                None => (2000, 500),
+                Some(64) => (64, 16),
+                Some(256) => (256, 64),
+                Some(_) => (1024, 256),
+            };
+            assert_eq!((image.width(), image.height()), expected);
+        }
+
+        // Small images are not enlarged.
+        let variants = process("image", upload(png(32, 32)), dir(), &config).await.unwrap();
+        let thumbnail = image::ImageReader::open(variants[1].upload.path()).unwrap()
+            .with_guessed_format().unwrap()
+            .decode().unwrap();
+        assert_eq!((thumbnail.width(), thumbnail.height()), (32, 32));
+
+        assert!(process("image", upload(b"not an image".to_vec()), dir(), &config).await.is_err());
+        assert!(process("image", upload(png(config.max_width + 1, 1)), dir(), &config).await.is_err());
+    }
+
+    #[test]
+    fn test_check_size() {
+        let config = Config::defaults(true).image;
+        assert_eq!(check_size(None, &config).unwrap(), None);
+        assert_eq!(check_size(Some(256), &config).unwrap(), Some(256));
+        assert!(check_size(Some(100), &config).is_err());
+    }
+}
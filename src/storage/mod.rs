@@ -0,0 +1,267 @@
+//! Blob storage of uploaded files, kept on the local filesystem or in an
+//! S3-compatible service so several replicas can share them.
+
+use std::{
+    fmt::{self, Display},
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use crate::{
+    config::{
+        ConfigError,
+        StorageBackend,
+        StorageConfig,
+    },
+    metrics,
+};
+
+mod fs;
+mod s3;
+mod upload;
+pub mod imaging;
+
+pub use fs::FsStore;
+pub use s3::S3Store;
+pub use upload::Upload;
+use imaging::Variant;
+
+#[derive(Debug)]
+pub enum StorageError {
+    NotFound,
+    Io(std::io::Error),
+    Backend(String),
+}
+
+impl Display for StorageError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            StorageError::NotFound => write!(f, "Blob not found."),
+            StorageError::Io(err) => write!(f, "{}", err),
+            StorageError::Backend(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+impl From<std::io::Error> for StorageError {
+    fn from(err: std::io::Error) -> Self {
+        if err.kind() == std::io::ErrorKind::NotFound {
+            StorageError::NotFound
+        } else {
+            StorageError::Io(err)
+        }
+    }
+}
+
+pub type StorageResult<T> = Result<T, StorageError>;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Metadata {
+    pub length: u64,
+    pub last_modified: Option<DateTime<Utc>>,
+}
+
+/// A flat namespace of blobs addressed by `/` separated keys.
+#[async_trait]
+pub trait BlobStore: Send + Sync {
+    async fn put(&self, key: &str, data: Vec<u8>) -> StorageResult<()>;
+
+    /// Move the file at `path`, staged in `staging_dir`, to `key`.
+    async fn put_file(&self, key: &str, path: &Path) -> StorageResult<()> {
+        self.put(key, tokio::fs::read(path).await?).await
+    }
+
+    /// Where uploads are staged before they are moved into the store.
+    fn staging_dir(&self) -> PathBuf {
+        std::env::temp_dir()
+    }
+
+    async fn get(&self, key: &str) -> StorageResult<Vec<u8>>;
+
+    /// The bytes from `start` to `end` inclusive, within the blob.
+    async fn get_range(&self, key: &str, start: u64, end: u64) -> StorageResult<Vec<u8>>;
+
+    /// Deleting a missing blob is not an error.
+    async fn delete(&self, key: &str) -> StorageResult<()>;
+
+    /// Delete every blob whose key starts with `prefix/`.
+    async fn delete_prefix(&self, prefix: &str) -> StorageResult<()>;
+
+    async fn metadata(&self, key: &str) -> StorageResult<Metadata>;
+}
+
+/// The configured blob store, and the layout of the files within it.
+#[derive(Clone)]
+pub struct Storage {
+    backend: Arc<dyn BlobStore>,
+    name: String,
+}
+
+impl Storage {
+    pub fn new(backend: Arc<dyn BlobStore>, name: String) -> Self {
+        Storage {
+            backend,
+            name,
+        }
+    }
+
+    pub fn open(config: &StorageConfig) -> Result<Self, ConfigError> {
+        match config.backend {
+            StorageBackend::Filesystem => {
+                let store = FsStore::open(&config.root)?;
+                Ok(Storage::new(Arc::new(store), config.root.clone()))
+            }
+            StorageBackend::S3 => {
+                let store = S3Store::open(config)?;
+                let name = format!("s3://{}", store.bucket());
+                Ok(Storage::new(Arc::new(store), name))
+            }
+        }
+    }
+
+    /// Describes where the blobs are kept.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub async fn put(&self, key: &str, data: Vec<u8>) -> StorageResult<()> {
+        let length = data.len();
+        self.backend.put(key, data).await?;
+        metrics::STORAGE_BYTES_WRITTEN.inc_by(length as i64);
+        Ok(())
+    }
+
+    /// Move an upload into the store.
+    pub async fn put_upload(&self, key: &str, upload: Upload) -> StorageResult<()> {
+        self.backend.put_file(key, upload.path()).await?;
+        debug!("Stored {} ({} bytes, sha256 {}).", key, upload.len(), upload.sha256());
+        metrics::STORAGE_BYTES_WRITTEN.inc_by(upload.len() as i64);
+        Ok(())
+    }
+
+    pub fn staging_dir(&self) -> PathBuf {
+        self.backend.staging_dir()
+    }
+
+    pub async fn get(&self, key: &str) -> StorageResult<Vec<u8>> {
+        let data = self.backend.get(key).await?;
+        metrics::STORAGE_BYTES_READ.inc_by(data.len() as i64);
+        Ok(data)
+    }
+
+    pub async fn get_range(&self, key: &str, start: u64, end: u64) -> StorageResult<Vec<u8>> {
+        let data = self.backend.get_range(key, start, end).await?;
+        metrics::STORAGE_BYTES_READ.inc_by(data.len() as i64);
+        Ok(data)
+    }
+
+    pub async fn delete(&self, key: &str) -> StorageResult<()> {
+        self.backend.delete(key).await
+    }
+
+    pub async fn delete_prefix(&self, prefix: &str) -> StorageResult<()> {
+        self.backend.delete_prefix(prefix).await
+    }
+
+    pub async fn metadata(&self, key: &str) -> StorageResult<Metadata> {
+        self.backend.metadata(key).await
+    }
+
+    /// Store every variant of an image under the key `key` gives for its size.
+    pub async fn put_image(&self, variants: Vec<Variant>, key: impl Fn(Option<u32>) -> String) -> StorageResult<()> {
+        for variant in variants {
+            self.put_upload(&key(variant.size), variant.upload).await?;
+        }
+        Ok(())
+    }
+
+    /// The key of the image at `size` and its metadata, falling back to the
+    /// full sized one for images stored without that thumbnail.
+    pub async fn find_image(&self, size: Option<u32>, key: impl Fn(Option<u32>) -> String) -> StorageResult<(String, Metadata)> {
+        match self.metadata(&key(size)).await {
+            Err(StorageError::NotFound) if size.is_some() => Ok((key(None), self.metadata(&key(None)).await?)),
+            result => Ok((key(size), result?)),
+        }
+    }
+
+    pub async fn delete_image(&self, sizes: Vec<Option<u32>>, key: impl Fn(Option<u32>) -> String) -> StorageResult<()> {
+        for size in sizes {
+            self.delete(&key(size)).await?;
+        }
+        Ok(())
+    }
+
+    pub fn user_avatar(&self, user_id: impl Display, size: Option<u32>) -> String {
+        format!("user/{}/{}", user_id, sized("avatar", size))
+    }
+
+    pub fn default_user_avatar(&self, size: Option<u32>) -> String {
+        format!("default/user/{}", sized("avatar", size))
+    }
+
+    pub fn product_dir(&self, shop_id: impl Display, product_key: impl Display) -> String {
+        format!("shop/{}/product/{}", shop_id, product_key)
+    }
+
+    pub fn product_image(&self, shop_id: impl Display, product_key: impl Display, size: Option<u32>) -> String {
+        format!("shop/{}/product/{}/{}", shop_id, product_key, sized("image", size))
+    }
+}
+
+/// `image.jpg` for the full sized image, `image_256.jpg` for a thumbnail.
+fn sized(name: &str, size: Option<u32>) -> String {
+    match size {
+        Some(size) => format!("{}_{}.jpg", name, size),
+        None => format!("{}.jpg", name),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{
+        BlobStore,
+        StorageError,
+    };
+
+    async fn exists(store: &dyn BlobStore, key: &str) -> bool {
+        match store.metadata(key).await {
+            Ok(_) => true,
+            Err(StorageError::NotFound) => false,
+            Err(err) => panic!("{}", err),
+        }
+    }
+
+    /// Exercise the contract every backend has to fulfill.
+    pub async fn check_store(store: &dyn BlobStore) {
+        assert!(!exists(store, "a/b/c.jpg").await);
+        match store.get("a/b/c.jpg").await {
+            Err(StorageError::NotFound) => {}
+            other => panic!("expected NotFound, got {:?}", other),
+        }
+
+        store.put("a/b/c.jpg", b"image".to_vec()).await.unwrap();
+        store.put("a/b/d/e.jpg", b"nested".to_vec()).await.unwrap();
+        store.put("a/bc.jpg", b"sibling".to_vec()).await.unwrap();
+        assert!(exists(store, "a/b/c.jpg").await);
+        assert_eq!(store.get("a/b/c.jpg").await.unwrap(), b"image");
+        assert_eq!(store.get_range("a/b/c.jpg", 1, 3).await.unwrap(), b"mag");
+        assert_eq!(store.get_range("a/b/c.jpg", 4, 4).await.unwrap(), b"e");
+        assert_eq!(store.metadata("a/b/c.jpg").await.unwrap().length, 5);
+
+        store.put("a/b/c.jpg", b"replaced".to_vec()).await.unwrap();
+        assert_eq!(store.get("a/b/c.jpg").await.unwrap(), b"replaced");
+
+        store.delete("a/b/c.jpg").await.unwrap();
+        store.delete("a/b/c.jpg").await.unwrap();
+        assert!(!exists(store, "a/b/c.jpg").await);
+
+        store.put("a/b/c.jpg", b"image".to_vec()).await.unwrap();
+        store.delete_prefix("a/b").await.unwrap();
+        assert!(!exists(store, "a/b/c.jpg").await);
+        assert!(!exists(store, "a/b/d/e.jpg").await);
+        assert!(exists(store, "a/bc.jpg").await);
+        store.delete_prefix("a/b").await.unwrap();
+        store.delete_prefix("a").await.unwrap();
+    }
+}
@@ -0,0 +1,301 @@
+use std::{
+    path::Path,
+    str::FromStr,
+};
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use bytes::Bytes;
+use futures::{
+    TryStreamExt,
+    stream,
+};
+use tokio::prelude::*;
+use rusoto_core::{
+    ByteStream,
+    HttpClient,
+    Region,
+    RusotoError,
+    credential::{
+        DefaultCredentialsProvider,
+        StaticProvider,
+    },
+};
+use rusoto_s3::{
+    Delete,
+    DeleteObjectRequest,
+    DeleteObjectsRequest,
+    GetObjectError,
+    GetObjectRequest,
+    HeadObjectError,
+    HeadObjectRequest,
+    ListObjectsV2Request,
+    ObjectIdentifier,
+    PutObjectRequest,
+    S3,
+    S3Client,
+};
+use crate::config::{
+    ConfigError,
+    StorageConfig,
+};
+use super::{
+    BlobStore,
+    Metadata,
+    StorageError,
+    StorageResult,
+};
+
+const UPLOAD_CHUNK_SIZE: usize = 64 * 1024;
+
+/// Blobs kept as objects of a bucket in S3 or an S3-compatible service.
+pub struct S3Store {
+    client: S3Client,
+    bucket: String,
+}
+
+fn backend_error<E: std::error::Error + 'static>(err: RusotoError<E>) -> StorageError {
+    StorageError::Backend(format!("S3 request failed: {}", err))
+}
+
+fn is_not_found<E>(err: &RusotoError<E>) -> bool {
+    match err {
+        RusotoError::Unknown(response) => response.status.as_u16() == 404,
+        _ => false,
+    }
+}
+
+impl S3Store {
+    pub fn open(config: &StorageConfig) -> Result<Self, ConfigError> {
+        let bucket = config.s3_bucket
+            .clone()
+            .ok_or_else(|| ConfigError::Invalid("storage.s3_bucket", "is required by the s3 backend.".to_string()))?;
+        let region = match &config.s3_endpoint {
+            Some(endpoint) => Region::Custom {
+                name: config.s3_region.clone(),
+                endpoint: endpoint.trim_end_matches('/').to_string(),
+            },
+            None => Region::from_str(&config.s3_region)
+                .map_err(|err| ConfigError::Invalid("storage.s3_region", err.to_string()))?,
+        };
+        let dispatcher = HttpClient::new()
+            .map_err(|err| ConfigError::Invalid("storage.s3_endpoint", err.to_string()))?;
+        let client = match (&config.s3_access_key, &config.s3_secret_key) {
+            (Some(access_key), Some(secret_key)) => S3Client::new_with(
+                dispatcher,
+                StaticProvider::new_minimal(access_key.clone(), secret_key.clone()),
+                region,
+            ),
+            _ => S3Client::new_with(
+                dispatcher,
+                DefaultCredentialsProvider::new()
+                    .map_err(|err| ConfigError::Invalid("storage.s3_access_key", err.to_string()))?,
+                region,
+            ),
+        };
+        Ok(S3Store {
+            client,
+            bucket,
+        })
+    }
+
+    pub fn bucket(&self) -> &str {
+        &self.bucket
+    }
+
+    /// The object at `key`, or the bytes of it in `range`.
+    async fn get_object(&self, key: &str, range: Option<String>) -> StorageResult<Vec<u8>> {
+        let output = self.client.get_object(GetObjectRequest {
+            bucket: self.bucket.clone(),
+            key: key.to_string(),
+            range,
+            ..Default::default()
+        })
+        .await
+        .map_err(|err| match err {
+            RusotoError::Service(GetObjectError::NoSuchKey(_)) => StorageError::NotFound,
+            err if is_not_found(&err) => StorageError::NotFound,
+            err => backend_error(err),
+        })?;
+
+        match output.body {
+            Some(body) => Ok(
+                body.try_fold(Vec::new(), |mut data, chunk| async move {
+                    data.extend_from_slice(&chunk);
+                    Ok(data)
+                })
+                .await?
+            ),
+            None => Ok(Vec::new()),
+        }
+    }
+}
+
+#[async_trait]
+impl BlobStore for S3Store {
+    async fn put(&self, key: &str, data: Vec<u8>) -> StorageResult<()> {
+        self.client.put_object(PutObjectRequest {
+            bucket: self.bucket.clone(),
+            key: key.to_string(),
+            content_length: Some(data.len() as i64),
+            body: Some(data.into()),
+            ..Default::default()
+        })
+        .await
+        .map_err(backend_error)?;
+        Ok(())
+    }
+
+    async fn put_file(&self, key: &str, path: &Path) -> StorageResult<()> {
+        let file = tokio::fs::File::open(path).await?;
+        let length = file.metadata().await?.len();
+        // Stream the file in chunks rather than reading it whole.
+        let body = stream::try_unfold(file, |mut file| async move {
+            let mut chunk = vec![0; UPLOAD_CHUNK_SIZE];
+            let read = file.read(&mut chunk).await?;
+            if read == 0 {
+                return Ok(None)
+            }
+            chunk.truncate(read);
+            Ok(Some((Bytes::from(chunk), file)))
+        });
+        self.client.put_object(PutObjectRequest {
+            bucket: self.bucket.clone(),
+            key: key.to_string(),
+            content_length: Some(length as i64),
+            body: Some(ByteStream::new_with_size(body, length as usize)),
+            ..Default::default()
+        })
+        .await
+        .map_err(backend_error)?;
+        Ok(())
+    }
+
+    async fn get(&self, key: &str) -> StorageResult<Vec<u8>> {
+        self.get_object(key, None).await
+    }
+
+    async fn get_range(&self, key: &str, start: u64, end: u64) -> StorageResult<Vec<u8>> {
+        self.get_object(key, Some(format!("bytes={}-{}", start, end))).await
+    }
+
+    async fn delete(&self, key: &str) -> StorageResult<()> {
+        self.client.delete_object(DeleteObjectRequest {
+            bucket: self.bucket.clone(),
+            key: key.to_string(),
+            ..Default::default()
+        })
+        .await
+        .map_err(backend_error)?;
+        Ok(())
+    }
+
+    async fn delete_prefix(&self, prefix: &str) -> StorageResult<()> {
+        let prefix = format!("{}/", prefix.trim_end_matches('/'));
+        let mut continuation_token = None;
+        loop {
+            let output = self.client.list_objects_v2(ListObjectsV2Request {
+                bucket: self.bucket.clone(),
+                prefix: Some(prefix.clone()),
+                continuation_token,
+                ..Default::default()
+            })
+            .await
+            .map_err(backend_error)?;
+
+            let objects: Vec<ObjectIdentifier> = output.contents
+                .unwrap_or_default()
+                .into_iter()
+                .filter_map(|object| object.key)
+                .map(|key| ObjectIdentifier { key, version_id: None })
+                .collect();
+            if !objects.is_empty() {
+                let output = self.client.delete_objects(DeleteObjectsRequest {
+                    bucket: self.bucket.clone(),
+                    delete: Delete { objects, quiet: Some(true) },
+                    ..Default::default()
+                })
+                .await
+                .map_err(backend_error)?;
+                if let Some(errors) = output.errors.filter(|errors| !errors.is_empty()) {
+                    return Err(StorageError::Backend(format!(
+                        "Failed to delete {} objects under {}: {:?}",
+                        errors.len(),
+                        prefix,
+                        errors[0].message,
+                    )))
+                }
+            }
+
+            match output.next_continuation_token {
+                Some(token) if output.is_truncated.unwrap_or(false) => continuation_token = Some(token),
+                _ => return Ok(()),
+            }
+        }
+    }
+
+    async fn metadata(&self, key: &str) -> StorageResult<Metadata> {
+        let output = self.client.head_object(HeadObjectRequest {
+            bucket: self.bucket.clone(),
+            key: key.to_string(),
+            ..Default::default()
+        })
+        .await
+        .map_err(|err| match err {
+            RusotoError::Service(HeadObjectError::NoSuchKey(_)) => StorageError::NotFound,
+            err if is_not_found(&err) => StorageError::NotFound,
+            err => backend_error(err),
+        })?;
+
+        Ok(Metadata {
+            length: output.content_length.unwrap_or(0) as u64,
+            last_modified: output.last_modified
+                .and_then(|date| DateTime::parse_from_rfc2822(&date).ok())
+                .map(|date| date.with_timezone(&Utc)),
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::env;
+    use rusoto_s3::{
+        CreateBucketRequest,
+        S3,
+    };
+    use crate::config::{
+        Config,
+        StorageBackend,
+    };
+    use crate::storage::test::check_store;
+    use super::S3Store;
+
+    /// Runs against the S3-compatible service at `PIGSKIT_TEST_S3_ENDPOINT`,
+    /// such as `minio server`, with the credentials in
+    /// `PIGSKIT_TEST_S3_ACCESS_KEY` and `PIGSKIT_TEST_S3_SECRET_KEY`:
+    ///
+    /// ```sh
+    /// PIGSKIT_TEST_S3_ENDPOINT=http://localhost:9000 \
+    /// PIGSKIT_TEST_S3_ACCESS_KEY=minioadmin PIGSKIT_TEST_S3_SECRET_KEY=minioadmin \
+    ///     cargo test test_s3_store -- --ignored
+    /// ```
+    #[tokio::test]
+    #[ignore]
+    async fn test_s3_store() {
+        let endpoint = env::var("PIGSKIT_TEST_S3_ENDPOINT").expect("PIGSKIT_TEST_S3_ENDPOINT is not set");
+        let mut config = Config::defaults(true).storage;
+        config.backend = StorageBackend::S3;
+        config.s3_endpoint = Some(endpoint);
+        config.s3_bucket = Some(format!("pigskit-test-{}", uuid::Uuid::new_v4().to_simple()));
+        config.s3_access_key = env::var("PIGSKIT_TEST_S3_ACCESS_KEY").ok();
+        config.s3_secret_key = env::var("PIGSKIT_TEST_S3_SECRET_KEY").ok();
+
+        let store = S3Store::open(&config).unwrap();
+        store.client.create_bucket(CreateBucketRequest {
+            bucket: store.bucket.clone(),
+            ..Default::default()
+        })
+        .await
+        .unwrap();
+        check_store(&store).await;
+    }
+}
@@ -0,0 +1,151 @@
+use std::{
+    io::{self, Write},
+    path::Path,
+};
+use futures::{
+    Stream,
+    StreamExt,
+    pin_mut,
+};
+use sha2::{
+    Digest,
+    Sha256,
+};
+use tempfile::NamedTempFile;
+use tokio::prelude::*;
+use crate::error::Error;
+
+/// Content staged in a temporary file until it is moved into the storage,
+/// the file is removed if the upload is dropped before that.
+pub struct Upload {
+    file: NamedTempFile,
+    length: u64,
+    sha256: String,
+}
+
+impl Upload {
+    /// Stream a multipart part into a temporary file under `dir`, hashing it
+    /// on the way. Fails with `PayloadTooLarge` past `max_length` bytes.
+    pub async fn receive<S, B, E>(dir: &Path, stream: S, max_length: u64) -> Result<Self, Error>
+    where
+        S: Stream<Item = Result<B, E>>,
+        B: AsRef<[u8]>,
+        E: Into<Error>,
+    {
+        let file = NamedTempFile::new_in(dir)?;
+        let mut writer = tokio::fs::File::from_std(file.reopen()?);
+        let mut hasher = Sha256::new();
+        let mut length = 0;
+
+        pin_mut!(stream);
+        while let Some(data) = stream.next().await {
+            let data = data.map_err(Into::into)?;
+            let data = data.as_ref();
+            length += data.len() as u64;
+            if length > max_length {
+                return Err(Error::payload_too_large())
+            }
+            writer.write_all(data).await?;
+            hasher.update(data);
+        }
+        writer.flush().await?;
+
+        Ok(Upload {
+            file,
+            length,
+            sha256: format!("{:x}", hasher.finalize()),
+        })
+    }
+
+    /// Write an upload synchronously with `write`, for content generated by the server.
+    pub fn write<F>(dir: &Path, write: F) -> io::Result<Self>
+    where
+        F: FnOnce(&mut dyn Write) -> io::Result<()>,
+    {
+        let mut file = NamedTempFile::new_in(dir)?;
+        let (length, sha256) = {
+            let mut writer = HashWriter {
+                inner: io::BufWriter::new(file.as_file_mut()),
+                hasher: Sha256::new(),
+                length: 0,
+            };
+            write(&mut writer)?;
+            writer.flush()?;
+            (writer.length, format!("{:x}", writer.hasher.finalize()))
+        };
+
+        Ok(Upload {
+            file,
+            length,
+            sha256,
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        self.file.path()
+    }
+
+    pub fn len(&self) -> u64 {
+        self.length
+    }
+
+    /// Hex encoded SHA-256 of the content.
+    pub fn sha256(&self) -> &str {
+        &self.sha256
+    }
+}
+
+struct HashWriter<W: Write> {
+    inner: W,
+    hasher: Sha256,
+    length: u64,
+}
+
+impl<W: Write> Write for HashWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        self.hasher.update(&buf[..written]);
+        self.length += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io;
+    use bytes::Bytes;
+    use futures::stream;
+    use tempfile::TempDir;
+    use super::Upload;
+
+    const HELLO_SHA256: &str = "2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824";
+
+    #[tokio::test]
+    async fn test_receive() {
+        let dir = TempDir::new().unwrap();
+        let chunks = || stream::iter(vec![Ok::<_, io::Error>(Bytes::from("hel")), Ok(Bytes::from("lo"))]);
+
+        let upload = Upload::receive(dir.path(), chunks(), 5).await.unwrap();
+        assert_eq!(upload.len(), 5);
+        assert_eq!(upload.sha256(), HELLO_SHA256);
+        assert_eq!(std::fs::read(upload.path()).unwrap(), b"hello");
+
+        assert!(Upload::receive(dir.path(), chunks(), 4).await.is_err());
+
+        let path = upload.path().to_owned();
+        drop(upload);
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn test_write() {
+        let dir = TempDir::new().unwrap();
+        let upload = Upload::write(dir.path(), |writer| writer.write_all(b"hello")).unwrap();
+        assert_eq!(upload.len(), 5);
+        assert_eq!(upload.sha256(), HELLO_SHA256);
+    }
+}
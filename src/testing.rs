@@ -0,0 +1,398 @@
+//! Harness for end to end tests: a throwaway Postgres with the schema
+//! applied, a temporary storage root, and the routes built on top of them.
+//!
+//! A Postgres cluster is initialized in a temporary directory with the
+//! `initdb`/`pg_ctl` binaries found in `PIGSKIT_TEST_PG_BIN`, `pg_config
+//! --bindir` or `PATH`. Set `PIGSKIT_TEST_DATABASE_DSN` to use a running
+//! server instead, where a database is created for every test.
+//!
+//! `TlsCluster` always initializes its own cluster, serving TLS.
+
+use std::{
+    env,
+    fs,
+    net::TcpListener,
+    os::unix::fs::PermissionsExt,
+    path::{Path, PathBuf},
+    process::Command,
+    sync::{
+        Arc,
+        Mutex,
+    },
+};
+use async_trait::async_trait;
+use rcgen::{
+    BasicConstraints,
+    Certificate,
+    CertificateParams,
+    DistinguishedName,
+    DnType,
+    IsCa,
+};
+use tempfile::TempDir;
+use tokio_postgres::NoTls;
+use uuid::Uuid;
+use warp::{
+    Reply,
+    filters::BoxedFilter,
+    http::Response,
+    hyper::body::Bytes,
+    test::RequestBuilder,
+};
+use crate::{
+    config::{
+        Config,
+        MailBackend,
+    },
+    context::RequestContext,
+    mail::Mail,
+    route,
+    sms::{
+        Sms,
+        SmsError,
+        SmsSender,
+    },
+    sql::migration,
+    state::{
+        State,
+        init_pool,
+    },
+    storage::Storage,
+};
+
+const ENV_PG_BIN: &str = "PIGSKIT_TEST_PG_BIN";
+const ENV_DATABASE_DSN: &str = "PIGSKIT_TEST_DATABASE_DSN";
+const TEST_CSRF_TOKEN: &str = "pigskit-test";
+
+enum TestDatabase {
+    /// A cluster owned by the test, stopped on drop.
+    Cluster {
+        pg_ctl: PathBuf,
+        data: PathBuf,
+        _dir: TempDir,
+    },
+    /// A database created in an external server, dropped on drop.
+    External {
+        dsn: String,
+        name: String,
+    },
+}
+
+fn pg_bin(name: &str) -> PathBuf {
+    if let Ok(dir) = env::var(ENV_PG_BIN) {
+        return Path::new(&dir).join(name)
+    }
+    let bindir = Command::new("pg_config")
+        .arg("--bindir")
+        .output()
+        .ok()
+        .filter(|output| output.status.success())
+        .and_then(|output| String::from_utf8(output.stdout).ok());
+    if let Some(bindir) = bindir {
+        let path = Path::new(bindir.trim()).join(name);
+        if path.exists() {
+            return path
+        }
+    }
+    PathBuf::from(name)
+}
+
+fn run(command: &mut Command) {
+    let output = command
+        .output()
+        .unwrap_or_else(|err| panic!("Failed to run {:?}: {}. Install Postgres or set {}.", command, err, ENV_DATABASE_DSN));
+    if !output.status.success() {
+        panic!(
+            "{:?} failed: {}{}",
+            command,
+            String::from_utf8_lossy(&output.stdout),
+            String::from_utf8_lossy(&output.stderr),
+        );
+    }
+}
+
+impl TestDatabase {
+    /// Returns the database and the connection string of it.
+    async fn create() -> (Self, String) {
+        if let Ok(dsn) = env::var(ENV_DATABASE_DSN) {
+            let name = format!("pigskit_test_{}", Uuid::new_v4().to_simple());
+            let (client, connection) = tokio_postgres::connect(&dsn, NoTls).await.expect("connect test server");
+            tokio::spawn(connection);
+            client.batch_execute(&format!("CREATE DATABASE {}", name)).await.expect("create test database");
+            let database_dsn = format!("{} dbname={}", dsn, name);
+            return (TestDatabase::External { dsn, name }, database_dsn)
+        }
+
+        let (database, socket) = TestDatabase::cluster(|_| String::new());
+        (database, format!("host={} user=postgres dbname=postgres", socket))
+    }
+
+    /// Start a cluster owned by the test, `configure` is called with the
+    /// directory of it before the start and returns extra server options.
+    /// Returns the cluster and its socket directory.
+    fn cluster<F: FnOnce(&Path) -> String>(configure: F) -> (Self, String) {
+        let dir = TempDir::new().expect("create temp dir");
+        let data = dir.path().join("data");
+        let socket = dir.path().to_str().expect("temp dir path").to_owned();
+        run(
+            Command::new(pg_bin("initdb"))
+                .arg("-D").arg(&data)
+                .args(["-A", "trust", "-U", "postgres", "--no-sync"])
+        );
+        let options = configure(dir.path());
+        let pg_ctl = pg_bin("pg_ctl");
+        run(
+            Command::new(&pg_ctl)
+                .arg("-D").arg(&data)
+                .arg("-l").arg(dir.path().join("postgres.log"))
+                .arg("-o").arg(format!("-k {} -c listen_addresses='' -c fsync=off {}", socket, options))
+                .args(["-w", "start"])
+        );
+        (TestDatabase::Cluster { pg_ctl, data, _dir: dir }, socket)
+    }
+}
+
+impl Drop for TestDatabase {
+    fn drop(&mut self) {
+        match self {
+            TestDatabase::Cluster { pg_ctl, data, .. } => {
+                let _ = Command::new(pg_ctl)
+                    .arg("-D").arg(data)
+                    .args(["-m", "immediate", "stop"])
+                    .output();
+            }
+            TestDatabase::External { dsn, name } => {
+                // Drop from another thread since we may be within the test's runtime.
+                let dsn = dsn.clone();
+                let name = name.clone();
+                let _ = std::thread::spawn(move || {
+                    let mut runtime = tokio::runtime::Builder::new()
+                        .basic_scheduler()
+                        .enable_all()
+                        .build()
+                        .expect("build runtime");
+                    runtime.block_on(async move {
+                        if let Ok((client, connection)) = tokio_postgres::connect(&dsn, NoTls).await {
+                            tokio::spawn(connection);
+                            let _ = client.batch_execute(&format!("DROP DATABASE IF EXISTS {} WITH (FORCE)", name)).await;
+                        }
+                    });
+                })
+                .join();
+            }
+        }
+    }
+}
+
+/// A self-signed certificate authority with the common name `name`.
+pub fn ca_certificate(name: &str) -> Certificate {
+    let mut params = CertificateParams::new(Vec::new());
+    params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+    params.distinguished_name = DistinguishedName::new();
+    params.distinguished_name.push(DnType::CommonName, name);
+    Certificate::from_params(params).expect("generate ca certificate")
+}
+
+/// Returns the certificate signed by `ca` and its private key in PEM.
+pub fn signed_certificate(ca: &Certificate, common_name: &str, subject_alt_names: Vec<String>) -> (String, String) {
+    let mut params = CertificateParams::new(subject_alt_names);
+    params.distinguished_name = DistinguishedName::new();
+    params.distinguished_name.push(DnType::CommonName, common_name);
+    let cert = Certificate::from_params(params).expect("generate certificate");
+    (cert.serialize_pem_with_signer(ca).expect("sign certificate"), cert.serialize_private_key_pem())
+}
+
+fn write_private(path: &Path, data: &str) {
+    fs::write(path, data).expect("write private key");
+    fs::set_permissions(path, fs::Permissions::from_mode(0o600)).expect("set key permissions");
+}
+
+/// A cluster owned by the test serving TLS on `localhost`, the server
+/// certificate is signed by a generated CA. `dir` holds the `ca.crt` of it,
+/// and `client.crt` and `client.key` signed by it for the user `postgres`.
+pub struct TlsCluster {
+    pub dsn: String,
+    pub dir: PathBuf,
+    _database: TestDatabase,
+}
+
+impl TlsCluster {
+    /// `None` if the tests use an external server, which the test cannot configure.
+    pub fn start() -> Option<Self> {
+        if env::var(ENV_DATABASE_DSN).is_ok() {
+            return None
+        }
+        let port = TcpListener::bind("127.0.0.1:0")
+            .and_then(|listener| listener.local_addr())
+            .expect("find a free port")
+            .port();
+        let (database, socket) = TestDatabase::cluster(|dir| {
+            let ca = ca_certificate("pigskit test ca");
+            fs::write(dir.join("ca.crt"), ca.serialize_pem().expect("serialize ca")).expect("write ca");
+            let (cert, key) = signed_certificate(&ca, "localhost", vec!["localhost".to_string()]);
+            fs::write(dir.join("server.crt"), cert).expect("write server certificate");
+            write_private(&dir.join("server.key"), &key);
+            let (cert, key) = signed_certificate(&ca, "postgres", Vec::new());
+            fs::write(dir.join("client.crt"), cert).expect("write client certificate");
+            write_private(&dir.join("client.key"), &key);
+            format!(
+                "-p {} -c listen_addresses=localhost -c ssl=on -c ssl_ca_file={1}/ca.crt \
+                -c ssl_cert_file={1}/server.crt -c ssl_key_file={1}/server.key",
+                port,
+                dir.display(),
+            )
+        });
+        Some(TlsCluster {
+            dsn: format!("host=localhost port={} user=postgres dbname=postgres", port),
+            dir: PathBuf::from(socket),
+            _database: database,
+        })
+    }
+}
+
+/// Keeps the text messages instead of sending them.
+#[derive(Clone, Default)]
+struct RecordingSender {
+    texts: Arc<Mutex<Vec<(String, String)>>>,
+}
+
+#[async_trait]
+impl SmsSender for RecordingSender {
+    async fn send(&self, to: &str, text: &str) -> Result<(), SmsError> {
+        self.texts.lock().unwrap().push((to.to_owned(), text.to_owned()));
+        Ok(())
+    }
+}
+
+pub struct TestServer {
+    pub state: State,
+    _storage: TempDir,
+    mail_dir: TempDir,
+    sms: RecordingSender,
+    // Dropped last, after the pool of `state`.
+    _database: TestDatabase,
+}
+
+impl TestServer {
+    pub async fn start() -> Self {
+        Self::start_with(|_| {}).await
+    }
+
+    /// Start with the test configuration changed by `configure`.
+    pub async fn start_with<F: FnOnce(&mut Config)>(configure: F) -> Self {
+        let (database, dsn) = TestDatabase::create().await;
+        let storage_dir = TempDir::new().expect("create storage dir");
+        let mail_dir = TempDir::new().expect("create mail dir");
+
+        let mut config = Config::defaults(true);
+        config.database.dsn = dsn;
+        config.database.pool_size = 4;
+        config.storage.root = storage_dir.path().to_str().expect("storage path").to_owned();
+        config.mail.backend = MailBackend::File;
+        config.mail.dir = Some(mail_dir.path().to_str().expect("mail path").to_owned());
+        configure(&mut config);
+        config.validate().expect("valid test config");
+
+        let db_pool = init_pool(&config.database).await.expect("init pool");
+        {
+            let mut conn = db_pool.get().await.expect("get connection");
+            migration::up(&mut conn).await.expect("apply migrations");
+        }
+
+        let storage = Storage::open(&config.storage).expect("open storage");
+        let mail = Mail::open(&config.mail).expect("open mail");
+        let sms = RecordingSender::default();
+        TestServer {
+            state: State::init(db_pool, storage, mail, Sms::new(Arc::new(sms.clone())), config),
+            sms,
+            _storage: storage_dir,
+            mail_dir,
+            _database: database,
+        }
+    }
+
+    /// The mails sent so far, oldest first.
+    pub fn mails(&self) -> Vec<String> {
+        let mut paths: Vec<PathBuf> = fs::read_dir(self.mail_dir.path())
+            .expect("read mail dir")
+            .map(|entry| entry.expect("read mail dir").path())
+            .filter(|path| path.extension().is_some_and(|extension| extension == "eml"))
+            .collect();
+        paths.sort();
+        paths.iter().map(|path| fs::read_to_string(path).expect("read mail")).collect()
+    }
+
+    /// The text messages sent so far and their recipients, oldest first.
+    pub fn texts(&self) -> Vec<(String, String)> {
+        self.sms.texts.lock().unwrap().clone()
+    }
+
+    pub fn routes(&self) -> BoxedFilter<(impl Reply,)> {
+        route::routes(self.state.clone())
+    }
+
+    /// Send the request as the web client would, echoing the CSRF token of
+    /// `session_cookie`. Use `routes` to send it as is.
+    pub async fn send(&self, request: RequestBuilder) -> Response<Bytes> {
+        self.send_in(RequestContext::new("test".to_string(), None, None), request).await
+    }
+
+    /// Send the request within `context`, as the server does.
+    pub async fn send_in(&self, context: Arc<RequestContext>, request: RequestBuilder) -> Response<Bytes> {
+        let routes = self.routes();
+        let request = request
+            .header("x-csrf-token", TEST_CSRF_TOKEN)
+            .reply(&routes);
+        context.scope(request).await
+    }
+}
+
+/// A `Cookie` header with the session and a CSRF token.
+pub fn session_cookie(name: &str, value: &str) -> String {
+    format!("{}={}; CSRF={}", name, value, TEST_CSRF_TOKEN)
+}
+
+/// The value of the cookie set by the response.
+pub fn cookie(response: &Response<Bytes>, name: &str) -> Option<String> {
+    let prefix = format!("{}=", name);
+    response.headers()
+        .get_all("set-cookie")
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .find(|value| value.starts_with(&prefix))
+        .map(|value| value[prefix.len()..].split(';').next().unwrap_or("").to_owned())
+}
+
+/// The `type` of the error in the response body.
+pub fn error_type(body: &[u8]) -> String {
+    let body: serde_json::Value = serde_json::from_slice(body).unwrap();
+    body["type"].as_str().unwrap().to_owned()
+}
+
+/// Encode the parts as a `multipart/form-data` body, returns the content type and the body.
+pub fn multipart(parts: &[(&str, &[u8])]) -> (String, Vec<u8>) {
+    let boundary = format!("pigskit-{}", Uuid::new_v4().to_simple());
+    let mut body = Vec::new();
+    for (name, data) in parts {
+        body.extend_from_slice(format!("--{}\r\n", boundary).as_bytes());
+        body.extend_from_slice(format!("Content-Disposition: form-data; name=\"{}\"; filename=\"{}\"\r\n", name, name).as_bytes());
+        // Images are sent with their type, as a browser would.
+        if let Ok(format) = image::guess_format(data) {
+            body.extend_from_slice(format!("Content-Type: {}\r\n", format.to_mime_type()).as_bytes());
+        }
+        body.extend_from_slice(b"\r\n");
+        body.extend_from_slice(data);
+        body.extend_from_slice(b"\r\n");
+    }
+    body.extend_from_slice(format!("--{}--\r\n", boundary).as_bytes());
+    (format!("multipart/form-data; boundary={}", boundary), body)
+}
+
+/// A blank PNG image.
+pub fn png(width: u32, height: u32) -> Vec<u8> {
+    let mut data = Vec::new();
+    image::DynamicImage::ImageRgba8(image::RgbaImage::new(width, height))
+        .write_to(&mut std::io::Cursor::new(&mut data), image::ImageFormat::Png)
+        .expect("encode png");
+    data
+}